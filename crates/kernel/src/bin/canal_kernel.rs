@@ -0,0 +1,69 @@
+//! Runs a Canal kernel.
+//!
+//! Usage: canal_kernel jupyter <kernelspec> <connection-file>
+//!
+//! The `jupyter` mode runs the interpreter of a kernelspec found from the current directory, and
+//! serves it to Jupyter frontends as a Jupyter kernel. It is installed for Jupyter with a
+//! kernel.json such as:
+//!
+//! ```json
+//! {
+//!   "display_name": "Bash (Canal)",
+//!   "language": "bash",
+//!   "argv": ["canal_kernel", "jupyter", "bash", "{connection_file}"]
+//! }
+//! ```
+
+use std::{env, error::Error, path::Path, process::ExitCode};
+
+use canal_kernel::{
+    jupyter::{
+        server::{self, ServerConfig},
+        ConnectionInfo,
+    },
+    kernel::KernelOptions,
+    kernelspec::{KernelRegistry, KernelSpecError},
+};
+
+const USAGE: &str = "Usage: canal_kernel jupyter <kernelspec> <connection-file>";
+
+/// Number of executions a frontend can queue before the kernel stops reading its requests
+const QUEUE_CAPACITY: usize = 64;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let [mode, name, connection_file] = args.as_slice() else {
+        eprintln!("{USAGE}");
+        return ExitCode::FAILURE;
+    };
+
+    let served = match mode.as_str() {
+        "jupyter" => serve_jupyter(name, Path::new(connection_file)).await,
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match served {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn serve_jupyter(name: &str, connection_file: &Path) -> Result<(), Box<dyn Error>> {
+    let connection = ConnectionInfo::load(connection_file)?;
+    let registry = KernelRegistry::discover(&env::current_dir()?);
+    let entry = registry
+        .get(name)
+        .ok_or_else(|| KernelSpecError::NotFound(name.to_string()))?;
+
+    let (terminal, _queue) = registry.launch(name, KernelOptions::new(QUEUE_CAPACITY))?;
+    let config = ServerConfig::new(connection, &entry.spec.language).interrupt_on_sigint(true);
+
+    Ok(server::serve(config, terminal).await?)
+}
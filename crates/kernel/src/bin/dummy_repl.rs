@@ -1 +1,29 @@
-fn main() {}
+//! Process standing in for an interpreter in tests. It exits at once, after trying to open each
+//! path given as argument for reading and for writing, which it reports on stdout as
+//! `<path>: read <ok|denied>, write <ok|denied>`.
+//!
+//! Usage: `dummy_repl [<path>...]`
+
+use std::{env, fs::OpenOptions};
+
+fn main() {
+    for path in env::args_os().skip(1) {
+        let read = OpenOptions::new().read(true).open(&path);
+        let write = OpenOptions::new().append(true).create(true).open(&path);
+
+        println!(
+            "{}: read {}, write {}",
+            path.to_string_lossy(),
+            outcome(read.is_ok()),
+            outcome(write.is_ok()),
+        );
+    }
+}
+
+fn outcome(ok: bool) -> &'static str {
+    if ok {
+        "ok"
+    } else {
+        "denied"
+    }
+}
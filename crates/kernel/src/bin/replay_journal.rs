@@ -0,0 +1,83 @@
+//! Prints the transcript of every session recorded in a kernel journal directory.
+//!
+//! Usage: replay_journal <journal-dir>
+
+use std::{env, path::PathBuf, process::ExitCode, time::SystemTime};
+
+use canal_kernel::journal::{self, Transcript};
+
+fn main() -> ExitCode {
+    let Some(dir) = env::args_os().nth(1).map(PathBuf::from) else {
+        eprintln!("Usage: replay_journal <journal-dir>");
+        return ExitCode::FAILURE;
+    };
+
+    match journal::read_transcripts(&dir) {
+        Ok(transcripts) => {
+            for (index, transcript) in transcripts.iter().enumerate() {
+                print_transcript(index, transcript);
+            }
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("{err}: {}", dir.display());
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn print_transcript(index: usize, transcript: &Transcript) {
+    println!(
+        "# Session {index} started at {}",
+        unix_time(transcript.started_at)
+    );
+
+    for cell in &transcript.cells {
+        let language = cell
+            .language
+            .as_deref()
+            .map(|language| format!(" in {language}"))
+            .unwrap_or_default();
+
+        println!();
+        println!(
+            "## [{}] message {}{language} from client {} ({:?}{}, {:?})",
+            unix_time(cell.submitted_at),
+            cell.message_id,
+            cell.client_id,
+            cell.lane,
+            if cell.independent {
+                ", independent"
+            } else {
+                ""
+            },
+            cell.status,
+        );
+
+        for line in cell.code.lines() {
+            println!("    {line}");
+        }
+
+        if let Some(output) = &cell.output {
+            println!("output: {} bytes, sha256 {}", output.bytes, output.sha256);
+        }
+    }
+
+    for interrupt in &transcript.interrupts {
+        println!("interrupted at {}", unix_time(*interrupt));
+    }
+
+    println!();
+}
+
+fn unix_time(time: SystemTime) -> String {
+    let since_epoch = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+
+    format!(
+        "{}.{:06}",
+        since_epoch.as_secs(),
+        since_epoch.subsec_micros()
+    )
+}
@@ -0,0 +1,217 @@
+//! Jupyter kernel answering from a script instead of running code, for tests:
+//! - `sleep` runs until the kernel is interrupted, by message or by `SIGINT`
+//! - `raise` fails with a `ValueError`
+//! - `display` displays data and `result` returns `42`
+//! - `warn` writes to stderr
+//! - `spawn` starts a `sleep` in the background and writes its pid
+//! - `exit` makes the kernel exit
+//! - Anything else is written back to stdout
+//!
+//! Usage: `stub_jupyter_kernel <connection file>`
+
+use std::{
+    env,
+    path::Path,
+    process::{self, Command},
+};
+
+use canal_kernel::jupyter::{ConnectionInfo, JupyterMessage, Session};
+use serde_json::{json, Value};
+use tokio::signal::unix::{signal, SignalKind};
+use zeromq::{PubSocket, RouterSocket, Socket, SocketRecv, SocketSend};
+
+struct StubKernel {
+    session: Session,
+    shell: RouterSocket,
+    iopub: PubSocket,
+    execution_count: u64,
+    /// `execute_request` waiting for an interrupt
+    sleeping: Option<JupyterMessage>,
+}
+
+#[tokio::main]
+async fn main() {
+    let path = env::args()
+        .nth(1)
+        .expect("Usage: stub_jupyter_kernel <connection file>");
+    let connection = ConnectionInfo::load(Path::new(&path)).expect("Connection file is invalid");
+    let session = connection
+        .session("stub")
+        .expect("Signature scheme is unsupported");
+
+    let mut shell = RouterSocket::new();
+    let mut control = RouterSocket::new();
+    let mut iopub = PubSocket::new();
+    shell
+        .bind(&connection.endpoint(connection.shell_port))
+        .await
+        .unwrap();
+    control
+        .bind(&connection.endpoint(connection.control_port))
+        .await
+        .unwrap();
+    iopub
+        .bind(&connection.endpoint(connection.iopub_port))
+        .await
+        .unwrap();
+
+    let mut sigint = signal(SignalKind::interrupt()).unwrap();
+    let mut kernel = StubKernel {
+        session,
+        shell,
+        iopub,
+        execution_count: 0,
+        sleeping: None,
+    };
+
+    loop {
+        tokio::select! {
+            message = kernel.shell.recv() => {
+                let Ok(message) = kernel.session.decode(message.unwrap()) else {
+                    continue;
+                };
+                kernel.handle_shell(message).await;
+            }
+            message = control.recv() => {
+                let Ok(message) = kernel.session.decode(message.unwrap()) else {
+                    continue;
+                };
+                if message.msg_type() == "interrupt_request" {
+                    kernel.interrupt().await;
+                    let reply = kernel.session.reply(&message, "interrupt_reply", json!({"status": "ok"}));
+                    control.send(kernel.session.encode(&reply)).await.unwrap();
+                }
+            }
+            _ = sigint.recv() => kernel.interrupt().await,
+        }
+    }
+}
+
+impl StubKernel {
+    async fn handle_shell(&mut self, request: JupyterMessage) {
+        match request.msg_type() {
+            "kernel_info_request" => {
+                self.publish(&request, "status", json!({"execution_state": "busy"}))
+                    .await;
+                let content = json!({
+                    "status": "ok",
+                    "protocol_version": "5.3",
+                    "implementation": "stub",
+                    "implementation_version": "0.1.0",
+                    "language_info": {"name": "stub", "version": "0.1.0", "file_extension": ".txt"},
+                    "banner": "Stub kernel",
+                });
+                self.reply(&request, "kernel_info_reply", content).await;
+                self.publish(&request, "status", json!({"execution_state": "idle"}))
+                    .await;
+            }
+            "execute_request" => self.execute(request).await,
+            _ => {}
+        }
+    }
+
+    async fn execute(&mut self, request: JupyterMessage) {
+        self.execution_count += 1;
+        let code = request.content["code"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+
+        self.publish(&request, "status", json!({"execution_state": "busy"}))
+            .await;
+        self.publish(
+            &request,
+            "execute_input",
+            json!({"code": code, "execution_count": self.execution_count}),
+        )
+        .await;
+
+        match code.as_str() {
+            "sleep" => {
+                self.sleeping = Some(request);
+                return;
+            }
+            "raise" => {
+                let error = json!({
+                    "ename": "ValueError",
+                    "evalue": "bad value",
+                    "traceback": ["Traceback (most recent call last):", "ValueError: bad value"],
+                });
+                self.publish(&request, "error", error.clone()).await;
+                self.finish(&request, error).await;
+                return;
+            }
+            "display" => {
+                let content = json!({"data": {"text/plain": "<Figure>", "image/png": "iVBORw0KGgo="}, "metadata": {}});
+                self.publish(&request, "display_data", content).await;
+            }
+            "result" => {
+                let content = json!({
+                    "execution_count": self.execution_count,
+                    "data": {"text/plain": "42"},
+                    "metadata": {},
+                });
+                self.publish(&request, "execute_result", content).await;
+            }
+            "warn" => {
+                self.publish(
+                    &request,
+                    "stream",
+                    json!({"name": "stderr", "text": "warning\n"}),
+                )
+                .await;
+            }
+            "spawn" => {
+                let output = Command::new("sh")
+                    .args(["-c", "sleep 300 >/dev/null 2>&1 & echo $!"])
+                    .output()
+                    .unwrap();
+                let text = String::from_utf8_lossy(&output.stdout);
+                let content = json!({"name": "stdout", "text": text});
+                self.publish(&request, "stream", content).await;
+            }
+            "exit" => process::exit(1),
+            _ => {
+                let content = json!({"name": "stdout", "text": format!("{code}\n")});
+                self.publish(&request, "stream", content).await;
+            }
+        }
+
+        self.finish(&request, json!({})).await;
+    }
+
+    async fn interrupt(&mut self) {
+        let Some(request) = self.sleeping.take() else {
+            return;
+        };
+
+        let error =
+            json!({"ename": "KeyboardInterrupt", "evalue": "", "traceback": ["KeyboardInterrupt"]});
+        self.publish(&request, "error", error.clone()).await;
+        self.finish(&request, error).await;
+    }
+
+    /// Replies to `request`, failed with `error` unless it is empty, and goes back to idle
+    async fn finish(&mut self, request: &JupyterMessage, error: Value) {
+        let mut content = json!({"status": "ok", "execution_count": self.execution_count});
+        if let Some(error) = error.as_object().filter(|error| !error.is_empty()) {
+            content["status"] = json!("error");
+            content.as_object_mut().unwrap().extend(error.clone());
+        }
+
+        self.reply(request, "execute_reply", content).await;
+        self.publish(request, "status", json!({"execution_state": "idle"}))
+            .await;
+    }
+
+    async fn reply(&mut self, request: &JupyterMessage, msg_type: &str, content: Value) {
+        let reply = self.session.reply(request, msg_type, content);
+        self.shell.send(self.session.encode(&reply)).await.unwrap();
+    }
+
+    async fn publish(&mut self, request: &JupyterMessage, msg_type: &str, content: Value) {
+        let mut message = self.session.reply(request, msg_type, content);
+        message.identities.clear();
+        let _ = self.iopub.send(self.session.encode(&message)).await;
+    }
+}
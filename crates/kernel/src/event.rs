@@ -0,0 +1,197 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::{monitor::UsageSample, output::Output, ClientId, MessageId};
+
+/// Number of events kept for a subscriber that reads slower than the kernel publishes
+pub const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+/// Number of recent events kept for subscribers that resume after a disconnection
+pub const REPLAY_BUFFER_CAPACITY: usize = 4096;
+
+/// Position of an event among all the events published by a kernel, starting from 0
+pub type Seq = u64;
+
+/// Everything that happens to an execution, published to every subscriber of the kernel
+/// regardless of which client submitted it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KernelEvent {
+    /// `None` for usage samples, which are not kept for replay
+    pub seq: Option<Seq>,
+    /// Id of the `Execute` request the event belongs to, `None` for usage samples
+    pub parent: Option<MessageId>,
+    /// Id of the client that submitted the `Execute` request, `None` for usage samples
+    pub client_id: Option<ClientId>,
+    pub content: EventContent,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventContent {
+    Status(ExecStatus),
+    Output(Output),
+    /// Resources used by the interpreter, sampled by the monitor of a `KernelManager`. It
+    /// belongs to the kernel rather than to one of its executions, and is only received live.
+    Usage(UsageSample),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExecStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+    TimedOut,
+    /// The interpreter was killed for exceeding a resource limit
+    LimitExceeded,
+}
+
+/// Receives the events of a kernel in order, starting with the replayed ones
+pub struct EventSubscription {
+    replay: VecDeque<KernelEvent>,
+    live: broadcast::Receiver<KernelEvent>,
+    history: Arc<Mutex<History>>,
+    next_seq: Seq,
+}
+
+impl EventSubscription {
+    /// Returns `None` once the kernel is gone.
+    ///
+    /// A subscriber that falls behind the live stream catches up from the replay buffer, so
+    /// events are only skipped when they are older than the buffer. Such a gap is visible as a
+    /// jump in `KernelEvent::seq`. Usage samples missed meanwhile are lost.
+    pub async fn recv(&mut self) -> Option<KernelEvent> {
+        loop {
+            if let Some(event) = self.replay.pop_front() {
+                self.next_seq = event.seq.map_or(self.next_seq, |seq| seq + 1);
+                return Some(event);
+            }
+
+            match self.live.recv().await {
+                Ok(event) => match event.seq {
+                    Some(seq) if seq < self.next_seq => continue,
+                    Some(seq) => {
+                        self.next_seq = seq + 1;
+                        return Some(event);
+                    }
+                    None => return Some(event),
+                },
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    self.replay = lock(&self.history).since(self.next_seq);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Publisher {
+    sender: broadcast::Sender<KernelEvent>,
+    history: Arc<Mutex<History>>,
+}
+
+struct History {
+    next_seq: Seq,
+    events: VecDeque<KernelEvent>,
+}
+
+impl History {
+    fn since(&self, seq: Seq) -> VecDeque<KernelEvent> {
+        self.events
+            .iter()
+            .filter(|event| event.seq.is_some_and(|event_seq| event_seq >= seq))
+            .cloned()
+            .collect()
+    }
+}
+
+impl Publisher {
+    pub(crate) fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        let history = History {
+            next_seq: 0,
+            events: VecDeque::with_capacity(REPLAY_BUFFER_CAPACITY),
+        };
+
+        Self {
+            sender,
+            history: Arc::new(Mutex::new(history)),
+        }
+    }
+
+    pub(crate) fn subscribe(&self) -> EventSubscription {
+        let history = lock(&self.history);
+
+        EventSubscription {
+            replay: VecDeque::new(),
+            live: self.sender.subscribe(),
+            history: self.history.clone(),
+            next_seq: history.next_seq,
+        }
+    }
+
+    pub(crate) fn resume(&self, from: Seq) -> EventSubscription {
+        // Holding the history lock while subscribing guarantees that no event is published
+        // between the replayed ones and the live ones
+        let history = lock(&self.history);
+
+        EventSubscription {
+            replay: history.since(from),
+            live: self.sender.subscribe(),
+            history: self.history.clone(),
+            next_seq: from,
+        }
+    }
+
+    pub(crate) fn publish(&self, parent: MessageId, client_id: ClientId, content: EventContent) {
+        let mut history = lock(&self.history);
+
+        let event = KernelEvent {
+            seq: Some(history.next_seq),
+            parent: Some(parent),
+            client_id: Some(client_id),
+            content,
+        };
+        history.next_seq += 1;
+
+        if history.events.len() == REPLAY_BUFFER_CAPACITY {
+            history.events.pop_front();
+        }
+        history.events.push_back(event.clone());
+
+        // Having no subscriber is not an error, the events are simply not observed
+        let _ = self.sender.send(event);
+    }
+
+    pub(crate) fn publish_status(
+        &self,
+        parent: MessageId,
+        client_id: ClientId,
+        status: ExecStatus,
+    ) {
+        self.publish(parent, client_id, EventContent::Status(status));
+    }
+
+    /// Publishes a usage sample to the current subscribers only, so that samples never push
+    /// the events of executions out of the replay buffer
+    pub(crate) fn publish_usage(&self, sample: UsageSample) {
+        let event = KernelEvent {
+            seq: None,
+            parent: None,
+            client_id: None,
+            content: EventContent::Usage(sample),
+        };
+
+        let _ = self.sender.send(event);
+    }
+}
+
+fn lock(history: &Mutex<History>) -> MutexGuard<'_, History> {
+    history.lock().expect("Event history is poisoned")
+}
@@ -0,0 +1,407 @@
+use std::{
+    fs,
+    io::{self, Cursor},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{mpsc, oneshot},
+    task,
+};
+
+use crate::{
+    event::{ExecStatus, Seq},
+    output::OutputSummary,
+    queue::Lane,
+    ClientId, KernelRequest, KernelResponse, MessageId,
+};
+
+const FILE_PREFIX: &str = "journal-";
+const FILE_EXTENSION: &str = "msgpack";
+
+pub struct JournalConfig {
+    pub dir: PathBuf,
+    /// A new file is started once the current one reaches this size
+    pub max_file_size: u64,
+}
+
+/// Append-only record of everything a kernel was asked to do and what it answered.
+///
+/// Entries are written as consecutive msgpack values to `journal-<index>.msgpack` files in the
+/// configured directory. A disabled journal (the default) drops every record.
+#[derive(Clone, Default)]
+pub struct Journal {
+    command_sender: Option<mpsc::UnboundedSender<WriterCommand>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub timestamp: SystemTime,
+    pub record: JournalRecord,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum JournalRecord {
+    /// First record written by a kernel
+    SessionStarted,
+    Execute {
+        message_id: MessageId,
+        client_id: ClientId,
+        code: String,
+        lane: Lane,
+        #[serde(default)]
+        language: Option<String>,
+        #[serde(default)]
+        independent: bool,
+    },
+    Interrupt,
+    Subscribe {
+        resume_from: Option<Seq>,
+    },
+    QueueStatus,
+    /// Digest of everything the REPL wrote for an execution, before any truncation
+    Output {
+        message_id: MessageId,
+        client_id: ClientId,
+        summary: OutputSummary,
+    },
+    Response {
+        client_id: Option<ClientId>,
+        response: KernelResponse,
+    },
+}
+
+impl From<&KernelRequest> for JournalRecord {
+    fn from(request: &KernelRequest) -> Self {
+        match request {
+            KernelRequest::Execute {
+                message_id,
+                client_id,
+                code,
+                lane,
+                language,
+                independent,
+                ..
+            } => JournalRecord::Execute {
+                message_id: *message_id,
+                client_id: *client_id,
+                code: code.clone(),
+                lane: *lane,
+                language: language.clone(),
+                independent: *independent,
+            },
+            KernelRequest::Interrupt => JournalRecord::Interrupt,
+            KernelRequest::Subscribe { resume_from, .. } => JournalRecord::Subscribe {
+                resume_from: *resume_from,
+            },
+            KernelRequest::QueueStatus => JournalRecord::QueueStatus,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum JournalError {
+    #[error("Journal could not be accessed")]
+    Io(#[from] io::Error),
+    #[error("Journal file {0} is corrupted")]
+    Corrupted(PathBuf, #[source] rmp_serde::decode::Error),
+}
+
+enum WriterCommand {
+    Write(JournalEntry),
+    Sync(oneshot::Sender<()>),
+}
+
+impl Journal {
+    /// Starts a new session in a new file of `config.dir`
+    pub async fn open(config: JournalConfig) -> Result<Self, JournalError> {
+        tokio::fs::create_dir_all(&config.dir).await?;
+
+        let index = journal_files(&config.dir)?
+            .last()
+            .map_or(0, |(index, _)| index + 1);
+        let writer = Writer::create(config, index).await?;
+
+        let (command_sender, command_receiver) = mpsc::unbounded_channel();
+        task::spawn(writer.run(command_receiver));
+
+        let journal = Self {
+            command_sender: Some(command_sender),
+        };
+        journal.record(JournalRecord::SessionStarted);
+
+        Ok(journal)
+    }
+
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.command_sender.is_some()
+    }
+
+    /// Waits until every record sent so far is written
+    pub async fn sync(&self) {
+        let Some(command_sender) = &self.command_sender else {
+            return;
+        };
+
+        let (done_sender, done_receiver) = oneshot::channel();
+        if command_sender
+            .send(WriterCommand::Sync(done_sender))
+            .is_ok()
+        {
+            let _ = done_receiver.await;
+        }
+    }
+
+    pub(crate) fn record(&self, record: JournalRecord) {
+        if let Some(command_sender) = &self.command_sender {
+            let entry = JournalEntry {
+                timestamp: SystemTime::now(),
+                record,
+            };
+            let _ = command_sender.send(WriterCommand::Write(entry));
+        }
+    }
+}
+
+struct Writer {
+    config: JournalConfig,
+    index: u32,
+    file: tokio::fs::File,
+    file_size: u64,
+}
+
+impl Writer {
+    async fn create(config: JournalConfig, index: u32) -> io::Result<Self> {
+        let file = tokio::fs::File::create(journal_path(&config.dir, index)).await?;
+
+        Ok(Self {
+            config,
+            index,
+            file,
+            file_size: 0,
+        })
+    }
+
+    async fn run(mut self, mut command_receiver: mpsc::UnboundedReceiver<WriterCommand>) {
+        while let Some(command) = command_receiver.recv().await {
+            match command {
+                WriterCommand::Write(entry) => {
+                    // A record that cannot be written is lost, the kernel keeps running
+                    let _ = self.write(&entry).await;
+                }
+                WriterCommand::Sync(done_sender) => {
+                    let _ = self.file.sync_data().await;
+                    let _ = done_sender.send(());
+                }
+            }
+        }
+    }
+
+    async fn write(&mut self, entry: &JournalEntry) -> io::Result<()> {
+        let data = rmp_serde::to_vec_named(entry)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        if self.file_size > 0 && self.file_size + data.len() as u64 > self.config.max_file_size {
+            self.rotate().await?;
+        }
+
+        self.file.write_all(&data).await?;
+        self.file.flush().await?;
+        self.file_size += data.len() as u64;
+
+        Ok(())
+    }
+
+    async fn rotate(&mut self) -> io::Result<()> {
+        self.file.sync_data().await?;
+
+        self.index += 1;
+        self.file = tokio::fs::File::create(journal_path(&self.config.dir, self.index)).await?;
+        self.file_size = 0;
+
+        Ok(())
+    }
+}
+
+/// Reads every entry of the journal in `dir`, oldest first.
+///
+/// The last file may end with an entry that was being written when the kernel stopped; such an
+/// entry is ignored, while any other unreadable entry is an error.
+pub fn read_entries(dir: &Path) -> Result<Vec<JournalEntry>, JournalError> {
+    let files = journal_files(dir)?;
+    let mut entries = Vec::new();
+
+    for (position, (_, path)) in files.iter().enumerate() {
+        let is_last_file = position + 1 == files.len();
+        let data = fs::read(path)?;
+        let mut cursor = Cursor::new(data.as_slice());
+
+        while (cursor.position() as usize) < data.len() {
+            match rmp_serde::from_read(&mut cursor) {
+                Ok(entry) => entries.push(entry),
+                Err(err) if is_last_file && is_truncated(&err) => break,
+                Err(err) => return Err(JournalError::Corrupted(path.clone(), err)),
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Whether the data ended in the middle of the entry
+fn is_truncated(err: &rmp_serde::decode::Error) -> bool {
+    match err {
+        rmp_serde::decode::Error::InvalidMarkerRead(err)
+        | rmp_serde::decode::Error::InvalidDataRead(err) => {
+            err.kind() == io::ErrorKind::UnexpectedEof
+        }
+        _ => false,
+    }
+}
+
+/// What happened during one kernel session, as rebuilt from its journal
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transcript {
+    pub started_at: SystemTime,
+    pub cells: Vec<TranscriptCell>,
+    pub interrupts: Vec<SystemTime>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TranscriptCell {
+    pub message_id: MessageId,
+    pub client_id: ClientId,
+    pub code: String,
+    pub lane: Lane,
+    /// Language of the cell in a polyglot kernel
+    pub language: Option<String>,
+    /// Run on a worker of the kernel rather than in order
+    pub independent: bool,
+    pub submitted_at: SystemTime,
+    pub finished_at: Option<SystemTime>,
+    /// `ExecStatus::Queued` when the journal ends before the execution was answered
+    pub status: ExecStatus,
+    pub output: Option<OutputSummary>,
+}
+
+/// Rebuilds the transcript of every session recorded in `dir`, oldest first
+pub fn read_transcripts(dir: &Path) -> Result<Vec<Transcript>, JournalError> {
+    Ok(transcripts(read_entries(dir)?))
+}
+
+pub fn transcripts(entries: impl IntoIterator<Item = JournalEntry>) -> Vec<Transcript> {
+    let mut transcripts: Vec<Transcript> = Vec::new();
+
+    for JournalEntry { timestamp, record } in entries {
+        if let JournalRecord::SessionStarted = record {
+            transcripts.push(Transcript {
+                started_at: timestamp,
+                cells: Vec::new(),
+                interrupts: Vec::new(),
+            });
+            continue;
+        }
+
+        let Some(transcript) = transcripts.last_mut() else {
+            continue;
+        };
+
+        match record {
+            JournalRecord::Execute {
+                message_id,
+                client_id,
+                code,
+                lane,
+                language,
+                independent,
+            } => transcript.cells.push(TranscriptCell {
+                message_id,
+                client_id,
+                code,
+                lane,
+                language,
+                independent,
+                submitted_at: timestamp,
+                finished_at: None,
+                status: ExecStatus::Queued,
+                output: None,
+            }),
+            JournalRecord::Interrupt => transcript.interrupts.push(timestamp),
+            JournalRecord::Output {
+                message_id,
+                summary,
+                ..
+            } => {
+                if let Some(cell) = transcript.pending_cell(message_id) {
+                    cell.output = Some(summary);
+                }
+            }
+            JournalRecord::Response { response, .. } => {
+                let (message_id, status) = match response {
+                    KernelResponse::Success(message_id) => (message_id, ExecStatus::Succeeded),
+                    KernelResponse::Failed(message_id) => (message_id, ExecStatus::Failed),
+                    KernelResponse::Cancelled(message_id) => (message_id, ExecStatus::Cancelled),
+                    KernelResponse::TimedOut(message_id) => (message_id, ExecStatus::TimedOut),
+                    KernelResponse::LimitExceeded(message_id, _) => {
+                        (message_id, ExecStatus::LimitExceeded)
+                    }
+                    KernelResponse::QueueStatus(_) => continue,
+                };
+
+                if let Some(cell) = transcript.pending_cell(message_id) {
+                    cell.status = status;
+                    cell.finished_at = Some(timestamp);
+                }
+            }
+            JournalRecord::SessionStarted
+            | JournalRecord::Subscribe { .. }
+            | JournalRecord::QueueStatus => {}
+        }
+    }
+
+    transcripts
+}
+
+impl Transcript {
+    /// Message ids may be reused by clients, so the oldest unanswered cell is the one meant
+    fn pending_cell(&mut self, message_id: MessageId) -> Option<&mut TranscriptCell> {
+        self.cells
+            .iter_mut()
+            .find(|cell| cell.message_id == message_id && cell.finished_at.is_none())
+    }
+}
+
+fn journal_path(dir: &Path, index: u32) -> PathBuf {
+    dir.join(format!("{FILE_PREFIX}{index:06}.{FILE_EXTENSION}"))
+}
+
+/// Journal files of `dir` sorted by index
+fn journal_files(dir: &Path) -> io::Result<Vec<(u32, PathBuf)>> {
+    let mut files = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let index = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(FILE_PREFIX))
+            .and_then(|name| name.strip_suffix(FILE_EXTENSION))
+            .and_then(|name| name.strip_suffix('.'))
+            .and_then(|index| index.parse().ok());
+
+        if let Some(index) = index {
+            files.push((index, path));
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
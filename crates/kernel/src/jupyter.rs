@@ -0,0 +1,313 @@
+//! Jupyter messaging protocol 5.3 over ZeroMQ, shared by the REPL driving Jupyter kernels and the
+//! Jupyter kernel serving Canal kernels to Jupyter frontends
+
+use std::{
+    fs,
+    io::{self, Write},
+    net::TcpListener,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use thiserror::Error;
+use uuid::Uuid;
+use zeromq::ZmqMessage;
+
+pub mod server;
+
+pub const PROTOCOL_VERSION: &str = "5.3";
+
+/// Separates the routing identities from the message
+const DELIMITER: &[u8] = b"<IDS|MSG>";
+
+const SIGNATURE_SCHEME: &str = "hmac-sha256";
+
+/// Content of a Jupyter connection file, telling where the sockets of a kernel are and how its
+/// messages are signed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    pub transport: String,
+    pub ip: String,
+    pub shell_port: u16,
+    pub iopub_port: u16,
+    pub stdin_port: u16,
+    pub control_port: u16,
+    pub hb_port: u16,
+    /// Empty when messages are not signed
+    pub key: String,
+    pub signature_scheme: String,
+    #[serde(default)]
+    pub kernel_name: String,
+}
+
+/// Header of a message, also sent as the parent header of the messages answering it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Header {
+    pub msg_id: String,
+    pub session: String,
+    pub username: String,
+    pub date: String,
+    pub msg_type: String,
+    pub version: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JupyterMessage {
+    /// Routing identities of the peer, only set on messages of ROUTER sockets
+    pub identities: Vec<Bytes>,
+    pub header: Header,
+    /// Empty object when the message answers nothing
+    pub parent_header: Value,
+    pub metadata: Value,
+    pub content: Value,
+    pub buffers: Vec<Bytes>,
+}
+
+#[derive(Error, Debug)]
+pub enum JupyterError {
+    #[error("Jupyter socket failed")]
+    Socket(#[from] zeromq::ZmqError),
+    #[error("Jupyter message has no delimiter")]
+    MissingDelimiter,
+    #[error("Jupyter message is missing frames")]
+    MissingFrames,
+    #[error("Jupyter message has an invalid signature")]
+    InvalidSignature,
+    #[error("Jupyter message is not valid JSON")]
+    Json(#[from] serde_json::Error),
+    #[error("Jupyter signature scheme {0} is not supported")]
+    UnsupportedScheme(String),
+    #[error("Jupyter kernel did not answer in time")]
+    Timeout,
+    #[error("Jupyter kernel I/O failed")]
+    Io(#[from] io::Error),
+}
+
+/// Creates and signs the messages of one side of a connection
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub id: String,
+    pub username: String,
+    key: Vec<u8>,
+}
+
+impl ConnectionInfo {
+    /// Picks free TCP ports on `ip` and a random signing key
+    pub fn allocate(ip: &str) -> io::Result<Self> {
+        // The listeners are kept until every port is picked, so that no port is picked twice
+        let listeners = (0..5)
+            .map(|_| TcpListener::bind((ip, 0)))
+            .collect::<io::Result<Vec<_>>>()?;
+        let ports = listeners
+            .iter()
+            .map(|listener| listener.local_addr().map(|addr| addr.port()))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            transport: "tcp".to_string(),
+            ip: ip.to_string(),
+            shell_port: ports[0],
+            iopub_port: ports[1],
+            stdin_port: ports[2],
+            control_port: ports[3],
+            hb_port: ports[4],
+            key: Uuid::new_v4().to_string(),
+            signature_scheme: SIGNATURE_SCHEME.to_string(),
+            kernel_name: String::new(),
+        })
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let content = fs::read(path)?;
+
+        serde_json::from_slice(&content)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Writes a new file that only its owner can read, since the key lets anyone sign messages
+    /// to the kernel
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let content = serde_json::to_vec_pretty(self)?;
+
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?
+            .write_all(&content)
+    }
+
+    /// Address of `port`, such as `tcp://127.0.0.1:5555`
+    pub fn endpoint(&self, port: u16) -> String {
+        format!("{}://{}:{}", self.transport, self.ip, port)
+    }
+
+    pub fn session(&self, username: &str) -> Result<Session, JupyterError> {
+        if !self.key.is_empty() && self.signature_scheme != SIGNATURE_SCHEME {
+            return Err(JupyterError::UnsupportedScheme(
+                self.signature_scheme.clone(),
+            ));
+        }
+
+        Ok(Session {
+            id: Uuid::new_v4().to_string(),
+            username: username.to_string(),
+            key: self.key.as_bytes().to_vec(),
+        })
+    }
+}
+
+impl Session {
+    /// New message answering nothing
+    pub fn message(&self, msg_type: &str, content: Value) -> JupyterMessage {
+        JupyterMessage {
+            identities: Vec::new(),
+            header: self.header(msg_type),
+            parent_header: json!({}),
+            metadata: json!({}),
+            content,
+            buffers: Vec::new(),
+        }
+    }
+
+    /// New message answering `parent`, routed back to the peer that sent it
+    pub fn reply(&self, parent: &JupyterMessage, msg_type: &str, content: Value) -> JupyterMessage {
+        JupyterMessage {
+            identities: parent.identities.clone(),
+            parent_header: serde_json::to_value(&parent.header).unwrap_or_else(|_| json!({})),
+            ..self.message(msg_type, content)
+        }
+    }
+
+    pub fn encode(&self, message: &JupyterMessage) -> ZmqMessage {
+        let parts = [
+            encode_json(&message.header),
+            encode_json(&message.parent_header),
+            encode_json(&message.metadata),
+            encode_json(&message.content),
+        ];
+
+        let mut frames = message.identities.clone();
+        frames.push(Bytes::from_static(DELIMITER));
+        frames.push(self.sign(&parts).into());
+        frames.extend(parts);
+        frames.extend(message.buffers.iter().cloned());
+
+        ZmqMessage::try_from(frames).expect("Jupyter message always has frames")
+    }
+
+    pub fn decode(&self, message: ZmqMessage) -> Result<JupyterMessage, JupyterError> {
+        let mut frames = message.into_vec();
+        let delimiter = frames
+            .iter()
+            .position(|frame| frame.as_ref() == DELIMITER)
+            .ok_or(JupyterError::MissingDelimiter)?;
+
+        let mut parts = frames.split_off(delimiter);
+        let identities = frames;
+        if parts.len() < 6 {
+            return Err(JupyterError::MissingFrames);
+        }
+
+        let buffers = parts.split_off(6);
+        if !self.key.is_empty() {
+            let signature = hex::decode(&parts[1]).map_err(|_| JupyterError::InvalidSignature)?;
+            self.mac(&parts[2..6])
+                .verify_slice(&signature)
+                .map_err(|_| JupyterError::InvalidSignature)?;
+        }
+
+        Ok(JupyterMessage {
+            identities,
+            header: serde_json::from_slice(&parts[2])?,
+            parent_header: serde_json::from_slice(&parts[3])?,
+            metadata: serde_json::from_slice(&parts[4])?,
+            content: serde_json::from_slice(&parts[5])?,
+            buffers,
+        })
+    }
+
+    fn header(&self, msg_type: &str) -> Header {
+        Header {
+            msg_id: Uuid::new_v4().to_string(),
+            session: self.id.clone(),
+            username: self.username.clone(),
+            date: format_date(SystemTime::now()),
+            msg_type: msg_type.to_string(),
+            version: PROTOCOL_VERSION.to_string(),
+        }
+    }
+
+    /// Hex HMAC-SHA256 of the header, parent header, metadata and content, empty without key
+    fn sign(&self, parts: &[Bytes]) -> String {
+        if self.key.is_empty() {
+            return String::new();
+        }
+
+        hex::encode(self.mac(parts).finalize().into_bytes())
+    }
+
+    fn mac(&self, parts: &[Bytes]) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any size");
+        for part in parts {
+            mac.update(part);
+        }
+
+        mac
+    }
+}
+
+impl JupyterMessage {
+    pub fn msg_type(&self) -> &str {
+        &self.header.msg_type
+    }
+
+    /// Id of the message this one answers
+    pub fn parent_msg_id(&self) -> Option<&str> {
+        self.parent_header.get("msg_id").and_then(Value::as_str)
+    }
+}
+
+fn encode_json(value: &impl Serialize) -> Bytes {
+    serde_json::to_vec(value)
+        .expect("Jupyter message parts are valid JSON")
+        .into()
+}
+
+/// ISO 8601 date in UTC with microseconds, as Jupyter expects in headers
+fn format_date(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (days, seconds_of_day) = (seconds / 86_400, seconds % 86_400);
+
+    // Civil date of a day count, from Howard Hinnant's `civil_from_days`
+    let days = days as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:06}Z",
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_micros(),
+    )
+}
@@ -0,0 +1,515 @@
+use std::{collections::HashMap, future};
+
+use bytes::Bytes;
+use serde_json::{json, Value};
+use tokio::{
+    signal::unix::{signal, Signal, SignalKind},
+    sync::mpsc,
+    task,
+};
+use tracing::{debug, warn};
+use zeromq::{PubSocket, RepSocket, RouterSocket, Socket, SocketRecv, SocketSend, ZmqMessage};
+
+use super::{ConnectionInfo, JupyterError, JupyterMessage, Session, PROTOCOL_VERSION};
+use crate::{
+    event::{EventContent, EventSubscription, ExecStatus, KernelEvent},
+    kernel::{Envelope, KernelSender, KernelTerminal},
+    output::{Output, OutputPolicy, OUTPUT_CHANNEL_CAPACITY},
+    queue::Lane,
+    terminal::TerminalMode,
+    ClientId, KernelRequest, KernelResponse, MessageId,
+};
+
+const USERNAME: &str = "kernel";
+
+/// Client of the executions requested by Jupyter frontends, which all share the kernel
+const CLIENT_ID: ClientId = 0;
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub connection: ConnectionInfo,
+    /// Language of the kernel, as told to frontends
+    pub language: String,
+    /// `SIGINT` interrupts the kernel instead of stopping the process, as frontends using the
+    /// `signal` interrupt mode expect
+    pub interrupt_on_sigint: bool,
+}
+
+impl ServerConfig {
+    pub fn new(connection: ConnectionInfo, language: impl Into<String>) -> Self {
+        Self {
+            connection,
+            language: language.into(),
+            interrupt_on_sigint: false,
+        }
+    }
+
+    pub fn interrupt_on_sigint(mut self, interrupt_on_sigint: bool) -> Self {
+        self.interrupt_on_sigint = interrupt_on_sigint;
+        self
+    }
+}
+
+/// Serves a kernel to Jupyter frontends (JupyterLab, nbclient, ...) as a Jupyter kernel, on the
+/// sockets of `config.connection`, until a frontend asks for a shutdown.
+///
+/// Executions are queued by the kernel, and their output is published as `stdout` streams when
+/// they run. A failed, interrupted or timed out execution is answered with an error, while an
+/// execution cancelled before it ran is aborted. Input requests, completion and inspection are
+/// not supported.
+pub async fn serve(config: ServerConfig, terminal: KernelTerminal) -> Result<(), JupyterError> {
+    let mut server = Server::bind(config, terminal).await?;
+
+    server.run().await
+}
+
+struct Server {
+    session: Session,
+    language: String,
+    shell: RouterSocket,
+    control: RouterSocket,
+    iopub: Iopub,
+    /// Bound for frontends to connect, although input is never requested
+    _stdin: RouterSocket,
+    heartbeat: task::JoinHandle<()>,
+    sigint: Option<Signal>,
+    sender: KernelSender,
+    responses: mpsc::Receiver<KernelResponse>,
+    /// Sends the requests to the kernel in order, so that waiting for room in its queue never
+    /// holds up the control channel
+    forwarder: mpsc::UnboundedSender<Envelope>,
+    forwarding: task::JoinHandle<()>,
+    events: EventSubscription,
+    execution_count: u64,
+    next_message_id: MessageId,
+    /// Executions sent to the kernel and not answered yet
+    executions: HashMap<MessageId, Execution>,
+}
+
+/// Publishes the messages of the kernel to every frontend
+struct Iopub {
+    session: Session,
+    socket: PubSocket,
+}
+
+struct Execution {
+    request: JupyterMessage,
+    execution_count: u64,
+    /// Nothing is published about a silent execution but its status and errors
+    silent: bool,
+    /// The kernel started running the execution
+    started: bool,
+    /// Output held back while it ends in the middle of a character
+    pending: Vec<u8>,
+}
+
+#[derive(Clone, Copy)]
+enum Channel {
+    Shell,
+    Control,
+}
+
+enum Event {
+    Request(Channel, ZmqMessage),
+    Kernel(KernelEvent),
+    Sigint,
+}
+
+impl Server {
+    async fn bind(config: ServerConfig, terminal: KernelTerminal) -> Result<Self, JupyterError> {
+        let ServerConfig {
+            connection,
+            language,
+            interrupt_on_sigint,
+        } = config;
+        let session = connection.session(USERNAME)?;
+
+        let mut shell = RouterSocket::new();
+        let mut control = RouterSocket::new();
+        let mut iopub = PubSocket::new();
+        let mut stdin = RouterSocket::new();
+        let mut heartbeat = RepSocket::new();
+        shell
+            .bind(&connection.endpoint(connection.shell_port))
+            .await?;
+        control
+            .bind(&connection.endpoint(connection.control_port))
+            .await?;
+        iopub
+            .bind(&connection.endpoint(connection.iopub_port))
+            .await?;
+        stdin
+            .bind(&connection.endpoint(connection.stdin_port))
+            .await?;
+        heartbeat
+            .bind(&connection.endpoint(connection.hb_port))
+            .await?;
+
+        let sigint = match interrupt_on_sigint {
+            true => Some(signal(SignalKind::interrupt())?),
+            false => None,
+        };
+
+        let (sender, responses) = terminal.split();
+        let (forwarder, envelopes) = mpsc::unbounded_channel();
+
+        Ok(Self {
+            iopub: Iopub {
+                session: session.clone(),
+                socket: iopub,
+            },
+            session,
+            language,
+            shell,
+            control,
+            _stdin: stdin,
+            heartbeat: task::spawn(echo(heartbeat)),
+            sigint,
+            events: sender.subscribe(),
+            forwarding: task::spawn(forward(sender.clone(), envelopes)),
+            sender,
+            responses,
+            forwarder,
+            execution_count: 0,
+            next_message_id: 0,
+            executions: HashMap::new(),
+        })
+    }
+
+    async fn run(&mut self) -> Result<(), JupyterError> {
+        loop {
+            let event = tokio::select! {
+                message = self.shell.recv() => Event::Request(Channel::Shell, message?),
+                message = self.control.recv() => Event::Request(Channel::Control, message?),
+                Some(event) = self.events.recv() => Event::Kernel(event),
+                // Executions are followed through their events
+                Some(_) = self.responses.recv() => continue,
+                Some(()) = next_sigint(&mut self.sigint) => Event::Sigint,
+            };
+
+            match event {
+                Event::Request(channel, message) => {
+                    let request = match self.session.decode(message) {
+                        Ok(request) => request,
+                        Err(err) => {
+                            warn!(%err, "Jupyter request ignored");
+                            continue;
+                        }
+                    };
+
+                    if request.msg_type() == "execute_request" {
+                        self.execute(request).await;
+                        continue;
+                    }
+
+                    let shutdown = request.msg_type() == "shutdown_request";
+                    self.answer(channel, request).await?;
+                    if shutdown {
+                        return Ok(());
+                    }
+                }
+                Event::Kernel(event) => self.follow(event).await?,
+                Event::Sigint => self.send(KernelRequest::Interrupt),
+            }
+        }
+    }
+
+    /// Sends the code to the kernel, the request being answered once the execution is done
+    async fn execute(&mut self, request: JupyterMessage) {
+        let code = request.content["code"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        let silent = request.content["silent"].as_bool().unwrap_or(false);
+        let store_history = request.content["store_history"]
+            .as_bool()
+            .unwrap_or(!silent);
+        if !silent && store_history {
+            self.execution_count += 1;
+        }
+
+        let message_id = self.next_message_id;
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+        self.executions.insert(
+            message_id,
+            Execution {
+                request,
+                execution_count: self.execution_count,
+                silent,
+                started: false,
+                pending: Vec::new(),
+            },
+        );
+
+        // The output is published from the events of the kernel, which carry it as well
+        let (io_sender, mut io_receiver) = mpsc::channel(OUTPUT_CHANNEL_CAPACITY);
+        task::spawn(async move { while io_receiver.recv().await.is_some() {} });
+
+        self.send(KernelRequest::Execute {
+            message_id,
+            client_id: CLIENT_ID,
+            code,
+            io_sender,
+            output_policy: OutputPolicy::Block,
+            terminal: TerminalMode::Raw,
+            lane: Lane::Interactive,
+            language: None,
+            independent: false,
+        });
+    }
+
+    /// Hands the request to the forwarding task, an interrupt taking effect at once
+    fn send(&self, request: KernelRequest) {
+        let _ = self.forwarder.send(self.sender.stamp(request));
+    }
+
+    /// Answers any request but `execute_request`, between a busy and an idle status
+    async fn answer(
+        &mut self,
+        channel: Channel,
+        request: JupyterMessage,
+    ) -> Result<(), JupyterError> {
+        let (msg_type, content) = match request.msg_type() {
+            "kernel_info_request" => ("kernel_info_reply", self.kernel_info()),
+            "interrupt_request" => {
+                self.send(KernelRequest::Interrupt);
+                ("interrupt_reply", json!({"status": "ok"}))
+            }
+            "shutdown_request" => {
+                let restart = request.content["restart"].as_bool().unwrap_or(false);
+                (
+                    "shutdown_reply",
+                    json!({"status": "ok", "restart": restart}),
+                )
+            }
+            "is_complete_request" => ("is_complete_reply", json!({"status": "unknown"})),
+            "complete_request" => {
+                let cursor = request.content["cursor_pos"].clone();
+                let content = json!({
+                    "status": "ok",
+                    "matches": [],
+                    "cursor_start": cursor,
+                    "cursor_end": cursor,
+                    "metadata": {},
+                });
+                ("complete_reply", content)
+            }
+            "inspect_request" => {
+                let content = json!({"status": "ok", "found": false, "data": {}, "metadata": {}});
+                ("inspect_reply", content)
+            }
+            "history_request" => ("history_reply", json!({"status": "ok", "history": []})),
+            "comm_info_request" => ("comm_info_reply", json!({"status": "ok", "comms": {}})),
+            msg_type => {
+                debug!(msg_type, "Jupyter request not supported");
+                return Ok(());
+            }
+        };
+
+        self.iopub.publish_status(&request, "busy").await;
+        let reply = self.session.reply(&request, msg_type, content);
+        let reply = self.session.encode(&reply);
+        match channel {
+            Channel::Shell => self.shell.send(reply).await?,
+            Channel::Control => self.control.send(reply).await?,
+        }
+        self.iopub.publish_status(&request, "idle").await;
+
+        Ok(())
+    }
+
+    fn kernel_info(&self) -> Value {
+        json!({
+            "status": "ok",
+            "protocol_version": PROTOCOL_VERSION,
+            "implementation": "canal",
+            "implementation_version": env!("CARGO_PKG_VERSION"),
+            "language_info": {"name": self.language},
+            "banner": format!("Canal kernel ({})", self.language),
+            "help_links": [],
+        })
+    }
+
+    /// Publishes what happens to an execution, and answers it once it is done
+    async fn follow(&mut self, event: KernelEvent) -> Result<(), JupyterError> {
+        // Usage samples belong to no execution
+        let Some(parent) = event.parent else {
+            return Ok(());
+        };
+        let Some(execution) = self.executions.get_mut(&parent) else {
+            return Ok(());
+        };
+
+        match event.content {
+            EventContent::Status(ExecStatus::Queued) | EventContent::Usage(_) => {}
+            EventContent::Status(ExecStatus::Running) => {
+                execution.started = true;
+                self.iopub.publish_status(&execution.request, "busy").await;
+                if !execution.silent {
+                    let content = json!({
+                        "code": execution.request.content["code"],
+                        "execution_count": execution.execution_count,
+                    });
+                    self.iopub
+                        .publish(&execution.request, "execute_input", content)
+                        .await;
+                }
+            }
+            EventContent::Output(output) => {
+                if execution.silent {
+                    return Ok(());
+                }
+
+                let (name, text) = match output {
+                    Output::Stream(data) => {
+                        execution.pending.extend_from_slice(&data);
+                        ("stdout", take_text(&mut execution.pending))
+                    }
+                    Output::Styled(_) => ("stdout", output.to_string()),
+                    Output::Truncated { .. } => ("stderr", format!("{output}\n")),
+                };
+                self.iopub
+                    .publish_stream(&execution.request, name, text)
+                    .await;
+            }
+            EventContent::Status(status) => {
+                if let Some(execution) = self.executions.remove(&parent) {
+                    self.finish(execution, status).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn finish(
+        &mut self,
+        mut execution: Execution,
+        status: ExecStatus,
+    ) -> Result<(), JupyterError> {
+        let request = &execution.request;
+        if !execution.silent && !execution.pending.is_empty() {
+            let text = String::from_utf8_lossy(&execution.pending).into_owned();
+            self.iopub.publish_stream(request, "stdout", text).await;
+            execution.pending.clear();
+        }
+
+        if !execution.started {
+            self.iopub.publish_status(request, "busy").await;
+        }
+
+        let execution_count = execution.execution_count;
+        let content = match status {
+            ExecStatus::Succeeded => json!({
+                "status": "ok",
+                "execution_count": execution_count,
+                "payload": [],
+                "user_expressions": {},
+            }),
+            ExecStatus::Cancelled if !execution.started => {
+                json!({"status": "aborted", "execution_count": execution_count})
+            }
+            status => {
+                let mut error = error_content(status);
+                self.iopub.publish(request, "error", error.clone()).await;
+
+                error["status"] = json!("error");
+                error["execution_count"] = json!(execution_count);
+                error
+            }
+        };
+
+        let reply = self.session.reply(request, "execute_reply", content);
+        self.shell.send(self.session.encode(&reply)).await?;
+        self.iopub.publish_status(request, "idle").await;
+
+        Ok(())
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.heartbeat.abort();
+        self.forwarding.abort();
+    }
+}
+
+/// Sends the stamped requests to the kernel, one after the other
+async fn forward(sender: KernelSender, mut envelopes: mpsc::UnboundedReceiver<Envelope>) {
+    while let Some(envelope) = envelopes.recv().await {
+        sender.forward(envelope).await;
+    }
+}
+
+impl Iopub {
+    async fn publish(&mut self, parent: &JupyterMessage, msg_type: &str, content: Value) {
+        let mut message = self.session.reply(parent, msg_type, content);
+        message.identities = vec![Bytes::from(format!(
+            "kernel.{}.{msg_type}",
+            self.session.id
+        ))];
+
+        // Messages are dropped while no frontend is subscribed, which is not an error
+        let _ = self.socket.send(self.session.encode(&message)).await;
+    }
+
+    async fn publish_status(&mut self, parent: &JupyterMessage, execution_state: &str) {
+        let content = json!({"execution_state": execution_state});
+        self.publish(parent, "status", content).await;
+    }
+
+    async fn publish_stream(&mut self, parent: &JupyterMessage, name: &str, text: String) {
+        if text.is_empty() {
+            return;
+        }
+
+        let content = json!({"name": name, "text": text});
+        self.publish(parent, "stream", content).await;
+    }
+}
+
+/// Content of the `error` message of an execution that did not succeed. Canal REPLs write
+/// tracebacks to the output, so the error only tells how the execution ended.
+fn error_content(status: ExecStatus) -> Value {
+    let (ename, evalue) = match status {
+        ExecStatus::Cancelled => ("KeyboardInterrupt", "Execution was interrupted"),
+        ExecStatus::TimedOut => ("TimeoutError", "Execution timed out"),
+        ExecStatus::LimitExceeded => ("ResourceLimitExceeded", "Resource limit exceeded"),
+        _ => ("ExecutionError", "Execution failed"),
+    };
+
+    json!({
+        "ename": ename,
+        "evalue": evalue,
+        "traceback": [format!("{ename}: {evalue}")],
+    })
+}
+
+/// Takes the text of `pending`, leaving a character split across outputs for the next one
+fn take_text(pending: &mut Vec<u8>) -> String {
+    let len = match std::str::from_utf8(pending) {
+        Err(err) if err.error_len().is_none() => err.valid_up_to(),
+        _ => pending.len(),
+    };
+    let rest = pending.split_off(len);
+    let text = String::from_utf8_lossy(pending).into_owned();
+    *pending = rest;
+
+    text
+}
+
+/// Answers the heartbeats of frontends, which check that the kernel is alive
+async fn echo(mut socket: RepSocket) {
+    while let Ok(message) = socket.recv().await {
+        if socket.send(message).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn next_sigint(sigint: &mut Option<Signal>) -> Option<()> {
+    match sigint {
+        Some(sigint) => sigint.recv().await,
+        None => future::pending().await,
+    }
+}
@@ -1,219 +1,920 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use bytes::Bytes;
 use tokio::{
-    sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+    sync::{mpsc, OwnedSemaphorePermit},
     task,
     time::sleep,
 };
 use tokio_util::sync::CancellationToken;
+use tracing::{debug, info_span, instrument, warn, Instrument, Span};
 
 use crate::{
+    event::{EventSubscription, ExecStatus, Publisher, Seq},
+    journal::{Journal, JournalRecord},
+    metrics::{KernelMetrics, MetricsSnapshot},
+    monitor::UsageSample,
+    output::{self, Output, OutputPolicy, Sink, OUTPUT_CHANNEL_CAPACITY},
+    polyglot::Polyglot,
+    queue::{KernelQueue, Lane},
     repl::{ReplError, ReplHandle},
-    KernelRequest, KernelResponse,
+    terminal::TerminalMode,
+    ClientId, KernelRequest, KernelResponse,
 };
 
 pub struct KernelTerminal {
-    request_sender: mpsc::Sender<KernelRequest>,
+    sender: KernelSender,
     response_receiver: mpsc::Receiver<KernelResponse>,
 }
 
+/// Sending half of a `KernelTerminal`, which can be cloned and shared while responses are
+/// received elsewhere
+#[derive(Clone)]
+pub struct KernelSender {
+    request_sender: mpsc::Sender<Envelope>,
+    publisher: Publisher,
+    metrics: Arc<KernelMetrics>,
+    sigint_control: Arc<SigintControl>,
+}
+
 impl KernelTerminal {
+    /// Sends a request to the kernel, see `KernelSender::send`
     pub async fn send(&self, message: KernelRequest) {
+        self.sender.send(message).await
+    }
+
+    pub async fn recv(&mut self) -> Option<KernelResponse> {
+        self.response_receiver.recv().await
+    }
+
+    /// Receives the status and output of every execution, from now on
+    pub fn subscribe(&self) -> EventSubscription {
+        self.sender.subscribe()
+    }
+
+    /// Receives the recent events from `seq` onwards, then every new event
+    pub fn resume(&self, seq: Seq) -> EventSubscription {
+        self.sender.resume(seq)
+    }
+
+    /// Current values of the metrics of the kernel since it was launched
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.sender.metrics()
+    }
+
+    /// Separates the sending half from the receiver of the responses
+    pub fn split(self) -> (KernelSender, mpsc::Receiver<KernelResponse>) {
+        (self.sender, self.response_receiver)
+    }
+}
+
+impl KernelSender {
+    /// Sends a request to the kernel.
+    ///
+    /// An `Interrupt` takes effect before this returns: it cancels the running execution and
+    /// every execution sent before it, even those the kernel has not received yet, while the
+    /// executions sent after it are run as usual.
+    pub async fn send(&self, message: KernelRequest) {
+        self.forward(self.stamp(message)).await
+    }
+
+    /// Prepares a request to be sent later as if it was sent now: an `Interrupt` takes effect
+    /// at once, and reaches the executions stamped before it
+    pub(crate) fn stamp(&self, message: KernelRequest) -> Envelope {
+        if let KernelRequest::Interrupt = message {
+            self.sigint_control.interrupt();
+        }
+
+        Envelope {
+            request: message,
+            sigint: self.sigint_control.current(),
+        }
+    }
+
+    /// Sends a stamped request, waiting while the queue of the kernel is full
+    pub(crate) async fn forward(&self, envelope: Envelope) {
         self.request_sender
-            .send(message)
+            .send(envelope)
             .await
             .expect("Kernel is killed")
     }
 
-    pub async fn recv(&mut self) -> Option<KernelResponse> {
-        self.response_receiver.recv().await
+    /// Receives the status and output of every execution, from now on
+    pub fn subscribe(&self) -> EventSubscription {
+        self.publisher.subscribe()
+    }
+
+    /// Receives the recent events from `seq` onwards, then every new event
+    pub fn resume(&self, seq: Seq) -> EventSubscription {
+        self.publisher.resume(seq)
+    }
+
+    /// Current values of the metrics of the kernel since it was launched
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// Publishes a sample of the resources used by the interpreter to the subscribers
+    pub(crate) fn publish_usage(&self, sample: UsageSample) {
+        self.publisher.publish_usage(sample);
+    }
+}
+
+pub struct KernelOptions {
+    /// Number of executions (running and queued) accepted before `KernelTerminal::send` waits
+    pub queue_capacity: usize,
+    pub journal: Journal,
+    /// Executions running longer than this are interrupted and answered with
+    /// `KernelResponse::TimedOut`
+    pub exec_timeout: Option<Duration>,
+    /// REPLs running the independent executions in parallel, each on its own, by language.
+    /// Those of the default language of a polyglot kernel, or of any language of other kernels,
+    /// are under `None`.
+    pub workers: BTreeMap<Option<String>, Vec<ReplHandle>>,
+}
+
+impl KernelOptions {
+    pub fn new(queue_capacity: usize) -> Self {
+        Self {
+            queue_capacity,
+            journal: Journal::disabled(),
+            exec_timeout: None,
+            workers: BTreeMap::new(),
+        }
+    }
+
+    pub fn journal(mut self, journal: Journal) -> Self {
+        self.journal = journal;
+        self
+    }
+
+    pub fn exec_timeout(mut self, exec_timeout: Duration) -> Self {
+        self.exec_timeout = Some(exec_timeout);
+        self
+    }
+
+    /// Runs the executions marked `independent` on the first idle of `workers`, in any order
+    /// and alongside the executions run in order on the primary REPL. The workers of a
+    /// polyglot kernel run its default language, see `language_workers` for the others.
+    pub fn workers(mut self, workers: Vec<ReplHandle>) -> Self {
+        self.workers.insert(None, workers);
+        self
+    }
+
+    /// Runs the independent executions of `language` on the first idle of `workers`, in a
+    /// polyglot kernel. Independent executions of a language without workers fail.
+    pub fn language_workers(
+        mut self,
+        language: impl Into<String>,
+        workers: Vec<ReplHandle>,
+    ) -> Self {
+        self.workers.insert(Some(language.into()), workers);
+        self
     }
 }
 
 pub struct Kernel {
-    repl: ReplHandle,
+    repls: Repls,
+    publisher: Publisher,
+    journal: Journal,
+    metrics: Arc<KernelMetrics>,
+    exec_timeout: Option<Duration>,
 }
 
 impl Kernel {
+    /// Runs the exec on the primary REPLs or on a worker
+    #[instrument(skip_all)]
     async fn handle_exec(
         &self,
+        target: Target<'_>,
         exec: Exec,
-        response_sender: mpsc::Sender<KernelResponse>,
-        exec_queue_cancellation: CancellationToken,
-    ) {
-        let result = self
-            .repl
-            .execute(exec.code, exec.io_sender, exec.sigint)
-            .await;
+        responder: Responder,
+    ) -> ExecStatus {
+        let (repl_io_sender, repl_io_receiver) = mpsc::channel(OUTPUT_CHANNEL_CAPACITY);
 
-        match result {
-            Ok(_) => {
-                let _ = response_sender
-                    .send(KernelResponse::Success(exec.message_id))
-                    .await;
-            }
-            Err(err) => {
-                match err {
-                    ReplError::Failed => {
-                        let _ = response_sender
-                            .send(KernelResponse::Failed(exec.message_id))
-                            .await;
-                    }
-                    ReplError::Interrupted => {
-                        let _ = response_sender
-                            .send(KernelResponse::Cancelled(exec.message_id))
-                            .await;
-                    }
-                }
+        let sink = Sink::new(
+            exec.message_id,
+            exec.client_id,
+            exec.output_policy,
+            exec.io_sender,
+            self.publisher.clone(),
+        );
+
+        self.publisher
+            .publish_status(exec.message_id, exec.client_id, ExecStatus::Running);
 
-                exec_queue_cancellation.cancel();
+        let started_at = Instant::now();
+        let ((result, timed_out), output_summary) = tokio::join!(
+            self.execute_with_timeout(
+                target,
+                exec.language.as_deref(),
+                exec.code,
+                repl_io_sender,
+                exec.sigint
+            ),
+            output::forward(repl_io_receiver, sink, exec.terminal),
+        );
+
+        let (response, status) = match &result {
+            Ok(_) => (
+                KernelResponse::Success(exec.message_id),
+                ExecStatus::Succeeded,
+            ),
+            Err(ReplError::Failed) => (KernelResponse::Failed(exec.message_id), ExecStatus::Failed),
+            Err(ReplError::Interrupted) if timed_out => (
+                KernelResponse::TimedOut(exec.message_id),
+                ExecStatus::TimedOut,
+            ),
+            Err(ReplError::Interrupted) => (
+                KernelResponse::Cancelled(exec.message_id),
+                ExecStatus::Cancelled,
+            ),
+            Err(ReplError::ResourceLimit(limit)) => {
+                warn!(%limit, "REPL killed for exceeding a resource limit");
+                (
+                    KernelResponse::LimitExceeded(exec.message_id, *limit),
+                    ExecStatus::LimitExceeded,
+                )
             }
         };
+
+        debug!(
+            ?status,
+            output_bytes = output_summary.bytes,
+            "exec finished"
+        );
+        self.metrics
+            .record_exec(started_at.elapsed(), output_summary.bytes);
+        self.metrics.record_result(status);
+        self.journal.record(JournalRecord::Output {
+            message_id: exec.message_id,
+            client_id: exec.client_id,
+            summary: output_summary,
+        });
+        self.publisher
+            .publish_status(exec.message_id, exec.client_id, status);
+        responder.send(Some(exec.client_id), response).await;
+
+        status
+    }
+
+    /// Runs the code, interrupting it once the timeout of the kernel is reached. The returned
+    /// flag tells whether the timeout was reached.
+    async fn execute_with_timeout(
+        &self,
+        target: Target<'_>,
+        language: Option<&str>,
+        code: String,
+        io_sender: mpsc::Sender<Bytes>,
+        sigint: CancellationToken,
+    ) -> (Result<(), ReplError>, bool) {
+        let execution = self
+            .repls
+            .execute(target, language, code, io_sender, sigint.clone());
+
+        let Some(exec_timeout) = self.exec_timeout else {
+            return (execution.await, false);
+        };
+
+        tokio::pin!(execution);
+        tokio::select! {
+            result = &mut execution => (result, false),
+            _ = sleep(exec_timeout) => {
+                warn!(?exec_timeout, "exec timed out, interrupting the REPL");
+                sigint.cancel();
+                (execution.await, true)
+            }
+        }
     }
 }
 
-pub fn launch(repl: ReplHandle, queue_capacity: usize) -> (KernelTerminal, Arc<Semaphore>) {
+pub fn launch(repl: ReplHandle, queue_capacity: usize) -> (KernelTerminal, Arc<KernelQueue>) {
+    launch_with(repl, KernelOptions::new(queue_capacity))
+}
+
+pub fn launch_with(repl: ReplHandle, options: KernelOptions) -> (KernelTerminal, Arc<KernelQueue>) {
+    launch_repls(Repls::Single(repl), options)
+}
+
+/// Launches a kernel running each execution on the REPL of its language, in the order they
+/// were sent whatever their language
+pub fn launch_polyglot(
+    polyglot: Polyglot,
+    options: KernelOptions,
+) -> (KernelTerminal, Arc<KernelQueue>) {
+    launch_repls(Repls::Polyglot(polyglot), options)
+}
+
+fn launch_repls(repls: Repls, options: KernelOptions) -> (KernelTerminal, Arc<KernelQueue>) {
+    let KernelOptions {
+        queue_capacity,
+        journal,
+        exec_timeout,
+        mut workers,
+    } = options;
+    workers.retain(|_, workers| !workers.is_empty());
+
     let (request_sender, request_receiver) = mpsc::channel(queue_capacity);
     let (response_sender, response_receiver) = mpsc::channel(2 * queue_capacity);
 
-    let (exec_sender, exec_receiver) = mpsc::channel(queue_capacity);
+    let (interactive_exec_sender, interactive_exec_receiver) = mpsc::channel(queue_capacity);
+    let (batch_exec_sender, batch_exec_receiver) = mpsc::channel(queue_capacity);
+    let (independent_exec_sender, independent_exec_receiver) = mpsc::channel(queue_capacity);
 
-    let (queue_cancellation_info_sender, queue_cancellation_info_receiver) = mpsc::channel(1);
-    let queue_cancellation_token = CancellationToken::new();
-
-    let queue_semaphore = Arc::new(Semaphore::new(queue_capacity));
+    let queue = Arc::new(KernelQueue::new(queue_capacity));
+    let publisher = Publisher::new();
+    let metrics = Arc::new(KernelMetrics::default());
+    let sigint_control = Arc::new(SigintControl::default());
+    let responder = Responder {
+        response_sender,
+        journal: journal.clone(),
+    };
 
     task::spawn(process_request(
         request_receiver,
-        exec_sender,
-        queue_cancellation_token.clone(),
-        queue_semaphore.clone(),
+        ExecSenders {
+            interactive: interactive_exec_sender,
+            batch: batch_exec_sender,
+            independent: (!workers.is_empty()).then_some(independent_exec_sender),
+        },
+        responder.clone(),
+        queue.clone(),
+        publisher.clone(),
+        journal.clone(),
     ));
 
-    let kernel = Kernel { repl };
+    let kernel = Arc::new(Kernel {
+        repls,
+        publisher: publisher.clone(),
+        journal,
+        metrics: metrics.clone(),
+        exec_timeout,
+    });
+    if !workers.is_empty() {
+        task::spawn(process_independent_exec(
+            kernel.clone(),
+            workers,
+            independent_exec_receiver,
+            responder.clone(),
+            queue.clone(),
+        ));
+    }
     task::spawn(process_exec(
         kernel,
-        exec_receiver,
-        response_sender,
-        queue_cancellation_info_receiver,
-        queue_cancellation_token.clone(),
-    ));
-
-    task::spawn(watch_cancellation_and_send_queue_cancellation_info(
-        queue_cancellation_info_sender.clone(),
-        queue_cancellation_token.clone(),
-        queue_semaphore.clone(),
+        ExecReceivers {
+            interactive: interactive_exec_receiver,
+            batch: batch_exec_receiver,
+        },
+        responder,
+        queue.clone(),
     ));
 
     let terminal = KernelTerminal {
-        request_sender,
+        sender: KernelSender {
+            request_sender,
+            publisher,
+            metrics,
+            sigint_control,
+        },
         response_receiver,
     };
 
-    (terminal, queue_semaphore)
+    (terminal, queue)
 }
 
 async fn process_exec(
-    kernel: Kernel,
+    kernel: Arc<Kernel>,
+    mut exec_receivers: ExecReceivers,
+    responder: Responder,
+    queue: Arc<KernelQueue>,
+) {
+    while let Some(exec) = exec_receivers.recv().await {
+        let message_id = exec.message_id;
+        let span = info_span!("process_exec", message_id, client_id = exec.client_id);
+
+        async {
+            // The exec was sent before an interrupt, which may have reached the kernel after
+            // the previous exec was done
+            if exec.sigint.is_cancelled() {
+                debug!("exec interrupted before it started");
+                cancel_exec(exec, &responder, &queue, &kernel).await;
+                return;
+            }
+
+            let wait = queue.start(message_id);
+            debug!(?wait, "exec picked up");
+            if let Some(wait) = wait {
+                kernel.metrics.record_queue_wait(wait);
+            }
+
+            let status = kernel
+                .handle_exec(Target::Primary, exec, responder.clone())
+                .await;
+            queue.finish(message_id);
+
+            // The queued execs that were sent before an interrupt are cancelled as they are
+            // picked up, the later ones are still run
+            if matches!(
+                status,
+                ExecStatus::Failed | ExecStatus::TimedOut | ExecStatus::LimitExceeded
+            ) {
+                drop_queued_execs(&mut exec_receivers, &responder, &queue, &kernel).await;
+            }
+        }
+        .instrument(span)
+        .await;
+    }
+}
+
+/// Cancels every exec waiting in any lane when the running exec fails, times out or exceeds a
+/// resource limit
+async fn drop_queued_execs(
+    exec_receivers: &mut ExecReceivers,
+    responder: &Responder,
+    queue: &KernelQueue,
+    kernel: &Kernel,
+) {
+    // An exec is pushed to the queue before it is sent to its lane, so each lane is
+    // guaranteed to yield (at least) its number of execs. Lanes are drained on their own, so
+    // that an exec sent to one lane after counting is not dropped instead of one counted in the
+    // other. Independent execs are left to the workers.
+    for (lane, number_of_dropped_exec) in queue.len_by_lane() {
+        for _ in 0..number_of_dropped_exec {
+            let Some(exec) = exec_receivers.lane(lane).recv().await else {
+                break;
+            };
+
+            debug!(dropped = exec.message_id, "queued exec cancelled");
+            cancel_exec(exec, responder, queue, kernel).await;
+        }
+    }
+}
+
+/// Sends each independent exec to the workers of its language, failing those of a language
+/// without workers
+async fn process_independent_exec(
+    kernel: Arc<Kernel>,
+    workers: BTreeMap<Option<String>, Vec<ReplHandle>>,
     mut exec_receiver: mpsc::Receiver<Exec>,
-    response_sender: mpsc::Sender<KernelResponse>,
-    mut queue_cancellation_info_receiver: mpsc::Receiver<usize>,
-    queue_cancellation_token: CancellationToken,
+    responder: Responder,
+    queue: Arc<KernelQueue>,
 ) {
-    loop {
-        let exec_result_sender = response_sender.clone();
+    let mut languages = BTreeMap::<_, Vec<_>>::new();
+    for (language, workers) in workers {
+        let language = kernel.repls.worker_language(language.as_deref());
+        languages.entry(language).or_default().extend(workers);
+    }
 
-        tokio::select! {
-            biased;
+    let pools = languages
+        .into_iter()
+        .map(|(language, workers)| {
+            // Every exec fits in the queue of its pool, which never makes the others wait
+            let (exec_sender, exec_receiver) = mpsc::channel(queue.capacity());
+            task::spawn(run_workers(
+                kernel.clone(),
+                workers,
+                exec_receiver,
+                responder.clone(),
+                queue.clone(),
+            ));
+            (language, exec_sender)
+        })
+        .collect::<BTreeMap<_, _>>();
 
-            Some(number_of_dropped_exec) = queue_cancellation_info_receiver.recv() => {
-                let mut execs = Vec::new();
-                exec_receiver.recv_many(&mut execs, number_of_dropped_exec).await;
+    while let Some(exec) = exec_receiver.recv().await {
+        let language = kernel.repls.worker_language(exec.language.as_deref());
 
-                for exec in execs.into_iter() {
-                    let _ = exec_result_sender.send(KernelResponse::Cancelled(exec.message_id)).await;
-                }
+        match pools.get(&language) {
+            Some(pool) => {
+                let _ = pool.send(exec).await;
             }
-            Some(exec) = exec_receiver.recv() => {
-                kernel.handle_exec(exec, exec_result_sender, queue_cancellation_token.clone()).await;
-
-                // This emulates latency of inter-process communication between kernel and REPL process.
-                // The average of time needed to send data is around 4-10 microseconds.
-                //
-                // Without any latency between the kernel and a real REPL process, the cancellation request
-                // (sent by a mpsc channel) will arrive slightly slower than the execution process by REPL.
-                if cfg!(debug_assertions) {
-                    sleep(Duration::from_micros(4)).await;
-                }
+            None => {
+                let span = info_span!(
+                    "process_independent_exec",
+                    message_id = exec.message_id,
+                    client_id = exec.client_id
+                );
+                let (kernel, responder, queue) = (kernel.clone(), responder.clone(), queue.clone());
+
+                task::spawn(
+                    async move {
+                        run_independent_exec(&kernel, Target::NoWorker, exec, responder, &queue)
+                            .await;
+                    }
+                    .instrument(span),
+                );
             }
-            else => {},
         }
     }
 }
 
-async fn process_request(
-    mut request_receiver: mpsc::Receiver<KernelRequest>,
-    exec_sender: mpsc::Sender<Exec>,
-    queue_cancellation_token: CancellationToken,
-    queue_semaphore: Arc<Semaphore>,
+/// Runs each exec on the first idle of `workers`. The execs sent before an interrupt are
+/// cancelled like those of the lanes, but a failing one leaves the others queued. A worker
+/// killed for exceeding a resource limit is dropped, and the execs fail once none is left.
+async fn run_workers(
+    kernel: Arc<Kernel>,
+    workers: Vec<ReplHandle>,
+    mut exec_receiver: mpsc::Receiver<Exec>,
+    responder: Responder,
+    queue: Arc<KernelQueue>,
 ) {
-    let sigint_control = CancellationToken::new();
+    let (idle_sender, mut idle_receiver) = mpsc::channel(workers.len());
+    for repl in workers {
+        let _ = idle_sender.try_send(IdleWorker {
+            repl,
+            idle_sender: idle_sender.clone(),
+        });
+    }
+    drop(idle_sender);
+
+    while let Some(exec) = exec_receiver.recv().await {
+        let span = info_span!(
+            "process_independent_exec",
+            message_id = exec.message_id,
+            client_id = exec.client_id
+        );
+
+        if exec.sigint.is_cancelled() {
+            async {
+                debug!("exec interrupted before it started");
+                cancel_exec(exec, &responder, &queue, &kernel).await;
+            }
+            .instrument(span)
+            .await;
+            continue;
+        }
+
+        let worker = idle_receiver.recv().await;
+        let (kernel, responder, queue) = (kernel.clone(), responder.clone(), queue.clone());
+
+        task::spawn(
+            async move {
+                let Some(worker) = worker else {
+                    run_independent_exec(&kernel, Target::NoWorkerLeft, exec, responder, &queue)
+                        .await;
+                    return;
+                };
+
+                let status = run_independent_exec(
+                    &kernel,
+                    Target::Worker(&worker.repl),
+                    exec,
+                    responder,
+                    &queue,
+                )
+                .await;
+                if status == Some(ExecStatus::LimitExceeded) {
+                    warn!("worker dropped for exceeding a resource limit");
+                    return;
+                }
+
+                let idle_sender = worker.idle_sender.clone();
+                let _ = idle_sender.send(worker).await;
+            }
+            .instrument(span),
+        );
+    }
+}
+
+/// Runs an independent exec on `target`, unless it was interrupted while it waited for a worker
+async fn run_independent_exec(
+    kernel: &Kernel,
+    target: Target<'_>,
+    exec: Exec,
+    responder: Responder,
+    queue: &KernelQueue,
+) -> Option<ExecStatus> {
+    if exec.sigint.is_cancelled() {
+        debug!("exec interrupted before it started");
+        cancel_exec(exec, &responder, queue, kernel).await;
+        return None;
+    }
+
+    let message_id = exec.message_id;
+    let wait = queue.start_on_worker(message_id);
+    debug!(?wait, "exec picked up by a worker");
+    if let Some(wait) = wait {
+        kernel.metrics.record_queue_wait(wait);
+    }
 
-    while let Some(msg) = request_receiver.recv().await {
-        let semaphore = queue_semaphore.clone();
+    let status = kernel.handle_exec(target, exec, responder).await;
+    queue.finish(message_id);
 
-        match msg {
-            KernelRequest::Execute {
-                message_id,
-                io_sender,
-                code,
-            } => {
-                let queue_permit = semaphore
-                    .acquire_owned()
-                    .await
-                    .expect("Queue semaphore could not acquire");
+    Some(status)
+}
 
-                let sigint = sigint_control.child_token();
+/// Answers an exec that leaves the queue without being run
+async fn cancel_exec(exec: Exec, responder: &Responder, queue: &KernelQueue, kernel: &Kernel) {
+    queue.discard(exec.message_id);
+    kernel.metrics.record_result(ExecStatus::Cancelled);
+    kernel
+        .publisher
+        .publish_status(exec.message_id, exec.client_id, ExecStatus::Cancelled);
+    responder
+        .send(
+            Some(exec.client_id),
+            KernelResponse::Cancelled(exec.message_id),
+        )
+        .await;
+}
 
-                let exec = Exec {
+async fn process_request(
+    mut request_receiver: mpsc::Receiver<Envelope>,
+    exec_senders: ExecSenders,
+    responder: Responder,
+    queue: Arc<KernelQueue>,
+    publisher: Publisher,
+    journal: Journal,
+) {
+    while let Some(Envelope {
+        request: msg,
+        sigint,
+    }) = request_receiver.recv().await
+    {
+        journal.record(JournalRecord::from(&msg));
+        let span = request_span(&msg);
+
+        async {
+            match msg {
+                KernelRequest::Execute {
                     message_id,
-                    code,
+                    client_id,
                     io_sender,
-                    sigint,
-                    queue_permit,
-                };
+                    code,
+                    output_policy,
+                    terminal,
+                    lane,
+                    language,
+                    independent,
+                } => {
+                    let enqueued_at = Instant::now();
+                    let queue_permit = queue.acquire().await;
+
+                    // A child token lets the kernel interrupt this exec alone on timeout
+                    let sigint = sigint.child_token();
+
+                    let exec = Exec {
+                        message_id,
+                        client_id,
+                        code,
+                        io_sender,
+                        output_policy,
+                        terminal,
+                        language,
+                        sigint,
+                        queue_permit,
+                    };
+
+                    let independent = independent && exec_senders.independent.is_some();
+                    queue.push(message_id, lane, independent, enqueued_at);
+                    publisher.publish_status(message_id, client_id, ExecStatus::Queued);
+                    let _ = exec_senders.send(lane, independent, exec).await;
+                    debug!("exec queued");
+                }
+                KernelRequest::Interrupt => {
+                    // Already applied by the terminal when the request was sent
+                    debug!("running exec and earlier execs interrupted");
+                }
+                KernelRequest::Subscribe {
+                    resume_from,
+                    event_sender,
+                } => {
+                    let mut subscription = match resume_from {
+                        Some(seq) => publisher.resume(seq),
+                        None => publisher.subscribe(),
+                    };
+
+                    task::spawn(async move {
+                        while let Some(event) = subscription.recv().await {
+                            if event_sender.send(event).await.is_err() {
+                                break;
+                            }
+                        }
+                    });
+                }
+                KernelRequest::QueueStatus => {
+                    responder
+                        .send(None, KernelResponse::QueueStatus(queue.status()))
+                        .await;
+                }
+            }
+        }
+        .instrument(span)
+        .await;
+    }
+}
+
+/// Span of the handling of one request, keyed by message id for executions
+fn request_span(request: &KernelRequest) -> Span {
+    match request {
+        KernelRequest::Execute {
+            message_id,
+            client_id,
+            lane,
+            independent,
+            ..
+        } => info_span!(
+            "process_request",
+            kind = "execute",
+            message_id,
+            client_id,
+            ?lane,
+            independent
+        ),
+        KernelRequest::Interrupt => info_span!("process_request", kind = "interrupt"),
+        KernelRequest::Subscribe { resume_from, .. } => {
+            info_span!("process_request", kind = "subscribe", ?resume_from)
+        }
+        KernelRequest::QueueStatus => info_span!("process_request", kind = "queue_status"),
+    }
+}
+
+/// REPLs of a kernel
+enum Repls {
+    Single(ReplHandle),
+    Polyglot(Polyglot),
+}
 
-                let _ = exec_sender.send(exec).await;
+/// REPL an exec runs on
+#[derive(Clone, Copy)]
+enum Target<'a> {
+    /// The REPLs running the execs in order
+    Primary,
+    /// A worker running the language of the exec
+    Worker(&'a ReplHandle),
+    /// No worker runs the language of the independent exec, which fails
+    NoWorker,
+    /// Every worker running the language of the independent exec was dropped, it fails
+    NoWorkerLeft,
+}
+
+impl Repls {
+    async fn execute(
+        &self,
+        target: Target<'_>,
+        language: Option<&str>,
+        code: String,
+        io_sender: mpsc::Sender<Bytes>,
+        sigint: CancellationToken,
+    ) -> Result<(), ReplError> {
+        match (target, self) {
+            (Target::Primary, Repls::Single(repl)) | (Target::Worker(repl), Repls::Single(_)) => {
+                repl.execute(code, io_sender, sigint).await
+            }
+            (Target::Primary, Repls::Polyglot(polyglot)) => {
+                polyglot.execute(language, code, io_sender, sigint).await
             }
-            KernelRequest::Interrupt => {
-                queue_cancellation_token.cancel();
-                sigint_control.cancel();
+            (Target::Worker(worker), Repls::Polyglot(polyglot)) => {
+                polyglot.execute_on(worker, code, io_sender, sigint).await
+            }
+            (Target::NoWorker, _) => {
+                let language = self.language(language).unwrap_or_default();
+                let error = format!("No worker runs {language}\n");
+                let _ = io_sender.send(Bytes::from(error)).await;
+                Err(ReplError::Failed)
+            }
+            (Target::NoWorkerLeft, _) => {
+                let _ = io_sender.send(Bytes::from("No worker is left\n")).await;
+                Err(ReplError::Failed)
             }
         }
     }
+
+    /// Language of an exec, which only polyglot kernels have by default
+    fn language<'a>(&'a self, language: Option<&'a str>) -> Option<&'a str> {
+        match self {
+            Repls::Single(_) => language,
+            Repls::Polyglot(polyglot) => Some(language.unwrap_or(polyglot.default_language())),
+        }
+    }
+
+    /// Key of the workers running `language`, `None` for those of every language of a single
+    /// REPL or of the default language of a polyglot kernel
+    fn worker_language(&self, language: Option<&str>) -> Option<String> {
+        match self {
+            Repls::Single(_) => None,
+            Repls::Polyglot(polyglot) => language
+                .filter(|language| *language != polyglot.default_language())
+                .map(str::to_string),
+        }
+    }
 }
 
-async fn watch_cancellation_and_send_queue_cancellation_info(
-    queue_cancellation_info_sender: mpsc::Sender<usize>,
-    queue_cancellation_token: CancellationToken,
-    queue_semaphore: Arc<Semaphore>,
-) {
-    loop {
+/// Request together with the interrupt generation it was sent in
+pub(crate) struct Envelope {
+    request: KernelRequest,
+    sigint: CancellationToken,
+}
+
+/// Holds the token of the current interrupt generation. An interrupt cancels the token, which
+/// reaches every exec sent so far, and starts a new generation for the execs sent afterwards.
+#[derive(Default)]
+struct SigintControl {
+    current: Mutex<CancellationToken>,
+}
+
+impl SigintControl {
+    fn current(&self) -> CancellationToken {
+        self.lock().clone()
+    }
+
+    fn interrupt(&self) {
+        let mut current = self.lock();
+
+        current.cancel();
+        *current = CancellationToken::new();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CancellationToken> {
+        self.current
+            .lock()
+            .expect("Interrupt generation is poisoned")
+    }
+}
+
+#[derive(Clone)]
+struct Responder {
+    response_sender: mpsc::Sender<KernelResponse>,
+    journal: Journal,
+}
+
+impl Responder {
+    async fn send(&self, client_id: Option<ClientId>, response: KernelResponse) {
+        self.journal.record(JournalRecord::Response {
+            client_id,
+            response: response.clone(),
+        });
+
+        let _ = self.response_sender.send(response).await;
+    }
+}
+
+struct ExecSenders {
+    interactive: mpsc::Sender<Exec>,
+    batch: mpsc::Sender<Exec>,
+    /// Set when the kernel has workers
+    independent: Option<mpsc::Sender<Exec>>,
+}
+
+impl ExecSenders {
+    async fn send(
+        &self,
+        lane: Lane,
+        independent: bool,
+        exec: Exec,
+    ) -> Result<(), mpsc::error::SendError<Exec>> {
+        match (independent, &self.independent, lane) {
+            (true, Some(sender), _) => sender.send(exec).await,
+            (_, _, Lane::Interactive) => self.interactive.send(exec).await,
+            (_, _, Lane::Batch) => self.batch.send(exec).await,
+        }
+    }
+}
+
+struct ExecReceivers {
+    interactive: mpsc::Receiver<Exec>,
+    batch: mpsc::Receiver<Exec>,
+}
+
+impl ExecReceivers {
+    /// Takes the next exec, preferring the interactive lane over the batch lane
+    async fn recv(&mut self) -> Option<Exec> {
         tokio::select! {
-            _ = queue_cancellation_token.cancelled() => {
-                let number_of_cancelled_exec = queue_semaphore.available_permits();
-                let _ = queue_cancellation_info_sender.send(number_of_cancelled_exec).await;
-            }
-            else => {}
+            biased;
+
+            Some(exec) = self.interactive.recv() => Some(exec),
+            Some(exec) = self.batch.recv() => Some(exec),
+            else => None,
         }
     }
+
+    fn lane(&mut self, lane: Lane) -> &mut mpsc::Receiver<Exec> {
+        match lane {
+            Lane::Interactive => &mut self.interactive,
+            Lane::Batch => &mut self.batch,
+        }
+    }
+}
+
+/// Worker waiting for an exec, along with the sender it is returned with once the exec is done.
+/// Workers hold the only senders, so that the channel closes once every worker is dropped.
+struct IdleWorker {
+    repl: ReplHandle,
+    idle_sender: mpsc::Sender<IdleWorker>,
 }
 
 struct Exec {
     message_id: u32,
+    client_id: ClientId,
     code: String,
-    io_sender: mpsc::UnboundedSender<Bytes>,
+    io_sender: mpsc::Sender<Output>,
+    output_policy: OutputPolicy,
+    terminal: TerminalMode,
+    language: Option<String>,
     sigint: CancellationToken,
     #[allow(dead_code)]
     queue_permit: OwnedSemaphorePermit,
@@ -0,0 +1,413 @@
+use std::{
+    collections::BTreeMap,
+    env, fs, io,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{self, Command, Stdio},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::Mutex;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    jupyter::ConnectionInfo,
+    kernel::{self, KernelOptions, KernelTerminal},
+    limits::ResourceLimits,
+    queue::KernelQueue,
+    repl::{
+        self,
+        bash::{self, BashRepl},
+        jupyter::{InterruptMode, JupyterConfig, JupyterRepl},
+        magic::Magics,
+        prompt::{InterruptKey, PromptConfig, PromptRepl, Regex},
+        ReplHandle,
+    },
+    sandbox::SandboxProfile,
+};
+
+const SPEC_FILE: &str = "kernel.json";
+
+/// Interpreter process run by a REPL, shared with it
+pub type ReplProcess = Arc<Mutex<process::Child>>;
+
+/// Colon separated directories searched after the workspace, like `PATH`
+const PATH_VARIABLE: &str = "CANAL_KERNEL_PATH";
+
+/// Replaced in `argv` by the directory holding the spec file
+const RESOURCE_DIR_PLACEHOLDER: &str = "{resource_dir}";
+
+/// Replaced in `argv` by the connection file of a Jupyter kernel
+const CONNECTION_FILE_PLACEHOLDER: &str = "{connection_file}";
+
+/// Address the sockets of Jupyter kernels are bound to
+const JUPYTER_IP: &str = "127.0.0.1";
+
+/// Describes an interpreter a kernel can run, read from `<name>/kernel.json` in a kernels
+/// directory, in the layout of Jupyter kernelspecs. The kernelspecs of Jupyter kernels are
+/// read as they are, since they are run by a `JupyterRepl` when `repl` is missing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KernelSpec {
+    pub display_name: String,
+    pub language: String,
+    /// Program and arguments of the interpreter
+    pub argv: Vec<String>,
+    /// Added to the environment of the kernel
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// How a Jupyter kernel is interrupted
+    #[serde(default)]
+    pub interrupt_mode: InterruptMode,
+    #[serde(default)]
+    pub repl: ReplAdapter,
+    /// Set in the interpreter before it is executed
+    #[serde(default)]
+    pub limits: ResourceLimits,
+    /// Runs the interpreter isolated from the host, for untrusted code
+    #[serde(default)]
+    pub sandbox: Option<SandboxProfile>,
+    /// Runs magic commands like `%time` or `%%bash` in the kernel. They are off unless the
+    /// spec asks for them, since cells of its interpreter starting with `%` would not reach it
+    /// anymore. Jupyter kernels have magics of their own.
+    #[serde(default)]
+    pub magics: bool,
+}
+
+/// `Repl` implementation driving the interpreter of a spec
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ReplAdapter {
+    /// `JupyterRepl`, `argv` taking the connection file as `{connection_file}`
+    #[default]
+    Jupyter,
+    /// `BashRepl`, `argv` being bash and the options given before its driver script
+    Bash,
+    /// `PromptRepl`, with the regexes of `PromptConfig`. The interpreter is interrupted with
+    /// `SIGINT`, or by writing `interrupt_input` to it when set.
+    Prompt {
+        prompt: String,
+        #[serde(default)]
+        continuation: Option<String>,
+        #[serde(default)]
+        error: Option<String>,
+        #[serde(default)]
+        interrupt_input: Option<String>,
+    },
+}
+
+/// Spec found by a `KernelRegistry`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelSpecEntry {
+    /// Name of the directory holding the spec, unique in a registry
+    pub name: String,
+    pub resource_dir: PathBuf,
+    pub spec: KernelSpec,
+}
+
+#[derive(Error, Debug)]
+pub enum KernelSpecError {
+    #[error("No kernelspec is named {0}")]
+    NotFound(String),
+    #[error("Kernelspec could not be read")]
+    Io(#[from] io::Error),
+    #[error("Kernelspec {0} is invalid")]
+    Invalid(PathBuf, #[source] serde_json::Error),
+    #[error("Kernelspec has an empty argv")]
+    EmptyArgv,
+    #[error("Kernelspec has an invalid pattern")]
+    Pattern(#[from] regex::Error),
+    #[error("Jupyter kernels need the network of the host, which their sandbox turns off")]
+    SandboxNetwork,
+}
+
+impl KernelSpec {
+    pub fn load(path: &Path) -> Result<Self, KernelSpecError> {
+        let content = fs::read(path)?;
+
+        serde_json::from_slice(&content)
+            .map_err(|err| KernelSpecError::Invalid(path.to_path_buf(), err))
+    }
+
+    /// Command spawning the interpreter with the pipes and process group its REPL expects.
+    /// `{connection_file}` is left as is, since only `launch_repl` writes connection files.
+    pub fn command(&self, resource_dir: &Path) -> Result<Command, KernelSpecError> {
+        self.command_with(resource_dir, None)
+    }
+
+    /// Spawns the interpreter and runs its REPL
+    pub fn launch_repl(&self, resource_dir: &Path) -> Result<ReplHandle, KernelSpecError> {
+        Ok(self.launch_repl_process(resource_dir)?.0)
+    }
+
+    /// Spawns the interpreter and runs its REPL, also returning the interpreter process
+    pub fn launch_repl_process(
+        &self,
+        resource_dir: &Path,
+    ) -> Result<(ReplHandle, ReplProcess), KernelSpecError> {
+        let launched = match &self.repl {
+            ReplAdapter::Jupyter => {
+                // Private to the user, and shown read-only to a sandboxed kernel, which could
+                // otherwise leave it in its workspace
+                let connection = ConnectionInfo::allocate(JUPYTER_IP)?;
+                let connection_file = env::temp_dir()
+                    .canonicalize()?
+                    .join(format!(".canal-kernel-{}.json", Uuid::new_v4()));
+                connection.write(&connection_file)?;
+
+                let process = self
+                    .command_with(resource_dir, Some(&connection_file))
+                    .and_then(|mut command| Ok(command.spawn()?))
+                    .inspect_err(|_| {
+                        let _ = fs::remove_file(&connection_file);
+                    })?;
+                let config = JupyterConfig::new(connection)
+                    .interrupt_mode(self.interrupt_mode)
+                    .connection_file(connection_file);
+
+                let process = Arc::new(Mutex::new(process));
+                (
+                    repl::launch_with::<JupyterRepl>(process.clone(), config),
+                    process,
+                )
+            }
+            ReplAdapter::Bash => {
+                let process = Arc::new(Mutex::new(self.command(resource_dir)?.spawn()?));
+
+                (repl::launch::<BashRepl>(process.clone()), process)
+            }
+            ReplAdapter::Prompt {
+                prompt,
+                continuation,
+                error,
+                interrupt_input,
+            } => {
+                // Patterns are checked before anything is spawned
+                let config = prompt_config(
+                    prompt,
+                    continuation.as_deref(),
+                    error.as_deref(),
+                    interrupt_input.as_deref(),
+                )?;
+                let process = Arc::new(Mutex::new(self.command(resource_dir)?.spawn()?));
+
+                (
+                    repl::launch_with::<PromptRepl>(process.clone(), config),
+                    process,
+                )
+            }
+        };
+
+        let (repl, process) = launched;
+        let mut repl = repl.with_limits(process.clone(), self.limits);
+        if self.magics {
+            repl = repl.with_magics(self.magics()?);
+        }
+
+        Ok((repl, process))
+    }
+
+    /// Built-in magics, with the environment, limits and sandbox of the interpreter
+    fn magics(&self) -> Result<Magics, KernelSpecError> {
+        let magics = Magics::new().env(self.env.clone()).limits(self.limits);
+
+        Ok(match self.sandbox()? {
+            Some(sandbox) => magics.sandbox(sandbox),
+            None => magics,
+        })
+    }
+
+    /// Sandbox of the interpreter, keeping the network of the host for Jupyter kernels
+    fn sandbox(&self) -> Result<Option<SandboxProfile>, KernelSpecError> {
+        let Some(sandbox) = &self.sandbox else {
+            return Ok(None);
+        };
+
+        match (&self.repl, sandbox.network) {
+            (ReplAdapter::Jupyter, Some(false)) => Err(KernelSpecError::SandboxNetwork),
+            (ReplAdapter::Jupyter, None) => Ok(Some(sandbox.clone().network(true))),
+            _ => Ok(Some(sandbox.clone())),
+        }
+    }
+
+    fn command_with(
+        &self,
+        resource_dir: &Path,
+        connection_file: Option<&Path>,
+    ) -> Result<Command, KernelSpecError> {
+        let resource_dir = resource_dir.to_string_lossy();
+        let connection_arg = connection_file.map(Path::to_string_lossy);
+        let mut argv = self.argv.iter().map(|arg| {
+            let arg = arg.replace(RESOURCE_DIR_PLACEHOLDER, &resource_dir);
+            match &connection_arg {
+                Some(connection_arg) => arg.replace(CONNECTION_FILE_PLACEHOLDER, connection_arg),
+                None => arg,
+            }
+        });
+        let program = argv.next().ok_or(KernelSpecError::EmptyArgv)?;
+
+        let mut command = Command::new(program);
+        command.args(argv).envs(&self.env);
+        self.limits.apply(&mut command);
+        if let Some(sandbox) = self.sandbox()? {
+            sandbox.apply(&mut command, connection_file)?;
+        }
+
+        match &self.repl {
+            ReplAdapter::Jupyter => {
+                command.stdin(Stdio::null()).process_group(0);
+            }
+            ReplAdapter::Bash => {
+                bash::configure(&mut command);
+            }
+            ReplAdapter::Prompt { .. } => {
+                command
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .process_group(0);
+            }
+        }
+
+        Ok(command)
+    }
+}
+
+impl KernelSpecEntry {
+    /// Builds the process the REPL of the spec expects, without launching the REPL. Jupyter
+    /// kernels are only given a connection file by `launch_repl`.
+    pub fn spawn(&self) -> Result<process::Child, KernelSpecError> {
+        Ok(self.spec.command(&self.resource_dir)?.spawn()?)
+    }
+
+    pub fn launch_repl(&self) -> Result<ReplHandle, KernelSpecError> {
+        self.spec.launch_repl(&self.resource_dir)
+    }
+
+    pub fn launch_repl_process(&self) -> Result<(ReplHandle, ReplProcess), KernelSpecError> {
+        self.spec.launch_repl_process(&self.resource_dir)
+    }
+}
+
+/// Kernelspecs available to a workspace, by name
+#[derive(Debug, Clone, Default)]
+pub struct KernelRegistry {
+    entries: BTreeMap<String, KernelSpecEntry>,
+}
+
+impl KernelRegistry {
+    /// Finds the specs of `search_dirs(workspace)`
+    pub fn discover(workspace: &Path) -> Self {
+        Self::from_dirs(search_dirs(workspace))
+    }
+
+    /// Finds the specs of the kernels directories in `dirs`. A spec hides the specs of the same
+    /// name in the directories after it, and invalid specs are skipped.
+    pub fn from_dirs(dirs: impl IntoIterator<Item = PathBuf>) -> Self {
+        let mut entries = BTreeMap::new();
+
+        for dir in dirs {
+            let Ok(read_dir) = fs::read_dir(&dir) else {
+                continue;
+            };
+
+            for resource_dir in read_dir.flatten().map(|entry| entry.path()) {
+                let Some(name) = resource_dir.file_name().and_then(|name| name.to_str()) else {
+                    continue;
+                };
+                if entries.contains_key(name) {
+                    continue;
+                }
+
+                let path = resource_dir.join(SPEC_FILE);
+                if !path.is_file() {
+                    continue;
+                }
+
+                match KernelSpec::load(&path) {
+                    Ok(spec) => {
+                        let entry = KernelSpecEntry {
+                            name: name.to_string(),
+                            resource_dir: resource_dir.clone(),
+                            spec,
+                        };
+                        entries.insert(entry.name.clone(), entry);
+                    }
+                    Err(err) => warn!(path = %path.display(), %err, "kernelspec skipped"),
+                }
+            }
+        }
+
+        Self { entries }
+    }
+
+    /// Specs sorted by name
+    pub fn list(&self) -> impl Iterator<Item = &KernelSpecEntry> {
+        self.entries.values()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&KernelSpecEntry> {
+        self.entries.get(name)
+    }
+
+    /// Launches a kernel running the interpreter of the spec `name`
+    pub fn launch(
+        &self,
+        name: &str,
+        options: KernelOptions,
+    ) -> Result<(KernelTerminal, Arc<KernelQueue>), KernelSpecError> {
+        let entry = self
+            .get(name)
+            .ok_or_else(|| KernelSpecError::NotFound(name.to_string()))?;
+
+        Ok(kernel::launch_with(entry.launch_repl()?, options))
+    }
+}
+
+/// Compiles the patterns of a `Prompt` adapter
+fn prompt_config(
+    prompt: &str,
+    continuation: Option<&str>,
+    error: Option<&str>,
+    interrupt_input: Option<&str>,
+) -> Result<PromptConfig, KernelSpecError> {
+    let mut config = PromptConfig::new(Regex::new(prompt)?);
+    if let Some(continuation) = continuation {
+        config = config.continuation(Regex::new(continuation)?);
+    }
+    if let Some(error) = error {
+        config = config.error(Regex::new(error)?);
+    }
+    if let Some(input) = interrupt_input {
+        config = config.interrupt(InterruptKey::Input(input.as_bytes().to_vec()));
+    }
+
+    Ok(config)
+}
+
+/// Kernels directories by precedence: the workspace, `CANAL_KERNEL_PATH`, the user data
+/// directory, then the system ones, the Canal directories before the Jupyter ones
+pub fn search_dirs(workspace: &Path) -> Vec<PathBuf> {
+    let mut dirs = vec![workspace.join(".canal").join("kernels")];
+
+    if let Some(paths) = env::var_os(PATH_VARIABLE) {
+        dirs.extend(env::split_paths(&paths).filter(|path| !path.as_os_str().is_empty()));
+    }
+
+    let user_data = env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")));
+    if let Some(user_data) = user_data {
+        dirs.push(user_data.join("canal").join("kernels"));
+        dirs.push(user_data.join("jupyter").join("kernels"));
+    }
+
+    for system_data in ["/usr/local/share", "/usr/share"] {
+        dirs.push(Path::new(system_data).join("canal").join("kernels"));
+        dirs.push(Path::new(system_data).join("jupyter").join("kernels"));
+    }
+
+    dirs
+}
@@ -1,24 +1,69 @@
+pub mod event;
+pub mod journal;
+pub mod jupyter;
 pub mod kernel;
+pub mod kernelspec;
+pub mod limits;
+pub mod manager;
+pub mod metrics;
+pub mod monitor;
+pub mod output;
+pub mod polyglot;
+pub mod procfs;
+pub mod queue;
 pub mod repl;
+pub mod sandbox;
+pub mod terminal;
+#[cfg(feature = "test-util")]
+pub mod test_util;
 
-use bytes::Bytes;
+use event::{KernelEvent, Seq};
+use limits::ResourceLimit;
+use output::{Output, OutputPolicy};
+use queue::{Lane, QueueStatus};
+use serde::{Deserialize, Serialize};
+use terminal::TerminalMode;
 use tokio::sync::mpsc;
 
 pub type MessageId = u32;
+pub type ClientId = u32;
 
 #[derive(Debug)]
 pub enum KernelRequest {
     Execute {
         message_id: MessageId,
+        client_id: ClientId,
         code: String,
-        io_sender: mpsc::UnboundedSender<Bytes>,
+        io_sender: mpsc::Sender<Output>,
+        output_policy: OutputPolicy,
+        terminal: TerminalMode,
+        lane: Lane,
+        /// Language of the REPL running the code in a polyglot kernel, the default one when
+        /// unset. Kernels of a single REPL run the code whatever its language.
+        language: Option<String>,
+        /// Runs on any idle worker of the kernel rather than in order on its primary REPL, see
+        /// `KernelOptions::workers`. Kernels without workers run it in order.
+        independent: bool,
     },
+    /// Cancels the running execution and every execution sent before this request
     Interrupt,
+    /// Forwards the events of every execution to `event_sender`, starting with the recent
+    /// events from `resume_from` onwards when given
+    Subscribe {
+        resume_from: Option<Seq>,
+        event_sender: mpsc::Sender<KernelEvent>,
+    },
+    QueueStatus,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum KernelResponse {
     Success(MessageId),
     Failed(MessageId),
     Cancelled(MessageId),
+    /// The execution was interrupted for running longer than `KernelOptions::exec_timeout`
+    TimedOut(MessageId),
+    /// The interpreter was killed for exceeding a limit of its kernelspec
+    LimitExceeded(MessageId, ResourceLimit),
+    QueueStatus(QueueStatus),
 }
@@ -0,0 +1,239 @@
+//! Resource limits of interpreters, set with `setrlimit` before they are executed
+
+use std::{
+    fmt, io, mem,
+    os::unix::process::CommandExt,
+    process::{self, Command},
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Mutex, time::sleep};
+
+use crate::procfs;
+
+/// How often an exiting interpreter is checked for having exited
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How long an exiting interpreter is given to exit
+const EXIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Bytes kept from the end of the output of an execution, where interpreters report failed
+/// allocations before they die
+const OUTPUT_TAIL_SIZE: usize = 4096;
+
+/// Lowercase signs of a failed allocation in the output of common interpreters and runtimes:
+/// `ENOMEM`, Python, C++, R, Node.js, Java and Julia
+const OUT_OF_MEMORY_MARKERS: [&str; 5] = [
+    "cannot allocate",
+    "memoryerror",
+    "bad_alloc",
+    "out of memory",
+    "outofmemoryerror",
+];
+
+/// Limits of the interpreter of a kernelspec, inherited by the processes it spawns. Limits left
+/// unset are the ones of the process launching the kernel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceLimits {
+    /// Address space in bytes (`RLIMIT_AS`)
+    pub memory_bytes: Option<u64>,
+    /// CPU time in seconds (`RLIMIT_CPU`), counted for each process on its own
+    pub cpu_seconds: Option<u64>,
+    /// Open file descriptors (`RLIMIT_NOFILE`)
+    pub open_files: Option<u64>,
+    /// Processes (`RLIMIT_NPROC`), counted for the whole user running the interpreter, and
+    /// ignored for root
+    pub processes: Option<u64>,
+}
+
+/// Limit an interpreter was killed for exceeding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResourceLimit {
+    Memory,
+    CpuTime,
+}
+
+/// How an interpreter exited, read without reaping it
+struct Exit {
+    /// Signal that killed the process
+    signal: Option<i32>,
+    success: bool,
+    cpu_time: Duration,
+}
+
+/// End of the output and standard error of an execution
+#[derive(Debug, Default)]
+pub(crate) struct OutputTail(Vec<u8>);
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Sets the limits in the process spawned by `command`, whose spawn fails when a limit is
+    /// above what the launching process is allowed
+    pub(crate) fn apply(&self, command: &mut Command) {
+        if self.is_empty() {
+            return;
+        }
+
+        let limits = *self;
+        // SAFETY: the closure only calls setrlimit, which is async-signal-safe, and allocates
+        // nothing
+        unsafe {
+            command.pre_exec(move || limits.set());
+        }
+    }
+
+    fn set(&self) -> io::Result<()> {
+        // The CPU limit sends SIGXCPU, and SIGKILL a second later to interpreters that ignore it
+        let limits = [
+            (libc::RLIMIT_AS, self.memory_bytes, self.memory_bytes),
+            (
+                libc::RLIMIT_CPU,
+                self.cpu_seconds,
+                self.cpu_seconds.map(|seconds| seconds + 1),
+            ),
+            (libc::RLIMIT_NOFILE, self.open_files, self.open_files),
+            (libc::RLIMIT_NPROC, self.processes, self.processes),
+        ];
+
+        for (resource, soft, hard) in limits {
+            let (Some(soft), Some(hard)) = (soft, hard) else {
+                continue;
+            };
+
+            let limit = libc::rlimit {
+                rlim_cur: soft as libc::rlim_t,
+                rlim_max: hard as libc::rlim_t,
+            };
+            // SAFETY: setrlimit only reads the limit
+            if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Limit the interpreter exceeded, when it exited for one.
+    ///
+    /// A CPU limit kills the interpreter with SIGXCPU, or SIGKILL when it is ignored. A memory
+    /// limit (of the address space, not of resident memory) only makes allocations fail, so it
+    /// is a best effort: the interpreter must have failed after reporting a failed allocation
+    /// at the end of `output`. Other deaths are plain failures. Open files and processes
+    /// limits only make the calls that need more fail.
+    ///
+    /// An interpreter that is still running only failed the execution, and is answered for at
+    /// once. One that is exiting may have closed its output first, and is waited for.
+    pub(crate) async fn exceeded(
+        &self,
+        process: &Arc<Mutex<process::Child>>,
+        output: &OutputTail,
+    ) -> Option<ResourceLimit> {
+        let pid = process.lock().await.id();
+
+        let mut waited = Duration::ZERO;
+        let exit = loop {
+            match peek_exit(pid) {
+                Ok(Some(exit)) => break exit,
+                Ok(None) if waited < EXIT_TIMEOUT && procfs::is_exiting(pid) => {
+                    sleep(EXIT_POLL_INTERVAL).await;
+                    waited += EXIT_POLL_INTERVAL;
+                }
+                // Reaped already, by whoever killed its tree, which leaves nothing to read. It is
+                // never reaped here, so that its tree can still be killed through its pid.
+                Ok(None) | Err(_) => return None,
+            }
+        };
+
+        self.exit_cause(&exit, output)
+    }
+
+    fn exit_cause(&self, exit: &Exit, output: &OutputTail) -> Option<ResourceLimit> {
+        let cpu_exhausted = self
+            .cpu_seconds
+            .is_some_and(|seconds| exit.cpu_time >= Duration::from_secs(seconds));
+        let memory_exhausted = self.memory_bytes.is_some() && output.reports_out_of_memory();
+
+        match exit.signal {
+            Some(libc::SIGXCPU) if self.cpu_seconds.is_some() => Some(ResourceLimit::CpuTime),
+            Some(libc::SIGKILL) if cpu_exhausted => Some(ResourceLimit::CpuTime),
+            _ if !exit.success && memory_exhausted => Some(ResourceLimit::Memory),
+            _ => None,
+        }
+    }
+
+    /// Whether `exceeded` needs the output of failed executions
+    pub(crate) fn watches_output(&self) -> bool {
+        self.memory_bytes.is_some()
+    }
+}
+
+impl OutputTail {
+    pub(crate) fn push(&mut self, data: &[u8]) {
+        self.0.extend_from_slice(data);
+        let excess = self.0.len().saturating_sub(OUTPUT_TAIL_SIZE);
+        self.0.drain(..excess);
+    }
+
+    fn reports_out_of_memory(&self) -> bool {
+        let output = String::from_utf8_lossy(&self.0).to_lowercase();
+        OUT_OF_MEMORY_MARKERS
+            .iter()
+            .any(|marker| output.contains(marker))
+    }
+}
+
+impl fmt::Display for ResourceLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ResourceLimit::Memory => "memory",
+            ResourceLimit::CpuTime => "CPU time",
+        })
+    }
+}
+
+/// Exit of the child `pid` once it exited, leaving it to be reaped by its `process::Child`
+fn peek_exit(pid: u32) -> io::Result<Option<Exit>> {
+    // SAFETY: both are plain C structs, for which zeroes are valid
+    let (mut info, mut usage): (libc::siginfo_t, libc::rusage) =
+        unsafe { (mem::zeroed(), mem::zeroed()) };
+
+    // The libc wrapper of waitid does not return the resource usage of the child
+    // SAFETY: the kernel writes into the two structs, which outlive the call
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_waitid,
+            libc::P_PID,
+            pid as libc::id_t,
+            &mut info as *mut libc::siginfo_t,
+            libc::WEXITED | libc::WNOHANG | libc::WNOWAIT,
+            &mut usage as *mut libc::rusage,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: waitid filled the fields of SIGCHLD, which are zeroes while the child runs
+    let (exited_pid, status) = unsafe { (info.si_pid(), info.si_status()) };
+    if exited_pid == 0 {
+        return Ok(None);
+    }
+
+    let killed = matches!(info.si_code, libc::CLD_KILLED | libc::CLD_DUMPED);
+    let cpu_time = [usage.ru_utime, usage.ru_stime]
+        .iter()
+        .map(|time| Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000))
+        .sum();
+
+    Ok(Some(Exit {
+        signal: killed.then_some(status),
+        success: !killed && status == 0,
+        cpu_time,
+    }))
+}
@@ -0,0 +1,453 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::{broadcast, mpsc, Mutex as AsyncMutex};
+use tracing::{debug, info};
+
+use crate::{
+    kernel::{self, KernelOptions, KernelSender},
+    kernelspec::{KernelRegistry, KernelSpecError, ReplProcess},
+    monitor::{Monitor, MonitorConfig, UsageSample, UsageWarning, Watch},
+    procfs::{self, ResourceUsage},
+    queue::{KernelQueue, QueueStatus},
+    repl, KernelRequest, KernelResponse,
+};
+
+use self::pool::Pool;
+
+pub mod cull;
+pub mod pool;
+
+pub type KernelId = u32;
+
+/// Number of manager events kept for a subscriber that reads slower than they happen
+pub const MANAGER_EVENT_CAPACITY: usize = 64;
+
+#[derive(Error, Debug)]
+pub enum ManagerError {
+    #[error("No kernel has id {0}")]
+    NotFound(KernelId),
+    #[error("Kernel could not be launched")]
+    Launch(#[from] KernelSpecError),
+    #[error("Warm-up of a kernel of {0} failed")]
+    WarmUp(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KernelState {
+    /// Nothing is running or queued
+    Idle,
+    Busy,
+    /// The interpreter exited, so every execution fails until the kernel is restarted
+    Dead,
+}
+
+/// Snapshot of a kernel of a `KernelManager`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KernelInfo {
+    pub id: KernelId,
+    /// Name of the kernelspec the kernel was launched from
+    pub spec_name: String,
+    /// Process of the interpreter
+    pub pid: u32,
+    pub state: KernelState,
+    pub started_at: SystemTime,
+    pub restarts: u32,
+    /// Number of clients connected to the kernel
+    pub connections: usize,
+    /// Time since the last request or connection, or since a culler keeping busy kernels last
+    /// saw it busy
+    pub idle_for: Duration,
+    pub queue: QueueStatus,
+    /// Resources used by the interpreter and its descendants
+    pub usage: ResourceUsage,
+    /// Last sample of the monitor of the kernel, with the peak memory and the CPU load
+    pub sample: Option<UsageSample>,
+}
+
+/// Something that happened to a kernel without being asked by its clients
+#[derive(Debug, Clone, PartialEq)]
+pub enum ManagerEvent {
+    /// The kernel is about to be shut down for being idle, see `CullPolicy`
+    Culled {
+        id: KernelId,
+        spec_name: String,
+        idle_for: Duration,
+    },
+    /// The interpreter uses more of a resource than the `MonitorConfig` of the manager warns
+    /// about
+    UsageWarning { id: KernelId, warning: UsageWarning },
+}
+
+/// Connection of a client to a kernel, which lasts until it is dropped
+pub struct KernelConnection {
+    activity: Arc<Activity>,
+}
+
+/// Launches kernels from the specs of a registry and keeps track of them by id.
+///
+/// Requests are routed to a kernel by its id, and the responses of each kernel are received
+/// separately. Kernels are shut down by killing the process tree of their interpreter, which
+/// also happens to every kernel left when the manager is dropped.
+pub struct KernelManager {
+    registry: KernelRegistry,
+    queue_capacity: usize,
+    monitor: Monitor,
+    state: Mutex<ManagerState>,
+    events: broadcast::Sender<ManagerEvent>,
+}
+
+#[derive(Default)]
+struct ManagerState {
+    next_id: KernelId,
+    kernels: BTreeMap<KernelId, Arc<ManagedKernel>>,
+    /// Pools of started kernels by spec name
+    pools: BTreeMap<String, Pool>,
+}
+
+struct ManagedKernel {
+    spec_name: String,
+    sender: KernelSender,
+    /// Shared apart from the sender, which keeps the kernel running while it is alive
+    responses: Arc<AsyncMutex<mpsc::Receiver<KernelResponse>>>,
+    queue: Arc<KernelQueue>,
+    process: ReplProcess,
+    pid: u32,
+    started_at: SystemTime,
+    restarts: u32,
+    /// Kept across restarts
+    activity: Arc<Activity>,
+    /// Samples the interpreter once the kernel has an id
+    monitor: Option<Watch>,
+}
+
+/// Use of a kernel by its clients
+struct Activity {
+    last: Mutex<Instant>,
+    connections: AtomicUsize,
+}
+
+impl KernelManager {
+    /// Manages kernels launched from `registry`, each accepting `queue_capacity` executions
+    pub fn new(registry: KernelRegistry, queue_capacity: usize) -> Self {
+        Self {
+            registry,
+            queue_capacity,
+            monitor: Monitor::new(MonitorConfig::default()),
+            state: Mutex::new(ManagerState::default()),
+            events: broadcast::channel(MANAGER_EVENT_CAPACITY).0,
+        }
+    }
+
+    /// Samples the interpreters of the kernels as configured, every second by default
+    pub fn monitor(mut self, config: MonitorConfig) -> Self {
+        self.monitor = Monitor::new(config);
+        self
+    }
+
+    pub fn registry(&self) -> &KernelRegistry {
+        &self.registry
+    }
+
+    /// Launches a kernel running the interpreter of the spec `spec_name`, or hands out a
+    /// kernel of its pool when there is one ready
+    pub fn start(&self, spec_name: &str) -> Result<KernelId, ManagerError> {
+        let kernel = match self.take_pooled(spec_name) {
+            Some(kernel) => kernel,
+            None => launch(
+                &self.registry,
+                spec_name,
+                KernelOptions::new(self.queue_capacity),
+            )?,
+        };
+
+        Ok(self.insert(kernel))
+    }
+
+    /// Launches a kernel with its own options, which never comes from a pool
+    pub fn start_with(
+        &self,
+        spec_name: &str,
+        options: KernelOptions,
+    ) -> Result<KernelId, ManagerError> {
+        let kernel = launch(&self.registry, spec_name, options)?;
+
+        Ok(self.insert(kernel))
+    }
+
+    /// Kernels sorted by id
+    pub fn list(&self) -> Vec<KernelInfo> {
+        let kernels = self.state().kernels.clone();
+
+        kernels
+            .into_iter()
+            .map(|(id, kernel)| kernel.info(id))
+            .collect()
+    }
+
+    pub fn info(&self, id: KernelId) -> Result<KernelInfo, ManagerError> {
+        Ok(self.get(id)?.info(id))
+    }
+
+    /// Sending half of the terminal of the kernel, to subscribe to its events for instance
+    pub fn sender(&self, id: KernelId) -> Result<KernelSender, ManagerError> {
+        Ok(self.get(id)?.sender.clone())
+    }
+
+    pub async fn send(&self, id: KernelId, request: KernelRequest) -> Result<(), ManagerError> {
+        let kernel = self.get(id)?;
+        kernel.activity.touch();
+        kernel.sender.send(request).await;
+
+        Ok(())
+    }
+
+    /// Counts a client as connected to the kernel until the connection is dropped
+    pub fn connect(&self, id: KernelId) -> Result<KernelConnection, ManagerError> {
+        let activity = self.get(id)?.activity.clone();
+        activity.touch();
+        activity.connections.fetch_add(1, Ordering::Relaxed);
+
+        Ok(KernelConnection { activity })
+    }
+
+    /// Receives what happens to the kernels from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<ManagerEvent> {
+        self.events.subscribe()
+    }
+
+    /// Receives the next response of the kernel, `None` once the kernel is restarted or shut
+    /// down
+    pub async fn recv(&self, id: KernelId) -> Result<Option<KernelResponse>, ManagerError> {
+        let responses = self.get(id)?.responses.clone();
+        let mut responses = responses.lock().await;
+
+        Ok(responses.recv().await)
+    }
+
+    pub async fn interrupt(&self, id: KernelId) -> Result<(), ManagerError> {
+        self.send(id, KernelRequest::Interrupt).await
+    }
+
+    pub async fn interrupt_all(&self) {
+        for kernel in self.kernels() {
+            kernel.sender.send(KernelRequest::Interrupt).await;
+        }
+    }
+
+    /// Kills the interpreter of the kernel and launches a new one from the same spec under the
+    /// same id. The executions of the previous interpreter fail, and its state is lost.
+    pub async fn restart(&self, id: KernelId) -> Result<(), ManagerError> {
+        let previous = self.get(id)?;
+        kill(&previous).await;
+
+        let mut kernel = launch(
+            &self.registry,
+            &previous.spec_name,
+            KernelOptions::new(self.queue_capacity),
+        )?;
+        kernel.restarts = previous.restarts + 1;
+        kernel.activity = previous.activity.clone();
+        kernel.activity.touch();
+        kernel.monitor = Some(self.watch(id, &kernel));
+        info!(id, pid = kernel.pid, "kernel restarted");
+        self.state().kernels.insert(id, Arc::new(kernel));
+
+        Ok(())
+    }
+
+    /// Kills the interpreter of the kernel and forgets the kernel
+    pub async fn shutdown(&self, id: KernelId) -> Result<(), ManagerError> {
+        let kernel = self
+            .state()
+            .kernels
+            .remove(&id)
+            .ok_or(ManagerError::NotFound(id))?;
+
+        kill(&kernel).await;
+        info!(id, "kernel shut down");
+
+        Ok(())
+    }
+
+    /// Shuts down every kernel, as the server exits, pooled ones included
+    pub async fn shutdown_all(&self) {
+        drop(std::mem::take(&mut self.state().pools));
+        let kernels = std::mem::take(&mut self.state().kernels);
+
+        for (id, kernel) in kernels {
+            kill(&kernel).await;
+            info!(id, "kernel shut down");
+        }
+    }
+
+    fn insert(&self, mut kernel: ManagedKernel) -> KernelId {
+        let mut state = self.state();
+        let id = state.next_id;
+        state.next_id += 1;
+        kernel.monitor = Some(self.watch(id, &kernel));
+        info!(
+            id,
+            spec_name = kernel.spec_name,
+            pid = kernel.pid,
+            "kernel started"
+        );
+        state.kernels.insert(id, Arc::new(kernel));
+
+        id
+    }
+
+    fn watch(&self, id: KernelId, kernel: &ManagedKernel) -> Watch {
+        let events = self.events.clone();
+
+        self.monitor
+            .watch(kernel.pid, kernel.sender.clone(), move |warning| {
+                let _ = events.send(ManagerEvent::UsageWarning { id, warning });
+            })
+    }
+
+    fn get(&self, id: KernelId) -> Result<Arc<ManagedKernel>, ManagerError> {
+        self.state()
+            .kernels
+            .get(&id)
+            .cloned()
+            .ok_or(ManagerError::NotFound(id))
+    }
+
+    fn kernels(&self) -> Vec<Arc<ManagedKernel>> {
+        self.state().kernels.values().cloned().collect()
+    }
+
+    fn state(&self) -> MutexGuard<'_, ManagerState> {
+        self.state.lock().expect("Kernel manager is poisoned")
+    }
+}
+
+impl Drop for KernelManager {
+    fn drop(&mut self) {
+        let mut state = self.state();
+        // Pooled kernels are killed as their pool is dropped
+        state.pools.clear();
+        for kernel in state.kernels.values() {
+            kill_now(kernel);
+        }
+    }
+}
+
+impl ManagedKernel {
+    fn info(&self, id: KernelId) -> KernelInfo {
+        KernelInfo {
+            id,
+            spec_name: self.spec_name.clone(),
+            pid: self.pid,
+            state: self.state(),
+            started_at: self.started_at,
+            restarts: self.restarts,
+            connections: self.activity.connections(),
+            idle_for: self.activity.idle_for(),
+            queue: self.queue.status(),
+            usage: procfs::usage(self.pid),
+            sample: self.monitor.as_ref().and_then(Watch::last),
+        }
+    }
+
+    fn state(&self) -> KernelState {
+        // The interpreter is left unreaped, so that its pid stays its own until it is killed
+        if procfs::child_exited(self.pid) != Some(false) {
+            KernelState::Dead
+        } else if self.queue.running().is_some() || !self.queue.is_empty() {
+            KernelState::Busy
+        } else {
+            KernelState::Idle
+        }
+    }
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            last: Mutex::new(Instant::now()),
+            connections: AtomicUsize::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        *self.last.lock().expect("Kernel activity is poisoned") = Instant::now();
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last
+            .lock()
+            .expect("Kernel activity is poisoned")
+            .elapsed()
+    }
+
+    fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for KernelConnection {
+    fn drop(&mut self) {
+        self.activity.touch();
+        self.activity.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Kills the process tree of the interpreter and reaps the interpreter
+async fn kill(kernel: &ManagedKernel) {
+    repl::kill(&kernel.process).await;
+    debug!(pid = kernel.pid, "kernel process tree killed");
+}
+
+/// Kills the process tree of the interpreter, and reaps the interpreter unless it is in use
+fn kill_now(kernel: &ManagedKernel) {
+    let process = kernel.process.try_lock();
+    if procfs::child_exited(kernel.pid).is_some() {
+        procfs::kill_tree(kernel.pid);
+    }
+
+    // Killed already, so waiting is brief
+    if let Ok(mut process) = process {
+        let _ = process.wait();
+    }
+}
+
+fn launch(
+    registry: &KernelRegistry,
+    spec_name: &str,
+    options: KernelOptions,
+) -> Result<ManagedKernel, ManagerError> {
+    let entry = registry
+        .get(spec_name)
+        .ok_or_else(|| KernelSpecError::NotFound(spec_name.to_string()))?;
+
+    let (repl, process) = entry.launch_repl_process()?;
+    let pid = process
+        .try_lock()
+        .expect("REPL process is used before the kernel is launched")
+        .id();
+    let (terminal, queue) = kernel::launch_with(repl, options);
+    let (sender, responses) = terminal.split();
+
+    Ok(ManagedKernel {
+        spec_name: spec_name.to_string(),
+        sender,
+        responses: Arc::new(AsyncMutex::new(responses)),
+        queue,
+        process,
+        pid,
+        started_at: SystemTime::now(),
+        restarts: 0,
+        activity: Arc::new(Activity::new()),
+        monitor: None,
+    })
+}
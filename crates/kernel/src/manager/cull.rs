@@ -0,0 +1,107 @@
+use std::{
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use tokio::{task, time::interval};
+use tracing::info;
+
+use super::{KernelId, KernelManager, KernelState, ManagerEvent};
+
+/// Decides which idle kernels a `KernelManager` shuts down to free their resources
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CullPolicy {
+    /// Kernels idle for longer are culled
+    pub idle_timeout: Duration,
+    /// How often kernels are checked by `KernelManager::spawn_culler`
+    pub interval: Duration,
+    /// Kernels some client is connected to are kept
+    pub only_disconnected: bool,
+    /// Busy kernels are culled as well, once their last request is older than `idle_timeout`
+    pub cull_busy: bool,
+}
+
+impl CullPolicy {
+    pub fn new(idle_timeout: Duration) -> Self {
+        Self {
+            idle_timeout,
+            interval: Duration::from_secs(60),
+            only_disconnected: false,
+            cull_busy: false,
+        }
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn only_disconnected(mut self, only_disconnected: bool) -> Self {
+        self.only_disconnected = only_disconnected;
+        self
+    }
+
+    pub fn cull_busy(mut self, cull_busy: bool) -> Self {
+        self.cull_busy = cull_busy;
+        self
+    }
+}
+
+impl KernelManager {
+    /// Shuts down the kernels idle for longer than the policy allows, after publishing
+    /// `ManagerEvent::Culled` for each of them, and returns their ids.
+    ///
+    /// A kernel is idle from its last request, or from the last time it was seen busy when
+    /// busy kernels are kept, which is at the latest the previous check.
+    pub async fn cull(&self, policy: &CullPolicy) -> Vec<KernelId> {
+        let kernels = self.state().kernels.clone();
+        let mut culled = Vec::new();
+
+        for (id, kernel) in kernels {
+            if kernel.state() == KernelState::Busy && !policy.cull_busy {
+                kernel.activity.touch();
+                continue;
+            }
+            if policy.only_disconnected && kernel.activity.connections() > 0 {
+                continue;
+            }
+
+            let idle_for = kernel.activity.idle_for();
+            if idle_for < policy.idle_timeout {
+                continue;
+            }
+
+            info!(id, ?idle_for, "culling idle kernel");
+            let _ = self.events.send(ManagerEvent::Culled {
+                id,
+                spec_name: kernel.spec_name.clone(),
+                idle_for,
+            });
+
+            // The kernel may have been shut down in the meantime
+            if self.shutdown(id).await.is_ok() {
+                culled.push(id);
+            }
+        }
+
+        culled
+    }
+
+    /// Culls kernels every `policy.interval` until the manager is dropped
+    pub fn spawn_culler(self: &Arc<Self>, policy: CullPolicy) -> task::JoinHandle<()> {
+        let manager = Arc::downgrade(self);
+
+        task::spawn(async move {
+            let mut ticks = interval(policy.interval);
+
+            loop {
+                ticks.tick().await;
+
+                let Some(manager) = Weak::upgrade(&manager) else {
+                    break;
+                };
+                manager.cull(&policy).await;
+            }
+        })
+    }
+}
@@ -0,0 +1,401 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    runtime::Handle,
+    select,
+    sync::{mpsc, Notify},
+    task,
+    time::{sleep, sleep_until},
+};
+use tracing::{debug, info, warn};
+
+use super::{kill, kill_now, launch, KernelManager, ManagedKernel, ManagerError};
+use crate::{
+    kernel::KernelOptions,
+    kernelspec::{KernelRegistry, KernelSpecError},
+    metrics::render_labels,
+    output::{OutputPolicy, OUTPUT_CHANNEL_CAPACITY},
+    queue::Lane,
+    terminal::TerminalMode,
+    KernelRequest, KernelResponse, MessageId,
+};
+
+/// Message id of the warm-up execution, which clients may see among the events of the kernel
+pub const WARM_UP_MESSAGE_ID: MessageId = MessageId::MAX;
+
+/// Delay before launching a kernel again after a launch or a warm-up failed
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Kernels of a spec that a `KernelManager` keeps started ahead of requests
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolConfig {
+    /// Number of kernels kept ready
+    pub size: usize,
+    /// Code executed by each kernel before it is ready, to import libraries for instance
+    pub warm_up: Option<String>,
+    /// Ready kernels older than this are replaced, so that none is handed out stale
+    pub max_age: Option<Duration>,
+}
+
+impl PoolConfig {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            warm_up: None,
+            max_age: None,
+        }
+    }
+
+    pub fn warm_up(mut self, code: impl Into<String>) -> Self {
+        self.warm_up = Some(code.into());
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+}
+
+/// Snapshot of the pool of a spec
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PoolStats {
+    pub spec_name: String,
+    pub size: usize,
+    /// Kernels ready to be handed out
+    pub ready: usize,
+    /// Kernels started from the pool
+    pub hits: u64,
+    /// Kernels launched on request because none was ready
+    pub misses: u64,
+}
+
+/// Pool of a spec, whose kernels are killed when it is dropped
+pub(super) struct Pool {
+    shared: Arc<PoolShared>,
+    refiller: task::JoinHandle<()>,
+}
+
+struct PoolShared {
+    spec_name: String,
+    config: PoolConfig,
+    ready: Mutex<VecDeque<PooledKernel>>,
+    /// Wakes the refiller up once a kernel is taken
+    taken: Notify,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct PooledKernel {
+    kernel: ManagedKernel,
+    ready_at: Instant,
+}
+
+/// Kernel being warmed up, which is killed when dropped before it is ready, as happens when
+/// the pool is dropped meanwhile
+struct WarmingKernel(Option<ManagedKernel>);
+
+impl KernelManager {
+    /// Keeps `config.size` kernels of the spec started, which `start` hands out before
+    /// launching new ones. Replaces the previous pool of the spec, killing its kernels.
+    pub fn set_pool(&self, spec_name: &str, config: PoolConfig) -> Result<(), ManagerError> {
+        if self.registry.get(spec_name).is_none() {
+            return Err(KernelSpecError::NotFound(spec_name.to_string()).into());
+        }
+
+        let shared = Arc::new(PoolShared {
+            spec_name: spec_name.to_string(),
+            config,
+            ready: Mutex::new(VecDeque::new()),
+            taken: Notify::new(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        });
+        let refiller = task::spawn(refill(
+            shared.clone(),
+            self.registry.clone(),
+            self.queue_capacity,
+        ));
+
+        let previous = self
+            .state()
+            .pools
+            .insert(spec_name.to_string(), Pool { shared, refiller });
+        drop(previous);
+
+        Ok(())
+    }
+
+    /// Stops keeping kernels of the spec started, and kills those that are ready
+    pub fn remove_pool(&self, spec_name: &str) {
+        let pool = self.state().pools.remove(spec_name);
+        drop(pool);
+    }
+
+    /// Pools sorted by spec name
+    pub fn pool_stats(&self) -> Vec<PoolStats> {
+        self.state()
+            .pools
+            .values()
+            .map(|pool| pool.shared.stats())
+            .collect()
+    }
+
+    /// Takes a ready kernel from the pool of the spec, if it has one
+    pub(super) fn take_pooled(&self, spec_name: &str) -> Option<ManagedKernel> {
+        let pool = self.state().pools.get(spec_name)?.shared.clone();
+        let kernel = pool.take();
+
+        match &kernel {
+            Some(kernel) => {
+                pool.hits.fetch_add(1, Ordering::Relaxed);
+                kernel.activity.touch();
+                debug!(spec_name, pid = kernel.pid, "kernel taken from pool");
+            }
+            None => {
+                pool.misses.fetch_add(1, Ordering::Relaxed);
+                debug!(spec_name, "kernel pool is empty");
+            }
+        }
+        pool.taken.notify_one();
+
+        kernel
+    }
+}
+
+/// Renders the stats of pools in the Prometheus text exposition format, each pool being labeled
+/// with its spec
+pub fn render_pool_prometheus(pools: &[PoolStats]) -> String {
+    let mut text = String::new();
+
+    for (name, kind, help, value) in [
+        (
+            "canal_kernel_pool_hits_total",
+            "counter",
+            "Kernels started from a pool.",
+            (|pool| pool.hits) as fn(&PoolStats) -> u64,
+        ),
+        (
+            "canal_kernel_pool_misses_total",
+            "counter",
+            "Kernels launched on request because their pool was empty.",
+            |pool| pool.misses,
+        ),
+        (
+            "canal_kernel_pool_ready",
+            "gauge",
+            "Kernels ready to be handed out.",
+            |pool| pool.ready as u64,
+        ),
+    ] {
+        let _ = writeln!(text, "# HELP {name} {help}");
+        let _ = writeln!(text, "# TYPE {name} {kind}");
+
+        for pool in pools {
+            let labels = render_labels(&[("spec", &pool.spec_name)], None);
+            let _ = writeln!(text, "{name}{labels} {}", value(pool));
+        }
+    }
+
+    text
+}
+
+impl PoolShared {
+    fn stats(&self) -> PoolStats {
+        PoolStats {
+            spec_name: self.spec_name.clone(),
+            size: self.config.size,
+            ready: self.ready().len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Oldest kernel that did not expire
+    fn take(&self) -> Option<ManagedKernel> {
+        kill_detached(self.expire());
+        self.ready().pop_front().map(|pooled| pooled.kernel)
+    }
+
+    /// Takes the kernels older than the max age out of the pool, for the caller to kill once
+    /// the pool is unlocked
+    fn expire(&self) -> Vec<ManagedKernel> {
+        let Some(max_age) = self.config.max_age else {
+            return Vec::new();
+        };
+
+        let mut ready = self.ready();
+        let mut expired = Vec::new();
+        while let Some(pooled) = ready.front() {
+            if pooled.ready_at.elapsed() < max_age {
+                break;
+            }
+
+            debug!(
+                spec_name = self.spec_name,
+                pid = pooled.kernel.pid,
+                "pooled kernel expired"
+            );
+            expired.extend(ready.pop_front().map(|pooled| pooled.kernel));
+        }
+
+        expired
+    }
+
+    /// When the oldest kernel expires
+    fn next_expiry(&self) -> Option<Instant> {
+        let max_age = self.config.max_age?;
+        let ready_at = self.ready().front()?.ready_at;
+
+        Some(ready_at + max_age)
+    }
+
+    fn ready(&self) -> MutexGuard<'_, VecDeque<PooledKernel>> {
+        self.ready.lock().expect("Kernel pool is poisoned")
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        self.refiller.abort();
+
+        let kernels = self
+            .shared
+            .ready()
+            .drain(..)
+            .map(|pooled| pooled.kernel)
+            .collect();
+        kill_detached(kernels);
+    }
+}
+
+/// Kills kernels on a task of their own, so that no caller waits for them to exit
+fn kill_detached(kernels: Vec<ManagedKernel>) {
+    if kernels.is_empty() {
+        return;
+    }
+
+    match Handle::try_current() {
+        Ok(runtime) => {
+            runtime.spawn(async move {
+                for kernel in &kernels {
+                    kill(kernel).await;
+                }
+            });
+        }
+        Err(_) => kernels.iter().for_each(kill_now),
+    }
+}
+
+/// Launches kernels until the pool is full, and again whenever some are taken or expire
+async fn refill(pool: Arc<PoolShared>, registry: KernelRegistry, queue_capacity: usize) {
+    info!(
+        spec_name = pool.spec_name,
+        size = pool.config.size,
+        "kernel pool started"
+    );
+
+    loop {
+        kill_detached(pool.expire());
+
+        if pool.ready().len() < pool.config.size {
+            match launch_warm(&pool, &registry, queue_capacity).await {
+                Ok(kernel) => {
+                    debug!(
+                        spec_name = pool.spec_name,
+                        pid = kernel.pid,
+                        "pooled kernel ready"
+                    );
+                    pool.ready().push_back(PooledKernel {
+                        kernel,
+                        ready_at: Instant::now(),
+                    });
+                }
+                Err(err) => {
+                    warn!(
+                        spec_name = pool.spec_name,
+                        "pooled kernel failed to start: {err}"
+                    );
+                    sleep(RETRY_DELAY).await;
+                }
+            }
+            continue;
+        }
+
+        match pool.next_expiry() {
+            Some(expiry) => select! {
+                _ = pool.taken.notified() => {}
+                _ = sleep_until(expiry.into()) => {}
+            },
+            None => pool.taken.notified().await,
+        }
+    }
+}
+
+/// Launches a kernel of the pool and executes the warm-up code on it
+async fn launch_warm(
+    pool: &PoolShared,
+    registry: &KernelRegistry,
+    queue_capacity: usize,
+) -> Result<ManagedKernel, ManagerError> {
+    let warming = WarmingKernel(Some(launch(
+        registry,
+        &pool.spec_name,
+        KernelOptions::new(queue_capacity),
+    )?));
+    let kernel = warming.0.as_ref().expect("Warming kernel is ready already");
+    let Some(code) = &pool.config.warm_up else {
+        return Ok(warming.ready());
+    };
+
+    let (io_sender, mut io_receiver) = mpsc::channel(OUTPUT_CHANNEL_CAPACITY);
+    let request = KernelRequest::Execute {
+        message_id: WARM_UP_MESSAGE_ID,
+        client_id: 0,
+        code: code.clone(),
+        io_sender,
+        output_policy: OutputPolicy::Block,
+        terminal: TerminalMode::Raw,
+        lane: Lane::Batch,
+        language: None,
+        independent: false,
+    };
+
+    let (_, response) = tokio::join!(
+        async { while io_receiver.recv().await.is_some() {} },
+        async {
+            kernel.sender.send(request).await;
+            kernel.responses.lock().await.recv().await
+        },
+    );
+
+    if response == Some(KernelResponse::Success(WARM_UP_MESSAGE_ID)) {
+        Ok(warming.ready())
+    } else {
+        kill(kernel).await;
+        Err(ManagerError::WarmUp(pool.spec_name.clone()))
+    }
+}
+
+impl WarmingKernel {
+    fn ready(mut self) -> ManagedKernel {
+        self.0.take().expect("Warming kernel is ready already")
+    }
+}
+
+impl Drop for WarmingKernel {
+    fn drop(&mut self) {
+        if let Some(kernel) = &self.0 {
+            kill_now(kernel);
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    fmt::Write,
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::event::ExecStatus;
+
+const SECONDS_BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0,
+];
+const BYTES_BUCKETS: [f64; 10] = [
+    64.0, 256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0, 16777216.0,
+];
+
+/// Instrumentation of one kernel, updated as executions go through it
+pub struct KernelMetrics {
+    state: Mutex<MetricsSnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MetricsSnapshot {
+    /// Seconds between the arrival of an `Execute` request and the start of its execution
+    pub queue_wait: Histogram,
+    /// Seconds spent by the REPL on an execution
+    pub exec_duration: Histogram,
+    /// Bytes written by the REPL for an execution
+    pub output_bytes: Histogram,
+    pub succeeded: u64,
+    pub failed: u64,
+    pub cancelled: u64,
+    pub timed_out: u64,
+    pub limit_exceeded: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Histogram {
+    /// Upper bounds of the buckets, in increasing order
+    pub bounds: Vec<f64>,
+    /// Number of observations per bucket (not cumulative), the last one being above every bound
+    pub counts: Vec<u64>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &[f64]) -> Self {
+        Self {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+
+        self.counts[bucket] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+impl Default for KernelMetrics {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new(MetricsSnapshot {
+                queue_wait: Histogram::new(&SECONDS_BUCKETS),
+                exec_duration: Histogram::new(&SECONDS_BUCKETS),
+                output_bytes: Histogram::new(&BYTES_BUCKETS),
+                succeeded: 0,
+                failed: 0,
+                cancelled: 0,
+                timed_out: 0,
+                limit_exceeded: 0,
+            }),
+        }
+    }
+}
+
+impl KernelMetrics {
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        self.state().clone()
+    }
+
+    pub(crate) fn record_queue_wait(&self, wait: Duration) {
+        self.state().queue_wait.observe(wait.as_secs_f64());
+    }
+
+    pub(crate) fn record_exec(&self, duration: Duration, output_bytes: u64) {
+        let mut state = self.state();
+
+        state.exec_duration.observe(duration.as_secs_f64());
+        state.output_bytes.observe(output_bytes as f64);
+    }
+
+    pub(crate) fn record_result(&self, status: ExecStatus) {
+        let mut state = self.state();
+
+        match status {
+            ExecStatus::Succeeded => state.succeeded += 1,
+            ExecStatus::Failed => state.failed += 1,
+            ExecStatus::Cancelled => state.cancelled += 1,
+            ExecStatus::TimedOut => state.timed_out += 1,
+            ExecStatus::LimitExceeded => state.limit_exceeded += 1,
+            ExecStatus::Queued | ExecStatus::Running => {}
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, MetricsSnapshot> {
+        self.state.lock().expect("Kernel metrics are poisoned")
+    }
+}
+
+impl MetricsSnapshot {
+    /// Renders the metrics in the Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        render_prometheus([(&[][..], self)])
+    }
+}
+
+/// Renders the metrics of several kernels in the Prometheus text exposition format, each kernel
+/// being told apart by its labels (e.g. `[("kernel", "3")]`)
+pub fn render_prometheus<'a>(
+    kernels: impl IntoIterator<Item = (&'a [(&'a str, &'a str)], &'a MetricsSnapshot)>,
+) -> String {
+    let kernels: Vec<_> = kernels.into_iter().collect();
+    let mut text = String::new();
+
+    write_histogram(
+        &mut text,
+        "canal_kernel_queue_wait_seconds",
+        "Time between the arrival of an execution and its start.",
+        kernels
+            .iter()
+            .map(|(labels, metrics)| (*labels, &metrics.queue_wait)),
+    );
+    write_histogram(
+        &mut text,
+        "canal_kernel_exec_duration_seconds",
+        "Time spent by the REPL on an execution.",
+        kernels
+            .iter()
+            .map(|(labels, metrics)| (*labels, &metrics.exec_duration)),
+    );
+    write_histogram(
+        &mut text,
+        "canal_kernel_output_bytes",
+        "Bytes written by the REPL for an execution.",
+        kernels
+            .iter()
+            .map(|(labels, metrics)| (*labels, &metrics.output_bytes)),
+    );
+
+    let name = "canal_kernel_executions_total";
+    let _ = writeln!(text, "# HELP {name} Executions by result.");
+    let _ = writeln!(text, "# TYPE {name} counter");
+
+    for (labels, metrics) in &kernels {
+        for (result, count) in [
+            ("success", metrics.succeeded),
+            ("failed", metrics.failed),
+            ("cancelled", metrics.cancelled),
+            ("timed_out", metrics.timed_out),
+            ("limit_exceeded", metrics.limit_exceeded),
+        ] {
+            let labels = render_labels(labels, Some(("result", result)));
+            let _ = writeln!(text, "{name}{labels} {count}");
+        }
+    }
+
+    text
+}
+
+fn write_histogram<'a>(
+    text: &mut String,
+    name: &str,
+    help: &str,
+    histograms: impl Iterator<Item = (&'a [(&'a str, &'a str)], &'a Histogram)>,
+) {
+    let _ = writeln!(text, "# HELP {name} {help}");
+    let _ = writeln!(text, "# TYPE {name} histogram");
+
+    for (labels, histogram) in histograms {
+        let mut cumulative = 0;
+
+        for (bound, count) in histogram.bounds.iter().zip(&histogram.counts) {
+            cumulative += count;
+            let le = bound.to_string();
+            let labels = render_labels(labels, Some(("le", &le)));
+            let _ = writeln!(text, "{name}_bucket{labels} {cumulative}");
+        }
+
+        let labels_inf = render_labels(labels, Some(("le", "+Inf")));
+        let labels = render_labels(labels, None);
+        let _ = writeln!(text, "{name}_bucket{labels_inf} {}", histogram.count);
+        let _ = writeln!(text, "{name}_sum{labels} {}", histogram.sum);
+        let _ = writeln!(text, "{name}_count{labels} {}", histogram.count);
+    }
+}
+
+pub(crate) fn render_labels(labels: &[(&str, &str)], extra: Option<(&str, &str)>) -> String {
+    let labels: Vec<_> = labels
+        .iter()
+        .copied()
+        .chain(extra)
+        .map(|(name, value)| format!("{name}=\"{}\"", escape_label_value(value)))
+        .collect();
+
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
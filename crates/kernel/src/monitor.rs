@@ -0,0 +1,280 @@
+//! Live resource usage of interpreters, sampled from `/proc`
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    task,
+    time::{interval, MissedTickBehavior},
+};
+use tracing::warn;
+
+use crate::{
+    kernel::KernelSender,
+    procfs::{self, ResourceUsage},
+};
+
+/// How often interpreters are sampled unless configured otherwise
+pub const DEFAULT_MONITOR_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Resources used by an interpreter and its descendants when they were sampled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct UsageSample {
+    /// Resident memory
+    pub rss_bytes: u64,
+    /// Highest `rss_bytes` sampled since the monitor started
+    pub peak_rss_bytes: u64,
+    /// CPU time spent since the previous sample, in percent of one CPU
+    pub cpu_percent: f64,
+    pub threads: usize,
+    pub processes: usize,
+}
+
+/// How often an interpreter is sampled, and the usage it is warned about
+#[derive(Debug, Clone, PartialEq)]
+pub struct MonitorConfig {
+    pub interval: Duration,
+    pub memory_warning_bytes: Option<u64>,
+    pub cpu_warning_percent: Option<f64>,
+    pub threads_warning: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UsageResource {
+    Memory,
+    Cpu,
+    Threads,
+}
+
+/// Usage of a resource went above its threshold, which is only warned about again once it went
+/// back below
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UsageWarning {
+    pub resource: UsageResource,
+    pub sample: UsageSample,
+}
+
+impl MonitorConfig {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            memory_warning_bytes: None,
+            cpu_warning_percent: None,
+            threads_warning: None,
+        }
+    }
+
+    pub fn memory_warning(mut self, bytes: u64) -> Self {
+        self.memory_warning_bytes = Some(bytes);
+        self
+    }
+
+    pub fn cpu_warning(mut self, percent: f64) -> Self {
+        self.cpu_warning_percent = Some(percent);
+        self
+    }
+
+    pub fn threads_warning(mut self, threads: usize) -> Self {
+        self.threads_warning = Some(threads);
+        self
+    }
+
+    /// Resources of the sample above their threshold
+    fn exceeded(&self, sample: &UsageSample) -> Vec<UsageResource> {
+        [
+            (
+                UsageResource::Memory,
+                self.memory_warning_bytes
+                    .is_some_and(|bytes| sample.rss_bytes > bytes),
+            ),
+            (
+                UsageResource::Cpu,
+                self.cpu_warning_percent
+                    .is_some_and(|percent| sample.cpu_percent > percent),
+            ),
+            (
+                UsageResource::Threads,
+                self.threads_warning
+                    .is_some_and(|threads| sample.threads > threads),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(resource, exceeded)| exceeded.then_some(resource))
+        .collect()
+    }
+}
+
+impl Default for MonitorConfig {
+    fn default() -> Self {
+        Self::new(DEFAULT_MONITOR_INTERVAL)
+    }
+}
+
+/// Samples the process trees of the interpreters it watches, all of them from one read of
+/// `/proc` per tick, and publishes each sample as an event of its kernel
+pub(crate) struct Monitor {
+    config: MonitorConfig,
+    watched: Arc<Mutex<HashMap<u32, Watched>>>,
+    /// Spawned with the first watch, as the monitor may be created outside of a runtime
+    sampler: Mutex<Option<task::JoinHandle<()>>>,
+}
+
+/// Interpreter watched by a `Monitor` until this is dropped
+pub(crate) struct Watch {
+    pid: u32,
+    watched: Arc<Mutex<HashMap<u32, Watched>>>,
+}
+
+struct Watched {
+    sender: KernelSender,
+    on_warning: Box<dyn Fn(UsageWarning) + Send>,
+    previous: Option<(Instant, Duration)>,
+    peak_rss_bytes: u64,
+    exceeded: Vec<UsageResource>,
+    last: Option<UsageSample>,
+}
+
+impl Monitor {
+    pub(crate) fn new(config: MonitorConfig) -> Self {
+        Self {
+            config,
+            watched: Arc::new(Mutex::new(HashMap::new())),
+            sampler: Mutex::new(None),
+        }
+    }
+
+    /// Samples the process tree of the interpreter `pid` until the returned watch is dropped,
+    /// publishing each sample to the subscribers of `sender`. `on_warning` is called as a
+    /// threshold of the config is crossed.
+    pub(crate) fn watch(
+        &self,
+        pid: u32,
+        sender: KernelSender,
+        on_warning: impl Fn(UsageWarning) + Send + 'static,
+    ) -> Watch {
+        let watched = Watched {
+            sender,
+            on_warning: Box::new(on_warning),
+            previous: None,
+            peak_rss_bytes: 0,
+            exceeded: Vec::new(),
+            last: None,
+        };
+        lock(&self.watched).insert(pid, watched);
+
+        let mut sampler = self.sampler.lock().expect("Monitor is poisoned");
+        if sampler.is_none() {
+            *sampler = Some(task::spawn(sample(
+                self.watched.clone(),
+                self.config.clone(),
+            )));
+        }
+
+        Watch {
+            pid,
+            watched: self.watched.clone(),
+        }
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        if let Some(sampler) = self.sampler.lock().expect("Monitor is poisoned").as_ref() {
+            sampler.abort();
+        }
+    }
+}
+
+impl Watch {
+    /// Last sample of the interpreter, with the peak memory and the CPU load
+    pub(crate) fn last(&self) -> Option<UsageSample> {
+        lock(&self.watched).get(&self.pid)?.last
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        lock(&self.watched).remove(&self.pid);
+    }
+}
+
+impl Watched {
+    fn sample(&mut self, pid: u32, usage: ResourceUsage, now: Instant, config: &MonitorConfig) {
+        let cpu_percent = match self.previous {
+            Some((at, cpu_time)) => {
+                let elapsed = now.duration_since(at).as_secs_f64();
+                let spent = usage.cpu_time.saturating_sub(cpu_time).as_secs_f64();
+                100.0 * spent / elapsed.max(f64::EPSILON)
+            }
+            None => 0.0,
+        };
+        self.previous = Some((now, usage.cpu_time));
+        self.peak_rss_bytes = self.peak_rss_bytes.max(usage.rss_bytes);
+
+        let sample = UsageSample {
+            rss_bytes: usage.rss_bytes,
+            peak_rss_bytes: self.peak_rss_bytes,
+            cpu_percent,
+            threads: usage.threads,
+            processes: usage.processes,
+        };
+        self.last = Some(sample);
+        self.sender.publish_usage(sample);
+
+        let exceeded = config.exceeded(&sample);
+        for resource in &exceeded {
+            if !self.exceeded.contains(resource) {
+                warn!(pid, ?resource, ?sample, "interpreter usage above threshold");
+                (self.on_warning)(UsageWarning {
+                    resource: *resource,
+                    sample,
+                });
+            }
+        }
+        self.exceeded = exceeded;
+    }
+}
+
+/// Samples the watched interpreters on every tick, skipping those that exited
+async fn sample(watched: Arc<Mutex<HashMap<u32, Watched>>>, config: MonitorConfig) {
+    let mut ticks = interval(config.interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        ticks.tick().await;
+
+        let pids = lock(&watched).keys().copied().collect::<Vec<_>>();
+        if pids.is_empty() {
+            continue;
+        }
+
+        // Reading the stats of every process of the system blocks
+        let usages = {
+            let pids = pids.clone();
+            task::spawn_blocking(move || procfs::usages(&pids)).await
+        };
+        let Ok(usages) = usages else {
+            continue;
+        };
+
+        let now = Instant::now();
+        let mut watched = lock(&watched);
+        for (pid, usage) in pids.into_iter().zip(usages) {
+            if usage.processes == 0 {
+                continue;
+            }
+            // Unwatched while /proc was read
+            if let Some(kernel) = watched.get_mut(&pid) {
+                kernel.sample(pid, usage, now, &config);
+            }
+        }
+    }
+}
+
+fn lock(watched: &Mutex<HashMap<u32, Watched>>) -> MutexGuard<'_, HashMap<u32, Watched>> {
+    watched.lock().expect("Monitor is poisoned")
+}
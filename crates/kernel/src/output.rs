@@ -0,0 +1,425 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncWriteExt, sync::mpsc, time};
+
+use crate::{
+    event::{EventContent, Publisher},
+    terminal::{StyledSpan, TerminalMode, TerminalProcessor, LINE_IDLE_TIMEOUT},
+    ClientId, MessageId,
+};
+
+/// Number of chunks buffered between the REPL and the kernel for each execution
+pub const OUTPUT_CHANNEL_CAPACITY: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Output {
+    Stream(Bytes),
+    /// Text with colors and attributes, see `TerminalMode::Styled`
+    Styled(Vec<StyledSpan>),
+    /// Replaces the part of the stream that was not delivered to the client
+    Truncated {
+        bytes: usize,
+        spill_path: Option<PathBuf>,
+    },
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Output::Stream(data) => write!(f, "{}", String::from_utf8_lossy(data)),
+            Output::Styled(spans) => spans.iter().try_for_each(|span| f.write_str(&span.text)),
+            Output::Truncated {
+                bytes,
+                spill_path: None,
+            } => write!(f, "output truncated {bytes} bytes"),
+            Output::Truncated {
+                bytes,
+                spill_path: Some(path),
+            } => write!(f, "output truncated {bytes} bytes (see {})", path.display()),
+        }
+    }
+}
+
+impl Output {
+    /// Number of bytes of text carried by the output
+    pub fn len(&self) -> usize {
+        match self {
+            Output::Stream(data) => data.len(),
+            Output::Styled(spans) => spans.iter().map(|span| span.text.len()).sum(),
+            Output::Truncated { .. } => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Splits the text into `[0, at)` kept in `self` and `[at, len)` returned, moving `at` back
+    /// to the nearest character boundary of styled text.
+    fn split_off(&mut self, at: usize) -> Output {
+        match self {
+            Output::Stream(data) => Output::Stream(data.split_off(at.min(data.len()))),
+            Output::Styled(spans) => {
+                let mut offset = 0;
+
+                for (index, span) in spans.iter_mut().enumerate() {
+                    if offset + span.text.len() <= at {
+                        offset += span.text.len();
+                        continue;
+                    }
+
+                    let mut boundary = at - offset;
+                    while !span.text.is_char_boundary(boundary) {
+                        boundary -= 1;
+                    }
+
+                    let first = StyledSpan {
+                        text: span.text.split_off(boundary),
+                        style: span.style,
+                    };
+                    let mut rest = vec![first];
+                    rest.extend(spans.drain(index + 1..));
+                    spans.retain(|span| !span.text.is_empty());
+
+                    return Output::Styled(rest);
+                }
+
+                Output::Styled(Vec::new())
+            }
+            Output::Truncated { .. } => Output::Styled(Vec::new()),
+        }
+    }
+
+    fn into_bytes(self) -> Bytes {
+        match self {
+            Output::Stream(data) => data,
+            output => output.to_string().into(),
+        }
+    }
+}
+
+/// Size and SHA-256 digest of everything the REPL wrote for an execution
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputSummary {
+    pub bytes: u64,
+    pub sha256: String,
+}
+
+/// Decides what happens to the output of an execution when the client reads slower than the
+/// REPL writes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum OutputPolicy {
+    /// Every byte is delivered; the REPL waits until the client catches up
+    #[default]
+    Block,
+    /// Delivers the first `head` bytes as they come and the last `tail` bytes once the
+    /// execution is done, dropping everything in between
+    DropMiddle { head: usize, tail: usize },
+    /// Delivers the first `limit` bytes and writes the rest to a file in `dir`
+    SpillToDisk { limit: usize, dir: PathBuf },
+}
+
+/// Moves the output of one execution from the REPL to the client until the REPL drops its sender
+pub(crate) async fn forward(
+    mut repl_receiver: mpsc::Receiver<Bytes>,
+    mut sink: Sink,
+    terminal: TerminalMode,
+) -> OutputSummary {
+    let mut processor = TerminalProcessor::new(terminal);
+    let mut hasher = Sha256::new();
+    let mut bytes = 0;
+
+    loop {
+        let data = if processor.has_line() {
+            match time::timeout(LINE_IDLE_TIMEOUT, repl_receiver.recv()).await {
+                Ok(data) => data,
+                Err(_) => {
+                    if let Some(output) = processor.flush_line() {
+                        sink.push(output).await;
+                    }
+                    continue;
+                }
+            }
+        } else {
+            repl_receiver.recv().await
+        };
+        let Some(data) = data else {
+            break;
+        };
+
+        hasher.update(&data);
+        bytes += data.len() as u64;
+
+        if let Some(output) = processor.process(data) {
+            sink.push(output).await;
+        }
+    }
+
+    if let Some(output) = processor.flush() {
+        sink.push(output).await;
+    }
+
+    sink.finish().await;
+
+    OutputSummary {
+        bytes,
+        sha256: hex::encode(hasher.finalize()),
+    }
+}
+
+/// Applies an output policy to the outputs of one execution, then delivers them to the client
+/// that submitted the execution and to every subscriber of the kernel
+pub(crate) struct Sink {
+    message_id: MessageId,
+    client_id: ClientId,
+    client_sender: mpsc::Sender<Output>,
+    publisher: Publisher,
+    truncation: Truncation,
+}
+
+enum Truncation {
+    None,
+    DropMiddle {
+        head: Head,
+        tail: Tail,
+    },
+    SpillToDisk {
+        head: Head,
+        dir: PathBuf,
+        spill: Option<Spill>,
+        spill_created: bool,
+        /// Bytes that could not be spilled
+        dropped: usize,
+    },
+}
+
+impl Sink {
+    pub(crate) fn new(
+        message_id: MessageId,
+        client_id: ClientId,
+        policy: OutputPolicy,
+        client_sender: mpsc::Sender<Output>,
+        publisher: Publisher,
+    ) -> Self {
+        let truncation = match policy {
+            OutputPolicy::Block => Truncation::None,
+            OutputPolicy::DropMiddle { head, tail } => Truncation::DropMiddle {
+                head: Head::new(head),
+                tail: Tail::new(tail),
+            },
+            OutputPolicy::SpillToDisk { limit, dir } => Truncation::SpillToDisk {
+                head: Head::new(limit),
+                dir,
+                spill: None,
+                spill_created: false,
+                dropped: 0,
+            },
+        };
+
+        Self {
+            message_id,
+            client_id,
+            client_sender,
+            publisher,
+            truncation,
+        }
+    }
+
+    async fn push(&mut self, output: Output) {
+        let rest = match &mut self.truncation {
+            Truncation::None => {
+                self.send(output).await;
+                return;
+            }
+            Truncation::DropMiddle { head, .. } | Truncation::SpillToDisk { head, .. } => {
+                let (output, rest) = head.take(output);
+                if let Some(output) = output {
+                    self.send(output).await;
+                }
+                rest
+            }
+        };
+
+        let Some(rest) = rest else {
+            return;
+        };
+
+        match &mut self.truncation {
+            Truncation::None => unreachable!("no output is held back without truncation"),
+            Truncation::DropMiddle { tail, .. } => tail.push(rest),
+            Truncation::SpillToDisk {
+                dir,
+                spill,
+                spill_created,
+                dropped,
+                ..
+            } => {
+                if !*spill_created {
+                    *spill = Spill::create(dir, self.message_id).await;
+                    *spill_created = true;
+                }
+
+                match spill.as_mut() {
+                    Some(spill) => spill.write(&rest.into_bytes()).await,
+                    // The spill file cannot be created, so the rest is only counted
+                    None => *dropped += rest.len(),
+                }
+            }
+        }
+    }
+
+    async fn finish(mut self) {
+        match std::mem::replace(&mut self.truncation, Truncation::None) {
+            Truncation::None => {}
+            Truncation::DropMiddle { tail, .. } => {
+                if tail.dropped > 0 {
+                    self.send(Output::Truncated {
+                        bytes: tail.dropped,
+                        spill_path: None,
+                    })
+                    .await;
+                }
+
+                for output in tail.buffer {
+                    self.send(output).await;
+                }
+            }
+            Truncation::SpillToDisk { spill, dropped, .. } => {
+                let (bytes, spill_path) = match spill {
+                    Some(mut spill) => {
+                        spill.flush().await;
+                        (spill.bytes + dropped, Some(spill.path))
+                    }
+                    None => (dropped, None),
+                };
+
+                if bytes > 0 {
+                    self.send(Output::Truncated { bytes, spill_path }).await;
+                }
+            }
+        }
+    }
+
+    async fn send(&self, output: Output) {
+        self.publisher.publish(
+            self.message_id,
+            self.client_id,
+            EventContent::Output(output.clone()),
+        );
+
+        // The output of a client that went away is drained, so the REPL is never blocked by it
+        let _ = self.client_sender.send(output).await;
+    }
+}
+
+/// Lets outputs through until the limit is reached
+struct Head {
+    remaining: usize,
+}
+
+impl Head {
+    fn new(limit: usize) -> Self {
+        Self { remaining: limit }
+    }
+
+    /// Splits `output` into the part within the limit and the part that exceeds it
+    fn take(&mut self, mut output: Output) -> (Option<Output>, Option<Output>) {
+        let rest = (output.len() > self.remaining).then(|| output.split_off(self.remaining));
+        self.remaining -= output.len();
+
+        ((!output.is_empty()).then_some(output), rest)
+    }
+}
+
+/// Keeps the last `limit` bytes
+struct Tail {
+    limit: usize,
+    len: usize,
+    buffer: VecDeque<Output>,
+    dropped: usize,
+}
+
+impl Tail {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            len: 0,
+            buffer: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, output: Output) {
+        self.len += output.len();
+        self.buffer.push_back(output);
+
+        while self.len > self.limit {
+            let Some(front) = self.buffer.front_mut() else {
+                break;
+            };
+
+            let overflow = self.len - self.limit;
+            let front_len = front.len();
+
+            if front_len <= overflow {
+                self.buffer.pop_front();
+                self.len -= front_len;
+                self.dropped += front_len;
+                continue;
+            }
+
+            let rest = front.split_off(overflow);
+            let removed = front_len - rest.len();
+            if removed == 0 {
+                // A character cannot be split, so the tail is slightly longer than its limit
+                break;
+            }
+
+            *front = rest;
+            self.len -= removed;
+            self.dropped += removed;
+        }
+    }
+}
+
+struct Spill {
+    path: PathBuf,
+    file: fs::File,
+    bytes: usize,
+}
+impl Spill {
+    async fn create(dir: &Path, message_id: MessageId) -> Option<Self> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let path = dir.join(format!("output-{message_id}-{timestamp}.log"));
+
+        fs::create_dir_all(dir).await.ok()?;
+        let file = fs::File::create(&path).await.ok()?;
+
+        Some(Self {
+            path,
+            file,
+            bytes: 0,
+        })
+    }
+
+    async fn write(&mut self, data: &[u8]) {
+        // Bytes that cannot be written are still reported as truncated
+        let _ = self.file.write_all(data).await;
+        self.bytes += data.len();
+    }
+
+    async fn flush(&mut self) {
+        let _ = self.file.flush().await;
+    }
+}
@@ -0,0 +1,333 @@
+//! Kernels running each cell on the REPL of its language, sharing one queue and passing
+//! values between the REPLs by name.
+//!
+//! A cell starting with `%%set <name>` is run for its output, which is stored as the value
+//! `name`: a scalar or a string by default, a table with `--csv`, or anything with `--msgpack`.
+//! The code of later cells reads it as `{{name}}`, replaced by the text of a scalar or the path
+//! of a CSV file holding a table. `{{name:csv}}` and `{{name:msgpack}}` are replaced by the path
+//! of a file holding the value in that format.
+
+use std::{
+    collections::BTreeMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex, MutexGuard},
+};
+
+use bytes::Bytes;
+use regex::{Captures, Regex};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+use uuid::Uuid;
+
+pub use value::{Table, Value, ValueError};
+
+use crate::{
+    output::OUTPUT_CHANNEL_CAPACITY,
+    repl::{ReplError, ReplHandle},
+};
+
+mod value;
+
+/// `{{name}}` or `{{name:format}}` in code
+static REFERENCE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\{\{([A-Za-z_][A-Za-z0-9_]*)(?::(csv|msgpack))?\}\}").expect("Pattern is valid")
+});
+
+/// REPLs of a kernel by language, launched with `kernel::launch_polyglot`. Executions without
+/// a language run on the REPL of the default language.
+pub struct Polyglot {
+    default: String,
+    repls: BTreeMap<String, ReplHandle>,
+    values: ValueStore,
+}
+
+/// Values shared by the REPLs of a polyglot kernel, and by the clones of the store
+#[derive(Clone)]
+pub struct ValueStore {
+    shared: Arc<StoreShared>,
+}
+
+struct StoreShared {
+    values: Mutex<BTreeMap<String, Value>>,
+    /// Holds the files that code is given in place of values, created on first use
+    dir: PathBuf,
+}
+
+/// How the output of a `%%set` cell is read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ValueFormat {
+    Scalar,
+    Csv,
+    Msgpack,
+}
+
+impl Polyglot {
+    /// Kernel running `repl` for `language`, and for executions without a language
+    pub fn new(language: impl Into<String>, repl: ReplHandle) -> Self {
+        let language = language.into();
+
+        Self {
+            default: language.clone(),
+            repls: BTreeMap::from([(language, repl)]),
+            values: ValueStore::new(),
+        }
+    }
+
+    /// Runs the executions of `language` on `repl`, replacing the previous REPL of the language
+    pub fn language(mut self, language: impl Into<String>, repl: ReplHandle) -> Self {
+        self.repls.insert(language.into(), repl);
+        self
+    }
+
+    /// Writes the files given to code in place of values in a new directory of `dir`, such as
+    /// the workspace of sandboxed REPLs, which do not see the temporary directory of the host
+    pub fn values_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.values = ValueStore::in_dir(dir);
+        self
+    }
+
+    /// Languages of the REPLs, sorted
+    pub fn languages(&self) -> Vec<&str> {
+        self.repls.keys().map(String::as_str).collect()
+    }
+
+    /// Language of the executions without one
+    pub fn default_language(&self) -> &str {
+        &self.default
+    }
+
+    /// Values shared by the REPLs, which clients may read and set as well
+    pub fn values(&self) -> &ValueStore {
+        &self.values
+    }
+
+    /// Runs `code` on the REPL of `language`, storing the value of a `%%set` cell. Executions
+    /// of a language without a REPL fail.
+    pub async fn execute(
+        &self,
+        language: Option<&str>,
+        code: String,
+        io_sender: mpsc::Sender<Bytes>,
+        sigint: CancellationToken,
+    ) -> Result<(), ReplError> {
+        let language = language.unwrap_or(&self.default);
+        let Some(repl) = self.repls.get(language) else {
+            let error = format!(
+                "No REPL runs {language}, the kernel runs {}\n",
+                self.languages().join(", ")
+            );
+            return fail(&io_sender, error).await;
+        };
+        debug!(language, "exec routed");
+
+        self.execute_on(repl, code, io_sender, sigint).await
+    }
+
+    /// Runs `code` on `repl`, a REPL of its language such as a worker of the kernel, with the
+    /// values of the kernel
+    pub(crate) async fn execute_on(
+        &self,
+        repl: &ReplHandle,
+        code: String,
+        io_sender: mpsc::Sender<Bytes>,
+        sigint: CancellationToken,
+    ) -> Result<(), ReplError> {
+        let code = match self.values.substitute(&code) {
+            Ok(code) => code,
+            Err(err) => {
+                return fail(&io_sender, format!("Values could not be written: {err}\n")).await
+            }
+        };
+
+        match parse_set(&code) {
+            Some(Ok((name, format, body))) => {
+                self.set(repl, name, format, body, io_sender, sigint).await
+            }
+            Some(Err(usage)) => fail(&io_sender, usage).await,
+            None => repl.execute(code, io_sender, sigint).await,
+        }
+    }
+
+    /// Runs the body of a `%%set` cell, its standard error going to `io_sender`, and stores its
+    /// output as `name`
+    async fn set(
+        &self,
+        repl: &ReplHandle,
+        name: &str,
+        format: ValueFormat,
+        body: &str,
+        io_sender: mpsc::Sender<Bytes>,
+        sigint: CancellationToken,
+    ) -> Result<(), ReplError> {
+        let (value_sender, mut value_receiver) = mpsc::channel(OUTPUT_CHANNEL_CAPACITY);
+
+        let (result, output) = tokio::join!(
+            repl.execute_with_stderr(
+                body.to_string(),
+                value_sender,
+                Some(io_sender.clone()),
+                sigint
+            ),
+            async {
+                let mut output = Vec::new();
+                while let Some(bytes) = value_receiver.recv().await {
+                    output.extend_from_slice(&bytes);
+                }
+                output
+            },
+        );
+        result?;
+
+        let value = match format {
+            ValueFormat::Scalar => Ok(Value::parse(&String::from_utf8_lossy(&output))),
+            ValueFormat::Csv => Table::from_csv(&String::from_utf8_lossy(&output)).map(Value::from),
+            ValueFormat::Msgpack => Value::from_msgpack(&output),
+        };
+
+        match value.and_then(|value| self.values.set(name, value)) {
+            Ok(()) => Ok(()),
+            Err(err) => fail(&io_sender, format!("{name} could not be set: {err}\n")).await,
+        }
+    }
+}
+
+impl ValueStore {
+    /// Store whose files are in the temporary directory
+    pub fn new() -> Self {
+        Self::in_dir(env::temp_dir())
+    }
+
+    /// Store whose files are in a new directory of `dir`
+    pub fn in_dir(dir: impl AsRef<Path>) -> Self {
+        Self {
+            shared: Arc::new(StoreShared {
+                values: Mutex::new(BTreeMap::new()),
+                dir: dir
+                    .as_ref()
+                    .join(format!(".canal-values-{}", Uuid::new_v4())),
+            }),
+        }
+    }
+
+    /// Stores `value` as `name`, which is made of ASCII letters, digits and underscores and
+    /// does not start with a digit
+    pub fn set(&self, name: &str, value: Value) -> Result<(), ValueError> {
+        if !is_name(name) {
+            return Err(ValueError::InvalidName(name.to_string()));
+        }
+
+        self.values().insert(name.to_string(), value);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<Value> {
+        self.values().get(name).cloned()
+    }
+
+    pub fn remove(&self, name: &str) -> Option<Value> {
+        self.values().remove(name)
+    }
+
+    /// Names of the values, sorted
+    pub fn names(&self) -> Vec<String> {
+        self.values().keys().cloned().collect()
+    }
+
+    /// Directory of the files holding values for code, removed with the last clone of the store
+    pub fn dir(&self) -> &Path {
+        &self.shared.dir
+    }
+
+    /// Replaces the references to values in `code`, leaving those to unknown values as they are
+    fn substitute(&self, code: &str) -> io::Result<String> {
+        let values = self.values();
+        let mut substituted = String::with_capacity(code.len());
+        let mut end = 0;
+
+        for reference in REFERENCE.captures_iter(code) {
+            let Some(value) = values.get(&reference[1]) else {
+                continue;
+            };
+
+            let whole = reference.get(0).expect("Match has a whole group");
+            substituted.push_str(&code[end..whole.start()]);
+            substituted.push_str(&self.reference(&reference, value)?);
+            end = whole.end();
+        }
+        substituted.push_str(&code[end..]);
+
+        Ok(substituted)
+    }
+
+    /// Text of the value, or path of the file holding it
+    fn reference(&self, reference: &Captures<'_>, value: &Value) -> io::Result<String> {
+        let (extension, content) = match (reference.get(2).map(|format| format.as_str()), value) {
+            (None, Value::Table(_)) | (Some("csv"), _) => ("csv", value.to_csv().into_bytes()),
+            (Some(_), _) => ("msgpack", value.to_msgpack()),
+            (None, scalar) => return Ok(scalar.to_string()),
+        };
+
+        fs::create_dir_all(self.dir())?;
+        let path = self.dir().join(format!("{}.{extension}", &reference[1]));
+        fs::write(&path, content)?;
+
+        Ok(path.to_string_lossy().into_owned())
+    }
+
+    fn values(&self) -> MutexGuard<'_, BTreeMap<String, Value>> {
+        self.shared
+            .values
+            .lock()
+            .expect("Polyglot values are poisoned")
+    }
+}
+
+impl Default for ValueStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for StoreShared {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Name, format and body of a `%%set` cell, or its usage when it is called wrongly
+fn parse_set(code: &str) -> Option<Result<(&str, ValueFormat, &str), String>> {
+    let rest = code.trim_start().strip_prefix("%%set")?;
+    let (line, body) = rest.split_once('\n').unwrap_or((rest, ""));
+    if !line.is_empty() && !line.starts_with(char::is_whitespace) {
+        return None;
+    }
+
+    let parsed = match line.split_whitespace().collect::<Vec<_>>()[..] {
+        [name] => Some((name, ValueFormat::Scalar)),
+        [name, "--csv"] => Some((name, ValueFormat::Csv)),
+        [name, "--msgpack"] => Some((name, ValueFormat::Msgpack)),
+        _ => None,
+    };
+
+    Some(
+        parsed
+            .map(|(name, format)| (name, format, body))
+            .ok_or_else(|| "usage: %%set <name> [--csv|--msgpack]\n".to_string()),
+    )
+}
+
+fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|char| char.is_ascii_alphanumeric() || char == '_')
+}
+
+async fn fail(io_sender: &mpsc::Sender<Bytes>, error: String) -> Result<(), ReplError> {
+    let _ = io_sender.send(Bytes::from(error)).await;
+    Err(ReplError::Failed)
+}
@@ -0,0 +1,169 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Value passed between the REPLs of a polyglot kernel
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Table(Table),
+}
+
+/// Table of text cells, as read from CSV
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Table {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+#[derive(Error, Debug)]
+pub enum ValueError {
+    #[error("{0} is not a valid value name")]
+    InvalidName(String),
+    #[error("CSV is invalid: {0}")]
+    Csv(String),
+    #[error("Msgpack value is invalid")]
+    Msgpack(#[from] rmp_serde::decode::Error),
+}
+
+impl Value {
+    /// Scalar written as `text`, which is a string unless it reads as a boolean or a number
+    pub fn parse(text: &str) -> Self {
+        let text = text.trim();
+
+        if let Ok(value) = text.parse() {
+            Value::Bool(value)
+        } else if let Ok(value) = text.parse() {
+            Value::Int(value)
+        } else if let Ok(value) = text.parse() {
+            Value::Float(value)
+        } else {
+            Value::String(text.to_string())
+        }
+    }
+
+    /// Reads a scalar, a string or a table with `columns` and `rows`
+    pub fn from_msgpack(bytes: &[u8]) -> Result<Self, ValueError> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+
+    /// Writes tables as maps with `columns` and `rows`
+    pub fn to_msgpack(&self) -> Vec<u8> {
+        rmp_serde::to_vec_named(self).expect("Values are serializable")
+    }
+
+    /// Text of a scalar on one line, or a table as CSV
+    pub fn to_csv(&self) -> String {
+        match self {
+            Value::Table(table) => table.to_csv(),
+            scalar => format!("{}\n", csv_field(&scalar.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    /// Scalars as they are written in most languages, and tables as CSV
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Bool(value) => write!(f, "{value}"),
+            Value::Int(value) => write!(f, "{value}"),
+            Value::Float(value) => write!(f, "{value:?}"),
+            Value::String(value) => f.write_str(value),
+            Value::Table(table) => f.write_str(&table.to_csv()),
+        }
+    }
+}
+
+impl Table {
+    /// Table whose columns are named by the first record of `text`
+    pub fn from_csv(text: &str) -> Result<Self, ValueError> {
+        let mut records = parse_csv(text)?.into_iter();
+        let columns = records
+            .next()
+            .ok_or_else(|| ValueError::Csv("no header".to_string()))?;
+
+        let rows = records.collect::<Vec<_>>();
+        if let Some(index) = rows.iter().position(|row| row.len() != columns.len()) {
+            return Err(ValueError::Csv(format!(
+                "row {} has {} fields, the header has {}",
+                index + 1,
+                rows[index].len(),
+                columns.len()
+            )));
+        }
+
+        Ok(Self { columns, rows })
+    }
+
+    pub fn to_csv(&self) -> String {
+        std::iter::once(&self.columns)
+            .chain(&self.rows)
+            .map(|record| {
+                let fields = record
+                    .iter()
+                    .map(|field| csv_field(field))
+                    .collect::<Vec<_>>();
+                fields.join(",") + "\n"
+            })
+            .collect()
+    }
+}
+
+impl From<Table> for Value {
+    fn from(table: Table) -> Self {
+        Value::Table(table)
+    }
+}
+
+/// Records of RFC 4180 CSV, ignoring blank lines and accepting LF line endings
+fn parse_csv(text: &str) -> Result<Vec<Vec<String>>, ValueError> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+
+    while let Some(char) = chars.next() {
+        match char {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => record.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' if !quoted => {
+                if !record.is_empty() || !field.is_empty() {
+                    record.push(std::mem::take(&mut field));
+                    records.push(std::mem::take(&mut record));
+                }
+            }
+            char => field.push(char),
+        }
+    }
+
+    if quoted {
+        return Err(ValueError::Csv("unterminated quoted field".to_string()));
+    }
+    if !record.is_empty() || !field.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+/// `field` quoted when it holds a separator, a quote or a line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
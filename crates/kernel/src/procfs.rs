@@ -0,0 +1,176 @@
+//! Process trees of REPLs, read from `/proc`
+
+use std::{collections::HashMap, fs, mem, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::repl;
+
+/// Resources used by a process and its descendants
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    /// Resident memory
+    pub rss_bytes: u64,
+    /// CPU time spent in user and system mode since each process started
+    pub cpu_time: Duration,
+    pub processes: usize,
+    pub threads: usize,
+}
+
+/// Set in the flags of a process once it started exiting
+const PF_EXITING: u64 = 0x4;
+
+/// Fields of `/proc/<pid>/stat`
+struct Stat {
+    ppid: u32,
+    /// `PF_*` flags of the kernel
+    flags: u64,
+    /// User and system CPU time, in clock ticks
+    cpu_ticks: u64,
+    rss_pages: u64,
+    threads: u64,
+}
+
+/// `pid` followed by its descendants, parents before their children. Empty when `pid` is gone.
+pub fn tree(pid: u32) -> Vec<u32> {
+    tree_stats(pid).into_iter().map(|(pid, _)| pid).collect()
+}
+
+pub fn usage(pid: u32) -> ResourceUsage {
+    usages(&[pid])[0]
+}
+
+/// Usage of each of `pids` and its descendants, in the same order, from a single read of
+/// `/proc`. A process is only counted in the first tree it belongs to.
+pub fn usages(pids: &[u32]) -> Vec<ResourceUsage> {
+    let mut all = all_stats();
+    // SAFETY: sysconf only reads a configuration value
+    let (ticks_per_second, page_size) = unsafe {
+        (
+            libc::sysconf(libc::_SC_CLK_TCK),
+            libc::sysconf(libc::_SC_PAGESIZE),
+        )
+    };
+
+    pids.iter()
+        .map(|pid| {
+            let stats = tree_stats_in(&mut all, *pid);
+            let cpu_ticks: u64 = stats.iter().map(|(_, stat)| stat.cpu_ticks).sum();
+            let rss_pages: u64 = stats.iter().map(|(_, stat)| stat.rss_pages).sum();
+            let threads: u64 = stats.iter().map(|(_, stat)| stat.threads).sum();
+
+            ResourceUsage {
+                rss_bytes: rss_pages * page_size.max(0) as u64,
+                cpu_time: Duration::from_secs_f64(
+                    cpu_ticks as f64 / ticks_per_second.max(1) as f64,
+                ),
+                processes: stats.len(),
+                threads: threads as usize,
+            }
+        })
+        .collect()
+}
+
+/// Whether the child `pid` of this process exited, read without reaping it. `None` once it was
+/// reaped, after which `pid` may belong to another process.
+pub(crate) fn child_exited(pid: u32) -> Option<bool> {
+    // SAFETY: siginfo_t is a plain C struct, for which zeroes are valid
+    let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+
+    // SAFETY: waitid only writes into the struct, which outlives the call
+    let result = unsafe {
+        libc::waitid(
+            libc::P_PID,
+            pid as libc::id_t,
+            &mut info,
+            libc::WEXITED | libc::WNOHANG | libc::WNOWAIT,
+        )
+    };
+    if result != 0 {
+        return None;
+    }
+
+    // SAFETY: waitid filled the fields of SIGCHLD, which are zeroes while the child runs
+    Some(unsafe { info.si_pid() } != 0)
+}
+
+/// Kills `pid`, its process group when it leads one, and every descendant, including those
+/// that left the group
+pub fn kill_tree(pid: u32) {
+    let tree = tree(pid);
+
+    repl::send_signal(pid, libc::SIGKILL);
+    for pid in tree {
+        // SAFETY: kill takes plain integers and does not touch memory of this process
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGKILL);
+        }
+    }
+}
+
+/// Whether `pid` is exiting, before it can be waited for
+pub(crate) fn is_exiting(pid: u32) -> bool {
+    read_stat(pid).is_some_and(|stat| stat.flags & PF_EXITING != 0)
+}
+
+fn tree_stats(root: u32) -> Vec<(u32, Stat)> {
+    tree_stats_in(&mut all_stats(), root)
+}
+
+/// Removes the tree of `root` from `stats`
+fn tree_stats_in(stats: &mut HashMap<u32, Stat>, root: u32) -> Vec<(u32, Stat)> {
+    let Some(root_stat) = stats.remove(&root) else {
+        return Vec::new();
+    };
+
+    let mut tree = vec![(root, root_stat)];
+    let mut index = 0;
+    while index < tree.len() {
+        let parent = tree[index].0;
+        let children = stats
+            .keys()
+            .copied()
+            .filter(|pid| stats[pid].ppid == parent)
+            .collect::<Vec<_>>();
+
+        for child in children {
+            if let Some(stat) = stats.remove(&child) {
+                tree.push((child, stat));
+            }
+        }
+        index += 1;
+    }
+
+    tree
+}
+
+fn all_stats() -> HashMap<u32, Stat> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return HashMap::new();
+    };
+
+    entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+        .filter_map(|pid| Some((pid, read_stat(pid)?)))
+        .collect()
+}
+
+fn read_stat(pid: u32) -> Option<Stat> {
+    let content = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+
+    // The command name is in parentheses and may contain anything, even parentheses
+    let fields = content[content.rfind(')')? + 1..]
+        .split_whitespace()
+        .collect::<Vec<_>>();
+    let field = |index: usize| fields.get(index)?.parse::<u64>().ok();
+
+    // Fields are numbered from 1 in proc(5), the state (field 3) being the first one here
+    Some(Stat {
+        ppid: field(1)? as u32,
+        flags: field(6)?,
+        cpu_ticks: field(11)? + field(12)?,
+        threads: field(17)?,
+        rss_pages: field(21)?,
+    })
+}
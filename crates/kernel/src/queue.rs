@@ -0,0 +1,286 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::MessageId;
+
+/// Read-only view of the execution queue of a kernel.
+///
+/// The capacity counts the running execution together with the queued ones, so a kernel
+/// launched with capacity `n` accepts `n` executions before `KernelTerminal::send` waits.
+pub struct KernelQueue {
+    capacity: usize,
+    semaphore: Arc<Semaphore>,
+    state: Mutex<QueueState>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    queued: VecDeque<QueuedExec>,
+    running: Option<RunningExec>,
+    on_workers: Vec<RunningExec>,
+    wait: WaitStats,
+}
+
+struct QueuedExec {
+    message_id: MessageId,
+    lane: Lane,
+    /// Waits for an idle worker rather than for the primary REPL
+    independent: bool,
+    enqueued_at: Instant,
+}
+
+/// Executions in the interactive lane are run before any execution waiting in the batch lane.
+/// The running execution is never preempted, and the order within a lane is preserved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Lane {
+    /// Cells submitted one by one by a user
+    Interactive,
+    /// Bulk submissions, such as "run all"
+    Batch,
+}
+
+impl Lane {
+    const ALL: [Lane; 2] = [Lane::Interactive, Lane::Batch];
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunningExec {
+    pub message_id: MessageId,
+    pub started_at: SystemTime,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WaitStats {
+    /// Number of executions that left the queue to be run
+    pub count: u64,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+impl WaitStats {
+    pub fn mean(&self) -> Duration {
+        let nanos = self
+            .total
+            .as_nanos()
+            .checked_div(u128::from(self.count))
+            .unwrap_or_default();
+
+        Duration::from_nanos(nanos as u64)
+    }
+
+    fn record(&mut self, wait: Duration) {
+        self.count += 1;
+        self.total += wait;
+        self.max = self.max.max(wait);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueStatus {
+    /// Queued message ids, next to be executed first
+    pub queued: Vec<MessageId>,
+    pub running: Option<RunningExec>,
+    /// Independent executions running on the workers of the kernel, oldest first
+    #[serde(default)]
+    pub on_workers: Vec<RunningExec>,
+    pub capacity: usize,
+    pub available: usize,
+    pub wait: WaitStats,
+}
+
+impl KernelQueue {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            semaphore: Arc::new(Semaphore::new(capacity)),
+            state: Mutex::new(QueueState::default()),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of executions that can still be submitted without waiting
+    pub fn available(&self) -> usize {
+        self.semaphore.available_permits()
+    }
+
+    pub fn len(&self) -> usize {
+        self.state().queued.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state().queued.is_empty()
+    }
+
+    /// Queued message ids, next to be executed first
+    pub fn queued(&self) -> Vec<MessageId> {
+        self.state().queued_ids()
+    }
+
+    pub fn queued_in(&self, lane: Lane) -> Vec<MessageId> {
+        self.state().queued_ids_in(lane).collect()
+    }
+
+    pub fn running(&self) -> Option<RunningExec> {
+        self.state().running.clone()
+    }
+
+    /// Independent executions running on the workers of the kernel, oldest first
+    pub fn running_on_workers(&self) -> Vec<RunningExec> {
+        self.state().on_workers.clone()
+    }
+
+    pub fn wait_stats(&self) -> WaitStats {
+        self.state().wait.clone()
+    }
+
+    pub fn status(&self) -> QueueStatus {
+        let state = self.state();
+
+        QueueStatus {
+            queued: state.queued_ids(),
+            running: state.running.clone(),
+            on_workers: state.on_workers.clone(),
+            capacity: self.capacity,
+            available: self.available(),
+            wait: state.wait.clone(),
+        }
+    }
+
+    pub(crate) async fn acquire(&self) -> OwnedSemaphorePermit {
+        self.semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Queue semaphore could not acquire")
+    }
+
+    pub(crate) fn push(
+        &self,
+        message_id: MessageId,
+        lane: Lane,
+        independent: bool,
+        enqueued_at: Instant,
+    ) {
+        self.state().queued.push_back(QueuedExec {
+            message_id,
+            lane,
+            independent,
+            enqueued_at,
+        });
+    }
+
+    /// Number of queued executions of each lane waiting for the primary REPL, all counted at
+    /// the same time
+    pub(crate) fn len_by_lane(&self) -> [(Lane, usize); 2] {
+        let state = self.state();
+
+        Lane::ALL.map(|lane| {
+            let len = state
+                .queued
+                .iter()
+                .filter(|exec| exec.lane == lane && !exec.independent)
+                .count();
+            (lane, len)
+        })
+    }
+
+    /// Moves an execution from the queue to the running slot, returning how long it waited
+    pub(crate) fn start(&self, message_id: MessageId) -> Option<Duration> {
+        let mut state = self.state();
+
+        let wait = state.dequeue(message_id);
+        state.running = Some(RunningExec::now(message_id));
+
+        wait
+    }
+
+    /// Moves an independent execution from the queue to the workers, returning how long it
+    /// waited
+    pub(crate) fn start_on_worker(&self, message_id: MessageId) -> Option<Duration> {
+        let mut state = self.state();
+
+        let wait = state.dequeue(message_id);
+        state.on_workers.push(RunningExec::now(message_id));
+
+        wait
+    }
+
+    pub(crate) fn finish(&self, message_id: MessageId) {
+        let mut state = self.state();
+
+        if state
+            .running
+            .as_ref()
+            .is_some_and(|running| running.message_id == message_id)
+        {
+            state.running = None;
+        }
+        state
+            .on_workers
+            .retain(|running| running.message_id != message_id);
+    }
+
+    /// Removes an execution that left the queue without being run
+    pub(crate) fn discard(&self, message_id: MessageId) {
+        self.state().remove(message_id);
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, QueueState> {
+        self.state.lock().expect("Queue state is poisoned")
+    }
+}
+
+impl RunningExec {
+    fn now(message_id: MessageId) -> Self {
+        Self {
+            message_id,
+            started_at: SystemTime::now(),
+        }
+    }
+}
+
+impl QueueState {
+    fn queued_ids(&self) -> Vec<MessageId> {
+        Lane::ALL
+            .into_iter()
+            .flat_map(|lane| self.queued_ids_in(lane))
+            .collect()
+    }
+
+    fn queued_ids_in(&self, lane: Lane) -> impl Iterator<Item = MessageId> + '_ {
+        self.queued
+            .iter()
+            .filter(move |exec| exec.lane == lane)
+            .map(|exec| exec.message_id)
+    }
+
+    /// Removes an execution leaving the queue to be run, recording how long it waited
+    fn dequeue(&mut self, message_id: MessageId) -> Option<Duration> {
+        let wait = self
+            .remove(message_id)
+            .map(|exec| exec.enqueued_at.elapsed());
+        if let Some(wait) = wait {
+            self.wait.record(wait);
+        }
+
+        wait
+    }
+
+    fn remove(&mut self, message_id: MessageId) -> Option<QueuedExec> {
+        let position = self
+            .queued
+            .iter()
+            .position(|exec| exec.message_id == message_id)?;
+
+        self.queued.remove(position)
+    }
+}
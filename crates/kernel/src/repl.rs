@@ -1,18 +1,40 @@
-use std::{process, sync::Arc};
+use std::{future::Future, process, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
 use bytes::Bytes;
 use thiserror::Error;
 use tokio::{
+    select,
     sync::{mpsc, oneshot, Mutex},
     task,
+    time::sleep,
 };
 use tokio_util::sync::CancellationToken;
+use tracing::{debug, info_span, instrument, Instrument, Span};
+
+use crate::{
+    limits::{OutputTail, ResourceLimit, ResourceLimits},
+    procfs,
+};
+
+use magic::Magics;
+
+/// How often a killed interpreter is checked for having exited
+const REAP_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+pub mod bash;
+pub mod jupyter;
+pub mod magic;
+pub mod prompt;
 
 #[async_trait]
 pub trait Repl {
+    /// Settings of the REPL given at launch, such as the prompts of an interpreter
+    type Config;
+
     fn new(
         process: Arc<Mutex<process::Child>>,
+        config: Self::Config,
         message_receiver: mpsc::Receiver<ReplMessage>,
     ) -> Self;
 
@@ -24,29 +46,114 @@ fn new(
 pub enum ReplMessage {
     Execute {
         notif_sender: oneshot::Sender<Result<(), ReplError>>,
-        io_sender: mpsc::UnboundedSender<Bytes>,
+        io_sender: mpsc::Sender<Bytes>,
+        /// Receives the standard error of REPLs that tell it apart from their output, which
+        /// goes to `io_sender` otherwise
+        stderr_sender: Option<mpsc::Sender<Bytes>>,
         sigint: CancellationToken,
         code: String,
+        /// Span of the kernel execution, under which the REPL handles the message
+        span: Span,
     },
 }
 
+impl ReplMessage {
+    pub fn span(&self) -> &Span {
+        match self {
+            ReplMessage::Execute { span, .. } => span,
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum ReplError {
     #[error("Execution failed")]
     Failed,
     #[error("Execution was interrupted")]
     Interrupted,
+    #[error("Resource limit exceeded: {0}")]
+    ResourceLimit(ResourceLimit),
 }
 
 pub struct ReplHandle {
     message_sender: mpsc::Sender<ReplMessage>,
+    /// Interpreter whose failures are checked against its limits
+    limits: Option<(Arc<Mutex<process::Child>>, ResourceLimits)>,
+    /// Runs the magic commands of the code before it reaches the REPL
+    magics: Option<Arc<Magics>>,
 }
 
 impl ReplHandle {
+    /// Executes `code`, its standard error mixed with its output
     pub async fn execute(
         &self,
         code: String,
-        io_sender: mpsc::UnboundedSender<Bytes>,
+        io_sender: mpsc::Sender<Bytes>,
+        sigint: CancellationToken,
+    ) -> Result<(), ReplError> {
+        self.execute_with_stderr(code, io_sender, None, sigint)
+            .await
+    }
+
+    /// Executes `code`, sending its standard error to `stderr_sender` when the REPL tells it
+    /// apart from the output
+    #[instrument(name = "repl_execute", skip_all)]
+    pub async fn execute_with_stderr(
+        &self,
+        code: String,
+        io_sender: mpsc::Sender<Bytes>,
+        stderr_sender: Option<mpsc::Sender<Bytes>>,
+        sigint: CancellationToken,
+    ) -> Result<(), ReplError> {
+        if let Some(magics) = &self.magics {
+            let executed = magics
+                .execute(self, &code, &io_sender, stderr_sender.as_ref(), &sigint)
+                .await;
+            if let Some(result) = executed {
+                return result;
+            }
+        }
+
+        self.execute_code(code, io_sender, stderr_sender, sigint)
+            .await
+    }
+
+    /// Executes `code` on the REPL itself
+    async fn execute_code(
+        &self,
+        code: String,
+        io_sender: mpsc::Sender<Bytes>,
+        stderr_sender: Option<mpsc::Sender<Bytes>>,
+        sigint: CancellationToken,
+    ) -> Result<(), ReplError> {
+        let Some((process, limits)) = &self.limits else {
+            return self.send_code(code, io_sender, stderr_sender, sigint).await;
+        };
+
+        let mut output = OutputTail::default();
+        let result = match limits.watches_output() {
+            true => {
+                self.send_code_watched(code, io_sender, stderr_sender, sigint, &mut output)
+                    .await
+            }
+            false => self.send_code(code, io_sender, stderr_sender, sigint).await,
+        };
+
+        match result {
+            Err(ReplError::Failed) => Err(match limits.exceeded(process, &output).await {
+                Some(limit) => ReplError::ResourceLimit(limit),
+                None => ReplError::Failed,
+            }),
+            result => result,
+        }
+    }
+
+    /// Sends `code` to the REPL and waits for its answer
+    async fn send_code(
+        &self,
+        code: String,
+        io_sender: mpsc::Sender<Bytes>,
+        stderr_sender: Option<mpsc::Sender<Bytes>>,
         sigint: CancellationToken,
     ) -> Result<(), ReplError> {
         let (notif_sender, notif_receiver) = oneshot::channel();
@@ -54,29 +161,178 @@ pub async fn execute(
             code,
             sigint,
             io_sender,
+            stderr_sender,
             notif_sender,
+            span: Span::current(),
         };
 
         let _ = self.message_sender.send(message).await;
-        notif_receiver.await.expect("Repl has been killed")
+        debug!("code sent to the REPL");
+
+        let result = notif_receiver.await.expect("Repl has been killed");
+        debug!(?result, "REPL answered");
+
+        result
+    }
+
+    /// Sends `code` to the REPL, keeping the end of what it outputs in `output` on the way
+    async fn send_code_watched(
+        &self,
+        code: String,
+        io_sender: mpsc::Sender<Bytes>,
+        stderr_sender: Option<mpsc::Sender<Bytes>>,
+        sigint: CancellationToken,
+        output: &mut OutputTail,
+    ) -> Result<(), ReplError> {
+        let (watched_io_sender, mut io_receiver) = mpsc::channel(1);
+        let (watched_stderr_sender, mut stderr_receiver) = mpsc::channel(1);
+        let stderr_sender = stderr_sender.map(|sender| (watched_stderr_sender, sender));
+
+        let execution = self.send_code(
+            code,
+            watched_io_sender,
+            stderr_sender.as_ref().map(|(watched, _)| watched.clone()),
+            sigint,
+        );
+        tokio::pin!(execution);
+
+        let result = loop {
+            let (data, sender) = select! {
+                result = &mut execution => break result,
+                Some(data) = io_receiver.recv() => (data, &io_sender),
+                Some(data) = stderr_receiver.recv() => {
+                    (data, &stderr_sender.as_ref().expect("Standard error is mixed").1)
+                }
+            };
+
+            output.push(&data);
+            let _ = sender.send(data).await;
+        };
+
+        // REPLs send their output before they answer
+        while let Ok(data) = io_receiver.try_recv() {
+            output.push(&data);
+            let _ = io_sender.send(data).await;
+        }
+        if let Some((_, sender)) = &stderr_sender {
+            while let Ok(data) = stderr_receiver.try_recv() {
+                output.push(&data);
+                let _ = sender.send(data).await;
+            }
+        }
+
+        result
+    }
+
+    /// Reports the failures of executions killing `process` for exceeding `limits` as
+    /// `ReplError::ResourceLimit`
+    pub fn with_limits(
+        mut self,
+        process: Arc<Mutex<process::Child>>,
+        limits: ResourceLimits,
+    ) -> Self {
+        if !limits.is_empty() {
+            self.limits = Some((process, limits));
+        }
+        self
+    }
+
+    /// Runs the magic commands of the executed code with `magics`
+    pub fn with_magics(mut self, magics: Magics) -> Self {
+        self.magics = Some(Arc::new(magics));
+        self
     }
 }
 
 pub fn launch<R>(repl_process: Arc<Mutex<process::Child>>) -> ReplHandle
+where
+    R: Repl + Send + 'static,
+    R::Config: Default,
+{
+    launch_with::<R>(repl_process, R::Config::default())
+}
+
+/// Runs the REPL of `repl_process`, whose process tree is killed once the REPL ends, as it does
+/// when its handle is dropped
+pub fn launch_with<R>(repl_process: Arc<Mutex<process::Child>>, config: R::Config) -> ReplHandle
+where
+    R: Repl + Send + 'static,
+{
+    let process = repl_process.clone();
+
+    spawn_then(
+        |message_receiver| R::new(repl_process, config, message_receiver),
+        async move { kill(&process).await },
+    )
+}
+
+/// Runs the REPL built by `build` from the receiver of its messages, for REPLs that need more
+/// than a process to be created
+pub fn spawn<R>(build: impl FnOnce(mpsc::Receiver<ReplMessage>) -> R) -> ReplHandle
+where
+    R: Repl + Send + 'static,
+{
+    spawn_then(build, async {})
+}
+
+/// Runs the REPL, then `ended` before the REPL is dropped
+fn spawn_then<R>(
+    build: impl FnOnce(mpsc::Receiver<ReplMessage>) -> R,
+    ended: impl Future<Output = ()> + Send + 'static,
+) -> ReplHandle
 where
     R: Repl + Send + 'static,
 {
     // Minimize message loss by using blocking message with limited number of buffer in channel
     let (message_sender, message_receiver) = mpsc::channel(1);
-    let repl = R::new(repl_process, message_receiver);
+    let mut repl = build(message_receiver);
 
-    task::spawn(run_repl(repl));
+    task::spawn(async move {
+        run_repl(&mut repl).await;
+        ended.await;
+    });
 
-    ReplHandle { message_sender }
+    ReplHandle {
+        message_sender,
+        limits: None,
+        magics: None,
+    }
 }
 
-async fn run_repl<R: Repl>(mut repl: R) {
+async fn run_repl<R: Repl>(repl: &mut R) {
     while let Some(message) = repl.next_message().await {
-        repl.handle_message(message).await;
+        let span = info_span!(parent: message.span(), "run_repl");
+        repl.handle_message(message).instrument(span).await;
+    }
+}
+
+/// Kills the process tree of the interpreter, then reaps it
+pub(crate) async fn kill(process: &Mutex<process::Child>) {
+    // The interpreter is only reaped through its process, so holding it keeps its pid and
+    // process group from being reused until it is killed
+    let mut process = process.lock().await;
+    if procfs::child_exited(process.id()).is_some() {
+        procfs::kill_tree(process.id());
+    }
+
+    while let Ok(None) = process.try_wait() {
+        sleep(REAP_POLL_INTERVAL).await;
+    }
+}
+
+/// Sends `signal` to the process group of `pid`, or to `pid` alone when it shares the process
+/// group of the kernel
+pub(crate) fn send_signal(pid: u32, signal: i32) {
+    let pid = pid as libc::pid_t;
+
+    // SAFETY: getpgid and kill take plain integers and do not touch memory of this process
+    unsafe {
+        let group = libc::getpgid(pid);
+
+        if group > 0 && group != libc::getpgid(0) {
+            libc::kill(-group, signal);
+        } else {
+            libc::kill(pid, signal);
+        }
     }
 }
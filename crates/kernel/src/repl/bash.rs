@@ -0,0 +1,303 @@
+use std::{
+    os::unix::process::CommandExt,
+    process::{self, Command, Stdio},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    process::{ChildStderr, ChildStdin, ChildStdout},
+    sync::{mpsc, Mutex},
+};
+use tokio_util::sync::CancellationToken;
+
+use super::{send_signal, Repl, ReplError, ReplMessage};
+
+const READ_BUFFER_SIZE: usize = 4096;
+
+/// Printed by the driver on stdout and stderr after each cell, followed by its exit status
+const DONE_MARKER: &[u8] = b"\x1ecanal-done ";
+
+/// Script run by bash, reading cells separated by NUL bytes from its stdin.
+///
+/// Each cell is evaluated in the shell itself, so variables, functions and the working directory
+/// are kept between cells. An interrupt only raises a flag, and the DEBUG trap then returns from
+/// every function up to the cell, which stops both external commands and loops of builtins.
+const DRIVER: &str = r#"
+shopt -s extdebug
+set -o functrace
+__canal_cell() { eval "$1"; }
+trap '__canal_interrupted=1' INT
+trap 'if [ -n "$__canal_interrupted" ] && [ -n "${FUNCNAME[0]}" ]; then return 2; fi' DEBUG
+while :; do
+    __canal_interrupted=
+    IFS= read -r -d '' __canal_code || break
+    __canal_cell "$__canal_code" </dev/null
+    __canal_status=$?
+    printf '\036canal-done %d\n' "$__canal_status"
+    printf '\036canal-done %d\n' "$__canal_status" >&2
+done
+"#;
+
+/// Command spawning the bash process driven by a `BashRepl`
+pub fn command() -> Command {
+    let mut command = Command::new("bash");
+    configure(&mut command);
+
+    command
+}
+
+/// Makes a command running bash, and possibly some of its options, drive a `BashRepl`. Bash runs
+/// in its own process group so that an interrupt reaches the commands it runs.
+pub fn configure(command: &mut Command) -> &mut Command {
+    command
+        .args(["--noprofile", "--norc", "-c", DRIVER])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+}
+
+/// Runs code in a persistent bash process spawned from `command`.
+///
+/// An execution succeeds when the last command of the code exits with status 0. The standard
+/// error is only told apart from the output by `ReplHandle::execute_with_stderr`. Code setting
+/// its own INT or DEBUG trap cannot be interrupted reliably.
+pub struct BashRepl {
+    process: Arc<Mutex<process::Child>>,
+    message_receiver: mpsc::Receiver<ReplMessage>,
+    stdin: ChildStdin,
+    stdout: Channel<ChildStdout>,
+    stderr: Channel<ChildStderr>,
+    /// Bash exited, so nothing can be executed anymore
+    exited: bool,
+}
+
+/// Output of bash, read until the marker ending the cell
+struct Channel<R> {
+    reader: R,
+    /// Output held back while it may be the start of the marker
+    pending: Vec<u8>,
+}
+
+enum Event {
+    Stdout(usize),
+    Stderr(usize),
+    Closed,
+    Interrupt,
+}
+
+impl BashRepl {
+    async fn execute(
+        &mut self,
+        code: &str,
+        io_sender: &mpsc::Sender<Bytes>,
+        stderr_sender: Option<&mpsc::Sender<Bytes>>,
+        sigint: &CancellationToken,
+    ) -> Result<(), ReplError> {
+        if self.exited {
+            return Err(ReplError::Failed);
+        }
+
+        // Bash variables cannot hold a NUL byte, and it separates the cells anyway
+        if code.contains('\0') {
+            let error = Bytes::from_static(b"bash: code cannot contain NUL bytes\n");
+            let _ = stderr_sender.unwrap_or(io_sender).send(error).await;
+            return Err(ReplError::Failed);
+        }
+
+        self.write_cell(code).await?;
+
+        let mut stdout_buffer = [0; READ_BUFFER_SIZE];
+        let mut stderr_buffer = [0; READ_BUFFER_SIZE];
+        let mut status = None;
+        let mut stderr_done = false;
+        let mut interrupted = false;
+
+        while status.is_none() || !stderr_done {
+            let event = tokio::select! {
+                read = self.stdout.reader.read(&mut stdout_buffer), if status.is_none() => {
+                    match read {
+                        Ok(0) | Err(_) => Event::Closed,
+                        Ok(len) => Event::Stdout(len),
+                    }
+                }
+                read = self.stderr.reader.read(&mut stderr_buffer), if !stderr_done => {
+                    match read {
+                        Ok(0) | Err(_) => Event::Closed,
+                        Ok(len) => Event::Stderr(len),
+                    }
+                }
+                _ = sigint.cancelled(), if !interrupted => Event::Interrupt,
+            };
+
+            match event {
+                Event::Stdout(len) => {
+                    self.stdout.pending.extend_from_slice(&stdout_buffer[..len]);
+                    let (output, done) = self.stdout.take_output();
+                    forward(output, io_sender).await;
+                    status = done;
+                }
+                Event::Stderr(len) => {
+                    self.stderr.pending.extend_from_slice(&stderr_buffer[..len]);
+                    let (output, done) = self.stderr.take_output();
+                    forward(output, stderr_sender.unwrap_or(io_sender)).await;
+                    stderr_done = done.is_some();
+                }
+                Event::Closed => {
+                    self.exited = true;
+                    forward(std::mem::take(&mut self.stdout.pending), io_sender).await;
+                    forward(
+                        std::mem::take(&mut self.stderr.pending),
+                        stderr_sender.unwrap_or(io_sender),
+                    )
+                    .await;
+
+                    return Err(if interrupted {
+                        ReplError::Interrupted
+                    } else {
+                        ReplError::Failed
+                    });
+                }
+                Event::Interrupt => {
+                    interrupted = true;
+                    let pid = self.process.lock().await.id();
+                    send_signal(pid, libc::SIGINT);
+                }
+            }
+        }
+
+        match status {
+            _ if interrupted => Err(ReplError::Interrupted),
+            Some(0) => Ok(()),
+            _ => Err(ReplError::Failed),
+        }
+    }
+
+    async fn write_cell(&mut self, code: &str) -> Result<(), ReplError> {
+        let written = async {
+            self.stdin.write_all(code.as_bytes()).await?;
+            self.stdin.write_all(b"\0").await?;
+            self.stdin.flush().await
+        };
+
+        written.await.map_err(|_| {
+            self.exited = true;
+            ReplError::Failed
+        })
+    }
+}
+
+impl<R> Channel<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Takes the pending output that cannot be part of the marker, and the exit status of the
+    /// cell once the whole marker line was read
+    fn take_output(&mut self) -> (Vec<u8>, Option<i32>) {
+        let marker_start = self
+            .pending
+            .windows(DONE_MARKER.len())
+            .position(|window| window == DONE_MARKER);
+
+        let Some(marker_start) = marker_start else {
+            // The end of the output may be the beginning of a marker split across reads
+            let held = (1..DONE_MARKER.len())
+                .rev()
+                .find(|len| self.pending.ends_with(&DONE_MARKER[..*len]))
+                .unwrap_or(0);
+            let output = self.pending.drain(..self.pending.len() - held).collect();
+
+            return (output, None);
+        };
+
+        let status_start = marker_start + DONE_MARKER.len();
+        let Some(status_len) = self.pending[status_start..]
+            .iter()
+            .position(|byte| *byte == b'\n')
+        else {
+            return (self.pending.drain(..marker_start).collect(), None);
+        };
+
+        let status = std::str::from_utf8(&self.pending[status_start..status_start + status_len])
+            .ok()
+            .and_then(|status| status.parse().ok())
+            .unwrap_or(-1);
+        let output = self.pending[..marker_start].to_vec();
+        self.pending.clear();
+
+        (output, Some(status))
+    }
+}
+
+async fn forward(output: Vec<u8>, sender: &mpsc::Sender<Bytes>) {
+    if output.is_empty() {
+        return;
+    }
+
+    // The output of a client that went away is simply lost
+    let _ = sender.send(output.into()).await;
+}
+
+#[async_trait]
+impl Repl for BashRepl {
+    type Config = ();
+
+    fn new(
+        process: Arc<Mutex<process::Child>>,
+        _config: (),
+        message_receiver: mpsc::Receiver<ReplMessage>,
+    ) -> Self {
+        let (stdin, stdout, stderr) = {
+            let mut child = process
+                .try_lock()
+                .expect("REPL process is used before the REPL is created");
+            let stdin = child.stdin.take().expect("bash has no piped stdin");
+            let stdout = child.stdout.take().expect("bash has no piped stdout");
+            let stderr = child.stderr.take().expect("bash has no piped stderr");
+
+            (
+                ChildStdin::from_std(stdin).expect("bash stdin cannot be used asynchronously"),
+                ChildStdout::from_std(stdout).expect("bash stdout cannot be used asynchronously"),
+                ChildStderr::from_std(stderr).expect("bash stderr cannot be used asynchronously"),
+            )
+        };
+
+        Self {
+            process,
+            message_receiver,
+            stdin,
+            stdout: Channel::new(stdout),
+            stderr: Channel::new(stderr),
+            exited: false,
+        }
+    }
+
+    async fn handle_message(&mut self, message: ReplMessage) {
+        match message {
+            ReplMessage::Execute {
+                notif_sender,
+                io_sender,
+                stderr_sender,
+                sigint,
+                code,
+                ..
+            } => {
+                let result = self
+                    .execute(&code, &io_sender, stderr_sender.as_ref(), &sigint)
+                    .await;
+                let _ = notif_sender.send(result);
+            }
+        }
+    }
+
+    async fn next_message(&mut self) -> Option<ReplMessage> {
+        self.message_receiver.recv().await
+    }
+}
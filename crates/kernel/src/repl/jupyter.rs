@@ -0,0 +1,411 @@
+use std::{fs, path::PathBuf, process, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    sync::{mpsc, Mutex},
+    time::{sleep, timeout, Instant},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+use zeromq::{DealerSocket, Socket, SocketRecv, SocketSend, SubSocket, ZmqMessage, ZmqResult};
+
+use super::{send_signal, Repl, ReplError, ReplMessage};
+use crate::{
+    jupyter::{ConnectionInfo, JupyterError, JupyterMessage, Session},
+    procfs,
+};
+
+const USERNAME: &str = "canal";
+
+/// How often the kernel process is checked while waiting for its messages
+const PROCESS_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long the kernel has to answer a `kernel_info_request` before it is asked again
+const HANDSHAKE_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How a Jupyter kernel is interrupted, as in the `interrupt_mode` of its kernel.json
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InterruptMode {
+    /// `SIGINT` sent to the kernel process
+    #[default]
+    Signal,
+    /// `interrupt_request` sent on the control socket
+    Message,
+}
+
+#[derive(Debug, Clone)]
+pub struct JupyterConfig {
+    pub connection: ConnectionInfo,
+    pub interrupt_mode: InterruptMode,
+    /// Connection file given to the kernel, removed with the REPL
+    pub connection_file: Option<PathBuf>,
+    /// How long the kernel has to answer once spawned
+    pub startup_timeout: Duration,
+}
+
+impl JupyterConfig {
+    pub fn new(connection: ConnectionInfo) -> Self {
+        Self {
+            connection,
+            interrupt_mode: InterruptMode::default(),
+            connection_file: None,
+            startup_timeout: Duration::from_secs(30),
+        }
+    }
+
+    pub fn interrupt_mode(mut self, interrupt_mode: InterruptMode) -> Self {
+        self.interrupt_mode = interrupt_mode;
+        self
+    }
+
+    pub fn connection_file(mut self, connection_file: PathBuf) -> Self {
+        self.connection_file = Some(connection_file);
+        self
+    }
+
+    pub fn startup_timeout(mut self, startup_timeout: Duration) -> Self {
+        self.startup_timeout = startup_timeout;
+        self
+    }
+}
+
+/// Runs code in a Jupyter kernel (ipykernel, IRkernel, ...) through the Jupyter messaging
+/// protocol.
+///
+/// Streams are forwarded as they are, the `text/plain` form of displayed data and results is
+/// written to the output, and errors are written with their traceback. The kernel process is
+/// killed with the REPL.
+pub struct JupyterRepl {
+    process: Arc<Mutex<process::Child>>,
+    config: JupyterConfig,
+    message_receiver: mpsc::Receiver<ReplMessage>,
+    /// Connected on the first execution, once the kernel answers
+    sockets: Option<Sockets>,
+    /// The kernel could not be reached or exited, so nothing can be executed anymore
+    exited: bool,
+}
+
+struct Sockets {
+    session: Session,
+    shell: DealerSocket,
+    control: DealerSocket,
+    iopub: SubSocket,
+}
+
+enum Event {
+    Iopub(ZmqResult<ZmqMessage>),
+    Shell(ZmqResult<ZmqMessage>),
+    Interrupt,
+    CheckProcess,
+}
+
+impl JupyterRepl {
+    async fn execute(
+        &mut self,
+        code: String,
+        io_sender: &mpsc::Sender<Bytes>,
+        stderr_sender: Option<&mpsc::Sender<Bytes>>,
+        sigint: &CancellationToken,
+    ) -> Result<(), ReplError> {
+        let stderr_sender = stderr_sender.unwrap_or(io_sender);
+
+        if self.exited {
+            return Err(ReplError::Failed);
+        }
+
+        if self.sockets.is_none() {
+            match self.connect().await {
+                Ok(sockets) => self.sockets = Some(sockets),
+                Err(err) => {
+                    self.exited = true;
+                    warn!(%err, "Jupyter kernel could not be reached");
+                    let error = format!("Jupyter kernel could not be reached: {err}\n");
+                    let _ = stderr_sender.send(error.into()).await;
+                    return Err(ReplError::Failed);
+                }
+            }
+        }
+
+        let sockets = self
+            .sockets
+            .as_mut()
+            .expect("Jupyter sockets are connected");
+        let request = sockets.session.message(
+            "execute_request",
+            json!({
+                "code": code,
+                "silent": false,
+                "store_history": true,
+                "user_expressions": {},
+                "allow_stdin": false,
+                "stop_on_error": true,
+            }),
+        );
+        let msg_id = request.header.msg_id.clone();
+        if sockets
+            .shell
+            .send(sockets.session.encode(&request))
+            .await
+            .is_err()
+        {
+            self.exited = true;
+            return Err(ReplError::Failed);
+        }
+
+        let mut reply_status = None;
+        let mut idle = false;
+        let mut interrupted = false;
+        // An interrupt arriving before the kernel runs the request would be lost, so it is only
+        // sent once the kernel published something about the request
+        let mut started = false;
+
+        while reply_status.is_none() || !idle {
+            let event = tokio::select! {
+                message = sockets.iopub.recv(), if !idle => Event::Iopub(message),
+                message = sockets.shell.recv(), if reply_status.is_none() => Event::Shell(message),
+                _ = sigint.cancelled(), if !interrupted => Event::Interrupt,
+                _ = sleep(PROCESS_CHECK_INTERVAL) => Event::CheckProcess,
+            };
+
+            match event {
+                Event::Iopub(message) => {
+                    let Some(message) = decode(&sockets.session, message, &msg_id) else {
+                        continue;
+                    };
+
+                    if !started {
+                        started = true;
+                        if interrupted {
+                            interrupt(self.config.interrupt_mode, &self.process, sockets).await;
+                        }
+                    }
+
+                    match message.msg_type() {
+                        "stream" => {
+                            let text = message.content["text"].as_str().unwrap_or_default();
+                            let sender = match message.content["name"].as_str() {
+                                Some("stderr") => stderr_sender,
+                                _ => io_sender,
+                            };
+                            forward(text.to_string(), sender).await;
+                        }
+                        "display_data" | "execute_result" => {
+                            if let Some(text) = message.content["data"]["text/plain"].as_str() {
+                                forward(format!("{text}\n"), io_sender).await;
+                            }
+                        }
+                        "error" => forward(format_error(&message.content), stderr_sender).await,
+                        "status" => idle = message.content["execution_state"] == "idle",
+                        _ => {}
+                    }
+                }
+                Event::Shell(message) => {
+                    if let Some(message) = decode(&sockets.session, message, &msg_id) {
+                        let status = message.content["status"].as_str().unwrap_or("error");
+                        reply_status = Some(status.to_string());
+                    }
+                }
+                Event::Interrupt => {
+                    interrupted = true;
+                    if started {
+                        interrupt(self.config.interrupt_mode, &self.process, sockets).await;
+                    }
+                }
+                Event::CheckProcess => {
+                    // Checked without reaping, which is left to whoever kills the process tree
+                    let pid = self.process.lock().await.id();
+                    if procfs::child_exited(pid) != Some(false) {
+                        self.exited = true;
+                        return Err(if interrupted {
+                            ReplError::Interrupted
+                        } else {
+                            ReplError::Failed
+                        });
+                    }
+                }
+            }
+        }
+
+        match reply_status.as_deref() {
+            _ if interrupted => Err(ReplError::Interrupted),
+            Some("ok") => Ok(()),
+            _ => Err(ReplError::Failed),
+        }
+    }
+
+    /// Connects to the sockets of the kernel and waits until its outputs can be received
+    async fn connect(&self) -> Result<Sockets, JupyterError> {
+        let connection = &self.config.connection;
+        let session = connection.session(USERNAME)?;
+        let deadline = Instant::now() + self.config.startup_timeout;
+
+        let mut shell = DealerSocket::new();
+        let mut control = DealerSocket::new();
+        let mut iopub = SubSocket::new();
+
+        let connected = timeout(self.config.startup_timeout, async {
+            shell
+                .connect(&connection.endpoint(connection.shell_port))
+                .await?;
+            control
+                .connect(&connection.endpoint(connection.control_port))
+                .await?;
+            iopub
+                .connect(&connection.endpoint(connection.iopub_port))
+                .await?;
+            iopub.subscribe("").await
+        });
+        connected.await.map_err(|_| JupyterError::Timeout)??;
+
+        // A subscription takes effect some time after it is sent, so the kernel is asked for
+        // its info until its status is received, which proves that outputs are not lost anymore
+        loop {
+            let request = session.message("kernel_info_request", json!({}));
+            shell.send(session.encode(&request)).await?;
+
+            let retry = (Instant::now() + HANDSHAKE_RETRY_INTERVAL).min(deadline);
+            while let Ok(message) = tokio::time::timeout_at(retry, iopub.recv()).await {
+                if decode(&session, message, &request.header.msg_id).is_some() {
+                    return Ok(Sockets {
+                        session,
+                        shell,
+                        control,
+                        iopub,
+                    });
+                }
+            }
+
+            if Instant::now() >= deadline {
+                return Err(JupyterError::Timeout);
+            }
+        }
+    }
+}
+
+impl Drop for JupyterRepl {
+    fn drop(&mut self) {
+        if let Some(connection_file) = &self.config.connection_file {
+            let _ = fs::remove_file(connection_file);
+        }
+
+        // Jupyter kernels do not exit when their client goes away. Their tree is killed before
+        // they are reaped, after which their process group could belong to anything.
+        if let Ok(mut process) = self.process.try_lock() {
+            if procfs::child_exited(process.id()).is_some() {
+                procfs::kill_tree(process.id());
+            }
+            let _ = process.wait();
+        }
+    }
+}
+
+async fn interrupt(mode: InterruptMode, process: &Mutex<process::Child>, sockets: &mut Sockets) {
+    match mode {
+        InterruptMode::Signal => {
+            let pid = process.lock().await.id();
+            send_signal(pid, libc::SIGINT);
+        }
+        InterruptMode::Message => {
+            let request = sockets.session.message("interrupt_request", json!({}));
+            let _ = sockets.control.send(sockets.session.encode(&request)).await;
+        }
+    }
+}
+
+/// Decodes a message answering the message `parent_msg_id`, ignoring any other message
+fn decode(
+    session: &Session,
+    message: ZmqResult<ZmqMessage>,
+    parent_msg_id: &str,
+) -> Option<JupyterMessage> {
+    let message = match message
+        .map_err(JupyterError::from)
+        .and_then(|message| session.decode(message))
+    {
+        Ok(message) => message,
+        Err(err) => {
+            warn!(%err, "Jupyter message ignored");
+            return None;
+        }
+    };
+
+    (message.parent_msg_id() == Some(parent_msg_id)).then_some(message)
+}
+
+/// Traceback of an `error` message, or its name and value when it has none
+fn format_error(content: &Value) -> String {
+    let traceback = content["traceback"]
+        .as_array()
+        .map(|lines| {
+            lines
+                .iter()
+                .filter_map(Value::as_str)
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+        .filter(|traceback| !traceback.is_empty());
+
+    let error = traceback.unwrap_or_else(|| {
+        format!(
+            "{}: {}",
+            content["ename"].as_str().unwrap_or("Error"),
+            content["evalue"].as_str().unwrap_or_default(),
+        )
+    });
+
+    format!("{error}\n")
+}
+
+async fn forward(text: String, sender: &mpsc::Sender<Bytes>) {
+    if text.is_empty() {
+        return;
+    }
+
+    // The output of a client that went away is simply lost
+    let _ = sender.send(text.into()).await;
+}
+
+#[async_trait]
+impl Repl for JupyterRepl {
+    type Config = JupyterConfig;
+
+    fn new(
+        process: Arc<Mutex<process::Child>>,
+        config: JupyterConfig,
+        message_receiver: mpsc::Receiver<ReplMessage>,
+    ) -> Self {
+        Self {
+            process,
+            config,
+            message_receiver,
+            sockets: None,
+            exited: false,
+        }
+    }
+
+    async fn handle_message(&mut self, message: ReplMessage) {
+        match message {
+            ReplMessage::Execute {
+                notif_sender,
+                io_sender,
+                stderr_sender,
+                sigint,
+                code,
+                ..
+            } => {
+                let result = self
+                    .execute(code, &io_sender, stderr_sender.as_ref(), &sigint)
+                    .await;
+                let _ = notif_sender.send(result);
+            }
+        }
+    }
+
+    async fn next_message(&mut self) -> Option<ReplMessage> {
+        self.message_receiver.recv().await
+    }
+}
@@ -0,0 +1,688 @@
+//! Magic commands run by the kernel in front of the interpreter, whatever its language.
+//!
+//! A line magic is a line starting with `%name`, and runs between the code before and after
+//! it. A cell magic is a first line starting with `%%name`, and gets the rest of the cell as its
+//! body. Lines naming no registered magic are left to the interpreter, for languages where `%`
+//! starts a comment or an operator.
+
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::CString,
+    fs,
+    io::{self, Write},
+    mem,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::{ffi::OsStrExt, fs::OpenOptionsExt, process::CommandExt},
+    },
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    process::Command as AsyncCommand,
+    select,
+    sync::{mpsc, Mutex},
+    time::timeout,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+use super::{send_signal, ReplError, ReplHandle};
+use crate::{limits::ResourceLimits, sandbox::SandboxProfile};
+
+const READ_BUFFER_SIZE: usize = 4096;
+
+/// How long an interrupted shell cell has to exit before its process group is killed
+const INTERRUPT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Runs a magic command, registered with `Magics::line_magic` or `Magics::cell_magic`
+#[async_trait]
+pub trait Magic: Send + Sync {
+    async fn run(
+        &self,
+        call: &MagicCall<'_>,
+        context: &mut MagicContext<'_>,
+    ) -> Result<(), ReplError>;
+}
+
+/// How a magic is called in a cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MagicCall<'a> {
+    pub name: &'a str,
+    /// Rest of the line of the magic
+    pub args: &'a str,
+    /// Rest of the cell, for cell magics
+    pub body: Option<&'a str>,
+}
+
+/// Directory and environment of the magics of a REPL, kept between executions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MagicState {
+    /// Changed by `%cd`. Paths given to magics are relative to it, and shell cells start in it.
+    pub cwd: PathBuf,
+    /// Set by `%env` and added to the environment of shell cells. The interpreter keeps the
+    /// environment it was launched with.
+    pub env: BTreeMap<String, String>,
+}
+
+/// Magics of a REPL: `%time` and `%%time`, `%%bash` and `%%sh`, `%env`, `%cd` and
+/// `%%writefile`, along with the registered ones.
+///
+/// Shell cells run with the resource limits and in the sandbox of the interpreter, and files are
+/// only written in the workspace of the sandbox.
+pub struct Magics {
+    line: BTreeMap<String, Arc<dyn Magic>>,
+    cell: BTreeMap<String, Arc<dyn Magic>>,
+    limits: ResourceLimits,
+    sandbox: Option<SandboxProfile>,
+    /// Only directory written by sandboxed magics
+    workspace: Option<PathBuf>,
+    state: Mutex<MagicState>,
+}
+
+/// What a magic can do while it runs
+pub struct MagicContext<'a> {
+    repl: &'a ReplHandle,
+    magics: &'a Magics,
+    pub state: &'a mut MagicState,
+    io_sender: &'a mpsc::Sender<Bytes>,
+    stderr_sender: Option<&'a mpsc::Sender<Bytes>>,
+    sigint: &'a CancellationToken,
+}
+
+/// Part of a cell, run in order with the others
+enum Segment<'a> {
+    Code(String),
+    Magic {
+        magic: Arc<dyn Magic>,
+        call: MagicCall<'a>,
+    },
+}
+
+/// `%time` and `%%time`, printing the wall-clock time of the execution
+struct Time;
+
+/// `%%bash` and `%%sh`, running the body of the cell in a shell
+struct Shell {
+    program: &'static str,
+}
+
+/// `%env`, listing, printing or setting the variables of shell cells
+struct Env;
+
+/// `%cd`, changing the directory of magics
+struct Cd;
+
+/// `%%writefile [-a] <file>`, writing or appending the body of the cell to a file
+struct WriteFile;
+
+impl Magics {
+    /// Built-in magics, starting in the current directory without variables of their own
+    pub fn new() -> Self {
+        let cwd = env::current_dir().unwrap_or_else(|_| PathBuf::from("/"));
+
+        Self {
+            line: BTreeMap::new(),
+            cell: BTreeMap::new(),
+            limits: ResourceLimits::default(),
+            sandbox: None,
+            workspace: None,
+            state: Mutex::new(MagicState {
+                cwd,
+                env: BTreeMap::new(),
+            }),
+        }
+        .line_magic("time", Time)
+        .line_magic("env", Env)
+        .line_magic("cd", Cd)
+        .cell_magic("time", Time)
+        .cell_magic("bash", Shell { program: "bash" })
+        .cell_magic("sh", Shell { program: "sh" })
+        .cell_magic("writefile", WriteFile)
+    }
+
+    /// Registers `magic` as `%name`, replacing the magic of that name
+    pub fn line_magic(mut self, name: impl Into<String>, magic: impl Magic + 'static) -> Self {
+        self.line.insert(name.into(), Arc::new(magic));
+        self
+    }
+
+    /// Registers `magic` as `%%name`, replacing the magic of that name
+    pub fn cell_magic(mut self, name: impl Into<String>, magic: impl Magic + 'static) -> Self {
+        self.cell.insert(name.into(), Arc::new(magic));
+        self
+    }
+
+    /// Variables of shell cells, added to the environment of the kernel
+    pub fn env(mut self, env: BTreeMap<String, String>) -> Self {
+        self.state.get_mut().env = env;
+        self
+    }
+
+    pub fn limits(mut self, limits: ResourceLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Runs shell cells in `sandbox`, and starts in its workspace
+    pub fn sandbox(mut self, sandbox: SandboxProfile) -> Self {
+        let workspace = sandbox
+            .workspace_dir()
+            .and_then(|workspace| workspace.canonicalize())
+            .ok();
+        if let Some(workspace) = &workspace {
+            self.state.get_mut().cwd = workspace.clone();
+        }
+
+        self.workspace = workspace;
+        self.sandbox = Some(sandbox);
+        self
+    }
+
+    /// Splits `code` into the magics it calls and the code between them, or `None` when it
+    /// calls none
+    fn parse<'a>(&self, code: &'a str) -> Option<Vec<Segment<'a>>> {
+        if let Some(line) = code.trim_start().strip_prefix("%%") {
+            let (line, body) = line.split_once('\n').unwrap_or((line, ""));
+            let (name, args) = split_call(line);
+            let magic = self.cell.get(name)?.clone();
+
+            return Some(vec![Segment::Magic {
+                magic,
+                call: MagicCall {
+                    name,
+                    args,
+                    body: Some(body),
+                },
+            }]);
+        }
+
+        let mut segments = Vec::new();
+        let mut lines = Vec::new();
+        let mut called = false;
+
+        for line in code.lines() {
+            let magic = line
+                .strip_prefix('%')
+                .map(split_call)
+                .and_then(|(name, args)| Some((self.line.get(name)?, name, args)));
+
+            match magic {
+                Some((magic, name, args)) => {
+                    push_code(&mut segments, &mut lines);
+                    segments.push(Segment::Magic {
+                        magic: magic.clone(),
+                        call: MagicCall {
+                            name,
+                            args,
+                            body: None,
+                        },
+                    });
+                    called = true;
+                }
+                None => lines.push(line),
+            }
+        }
+        push_code(&mut segments, &mut lines);
+
+        called.then_some(segments)
+    }
+
+    /// Runs the magics `code` calls and the code between them, or `None` when it calls none
+    pub(super) async fn execute(
+        &self,
+        repl: &ReplHandle,
+        code: &str,
+        io_sender: &mpsc::Sender<Bytes>,
+        stderr_sender: Option<&mpsc::Sender<Bytes>>,
+        sigint: &CancellationToken,
+    ) -> Option<Result<(), ReplError>> {
+        let segments = self.parse(code)?;
+
+        let mut state = self.state.lock().await;
+        let mut context = MagicContext {
+            repl,
+            magics: self,
+            state: &mut state,
+            io_sender,
+            stderr_sender,
+            sigint,
+        };
+
+        for segment in segments {
+            let result = match segment {
+                Segment::Code(code) => context.execute(&code).await,
+                Segment::Magic { magic, call } => {
+                    debug!(name = call.name, "running magic");
+                    magic.run(&call, &mut context).await
+                }
+            };
+
+            if result.is_err() {
+                return Some(result);
+            }
+        }
+
+        Some(Ok(()))
+    }
+}
+
+impl Default for Magics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MagicContext<'_> {
+    /// Executes `code` on the interpreter, without looking for magics in it
+    pub async fn execute(&self, code: &str) -> Result<(), ReplError> {
+        self.repl
+            .execute_code(
+                code.to_string(),
+                self.io_sender.clone(),
+                self.stderr_sender.cloned(),
+                self.sigint.clone(),
+            )
+            .await
+    }
+
+    /// Sends `text` as output of the execution
+    pub async fn print(&self, text: impl Into<String>) {
+        let _ = self.io_sender.send(Bytes::from(text.into())).await;
+    }
+
+    /// Sends `text` to the standard error of the execution
+    pub async fn print_error(&self, text: impl Into<String>) {
+        let _ = self.stderr_sender().send(Bytes::from(text.into())).await;
+    }
+
+    /// Prints `message` to the standard error and fails, as interpreters do on errors
+    pub async fn fail(&self, message: impl Into<String>) -> Result<(), ReplError> {
+        self.print_error(message.into() + "\n").await;
+        Err(ReplError::Failed)
+    }
+
+    pub fn io_sender(&self) -> &mpsc::Sender<Bytes> {
+        self.io_sender
+    }
+
+    /// Standard error of the execution, mixed with its output when the REPL is not told apart
+    pub fn stderr_sender(&self) -> &mpsc::Sender<Bytes> {
+        self.stderr_sender.unwrap_or(self.io_sender)
+    }
+
+    /// Cancelled when the execution is interrupted
+    pub fn sigint(&self) -> &CancellationToken {
+        self.sigint
+    }
+
+    /// Command spawning `program` with the variables of the magics, the resource limits of
+    /// the interpreter and in its sandbox. It starts in `MagicState::cwd`, or in the workspace
+    /// when sandboxed.
+    pub fn command(&self, program: &str) -> io::Result<Command> {
+        let mut command = Command::new(program);
+        command.current_dir(&self.state.cwd).envs(&self.state.env);
+        self.magics.limits.apply(&mut command);
+        if let Some(sandbox) = &self.magics.sandbox {
+            sandbox.apply(&mut command, None)?;
+        }
+
+        Ok(command)
+    }
+
+    /// `path` relative to `MagicState::cwd`, refused outside of the workspace when sandboxed
+    pub fn resolve(&self, path: &str) -> io::Result<PathBuf> {
+        let path = self.state.cwd.join(expand_home(path, &self.state.env));
+        if self.magics.workspace.is_some() {
+            // The file itself may not exist yet
+            self.open_dir(path.parent().unwrap_or(&path))?;
+        }
+
+        Ok(path)
+    }
+
+    /// Directory `path`, its real path looked up without leaving the workspace when sandboxed
+    fn real_dir(&self, path: &Path) -> io::Result<PathBuf> {
+        if self.magics.workspace.is_none() {
+            let path = path.canonicalize()?;
+            return match path.is_dir() {
+                true => Ok(path),
+                false => Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+            };
+        }
+
+        let dir = self.open_dir(path)?;
+        fs::read_link(format!("/proc/self/fd/{}", dir.as_raw_fd()))
+    }
+
+    /// Opens the directory `path` of the workspace, without resolving anything outside of it on
+    /// the way, symbolic links and `..` included. The kernel runs outside of the sandbox, so
+    /// paths outside of the workspace are refused before they are ever looked up.
+    fn open_dir(&self, path: &Path) -> io::Result<OwnedFd> {
+        let workspace = self
+            .magics
+            .workspace
+            .as_deref()
+            .ok_or_else(outside_workspace)?;
+        let relative = match path.strip_prefix(workspace) {
+            Ok(relative) if relative.as_os_str().is_empty() => Path::new("."),
+            Ok(relative) => relative,
+            Err(_) => return Err(outside_workspace()),
+        };
+        let relative = CString::new(relative.as_os_str().as_bytes())?;
+        let root = fs::File::open(workspace)?;
+
+        // SAFETY: open_how is a plain C struct, for which zeroes are valid
+        let mut how: libc::open_how = unsafe { mem::zeroed() };
+        how.flags = (libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC) as u64;
+        how.resolve = libc::RESOLVE_BENEATH | libc::RESOLVE_NO_MAGICLINKS;
+
+        // SAFETY: openat2 only reads the path and the struct, which outlive the call
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_openat2,
+                root.as_raw_fd(),
+                relative.as_ptr(),
+                &how as *const libc::open_how,
+                mem::size_of::<libc::open_how>(),
+            )
+        };
+        if fd < 0 {
+            let err = io::Error::last_os_error();
+            return Err(match err.raw_os_error() {
+                Some(libc::EXDEV) => outside_workspace(),
+                _ => err,
+            });
+        }
+
+        // SAFETY: openat2 returned a new descriptor, owned by nothing else
+        Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+    }
+
+    /// Opens `path`, as given by `resolve`, with `options`. When sandboxed, the file is opened
+    /// in its directory as found in the workspace, and a symbolic link is not followed, so that
+    /// nothing outside of the workspace is ever created or truncated.
+    pub fn open(&self, path: &Path, options: &fs::OpenOptions) -> io::Result<fs::File> {
+        if self.magics.workspace.is_none() {
+            return options.open(path);
+        }
+
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            return Err(outside_workspace());
+        };
+        // The directory is held open, so it cannot be swapped for a link until the file is
+        let dir = self.open_dir(parent)?;
+        let path = Path::new(&format!("/proc/self/fd/{}", dir.as_raw_fd())).join(name);
+
+        options.clone().custom_flags(libc::O_NOFOLLOW).open(path)
+    }
+}
+
+#[async_trait]
+impl Magic for Time {
+    async fn run(
+        &self,
+        call: &MagicCall<'_>,
+        context: &mut MagicContext<'_>,
+    ) -> Result<(), ReplError> {
+        let code = call.body.unwrap_or(call.args);
+
+        let start = Instant::now();
+        let result = match code.trim().is_empty() {
+            true => Ok(()),
+            false => context.execute(code).await,
+        };
+        context
+            .print(format!("Wall time: {:.2?}\n", start.elapsed()))
+            .await;
+
+        result
+    }
+}
+
+#[async_trait]
+impl Magic for Shell {
+    async fn run(
+        &self,
+        call: &MagicCall<'_>,
+        context: &mut MagicContext<'_>,
+    ) -> Result<(), ReplError> {
+        // The sandbox starts the shell in the workspace, so it changes directory itself
+        let script = format!(
+            "cd -- {} || exit\n{}",
+            shell_quote(&context.state.cwd),
+            call.body.unwrap_or_default()
+        );
+
+        let spawned = context.command(self.program).and_then(|mut command| {
+            command
+                .arg("-c")
+                .arg(script)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .process_group(0);
+
+            AsyncCommand::from(command).kill_on_drop(true).spawn()
+        });
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(err) => return context.fail(format!("{}: {err}", self.program)).await,
+        };
+
+        let pid = child.id().unwrap_or_default();
+        let sigint = context.sigint();
+        let (_, _, status) = tokio::join!(
+            forward(child.stdout.take(), context.io_sender()),
+            forward(child.stderr.take(), context.stderr_sender()),
+            async {
+                select! {
+                    status = child.wait() => status,
+                    _ = sigint.cancelled() => {
+                        send_signal(pid, libc::SIGINT);
+                        match timeout(INTERRUPT_TIMEOUT, child.wait()).await {
+                            Ok(status) => status,
+                            // Not reaped yet, so its process group is still its own
+                            Err(_) => {
+                                send_signal(pid, libc::SIGKILL);
+                                child.wait().await
+                            }
+                        }
+                    }
+                }
+            },
+        );
+
+        match status {
+            _ if sigint.is_cancelled() => Err(ReplError::Interrupted),
+            Ok(status) if status.success() => Ok(()),
+            _ => Err(ReplError::Failed),
+        }
+    }
+}
+
+#[async_trait]
+impl Magic for Env {
+    async fn run(
+        &self,
+        call: &MagicCall<'_>,
+        context: &mut MagicContext<'_>,
+    ) -> Result<(), ReplError> {
+        let assignment = call
+            .args
+            .split_once('=')
+            .or_else(|| call.args.split_once(char::is_whitespace));
+
+        match assignment {
+            _ if call.args.is_empty() => {
+                let mut variables = env::vars().collect::<BTreeMap<_, _>>();
+                variables.extend(context.state.env.clone());
+
+                let listing = variables
+                    .iter()
+                    .map(|(name, value)| format!("{name}={value}\n"))
+                    .collect::<String>();
+                context.print(listing).await;
+            }
+            Some((name, value)) => {
+                let (name, value) = (name.trim(), value.trim());
+                context
+                    .state
+                    .env
+                    .insert(name.to_string(), value.to_string());
+                context.print(format!("env: {name}={value}\n")).await;
+            }
+            None => {
+                let name = call.args;
+                match variable(name, &context.state.env) {
+                    Some(value) => context.print(format!("{value}\n")).await,
+                    None => return context.fail(format!("env: {name} is not set")).await,
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Magic for Cd {
+    async fn run(
+        &self,
+        call: &MagicCall<'_>,
+        context: &mut MagicContext<'_>,
+    ) -> Result<(), ReplError> {
+        let target = match call.args {
+            "" => "~",
+            args => args,
+        };
+
+        let path = context
+            .state
+            .cwd
+            .join(expand_home(target, &context.state.env));
+        match context.real_dir(&path) {
+            Ok(path) => {
+                context.print(format!("{}\n", path.display())).await;
+                context.state.cwd = path;
+                Ok(())
+            }
+            Err(err) if err.raw_os_error() == Some(libc::ENOTDIR) => {
+                context.fail(format!("cd: {target}: Not a directory")).await
+            }
+            Err(err) => context.fail(format!("cd: {target}: {err}")).await,
+        }
+    }
+}
+
+#[async_trait]
+impl Magic for WriteFile {
+    async fn run(
+        &self,
+        call: &MagicCall<'_>,
+        context: &mut MagicContext<'_>,
+    ) -> Result<(), ReplError> {
+        let (append, file) = match split_call(call.args) {
+            ("-a" | "--append", file) => (true, file),
+            _ => (false, call.args),
+        };
+        if file.is_empty() {
+            return context
+                .fail("writefile: usage: %%writefile [-a] <file>")
+                .await;
+        }
+
+        let written = context.resolve(file).and_then(|path| {
+            let verb = match (append, fs::symlink_metadata(&path).is_ok()) {
+                (true, _) => "Appending to",
+                (false, true) => "Overwriting",
+                (false, false) => "Writing",
+            };
+
+            let mut file = context.open(
+                &path,
+                fs::OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .append(append)
+                    .truncate(!append),
+            )?;
+            file.write_all(call.body.unwrap_or_default().as_bytes())?;
+
+            Ok(verb)
+        });
+
+        match written {
+            Ok(verb) => {
+                context.print(format!("{verb} {file}\n")).await;
+                Ok(())
+            }
+            Err(err) => context.fail(format!("writefile: {file}: {err}")).await,
+        }
+    }
+}
+
+fn outside_workspace() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "outside of the workspace")
+}
+
+/// Name of a magic and its arguments
+fn split_call(line: &str) -> (&str, &str) {
+    let line = line.trim();
+
+    match line.split_once(char::is_whitespace) {
+        Some((name, args)) => (name, args.trim_start()),
+        None => (line, ""),
+    }
+}
+
+/// Adds the code of `lines` to the segments without its surrounding blank lines, unless it is
+/// blank
+fn push_code(segments: &mut Vec<Segment<'_>>, lines: &mut Vec<&str>) {
+    let code = lines.join("\n");
+    lines.clear();
+
+    if !code.trim().is_empty() {
+        segments.push(Segment::Code(code.trim_matches('\n').to_string()));
+    }
+}
+
+/// Value of a variable of the magics, or else of the kernel
+fn variable(name: &str, env: &BTreeMap<String, String>) -> Option<String> {
+    env.get(name).cloned().or_else(|| env::var(name).ok())
+}
+
+/// `path` with a leading `~` replaced by the home directory
+fn expand_home(path: &str, env: &BTreeMap<String, String>) -> PathBuf {
+    let home = || variable("HOME", env).unwrap_or_else(|| "/".to_string());
+
+    match path.strip_prefix('~') {
+        Some("") => PathBuf::from(home()),
+        Some(rest) if rest.starts_with('/') => Path::new(&home()).join(&rest[1..]),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// `path` quoted for a POSIX shell
+fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', r"'\''"))
+}
+
+/// Sends everything read from `reader` to `sender`
+async fn forward(reader: Option<impl AsyncRead + Unpin>, sender: &mpsc::Sender<Bytes>) {
+    let Some(mut reader) = reader else {
+        return;
+    };
+
+    let mut buffer = [0; READ_BUFFER_SIZE];
+    while let Ok(len @ 1..) = reader.read(&mut buffer).await {
+        let _ = sender.send(Bytes::copy_from_slice(&buffer[..len])).await;
+    }
+}
@@ -0,0 +1,354 @@
+use std::{process, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    process::{ChildStdin, ChildStdout},
+    sync::{mpsc, Mutex},
+};
+use tokio_util::sync::CancellationToken;
+
+use super::{send_signal, Repl, ReplError, ReplMessage};
+
+pub use regex::bytes::Regex;
+
+const READ_BUFFER_SIZE: usize = 4096;
+
+/// How a line-oriented interpreter (R, node, sqlite3, psql, ...) tells that it is waiting for
+/// input.
+///
+/// Prompts are matched against the end of the last unterminated line of output, so they are
+/// usually anchored with `$`, e.g. `> $` for node.
+#[derive(Debug, Clone)]
+pub struct PromptConfig {
+    /// Printed when the interpreter waits for a new statement
+    pub prompt: Regex,
+    /// Printed when the current statement needs more lines
+    pub continuation: Option<Regex>,
+    /// Output lines matching it make the execution fail
+    pub error: Option<Regex>,
+    pub interrupt: InterruptKey,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InterruptKey {
+    /// Sends the signal to the process group of the interpreter, or to the interpreter alone
+    /// when it shares the process group of the kernel
+    Signal(i32),
+    /// Writes the bytes to the standard input of the interpreter
+    Input(Vec<u8>),
+}
+
+impl PromptConfig {
+    /// Interrupts with `SIGINT`, without continuation prompt nor error pattern
+    pub fn new(prompt: Regex) -> Self {
+        Self {
+            prompt,
+            continuation: None,
+            error: None,
+            interrupt: InterruptKey::Signal(libc::SIGINT),
+        }
+    }
+
+    pub fn continuation(mut self, continuation: Regex) -> Self {
+        self.continuation = Some(continuation);
+        self
+    }
+
+    pub fn error(mut self, error: Regex) -> Self {
+        self.error = Some(error);
+        self
+    }
+
+    pub fn interrupt(mut self, interrupt: InterruptKey) -> Self {
+        self.interrupt = interrupt;
+        self
+    }
+}
+
+/// Drives an interpreter through its standard input and output, the way a user types in it.
+///
+/// The code is written line by line, each line waiting for a prompt, and the execution is done
+/// once the primary prompt follows the last line. Neither the prompts nor the banner printed
+/// before the first prompt are part of the output.
+///
+/// The process must be spawned with piped stdin and stdout. Interpreters that prompt on stderr
+/// need it redirected to stdout.
+pub struct PromptRepl {
+    process: Arc<Mutex<process::Child>>,
+    config: PromptConfig,
+    message_receiver: mpsc::Receiver<ReplMessage>,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+    /// Unterminated line of output, held back until it is known whether it is a prompt
+    pending: Vec<u8>,
+    /// The banner was read and the interpreter waits for a statement
+    ready: bool,
+    /// The interpreter closed its output, so nothing can be executed anymore
+    exited: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Prompt {
+    Primary,
+    Continuation,
+}
+
+/// State of the execution whose output is being read
+struct Execution<'a> {
+    /// `None` while the banner is read
+    io_sender: Option<&'a mpsc::Sender<Bytes>>,
+    sigint: &'a CancellationToken,
+    interrupted: bool,
+    failed: bool,
+}
+
+enum Event {
+    Read(usize),
+    Closed,
+    Interrupt,
+}
+
+impl PromptRepl {
+    async fn execute(
+        &mut self,
+        code: &str,
+        io_sender: &mpsc::Sender<Bytes>,
+        sigint: &CancellationToken,
+    ) -> Result<(), ReplError> {
+        if self.exited {
+            return Err(ReplError::Failed);
+        }
+
+        let mut execution = Execution {
+            io_sender: None,
+            sigint,
+            interrupted: false,
+            failed: false,
+        };
+
+        if !self.ready {
+            self.read_until_prompt(&mut execution).await?;
+            self.ready = true;
+        }
+        execution.io_sender = Some(io_sender);
+
+        let mut prompt = Prompt::Primary;
+        for line in code.lines() {
+            if execution.interrupted {
+                break;
+            }
+
+            self.write_line(line.as_bytes()).await?;
+            prompt = self.read_until_prompt(&mut execution).await?;
+        }
+
+        // Blocks of some interpreters (such as Python) are only closed by an empty line
+        if prompt == Prompt::Continuation && !execution.interrupted {
+            self.write_line(b"").await?;
+            prompt = self.read_until_prompt(&mut execution).await?;
+        }
+
+        // The code is incomplete, the statement is abandoned
+        if prompt == Prompt::Continuation && !execution.interrupted {
+            self.interrupt().await;
+            self.read_until_prompt(&mut execution).await?;
+            execution.failed = true;
+        }
+
+        if execution.interrupted {
+            Err(ReplError::Interrupted)
+        } else if execution.failed {
+            Err(ReplError::Failed)
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn write_line(&mut self, line: &[u8]) -> Result<(), ReplError> {
+        let written = async {
+            self.stdin.write_all(line).await?;
+            self.stdin.write_all(b"\n").await?;
+            self.stdin.flush().await
+        };
+
+        written.await.map_err(|_| {
+            self.exited = true;
+            ReplError::Failed
+        })
+    }
+
+    /// Forwards the output until the interpreter prints a prompt, interrupting it on the way
+    /// when asked to
+    async fn read_until_prompt(
+        &mut self,
+        execution: &mut Execution<'_>,
+    ) -> Result<Prompt, ReplError> {
+        let mut buffer = [0; READ_BUFFER_SIZE];
+
+        loop {
+            let (output, prompt) = self.take_output();
+            self.forward(output, execution).await;
+
+            if let Some(prompt) = prompt {
+                return Ok(prompt);
+            }
+
+            let event = tokio::select! {
+                read = self.stdout.read(&mut buffer) => match read {
+                    Ok(0) | Err(_) => Event::Closed,
+                    Ok(len) => Event::Read(len),
+                },
+                _ = execution.sigint.cancelled(), if !execution.interrupted => Event::Interrupt,
+            };
+
+            match event {
+                Event::Read(len) => self.pending.extend_from_slice(&buffer[..len]),
+                Event::Closed => {
+                    self.exited = true;
+                    let output = std::mem::take(&mut self.pending);
+                    self.forward(output, execution).await;
+
+                    return Err(if execution.interrupted {
+                        ReplError::Interrupted
+                    } else {
+                        ReplError::Failed
+                    });
+                }
+                Event::Interrupt => {
+                    execution.interrupted = true;
+                    self.interrupt().await;
+                }
+            }
+        }
+    }
+
+    /// Takes the complete lines of the pending output, and the last line as well when it ends
+    /// with a prompt
+    fn take_output(&mut self) -> (Vec<u8>, Option<Prompt>) {
+        let lines_end = self
+            .pending
+            .iter()
+            .rposition(|byte| *byte == b'\n')
+            .map_or(0, |position| position + 1);
+        let mut output: Vec<u8> = self.pending.drain(..lines_end).collect();
+
+        let prompts = [
+            (Some(&self.config.prompt), Prompt::Primary),
+            (self.config.continuation.as_ref(), Prompt::Continuation),
+        ];
+
+        for (regex, prompt) in prompts {
+            let Some(regex) = regex else {
+                continue;
+            };
+
+            let prompt_start = regex
+                .find_iter(&self.pending)
+                .find(|found| found.end() == self.pending.len())
+                .map(|found| found.start());
+
+            if let Some(prompt_start) = prompt_start {
+                output.extend_from_slice(&self.pending[..prompt_start]);
+                self.pending.clear();
+
+                return (output, Some(prompt));
+            }
+        }
+
+        (output, None)
+    }
+
+    async fn forward(&self, output: Vec<u8>, execution: &mut Execution<'_>) {
+        let Some(io_sender) = execution.io_sender else {
+            return;
+        };
+
+        if output.is_empty() {
+            return;
+        }
+
+        if let Some(error) = &self.config.error {
+            if output
+                .split(|byte| *byte == b'\n')
+                .any(|line| error.is_match(line))
+            {
+                execution.failed = true;
+            }
+        }
+
+        // The output of a client that went away is simply lost
+        let _ = io_sender.send(output.into()).await;
+    }
+
+    async fn interrupt(&mut self) {
+        match &self.config.interrupt {
+            InterruptKey::Signal(signal) => {
+                let pid = self.process.lock().await.id();
+                send_signal(pid, *signal);
+            }
+            InterruptKey::Input(input) => {
+                let _ = self.stdin.write_all(input).await;
+                let _ = self.stdin.flush().await;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Repl for PromptRepl {
+    type Config = PromptConfig;
+
+    fn new(
+        process: Arc<Mutex<process::Child>>,
+        config: PromptConfig,
+        message_receiver: mpsc::Receiver<ReplMessage>,
+    ) -> Self {
+        let (stdin, stdout) = {
+            let mut child = process
+                .try_lock()
+                .expect("REPL process is used before the REPL is created");
+            let stdin = child.stdin.take().expect("REPL process has no piped stdin");
+            let stdout = child
+                .stdout
+                .take()
+                .expect("REPL process has no piped stdout");
+
+            (
+                ChildStdin::from_std(stdin).expect("REPL stdin cannot be used asynchronously"),
+                ChildStdout::from_std(stdout).expect("REPL stdout cannot be used asynchronously"),
+            )
+        };
+
+        Self {
+            process,
+            config,
+            message_receiver,
+            stdin,
+            stdout,
+            pending: Vec::new(),
+            ready: false,
+            exited: false,
+        }
+    }
+
+    async fn handle_message(&mut self, message: ReplMessage) {
+        match message {
+            ReplMessage::Execute {
+                notif_sender,
+                io_sender,
+                sigint,
+                code,
+                ..
+            } => {
+                let result = self.execute(&code, &io_sender, &sigint).await;
+                let _ = notif_sender.send(result);
+            }
+        }
+    }
+
+    async fn next_message(&mut self) -> Option<ReplMessage> {
+        self.message_receiver.recv().await
+    }
+}
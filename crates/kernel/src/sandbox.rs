@@ -0,0 +1,534 @@
+//! Sandbox of interpreters running untrusted code, made of Linux namespaces and a seccomp filter
+
+use std::{
+    env,
+    ffi::CString,
+    io, mem,
+    os::unix::{ffi::OsStrExt, process::CommandExt},
+    path::{Path, PathBuf},
+    process::Command,
+    ptr,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// `mount_setattr` flag applying the attributes to every mount below the path
+const AT_RECURSIVE: libc::c_uint = 0x8000;
+
+const MOUNT_ATTR_RDONLY: u64 = 0x1;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// Syscalls of the x32 ABI, which have this bit set and are refused altogether
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// Highest capability known to the kernel, every capability up to it being dropped
+const CAP_LAST_CAP: libc::c_ulong = 63;
+
+/// Offsets in `seccomp_data`
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+/// Low half of the first argument, little endian
+const SECCOMP_DATA_ARG0: u32 = 16;
+
+/// Syscalls that change the sandbox or reach beyond it, which fail with `EPERM`
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_open_tree,
+    libc::SYS_move_mount,
+    libc::SYS_fsopen,
+    libc::SYS_fsconfig,
+    libc::SYS_fsmount,
+    libc::SYS_fspick,
+    libc::SYS_mount_setattr,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_kexec_load,
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_acct,
+    libc::SYS_quotactl,
+    libc::SYS_syslog,
+];
+
+/// Namespaces that `clone` may not create
+const NAMESPACE_FLAGS: libc::c_int = libc::CLONE_NEWUSER
+    | libc::CLONE_NEWNS
+    | libc::CLONE_NEWPID
+    | libc::CLONE_NEWNET
+    | libc::CLONE_NEWIPC
+    | libc::CLONE_NEWUTS
+    | libc::CLONE_NEWCGROUP;
+
+/// Sandbox of the interpreter of a kernelspec, which needs unprivileged user namespaces.
+///
+/// The interpreter runs in its own user, mount and pid namespaces, and in its own network
+/// namespace unless `network` is set, which it is by default for Jupyter kernels. The root filesystem is read-only, the `hidden`
+/// directories are replaced by empty ones, and the workspace is the only directory written
+/// through to the host. A seccomp filter refuses the syscalls that would leave the sandbox or
+/// reach the host kernel, like mounting or tracing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SandboxProfile {
+    /// Writable directory the interpreter starts in, by default the current directory of the
+    /// process launching the kernel
+    #[serde(default)]
+    pub workspace: Option<PathBuf>,
+    /// Directories replaced by an empty tmpfs, private to the kernel
+    #[serde(default = "default_hidden")]
+    pub hidden: Vec<PathBuf>,
+    /// Keeps the network of the host. Off by default, except for Jupyter kernels, which need it
+    /// to reach their client and refuse to turn it off.
+    #[serde(default)]
+    pub network: Option<bool>,
+}
+
+/// Everything the spawned process needs, prepared before it is forked since it may not
+/// allocate afterwards
+struct Setup {
+    namespaces: libc::c_int,
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+    workspace: CString,
+    /// Directories from the root down to the workspace, created when they are hidden
+    workspace_ancestors: Vec<CString>,
+    /// File of the host shown read-only, and the directories down to it
+    file: Option<(CString, Vec<CString>)>,
+    hidden: Vec<CString>,
+    filter: Vec<libc::sock_filter>,
+}
+
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+impl Default for SandboxProfile {
+    fn default() -> Self {
+        Self {
+            workspace: None,
+            hidden: default_hidden(),
+            network: None,
+        }
+    }
+}
+
+impl SandboxProfile {
+    pub fn workspace(mut self, workspace: impl Into<PathBuf>) -> Self {
+        self.workspace = Some(workspace.into());
+        self
+    }
+
+    pub fn hidden(mut self, hidden: Vec<PathBuf>) -> Self {
+        self.hidden = hidden;
+        self
+    }
+
+    pub fn network(mut self, network: bool) -> Self {
+        self.network = Some(network);
+        self
+    }
+
+    /// Directory written through to the host, where files shared with the interpreter go
+    pub fn workspace_dir(&self) -> io::Result<PathBuf> {
+        match &self.workspace {
+            Some(workspace) => Ok(workspace.clone()),
+            None => env::current_dir(),
+        }
+    }
+
+    /// Makes `command` spawn its process in the sandbox, the spawn failing when the sandbox
+    /// cannot be set up. `file`, which need not be in the workspace, is shown read-only at
+    /// the same path.
+    pub(crate) fn apply(&self, command: &mut Command, file: Option<&Path>) -> io::Result<()> {
+        let setup = self.setup(file)?;
+
+        // SAFETY: the closure only makes syscalls on memory prepared before the fork
+        unsafe {
+            command.pre_exec(move || setup.enter());
+        }
+
+        Ok(())
+    }
+
+    fn setup(&self, file: Option<&Path>) -> io::Result<Setup> {
+        let workspace = self.workspace_dir()?.canonicalize()?;
+        let workspace_ancestors = c_ancestors(&workspace)?;
+        let file = match file {
+            Some(file) => {
+                let file = file.canonicalize()?;
+                let ancestors = c_ancestors(file.parent().unwrap_or(&file))?;
+                Some((c_path(&file)?, ancestors))
+            }
+            None => None,
+        };
+        let hidden = self
+            .hidden
+            .iter()
+            .filter(|dir| dir.is_dir())
+            .map(|dir| c_path(dir))
+            .collect::<io::Result<_>>()?;
+
+        let mut namespaces = libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWPID;
+        if self.network != Some(true) {
+            namespaces |= libc::CLONE_NEWNET;
+        }
+
+        // SAFETY: getuid and getgid cannot fail
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+
+        Ok(Setup {
+            namespaces,
+            uid_map: format!("{uid} {uid} 1").into_bytes(),
+            gid_map: format!("{gid} {gid} 1").into_bytes(),
+            workspace: c_path(&workspace)?,
+            workspace_ancestors,
+            file,
+            hidden,
+            filter: seccomp_filter(),
+        })
+    }
+}
+
+impl Setup {
+    /// Runs in the spawned process between fork and exec
+    fn enter(&self) -> io::Result<()> {
+        // SAFETY: every call below is a plain syscall on memory owned by `self`
+        unsafe {
+            check(libc::unshare(self.namespaces))?;
+            write_file(c"/proc/self/setgroups", b"deny")?;
+            write_file(c"/proc/self/uid_map", &self.uid_map)?;
+            write_file(c"/proc/self/gid_map", &self.gid_map)?;
+
+            // The process only enters its pid namespace through a child, which becomes the
+            // interpreter while this process waits for it
+            let pid = check(libc::fork())?;
+            if pid > 0 {
+                wait_and_exit(pid);
+            }
+            // Killing the process waiting for it, as its REPL does, kills the interpreter too
+            check(libc::prctl(
+                libc::PR_SET_PDEATHSIG,
+                libc::SIGKILL as libc::c_ulong,
+                0,
+                0,
+                0,
+            ))?;
+
+            self.mount()?;
+            check(libc::chdir(self.workspace.as_ptr()))?;
+
+            for capability in 0..=CAP_LAST_CAP {
+                libc::prctl(libc::PR_CAPBSET_DROP, capability, 0, 0, 0);
+            }
+            check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+
+            let program = libc::sock_fprog {
+                len: self.filter.len() as libc::c_ushort,
+                filter: self.filter.as_ptr() as *mut libc::sock_filter,
+            };
+            check(libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &program as *const libc::sock_fprog,
+                0,
+                0,
+            ))?;
+        }
+
+        Ok(())
+    }
+
+    /// Makes the root read-only, hides directories and mounts the workspace and the file over
+    /// them
+    unsafe fn mount(&self) -> io::Result<()> {
+        check(libc::mount(
+            ptr::null(),
+            c"/".as_ptr(),
+            ptr::null(),
+            libc::MS_REC | libc::MS_PRIVATE,
+            ptr::null(),
+        ))?;
+
+        // Taken before anything is hidden or read-only
+        let workspace = check(libc::syscall(
+            libc::SYS_open_tree,
+            libc::AT_FDCWD,
+            self.workspace.as_ptr(),
+            libc::OPEN_TREE_CLONE | libc::O_CLOEXEC as libc::c_uint | AT_RECURSIVE,
+        ) as libc::c_int)?;
+        let file = match &self.file {
+            Some((path, _)) => Some(check(libc::syscall(
+                libc::SYS_open_tree,
+                libc::AT_FDCWD,
+                path.as_ptr(),
+                libc::OPEN_TREE_CLONE | libc::O_CLOEXEC as libc::c_uint,
+            ) as libc::c_int)?),
+            None => None,
+        };
+
+        let read_only = MountAttr {
+            attr_set: MOUNT_ATTR_RDONLY,
+            attr_clr: 0,
+            propagation: 0,
+            userns_fd: 0,
+        };
+        check(libc::syscall(
+            libc::SYS_mount_setattr,
+            libc::AT_FDCWD,
+            c"/".as_ptr(),
+            AT_RECURSIVE,
+            &read_only as *const MountAttr,
+            mem::size_of::<MountAttr>(),
+        ) as libc::c_int)?;
+        if let Some(file) = file {
+            check(libc::syscall(
+                libc::SYS_mount_setattr,
+                file,
+                c"".as_ptr(),
+                libc::AT_EMPTY_PATH,
+                &read_only as *const MountAttr,
+                mem::size_of::<MountAttr>(),
+            ) as libc::c_int)?;
+        }
+
+        for dir in &self.hidden {
+            check(libc::mount(
+                c"tmpfs".as_ptr(),
+                dir.as_ptr(),
+                c"tmpfs".as_ptr(),
+                libc::MS_NOSUID | libc::MS_NODEV,
+                c"mode=755".as_ptr().cast(),
+            ))?;
+        }
+
+        // Directories that already exist fail with EEXIST, or EROFS outside hidden ones
+        for dir in &self.workspace_ancestors {
+            libc::mkdir(dir.as_ptr(), 0o755);
+        }
+        check(libc::syscall(
+            libc::SYS_move_mount,
+            workspace,
+            c"".as_ptr(),
+            libc::AT_FDCWD,
+            self.workspace.as_ptr(),
+            libc::MOVE_MOUNT_F_EMPTY_PATH,
+        ) as libc::c_int)?;
+        libc::close(workspace);
+
+        if let (Some(file), Some((path, ancestors))) = (file, &self.file) {
+            // The file is mounted over one created in a hidden directory, or over itself
+            for dir in ancestors {
+                libc::mkdir(dir.as_ptr(), 0o755);
+            }
+            let target = libc::open(
+                path.as_ptr(),
+                libc::O_CREAT | libc::O_RDONLY | libc::O_CLOEXEC,
+                0o600,
+            );
+            if target != -1 {
+                libc::close(target);
+            }
+            check(libc::syscall(
+                libc::SYS_move_mount,
+                file,
+                c"".as_ptr(),
+                libc::AT_FDCWD,
+                path.as_ptr(),
+                libc::MOVE_MOUNT_F_EMPTY_PATH,
+            ) as libc::c_int)?;
+            libc::close(file);
+        }
+
+        // Shows the processes of the pid namespace only
+        check(libc::mount(
+            c"proc".as_ptr(),
+            c"/proc".as_ptr(),
+            c"proc".as_ptr(),
+            libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+            ptr::null(),
+        ))?;
+
+        Ok(())
+    }
+}
+
+fn default_hidden() -> Vec<PathBuf> {
+    [
+        "/home", "/root", "/tmp", "/var/tmp", "/run", "/mnt", "/media",
+    ]
+    .into_iter()
+    .map(PathBuf::from)
+    .collect()
+}
+
+/// Directories from the root down to `dir`, the root excluded
+fn c_ancestors(dir: &Path) -> io::Result<Vec<CString>> {
+    dir.ancestors()
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .skip(1)
+        .map(c_path)
+        .collect()
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+/// Returns the error of a syscall that returned -1
+fn check<T: Copy + PartialEq + From<i8>>(result: T) -> io::Result<T> {
+    if result == T::from(-1) {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+unsafe fn write_file(path: &std::ffi::CStr, content: &[u8]) -> io::Result<()> {
+    let fd = check(libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC))?;
+    let written = libc::write(fd, content.as_ptr().cast(), content.len());
+    libc::close(fd);
+
+    if written == content.len() as isize {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Waits for the interpreter and exits the way it did, without ever returning. Interrupts are
+/// meant for the interpreter, which shares the process group.
+unsafe fn wait_and_exit(pid: libc::pid_t) -> ! {
+    libc::signal(libc::SIGINT, libc::SIG_IGN);
+    // The pipe telling the parent whether exec failed is only closed by the interpreter
+    libc::syscall(libc::SYS_close_range, 0, libc::c_uint::MAX, 0);
+
+    let mut status = 0;
+    while libc::waitpid(pid, &mut status, 0) == -1 {
+        if *libc::__errno_location() != libc::EINTR {
+            libc::_exit(1);
+        }
+    }
+
+    if libc::WIFSIGNALED(status) {
+        let signal = libc::WTERMSIG(status);
+        libc::signal(signal, libc::SIG_DFL);
+        libc::kill(libc::getpid(), signal);
+    }
+    libc::_exit(libc::WEXITSTATUS(status));
+}
+
+/// Filter allowing every syscall of the native architecture but the denied ones, and `clone`
+/// creating namespaces. `clone3` is reported as missing, since its flags cannot be checked, so
+/// that the C library falls back to `clone`.
+fn seccomp_filter() -> Vec<libc::sock_filter> {
+    let load = |offset| bpf_stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, offset);
+    let deny = |errno: libc::c_int| {
+        bpf_stmt(
+            libc::BPF_RET | libc::BPF_K,
+            libc::SECCOMP_RET_ERRNO | errno as u32,
+        )
+    };
+    let allow = bpf_stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW);
+
+    let mut filter = vec![
+        load(SECCOMP_DATA_ARCH),
+        bpf_jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            AUDIT_ARCH,
+            1,
+            0,
+        ),
+        bpf_stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        load(SECCOMP_DATA_NR),
+    ];
+
+    #[cfg(target_arch = "x86_64")]
+    filter.extend([
+        bpf_jump(
+            libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+            X32_SYSCALL_BIT,
+            0,
+            1,
+        ),
+        deny(libc::EPERM),
+    ]);
+
+    for syscall in DENIED_SYSCALLS {
+        filter.extend([
+            bpf_jump(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                *syscall as u32,
+                0,
+                1,
+            ),
+            deny(libc::EPERM),
+        ]);
+    }
+
+    filter.extend([
+        bpf_jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            libc::SYS_clone3 as u32,
+            0,
+            1,
+        ),
+        deny(libc::ENOSYS),
+        bpf_jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            libc::SYS_clone as u32,
+            0,
+            3,
+        ),
+        load(SECCOMP_DATA_ARG0),
+        bpf_jump(
+            libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K,
+            NAMESPACE_FLAGS as u32,
+            0,
+            1,
+        ),
+        deny(libc::EPERM),
+        allow,
+    ]);
+
+    filter
+}
+
+fn bpf_stmt(code: u32, k: u32) -> libc::sock_filter {
+    bpf_jump(code, k, 0, 0)
+}
+
+fn bpf_jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
@@ -0,0 +1,416 @@
+use std::{mem, time::Duration};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::output::Output;
+
+/// How the stream output of an execution is rewritten before it leaves the kernel.
+///
+/// Every mode other than `Raw` collapses carriage-return overwrites and backspaces, so a
+/// progress bar that redraws itself a thousand times ends up as the last state of its line.
+/// A line is only forwarded once it is terminated, reaches `MAX_LINE_LENGTH`, stays unchanged
+/// for `LINE_IDLE_TIMEOUT` or the execution is done.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TerminalMode {
+    /// Bytes are forwarded exactly as the REPL wrote them
+    #[default]
+    Raw,
+    /// Colors and text attributes are kept as ANSI SGR codes
+    Keep,
+    /// Every escape sequence is removed
+    Strip,
+    /// Colors and text attributes are converted to `Output::Styled`
+    Styled,
+}
+
+/// Number of characters after which an unterminated line is forwarded, and past which the
+/// cursor cannot move
+pub const MAX_LINE_LENGTH: usize = 16 * 1024;
+
+/// Time after which a line left unterminated by the REPL, like a prompt, is forwarded as is.
+/// What the REPL writes next is forwarded after it.
+pub const LINE_IDLE_TIMEOUT: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StyledSpan {
+    pub text: String,
+    pub style: Style,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Style {
+    pub bold: bool,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub inverse: bool,
+    pub foreground: Option<Color>,
+    pub background: Option<Color>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Color {
+    /// One of the 256 colors of the xterm palette, the first 16 being the named ANSI colors
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+pub(crate) struct TerminalProcessor {
+    mode: TerminalMode,
+    /// Trailing bytes of an incomplete UTF-8 character
+    pending: Vec<u8>,
+    parser: Parser,
+    style: Style,
+    line: Vec<Cell>,
+    cursor: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Cell {
+    ch: char,
+    style: Style,
+}
+
+enum Parser {
+    Ground,
+    Escape,
+    Csi(String),
+    Osc,
+    OscEscape,
+}
+
+impl TerminalProcessor {
+    pub(crate) fn new(mode: TerminalMode) -> Self {
+        Self {
+            mode,
+            pending: Vec::new(),
+            parser: Parser::Ground,
+            style: Style::default(),
+            line: Vec::new(),
+            cursor: 0,
+        }
+    }
+
+    /// Returns the lines completed by `data`
+    pub(crate) fn process(&mut self, data: Bytes) -> Option<Output> {
+        if self.mode == TerminalMode::Raw {
+            return Some(Output::Stream(data));
+        }
+
+        let mut lines = Vec::new();
+
+        self.pending.extend_from_slice(&data);
+        let pending = mem::take(&mut self.pending);
+        let mut rest = pending.as_slice();
+
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(text) => {
+                    self.feed(text, &mut lines);
+                    break;
+                }
+                Err(err) => {
+                    let (valid, invalid) = rest.split_at(err.valid_up_to());
+                    self.feed(std::str::from_utf8(valid).unwrap_or_default(), &mut lines);
+
+                    match err.error_len() {
+                        Some(len) => {
+                            self.feed("\u{fffd}", &mut lines);
+                            rest = &invalid[len..];
+                        }
+                        None => {
+                            self.pending = invalid.to_vec();
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        self.render(lines)
+    }
+
+    /// Returns the unterminated line once the execution is done
+    pub(crate) fn flush(&mut self) -> Option<Output> {
+        if self.mode == TerminalMode::Raw {
+            return None;
+        }
+
+        if !self.pending.is_empty() {
+            self.pending.clear();
+            self.write(char::REPLACEMENT_CHARACTER);
+        }
+
+        self.parser = Parser::Ground;
+
+        self.flush_line()
+    }
+
+    /// Whether an unterminated line is waiting to be forwarded
+    pub(crate) fn has_line(&self) -> bool {
+        !self.line.is_empty()
+    }
+
+    /// Returns the unterminated line, leaving an incomplete character or escape sequence for
+    /// what comes next
+    pub(crate) fn flush_line(&mut self) -> Option<Output> {
+        self.cursor = 0;
+
+        let line = mem::take(&mut self.line);
+        if line.is_empty() {
+            None
+        } else {
+            self.render(vec![line])
+        }
+    }
+
+    fn feed(&mut self, text: &str, lines: &mut Vec<Vec<Cell>>) {
+        for ch in text.chars() {
+            self.parser = match mem::replace(&mut self.parser, Parser::Ground) {
+                Parser::Ground => match ch {
+                    '\x1b' => Parser::Escape,
+                    '\n' => {
+                        let mut line = mem::take(&mut self.line);
+                        line.push(Cell {
+                            ch,
+                            style: Style::default(),
+                        });
+                        lines.push(line);
+                        self.cursor = 0;
+                        Parser::Ground
+                    }
+                    '\r' => {
+                        self.cursor = 0;
+                        Parser::Ground
+                    }
+                    '\x08' => {
+                        self.cursor = self.cursor.saturating_sub(1);
+                        Parser::Ground
+                    }
+                    '\x07' => Parser::Ground,
+                    _ => {
+                        if self.cursor >= MAX_LINE_LENGTH {
+                            self.pad_to_cursor();
+                            lines.push(mem::take(&mut self.line));
+                            self.cursor = 0;
+                        }
+                        self.write(ch);
+                        Parser::Ground
+                    }
+                },
+                Parser::Escape => match ch {
+                    '[' => Parser::Csi(String::new()),
+                    ']' => Parser::Osc,
+                    // Other escape sequences are two characters long and are dropped
+                    _ => Parser::Ground,
+                },
+                Parser::Csi(mut params) => match ch {
+                    '\x40'..='\x7e' => {
+                        self.dispatch_csi(&params, ch);
+                        Parser::Ground
+                    }
+                    _ => {
+                        params.push(ch);
+                        Parser::Csi(params)
+                    }
+                },
+                Parser::Osc => match ch {
+                    '\x07' => Parser::Ground,
+                    '\x1b' => Parser::OscEscape,
+                    _ => Parser::Osc,
+                },
+                Parser::OscEscape => match ch {
+                    '\\' => Parser::Ground,
+                    _ => Parser::Osc,
+                },
+            };
+        }
+    }
+
+    fn write(&mut self, ch: char) {
+        let cell = Cell {
+            ch,
+            style: self.style,
+        };
+
+        if self.cursor < self.line.len() {
+            self.line[self.cursor] = cell;
+        } else {
+            self.pad_to_cursor();
+            self.line.push(cell);
+        }
+
+        self.cursor += 1;
+    }
+
+    fn pad_to_cursor(&mut self) {
+        while self.line.len() < self.cursor {
+            self.line.push(Cell {
+                ch: ' ',
+                style: Style::default(),
+            });
+        }
+    }
+
+    fn dispatch_csi(&mut self, params: &str, action: char) {
+        let mut numbers = params
+            .split([';', ':'])
+            .map(|param| param.parse::<u16>().unwrap_or(0));
+        let count = numbers.next().unwrap_or(0).max(1) as usize;
+
+        match action {
+            'm' => self.select_graphic_rendition(params),
+            // Erase in line
+            'K' => match params {
+                "" | "0" => self.line.truncate(self.cursor),
+                "1" => {
+                    self.pad_to_cursor();
+                    for cell in self.line.iter_mut().take(self.cursor + 1) {
+                        cell.ch = ' ';
+                    }
+                }
+                _ => self.line.clear(),
+            },
+            // Cursor back, forward and horizontal absolute
+            'D' => self.cursor = self.cursor.saturating_sub(count),
+            'C' => self.cursor = (self.cursor + count).min(MAX_LINE_LENGTH),
+            'G' => self.cursor = (count - 1).min(MAX_LINE_LENGTH),
+            // Other sequences (cursor up/down, scrolling, ...) cannot be applied to a stream
+            _ => {}
+        }
+    }
+
+    fn select_graphic_rendition(&mut self, params: &str) {
+        let mut codes = params
+            .split([';', ':'])
+            .map(|param| param.parse::<u8>().unwrap_or(0));
+
+        if params.is_empty() {
+            self.style = Style::default();
+            return;
+        }
+
+        while let Some(code) = codes.next() {
+            let style = &mut self.style;
+
+            match code {
+                0 => *style = Style::default(),
+                1 => style.bold = true,
+                2 => style.dim = true,
+                3 => style.italic = true,
+                4 => style.underline = true,
+                7 => style.inverse = true,
+                22 => {
+                    style.bold = false;
+                    style.dim = false;
+                }
+                23 => style.italic = false,
+                24 => style.underline = false,
+                27 => style.inverse = false,
+                30..=37 => style.foreground = Some(Color::Indexed(code - 30)),
+                38 => style.foreground = extended_color(&mut codes),
+                39 => style.foreground = None,
+                40..=47 => style.background = Some(Color::Indexed(code - 40)),
+                48 => style.background = extended_color(&mut codes),
+                49 => style.background = None,
+                90..=97 => style.foreground = Some(Color::Indexed(code - 90 + 8)),
+                100..=107 => style.background = Some(Color::Indexed(code - 100 + 8)),
+                _ => {}
+            }
+        }
+    }
+
+    fn render(&self, lines: Vec<Vec<Cell>>) -> Option<Output> {
+        if lines.is_empty() {
+            return None;
+        }
+
+        let cells = lines.into_iter().flatten();
+
+        let output = match self.mode {
+            TerminalMode::Raw => unreachable!("raw output is never processed"),
+            TerminalMode::Strip => {
+                Output::Stream(cells.map(|cell| cell.ch).collect::<String>().into())
+            }
+            TerminalMode::Keep => {
+                let mut text = String::new();
+                let mut current = Style::default();
+
+                for cell in cells {
+                    if cell.style != current {
+                        text.push_str(&sgr(&cell.style));
+                        current = cell.style;
+                    }
+                    text.push(cell.ch);
+                }
+
+                if current != Style::default() {
+                    text.push_str("\x1b[0m");
+                }
+
+                Output::Stream(text.into())
+            }
+            TerminalMode::Styled => {
+                let mut spans: Vec<StyledSpan> = Vec::new();
+
+                for cell in cells {
+                    match spans.last_mut() {
+                        Some(span) if span.style == cell.style || cell.ch == '\n' => {
+                            span.text.push(cell.ch)
+                        }
+                        _ => spans.push(StyledSpan {
+                            text: cell.ch.to_string(),
+                            style: cell.style,
+                        }),
+                    }
+                }
+
+                Output::Styled(spans)
+            }
+        };
+
+        Some(output)
+    }
+}
+
+fn extended_color(codes: &mut impl Iterator<Item = u8>) -> Option<Color> {
+    match codes.next()? {
+        5 => Some(Color::Indexed(codes.next()?)),
+        2 => Some(Color::Rgb(codes.next()?, codes.next()?, codes.next()?)),
+        _ => None,
+    }
+}
+
+/// Renders a style from scratch, starting with a reset
+fn sgr(style: &Style) -> String {
+    let mut codes = vec!["0".to_string()];
+
+    for (enabled, code) in [
+        (style.bold, "1"),
+        (style.dim, "2"),
+        (style.italic, "3"),
+        (style.underline, "4"),
+        (style.inverse, "7"),
+    ] {
+        if enabled {
+            codes.push(code.to_string());
+        }
+    }
+
+    for (color, base) in [(style.foreground, 38u16), (style.background, 48)] {
+        match color {
+            Some(Color::Indexed(index)) if index < 8 => {
+                codes.push((base - 8 + u16::from(index)).to_string())
+            }
+            Some(Color::Indexed(index)) if index < 16 => {
+                codes.push((base + 52 + u16::from(index) - 8).to_string())
+            }
+            Some(Color::Indexed(index)) => codes.push(format!("{base};5;{index}")),
+            Some(Color::Rgb(r, g, b)) => codes.push(format!("{base};2;{r};{g};{b}")),
+            None => {}
+        }
+    }
+
+    format!("\x1b[{}m", codes.join(";"))
+}
@@ -0,0 +1,241 @@
+//! Fake REPL and helpers for testing code built on the kernel, enabled by the `test-util` feature
+
+use std::{
+    process,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::{
+    sync::{mpsc, Mutex},
+    time::sleep,
+};
+
+use crate::{
+    limits::ResourceLimit,
+    output::Output,
+    repl::{self, Repl, ReplError, ReplHandle, ReplMessage},
+};
+
+/// Tells a `FakeRepl` how to answer the code it receives.
+///
+/// Code is matched against the rules in the order they were added, and code matching no rule
+/// is echoed back as output. Clones share the record of received code.
+#[derive(Debug, Clone, Default)]
+pub struct FakeScript {
+    rules: Vec<(String, FakeResponse)>,
+    latency: Duration,
+    calls: Arc<StdMutex<Vec<String>>>,
+}
+
+/// Outputs and outcome of one execution of a `FakeRepl`
+#[derive(Debug, Clone, Default)]
+pub struct FakeResponse {
+    steps: Vec<FakeStep>,
+    outcome: FakeOutcome,
+}
+
+#[derive(Debug, Clone)]
+enum FakeStep {
+    Output(Bytes),
+    Sleep(Duration),
+}
+
+#[derive(Debug, Clone, Default)]
+enum FakeOutcome {
+    #[default]
+    Succeed,
+    Fail,
+    Exceed(ResourceLimit),
+    Hang,
+}
+
+impl FakeScript {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers code containing `pattern` with `response`
+    pub fn on(mut self, pattern: impl Into<String>, response: FakeResponse) -> Self {
+        self.rules.push((pattern.into(), response));
+        self
+    }
+
+    /// Delays every execution, as the inter-process communication with a real REPL does
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Code of every execution received so far, oldest first
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().expect("Fake calls are poisoned").clone()
+    }
+
+    fn response_to(&self, code: &str) -> FakeResponse {
+        self.rules
+            .iter()
+            .find(|(pattern, _)| code.contains(pattern.as_str()))
+            .map(|(_, response)| response.clone())
+            .unwrap_or_else(|| FakeResponse::new().output(code.to_string()))
+    }
+}
+
+impl FakeResponse {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn output(mut self, data: impl Into<Bytes>) -> Self {
+        self.steps.push(FakeStep::Output(data.into()));
+        self
+    }
+
+    /// Waits before the next output, like a long computation does
+    pub fn sleep(mut self, duration: Duration) -> Self {
+        self.steps.push(FakeStep::Sleep(duration));
+        self
+    }
+
+    /// Ends the execution with `ReplError::Failed` after the outputs
+    pub fn fail(mut self) -> Self {
+        self.outcome = FakeOutcome::Fail;
+        self
+    }
+
+    /// Ends the execution with `ReplError::ResourceLimit` after the outputs, as if the
+    /// interpreter was killed for exceeding `limit`
+    pub fn exceed(mut self, limit: ResourceLimit) -> Self {
+        self.outcome = FakeOutcome::Exceed(limit);
+        self
+    }
+
+    /// Never ends the execution after the outputs, only an interrupt does
+    pub fn hang(mut self) -> Self {
+        self.outcome = FakeOutcome::Hang;
+        self
+    }
+}
+
+/// REPL answering from a `FakeScript` instead of running code. An interrupt stops the
+/// response where it is and ends the execution with `ReplError::Interrupted`.
+pub struct FakeRepl {
+    script: FakeScript,
+    message_receiver: mpsc::Receiver<ReplMessage>,
+}
+
+impl FakeRepl {
+    pub fn with_script(script: FakeScript, message_receiver: mpsc::Receiver<ReplMessage>) -> Self {
+        Self {
+            script,
+            message_receiver,
+        }
+    }
+
+    async fn respond(&self, code: String, io_sender: mpsc::Sender<Bytes>) -> Result<(), ReplError> {
+        // Even a zero sleep waits for the next tick of the timer
+        if !self.script.latency.is_zero() {
+            sleep(self.script.latency).await;
+        }
+
+        let response = self.script.response_to(&code);
+        self.script
+            .calls
+            .lock()
+            .expect("Fake calls are poisoned")
+            .push(code);
+
+        for step in response.steps {
+            match step {
+                // The output of a client that went away is simply lost
+                FakeStep::Output(data) => {
+                    let _ = io_sender.send(data).await;
+                }
+                FakeStep::Sleep(duration) => sleep(duration).await,
+            }
+        }
+
+        match response.outcome {
+            FakeOutcome::Succeed => Ok(()),
+            FakeOutcome::Fail => Err(ReplError::Failed),
+            FakeOutcome::Exceed(limit) => Err(ReplError::ResourceLimit(limit)),
+            FakeOutcome::Hang => std::future::pending().await,
+        }
+    }
+}
+
+#[async_trait]
+impl Repl for FakeRepl {
+    type Config = FakeScript;
+
+    /// The process is not used
+    fn new(
+        _process: Arc<Mutex<process::Child>>,
+        script: FakeScript,
+        message_receiver: mpsc::Receiver<ReplMessage>,
+    ) -> Self {
+        Self::with_script(script, message_receiver)
+    }
+
+    async fn handle_message(&mut self, message: ReplMessage) {
+        match message {
+            ReplMessage::Execute {
+                notif_sender,
+                io_sender,
+                sigint,
+                code,
+                ..
+            } => {
+                let result = tokio::select! {
+                    result = self.respond(code, io_sender) => result,
+                    _ = sigint.cancelled() => Err(ReplError::Interrupted),
+                };
+
+                let _ = notif_sender.send(result);
+            }
+        }
+    }
+
+    async fn next_message(&mut self) -> Option<ReplMessage> {
+        self.message_receiver.recv().await
+    }
+}
+
+/// Runs a `FakeRepl` following `script`
+pub fn launch_fake_repl(script: FakeScript) -> ReplHandle {
+    repl::spawn(|message_receiver| FakeRepl::with_script(script, message_receiver))
+}
+
+/// Collects what a REPL writes until it drops its sender
+pub async fn take_all_output(mut source: mpsc::Receiver<Bytes>) -> BytesMut {
+    let mut buffer = BytesMut::new();
+    while let Some(data) = source.recv().await {
+        buffer.put(data);
+    }
+
+    buffer
+}
+
+/// Collects the stream outputs sent to a client until the kernel drops its sender
+pub async fn take_all_stream(mut source: mpsc::Receiver<Output>) -> BytesMut {
+    let mut buffer = BytesMut::new();
+    while let Some(output) = source.recv().await {
+        if let Output::Stream(data) = output {
+            buffer.put(data);
+        }
+    }
+
+    buffer
+}
+
+/// Collects every output sent to a client until the kernel drops its sender
+pub async fn take_all_outputs(mut source: mpsc::Receiver<Output>) -> Vec<Output> {
+    let mut outputs = Vec::new();
+    while let Some(output) = source.recv().await {
+        outputs.push(output);
+    }
+
+    outputs
+}
@@ -0,0 +1,217 @@
+mod utils;
+
+use std::{sync::Arc, time::Duration};
+
+use canal_kernel::{
+    kernel::{self, KernelTerminal},
+    repl::{
+        self,
+        bash::{self, BashRepl},
+        ReplError, ReplHandle,
+    },
+    test_util::{take_all_output, take_all_stream},
+    KernelRequest, KernelResponse,
+};
+use googletest::prelude::*;
+use tokio::{
+    sync::{mpsc, Mutex},
+    task,
+    time::sleep,
+};
+use tokio_util::sync::CancellationToken;
+use utils::create_request_exec;
+
+#[googletest::test]
+#[tokio::test]
+async fn bash_repl_keeps_its_state_between_executions() {
+    let handle = launch_bash();
+
+    let (result, _, _) = execute(
+        &handle,
+        "export GREETING=hello\ncd /tmp\nshout() { echo \"$1!\"; }",
+    )
+    .await;
+    expect_that!(result, ok(anything()));
+
+    let (result, stdout, _) = execute(&handle, "shout \"$GREETING\"\npwd").await;
+    expect_that!(result, ok(anything()));
+    expect_that!(stdout, eq("hello!\n/tmp\n"));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn bash_repl_splits_stdout_and_stderr() {
+    let handle = launch_bash();
+
+    let (result, stdout, stderr) = execute(&handle, "echo out; echo err >&2; printf end").await;
+
+    expect_that!(result, ok(anything()));
+    expect_that!(stdout, eq("out\nend"));
+    expect_that!(stderr, eq("err\n"));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn bash_repl_mixes_stderr_with_the_output_when_not_asked_to_split_them() {
+    let handle = launch_bash();
+    let (io_sender, io_receiver) = mpsc::channel(8);
+
+    let (result, output) = tokio::join!(
+        handle.execute(
+            "echo err >&2".to_string(),
+            io_sender,
+            CancellationToken::new()
+        ),
+        take_all_output(io_receiver),
+    );
+
+    expect_that!(result, ok(anything()));
+    expect_that!(output, is_utf8_string(eq("err\n")));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn bash_repl_reports_the_exit_status_of_the_last_command() {
+    let handle = launch_bash();
+
+    let (result, _, _) = execute(&handle, "false").await;
+    expect_that!(result, pat!(Err(pat!(ReplError::Failed))));
+
+    let (result, _, _) = execute(&handle, "false; true").await;
+    expect_that!(result, ok(anything()));
+
+    let (result, _, stderr) = execute(&handle, "if then").await;
+    expect_that!(result, pat!(Err(pat!(ReplError::Failed))));
+    expect_that!(stderr, contains_substring("syntax error"));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn bash_repl_interrupts_the_running_command() {
+    let handle = Arc::new(launch_bash());
+
+    let (result, stdout) =
+        interrupt_after(&handle, "X=kept; echo before; sleep 5; echo never").await;
+    expect_that!(result, pat!(Err(pat!(ReplError::Interrupted))));
+    expect_that!(stdout, eq("before\n"));
+
+    let (result, stdout, _) = execute(&handle, "echo $X").await;
+    expect_that!(result, ok(anything()));
+    expect_that!(stdout, eq("kept\n"));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn bash_repl_interrupts_loops_of_builtins() {
+    let handle = Arc::new(launch_bash());
+
+    let (result, stdout) =
+        interrupt_after(&handle, "spin() { while :; do :; done; }; spin; echo never").await;
+    expect_that!(result, pat!(Err(pat!(ReplError::Interrupted))));
+    expect_that!(stdout, eq(""));
+
+    let (result, stdout, _) = execute(&handle, "echo after").await;
+    expect_that!(result, ok(anything()));
+    expect_that!(stdout, eq("after\n"));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn bash_repl_fails_once_bash_exited() {
+    let handle = launch_bash();
+
+    let (result, _, _) = execute(&handle, "exit 0").await;
+    expect_that!(result, pat!(Err(pat!(ReplError::Failed))));
+
+    let (result, _, _) = execute(&handle, "echo after").await;
+    expect_that!(result, pat!(Err(pat!(ReplError::Failed))));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_runs_and_interrupts_bash() {
+    let (mut terminal, _queue) = kernel::launch(launch_bash(), 10);
+    let (request1, io_receiver1) = create_request_exec(1, "echo start; sleep 5");
+    let (request2, io_receiver2) = create_request_exec(2, "echo done");
+
+    terminal.send(request1).await;
+    sleep(Duration::from_millis(200)).await;
+    terminal.send(KernelRequest::Interrupt).await;
+    terminal.send(request2).await;
+
+    expect_that!(
+        recv(&mut terminal).await,
+        pat!(KernelResponse::Cancelled(eq(1)))
+    );
+    expect_that!(
+        recv(&mut terminal).await,
+        pat!(KernelResponse::Success(eq(2)))
+    );
+    expect_that!(
+        take_all_stream(io_receiver1).await,
+        is_utf8_string(eq("start\n"))
+    );
+    expect_that!(
+        take_all_stream(io_receiver2).await,
+        is_utf8_string(eq("done\n"))
+    );
+}
+
+async fn execute(
+    handle: &ReplHandle,
+    code: &str,
+) -> (std::result::Result<(), ReplError>, String, String) {
+    let (io_sender, io_receiver) = mpsc::channel(8);
+    let (stderr_sender, stderr_receiver) = mpsc::channel(8);
+
+    let (result, stdout, stderr) = tokio::join!(
+        handle.execute_with_stderr(
+            code.to_string(),
+            io_sender,
+            Some(stderr_sender),
+            CancellationToken::new()
+        ),
+        take_all_output(io_receiver),
+        take_all_output(stderr_receiver),
+    );
+
+    (
+        result,
+        String::from_utf8_lossy(&stdout).into_owned(),
+        String::from_utf8_lossy(&stderr).into_owned(),
+    )
+}
+
+/// Executes `code` and interrupts it once it had time to start
+async fn interrupt_after(
+    handle: &Arc<ReplHandle>,
+    code: &str,
+) -> (std::result::Result<(), ReplError>, String) {
+    let (io_sender, io_receiver) = mpsc::channel(8);
+    let sigint = CancellationToken::new();
+
+    let job = {
+        let handle = handle.clone();
+        let code = code.to_string();
+        let sigint = sigint.clone();
+        task::spawn(async move { handle.execute(code, io_sender, sigint).await })
+    };
+
+    sleep(Duration::from_millis(200)).await;
+    sigint.cancel();
+
+    let result = job.await.unwrap();
+    let output = take_all_output(io_receiver).await;
+
+    (result, String::from_utf8_lossy(&output).into_owned())
+}
+
+async fn recv(terminal: &mut KernelTerminal) -> KernelResponse {
+    terminal.recv().await.unwrap()
+}
+
+fn launch_bash() -> ReplHandle {
+    let process = bash::command().spawn().unwrap();
+
+    repl::launch::<BashRepl>(Arc::new(Mutex::new(process)))
+}
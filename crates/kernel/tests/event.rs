@@ -0,0 +1,221 @@
+mod utils;
+
+use canal_kernel::{
+    event::{EventContent, EventSubscription, ExecStatus, KernelEvent},
+    kernel::{self, KernelTerminal},
+    output::Output,
+    test_util::take_all_stream,
+    ClientId, KernelRequest, MessageId,
+};
+use googletest::prelude::*;
+use tokio::sync::mpsc;
+use utils::{launch_mock_repl, ExecRequest};
+
+#[googletest::test]
+#[tokio::test]
+async fn every_subscriber_receives_the_status_and_output_of_an_execution() {
+    let mut terminal = launch_terminal(10);
+    let mut subscriber1 = terminal.subscribe();
+    let mut subscriber2 = terminal.subscribe();
+    let (request, io_receiver) = ExecRequest::new(1, "1").client_id(7).build();
+
+    terminal.send(request).await;
+    terminal.recv().await.unwrap();
+
+    let events1 = take_events(&mut subscriber1, 4).await;
+    let events2 = take_events(&mut subscriber2, 4).await;
+
+    expect_that!(
+        events1,
+        elements_are![
+            event(1, 7, EventContent::Status(ExecStatus::Queued)),
+            event(1, 7, EventContent::Status(ExecStatus::Running)),
+            event(1, 7, EventContent::Output(Output::Stream("1".into()))),
+            event(1, 7, EventContent::Status(ExecStatus::Succeeded)),
+        ]
+    );
+    expect_that!(events2, eq(events1.clone()));
+    expect_that!(take_all_stream(io_receiver).await, is_utf8_string(eq("1")));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn subscriber_receives_executions_of_all_clients() {
+    let mut terminal = launch_terminal(10);
+    let mut subscriber = terminal.subscribe();
+    let (request1, io_receiver1) = ExecRequest::new(10, "a").client_id(1).build();
+    let (request2, io_receiver2) = ExecRequest::new(20, "b").client_id(2).build();
+
+    terminal.send(request1).await;
+    terminal.send(request2).await;
+    terminal.recv().await.unwrap();
+    terminal.recv().await.unwrap();
+
+    let outputs: Vec<_> = take_events(&mut subscriber, 8)
+        .await
+        .into_iter()
+        .filter(|event| matches!(event.content, EventContent::Output(_)))
+        .collect();
+
+    expect_that!(
+        outputs,
+        elements_are![
+            event(10, 1, EventContent::Output(Output::Stream("a".into()))),
+            event(20, 2, EventContent::Output(Output::Stream("b".into()))),
+        ]
+    );
+
+    // Each submitter still receives only its own output
+    expect_that!(take_all_stream(io_receiver1).await, is_utf8_string(eq("a")));
+    expect_that!(take_all_stream(io_receiver2).await, is_utf8_string(eq("b")));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn subscriber_is_notified_of_dropped_executions() {
+    let mut terminal = launch_terminal(10);
+    let mut subscriber = terminal.subscribe();
+    let (request1, _io_receiver1) = ExecRequest::new(99, "buggy").client_id(1).build();
+    let (request2, _io_receiver2) = ExecRequest::new(2, "2").client_id(2).build();
+
+    terminal.send(request1).await;
+    terminal.send(request2).await;
+    terminal.recv().await.unwrap();
+    terminal.recv().await.unwrap();
+
+    let statuses_of = |events: &[KernelEvent], parent| -> Vec<KernelEvent> {
+        events
+            .iter()
+            .filter(|event| event.parent == Some(parent))
+            .filter(|event| matches!(event.content, EventContent::Status(_)))
+            .cloned()
+            .collect()
+    };
+    let events = take_events(&mut subscriber, 6).await;
+
+    expect_that!(
+        statuses_of(&events, 99),
+        elements_are![
+            event(99, 1, EventContent::Status(ExecStatus::Queued)),
+            event(99, 1, EventContent::Status(ExecStatus::Running)),
+            event(99, 1, EventContent::Status(ExecStatus::Failed)),
+        ]
+    );
+    expect_that!(
+        statuses_of(&events, 2),
+        elements_are![
+            event(2, 2, EventContent::Status(ExecStatus::Queued)),
+            event(2, 2, EventContent::Status(ExecStatus::Cancelled)),
+        ]
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn events_are_numbered_in_publication_order() {
+    let mut terminal = launch_terminal(10);
+    let mut subscriber = terminal.subscribe();
+    let (request1, _io_receiver1) = ExecRequest::new(1, "1").client_id(1).build();
+    let (request2, _io_receiver2) = ExecRequest::new(2, "2").client_id(1).build();
+
+    terminal.send(request1).await;
+    terminal.send(request2).await;
+    terminal.recv().await.unwrap();
+    terminal.recv().await.unwrap();
+
+    let seqs: Vec<_> = take_events(&mut subscriber, 8)
+        .await
+        .into_iter()
+        .map(|event| event.seq)
+        .collect();
+
+    expect_that!(seqs, eq((0..8).map(Some).collect::<Vec<_>>()));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn resumed_subscriber_receives_missed_events_then_live_events() {
+    let mut terminal = launch_terminal(10);
+    let (request1, _io_receiver1) = ExecRequest::new(1, "before").client_id(1).build();
+
+    terminal.send(request1).await;
+    terminal.recv().await.unwrap();
+
+    // The client saw the first two events (queued and running) before disconnecting
+    let mut subscriber = terminal.resume(2);
+    let (request2, _io_receiver2) = ExecRequest::new(2, "after").client_id(1).build();
+
+    terminal.send(request2).await;
+    terminal.recv().await.unwrap();
+
+    let events = take_events(&mut subscriber, 6).await;
+
+    expect_that!(
+        events.iter().map(|event| event.seq).collect::<Vec<_>>(),
+        eq((2..8).map(Some).collect::<Vec<_>>())
+    );
+    expect_that!(
+        events,
+        elements_are![
+            event(1, 1, EventContent::Output(Output::Stream("before".into()))),
+            event(1, 1, EventContent::Status(ExecStatus::Succeeded)),
+            event(2, 1, EventContent::Status(ExecStatus::Queued)),
+            event(2, 1, EventContent::Status(ExecStatus::Running)),
+            event(2, 1, EventContent::Output(Output::Stream("after".into()))),
+            event(2, 1, EventContent::Status(ExecStatus::Succeeded)),
+        ]
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_forwards_resumed_events_to_the_subscribing_client() {
+    let mut terminal = launch_terminal(10);
+    let (request, _io_receiver) = ExecRequest::new(1, "1").client_id(1).build();
+
+    terminal.send(request).await;
+    terminal.recv().await.unwrap();
+
+    let (event_sender, mut event_receiver) = mpsc::channel(8);
+    terminal
+        .send(KernelRequest::Subscribe {
+            resume_from: Some(3),
+            event_sender,
+        })
+        .await;
+
+    expect_that!(
+        event_receiver.recv().await,
+        some(all!(
+            field!(KernelEvent.seq, eq(Some(3))),
+            event(1, 1, EventContent::Status(ExecStatus::Succeeded))
+        ))
+    );
+}
+
+fn event(
+    parent: MessageId,
+    client_id: ClientId,
+    content: EventContent,
+) -> impl Matcher<ActualT = KernelEvent> {
+    all!(
+        field!(KernelEvent.parent, eq(Some(parent))),
+        field!(KernelEvent.client_id, eq(Some(client_id))),
+        field!(KernelEvent.content, eq(content)),
+    )
+}
+
+async fn take_events(subscriber: &mut EventSubscription, count: usize) -> Vec<KernelEvent> {
+    let mut events = Vec::new();
+    for _ in 0..count {
+        events.push(subscriber.recv().await.unwrap());
+    }
+
+    events
+}
+
+fn launch_terminal(capacity: usize) -> KernelTerminal {
+    let (terminal, _queue) = kernel::launch(launch_mock_repl(), capacity);
+
+    terminal
+}
@@ -0,0 +1,249 @@
+mod utils;
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use canal_kernel::{
+    event::ExecStatus,
+    journal::{self, Journal, JournalConfig, JournalError, JournalRecord, TranscriptCell},
+    kernel::{self, KernelOptions, KernelTerminal},
+    output::OutputSummary,
+    queue::Lane,
+    KernelResponse,
+};
+use googletest::prelude::*;
+use utils::{launch_mock_repl, ExecRequest};
+
+// SHA-256 of "1"
+const DIGEST_OF_1: &str = "6b86b273ff34fce19d6b804eff5a3f5747ada4eaa22f1d49c01e52ddb7875b4b";
+
+#[googletest::test]
+#[tokio::test]
+async fn journal_records_requests_responses_and_output_digests() {
+    let dir = journal_dir("records");
+    let journal = open_journal(&dir, 1024 * 1024).await;
+    let mut terminal = launch_terminal(journal.clone());
+
+    let (request1, _io_receiver1) = ExecRequest::new(1, "1").client_id(5).build();
+    let (request2, _io_receiver2) = ExecRequest::new(2, "buggy").client_id(5).build();
+    let (request3, _io_receiver3) = ExecRequest::new(3, "3").client_id(5).build();
+
+    terminal.send(request1).await;
+    terminal.recv().await.unwrap();
+    terminal.send(request2).await;
+    terminal.send(request3).await;
+    terminal.recv().await.unwrap();
+    terminal.recv().await.unwrap();
+    journal.sync().await;
+
+    let records: Vec<_> = journal::read_entries(&dir)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.record)
+        .collect();
+
+    expect_that!(
+        records,
+        contains_each![
+            eq(JournalRecord::SessionStarted),
+            eq(JournalRecord::Execute {
+                message_id: 1,
+                client_id: 5,
+                code: "1".to_string(),
+                lane: Lane::Interactive,
+                language: None,
+                independent: false,
+            }),
+            pat!(JournalRecord::Output {
+                message_id: eq(1),
+                client_id: eq(5),
+                summary: field!(OutputSummary.sha256, eq(DIGEST_OF_1)),
+            }),
+            eq(JournalRecord::Response {
+                client_id: Some(5),
+                response: KernelResponse::Success(1),
+            }),
+            eq(JournalRecord::Response {
+                client_id: Some(5),
+                response: KernelResponse::Failed(2),
+            }),
+            eq(JournalRecord::Response {
+                client_id: Some(5),
+                response: KernelResponse::Cancelled(3),
+            }),
+        ]
+    );
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn journal_rebuilds_the_transcript_of_a_session() {
+    let dir = journal_dir("transcript");
+    let journal = open_journal(&dir, 1024 * 1024).await;
+    let mut terminal = launch_terminal(journal.clone());
+
+    let (request1, _io_receiver1) = ExecRequest::new(1, "1").client_id(5).build();
+    let (request2, _io_receiver2) = ExecRequest::new(2, "buggy").client_id(5).build();
+
+    terminal.send(request1).await;
+    terminal.send(request2).await;
+    terminal.recv().await.unwrap();
+    terminal.recv().await.unwrap();
+    journal.sync().await;
+
+    let transcripts = journal::read_transcripts(&dir).unwrap();
+
+    assert_that!(transcripts, len(eq(1)));
+    expect_that!(
+        transcripts[0].cells,
+        elements_are![
+            pat!(TranscriptCell {
+                message_id: eq(1),
+                client_id: eq(5),
+                code: eq("1"),
+                status: eq(ExecStatus::Succeeded),
+                finished_at: some(anything()),
+                output: some(field!(OutputSummary.sha256, eq(DIGEST_OF_1))),
+            }),
+            pat!(TranscriptCell {
+                message_id: eq(2),
+                code: eq("buggy"),
+                status: eq(ExecStatus::Failed),
+                output: some(field!(OutputSummary.bytes, eq(5))),
+            }),
+        ]
+    );
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn journal_rotates_files_and_reads_them_back_in_order() {
+    let dir = journal_dir("rotation");
+    let journal = open_journal(&dir, 128).await;
+    let mut terminal = launch_terminal(journal.clone());
+
+    for message_id in 0..10 {
+        let (request, _io_receiver) = ExecRequest::new(message_id, "1").client_id(5).build();
+        terminal.send(request).await;
+        terminal.recv().await.unwrap();
+    }
+    journal.sync().await;
+
+    let number_of_files = fs::read_dir(&dir).unwrap().count();
+    let transcripts = journal::read_transcripts(&dir).unwrap();
+    let message_ids: Vec<_> = transcripts[0]
+        .cells
+        .iter()
+        .map(|cell| cell.message_id)
+        .collect();
+
+    expect_that!(number_of_files, gt(1));
+    expect_that!(message_ids, eq((0..10).collect::<Vec<_>>()));
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn journal_ignores_an_entry_cut_short_at_the_end() {
+    let dir = journal_dir("truncated");
+    let journal = open_journal(&dir, 1024 * 1024).await;
+    let mut terminal = launch_terminal(journal.clone());
+
+    let (request, _io_receiver) = ExecRequest::new(1, "1").client_id(5).build();
+    terminal.send(request).await;
+    terminal.recv().await.unwrap();
+    journal.sync().await;
+
+    let entries = journal::read_entries(&dir).unwrap();
+    let path = fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+    let data = fs::read(&path).unwrap();
+    fs::write(&path, &data[..data.len() - 3]).unwrap();
+
+    expect_that!(
+        journal::read_entries(&dir),
+        ok(eq(entries[..entries.len() - 1].to_vec()))
+    );
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn journal_refuses_a_corrupted_last_file() {
+    let dir = journal_dir("corrupted");
+    let journal = open_journal(&dir, 1024 * 1024).await;
+    journal.sync().await;
+
+    let path = fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+    let mut data = fs::read(&path).unwrap();
+    // A marker MessagePack never uses
+    data.push(0xc1);
+    fs::write(&path, &data).unwrap();
+
+    expect_that!(
+        journal::read_entries(&dir),
+        err(pat!(JournalError::Corrupted(eq(path.clone()), anything())))
+    );
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn replay_tool_prints_the_transcript() {
+    let dir = journal_dir("replay");
+    let journal = open_journal(&dir, 1024 * 1024).await;
+    let mut terminal = launch_terminal(journal.clone());
+
+    let (request, _io_receiver) = ExecRequest::new(1, "print('hello')").client_id(5).build();
+    terminal.send(request).await;
+    terminal.recv().await.unwrap();
+    journal.sync().await;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_replay_journal"))
+        .arg(&dir)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    expect_that!(output.status.success(), eq(true));
+    expect_that!(stdout, contains_substring("message 1 from client 5"));
+    expect_that!(stdout, contains_substring("    print('hello')"));
+    expect_that!(stdout, contains_substring("Succeeded"));
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+fn journal_dir(name: &str) -> PathBuf {
+    env::temp_dir().join(format!(
+        "canal-kernel-journal-{name}-{}",
+        std::process::id()
+    ))
+}
+
+async fn open_journal(dir: &Path, max_file_size: u64) -> Journal {
+    let _ = fs::remove_dir_all(dir);
+
+    Journal::open(JournalConfig {
+        dir: dir.to_path_buf(),
+        max_file_size,
+    })
+    .await
+    .unwrap()
+}
+
+fn launch_terminal(journal: Journal) -> KernelTerminal {
+    let options = KernelOptions::new(10).journal(journal);
+
+    let (terminal, _queue) = kernel::launch_with(launch_mock_repl(), options);
+
+    terminal
+}
@@ -0,0 +1,297 @@
+mod utils;
+
+use std::{
+    env, fs, os::unix::fs::PermissionsExt, path::PathBuf, process::Command, sync::Arc,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use canal_kernel::{
+    jupyter::{ConnectionInfo, JupyterError},
+    kernel::KernelOptions,
+    kernelspec::KernelRegistry,
+    repl::{
+        self,
+        jupyter::{InterruptMode, JupyterConfig, JupyterRepl},
+        ReplError, ReplHandle,
+    },
+    test_util::{take_all_output, take_all_stream},
+    KernelRequest, KernelResponse,
+};
+use googletest::prelude::*;
+use serde_json::json;
+use tokio::{
+    sync::{mpsc, Mutex},
+    task,
+    time::sleep,
+};
+use tokio_util::sync::CancellationToken;
+use utils::create_request_exec;
+use zeromq::ZmqMessage;
+
+#[googletest::test]
+fn jupyter_messages_are_signed_and_verified() {
+    let connection = ConnectionInfo::allocate("127.0.0.1").unwrap();
+    let client = connection.session("client").unwrap();
+    let kernel = connection.session("kernel").unwrap();
+
+    let request = client.message("execute_request", json!({"code": "1 + 1"}));
+    let received = kernel.decode(client.encode(&request)).unwrap();
+    expect_that!(received, eq(request.clone()));
+
+    let reply = kernel.reply(&received, "execute_reply", json!({"status": "ok"}));
+    expect_that!(reply.parent_msg_id(), some(eq(&request.header.msg_id)));
+    expect_that!(reply.header.session, eq(kernel.id.clone()));
+    expect_that!(reply.header.version, eq("5.3"));
+
+    // A message changed on the way is refused
+    let mut frames = client.encode(&request).into_vec();
+    let content = frames.len() - 1;
+    frames[content] = Bytes::from_static(br#"{"code": "rm -rf /"}"#);
+    expect_that!(
+        kernel.decode(ZmqMessage::try_from(frames).unwrap()),
+        err(pat!(JupyterError::InvalidSignature))
+    );
+}
+
+#[googletest::test]
+fn jupyter_messages_are_not_signed_without_key() {
+    let connection = ConnectionInfo {
+        key: String::new(),
+        ..ConnectionInfo::allocate("127.0.0.1").unwrap()
+    };
+    let session = connection.session("client").unwrap();
+
+    let encoded = session.encode(&session.message("kernel_info_request", json!({})));
+
+    expect_that!(encoded.get(1), some(eq(&Bytes::new())));
+    expect_that!(session.decode(encoded), ok(anything()));
+}
+
+#[googletest::test]
+fn connection_files_are_only_readable_by_their_owner() {
+    let connection = ConnectionInfo::allocate("127.0.0.1").unwrap();
+    let connection_file = env::temp_dir().join(format!(
+        "canal-kernel-jupyter-mode-{}.json",
+        std::process::id()
+    ));
+    let _ = fs::remove_file(&connection_file);
+
+    connection.write(&connection_file).unwrap();
+
+    expect_that!(
+        fs::metadata(&connection_file).unwrap().permissions().mode() & 0o777,
+        eq(0o600)
+    );
+    expect_that!(
+        ConnectionInfo::load(&connection_file),
+        ok(eq(connection.clone()))
+    );
+    // An existing file, which someone else may be able to read, is not reused
+    expect_that!(connection.write(&connection_file), err(anything()));
+
+    let _ = fs::remove_file(connection_file);
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn jupyter_repl_forwards_streams_displays_and_results() {
+    let stub = StubKernel::launch("outputs", InterruptMode::Message);
+
+    let (result, stdout, stderr) = execute(&stub.handle, "hello").await;
+    expect_that!(result, ok(anything()));
+    expect_that!(stdout, eq("hello\n"));
+    expect_that!(stderr, eq(""));
+
+    let (result, stdout, stderr) = execute(&stub.handle, "warn").await;
+    expect_that!(result, ok(anything()));
+    expect_that!(stdout, eq(""));
+    expect_that!(stderr, eq("warning\n"));
+
+    let (_, stdout, _) = execute(&stub.handle, "display").await;
+    expect_that!(stdout, eq("<Figure>\n"));
+
+    let (_, stdout, _) = execute(&stub.handle, "result").await;
+    expect_that!(stdout, eq("42\n"));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn jupyter_repl_fails_with_the_traceback_of_errors() {
+    let stub = StubKernel::launch("errors", InterruptMode::Message);
+
+    let (result, _, stderr) = execute(&stub.handle, "raise").await;
+
+    expect_that!(result, err(pat!(ReplError::Failed)));
+    expect_that!(
+        stderr,
+        eq("Traceback (most recent call last):\nValueError: bad value\n")
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn jupyter_repl_interrupts_by_message() {
+    let stub = StubKernel::launch("interrupt-message", InterruptMode::Message);
+
+    expect_that!(
+        interrupt_after(&stub.handle, "sleep").await,
+        err(pat!(ReplError::Interrupted))
+    );
+
+    let (result, stdout, _) = execute(&stub.handle, "after").await;
+    expect_that!(result, ok(anything()));
+    expect_that!(stdout, eq("after\n"));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn jupyter_repl_interrupts_by_signal() {
+    let stub = StubKernel::launch("interrupt-signal", InterruptMode::Signal);
+
+    expect_that!(
+        interrupt_after(&stub.handle, "sleep").await,
+        err(pat!(ReplError::Interrupted))
+    );
+
+    let (result, _, _) = execute(&stub.handle, "after").await;
+    expect_that!(result, ok(anything()));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn jupyter_repl_fails_once_the_kernel_exited() {
+    let stub = StubKernel::launch("exit", InterruptMode::Message);
+
+    let (result, _, _) = execute(&stub.handle, "exit").await;
+    expect_that!(result, err(pat!(ReplError::Failed)));
+
+    let (result, _, _) = execute(&stub.handle, "after").await;
+    expect_that!(result, err(pat!(ReplError::Failed)));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn registry_launches_jupyter_kernels_from_their_kernelspec() {
+    let dir = env::temp_dir().join(format!("canal-kernel-jupyter-spec-{}", std::process::id()));
+    let resource_dir = dir.join("stub");
+    fs::create_dir_all(&resource_dir).unwrap();
+    let spec = json!({
+        "display_name": "Stub",
+        "language": "stub",
+        "argv": [env!("CARGO_BIN_EXE_stub_jupyter_kernel"), "{connection_file}"],
+        "interrupt_mode": "message",
+    });
+    fs::write(resource_dir.join("kernel.json"), spec.to_string()).unwrap();
+    let registry = KernelRegistry::from_dirs([dir.clone()]);
+
+    let (mut terminal, _queue) = registry.launch("stub", KernelOptions::new(10)).unwrap();
+    let (request1, io_receiver1) = create_request_exec(1, "sleep");
+    let (request2, io_receiver2) = create_request_exec(2, "result");
+
+    terminal.send(request1).await;
+    sleep(Duration::from_millis(500)).await;
+    terminal.send(KernelRequest::Interrupt).await;
+    terminal.send(request2).await;
+
+    expect_that!(
+        terminal.recv().await,
+        some(pat!(KernelResponse::Cancelled(eq(1))))
+    );
+    expect_that!(
+        terminal.recv().await,
+        some(pat!(KernelResponse::Success(eq(2))))
+    );
+    expect_that!(
+        take_all_stream(io_receiver1).await,
+        is_utf8_string(eq("KeyboardInterrupt\n"))
+    );
+    expect_that!(
+        take_all_stream(io_receiver2).await,
+        is_utf8_string(eq("42\n"))
+    );
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+/// Stub kernel run by a `JupyterRepl`
+struct StubKernel {
+    handle: Arc<ReplHandle>,
+    connection_file: PathBuf,
+}
+
+impl StubKernel {
+    fn launch(name: &str, interrupt_mode: InterruptMode) -> Self {
+        let connection = ConnectionInfo::allocate("127.0.0.1").unwrap();
+        let connection_file = env::temp_dir().join(format!(
+            "canal-kernel-jupyter-{name}-{}.json",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&connection_file);
+        connection.write(&connection_file).unwrap();
+
+        let process = Command::new(env!("CARGO_BIN_EXE_stub_jupyter_kernel"))
+            .arg(&connection_file)
+            .spawn()
+            .unwrap();
+        let config = JupyterConfig::new(connection).interrupt_mode(interrupt_mode);
+        let handle = repl::launch_with::<JupyterRepl>(Arc::new(Mutex::new(process)), config);
+
+        Self {
+            handle: Arc::new(handle),
+            connection_file,
+        }
+    }
+}
+
+impl Drop for StubKernel {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.connection_file);
+    }
+}
+
+async fn execute(
+    handle: &ReplHandle,
+    code: &str,
+) -> (std::result::Result<(), ReplError>, String, String) {
+    let (io_sender, io_receiver) = mpsc::channel(8);
+    let (stderr_sender, stderr_receiver) = mpsc::channel(8);
+
+    let (result, stdout, stderr) = tokio::join!(
+        handle.execute_with_stderr(
+            code.to_string(),
+            io_sender,
+            Some(stderr_sender),
+            CancellationToken::new()
+        ),
+        take_all_output(io_receiver),
+        take_all_output(stderr_receiver),
+    );
+
+    (
+        result,
+        String::from_utf8_lossy(&stdout).into_owned(),
+        String::from_utf8_lossy(&stderr).into_owned(),
+    )
+}
+
+/// Executes `code` and interrupts it once it had time to start
+async fn interrupt_after(
+    handle: &Arc<ReplHandle>,
+    code: &str,
+) -> std::result::Result<(), ReplError> {
+    let (io_sender, _io_receiver) = mpsc::channel(8);
+    let sigint = CancellationToken::new();
+
+    let job = {
+        let handle = handle.clone();
+        let code = code.to_string();
+        let sigint = sigint.clone();
+        task::spawn(async move { handle.execute(code, io_sender, sigint).await })
+    };
+
+    sleep(Duration::from_millis(500)).await;
+    sigint.cancel();
+
+    job.await.unwrap()
+}
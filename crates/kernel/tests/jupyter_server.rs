@@ -0,0 +1,279 @@
+use std::{env, fs, path::PathBuf, sync::Arc, time::Duration};
+
+use canal_kernel::{
+    jupyter::{
+        server::{self, ServerConfig},
+        ConnectionInfo, JupyterMessage, Session,
+    },
+    kernel,
+    kernelspec::KernelRegistry,
+    repl::{jupyter::InterruptMode, ReplError, ReplHandle},
+    test_util::{launch_fake_repl, take_all_output, FakeResponse, FakeScript},
+};
+use googletest::prelude::*;
+use serde_json::{json, Value};
+use tokio::{
+    sync::mpsc,
+    task,
+    time::{sleep, timeout},
+};
+use tokio_util::sync::CancellationToken;
+use zeromq::{DealerSocket, Socket, SocketRecv, SocketSend};
+
+#[googletest::test]
+#[tokio::test]
+async fn jupyter_server_answers_kernel_info_and_shuts_down() {
+    let connection = ConnectionInfo::allocate("127.0.0.1").unwrap();
+    let (terminal, _queue) = kernel::launch(launch_fake_repl(FakeScript::new()), 10);
+    let server = task::spawn(server::serve(
+        ServerConfig::new(connection.clone(), "fake"),
+        terminal,
+    ));
+
+    let session = connection.session("client").unwrap();
+    let mut shell = DealerSocket::new();
+    shell
+        .connect(&connection.endpoint(connection.shell_port))
+        .await
+        .unwrap();
+    let mut control = DealerSocket::new();
+    control
+        .connect(&connection.endpoint(connection.control_port))
+        .await
+        .unwrap();
+
+    let reply = request(&session, &mut shell, "kernel_info_request", json!({})).await;
+    expect_that!(reply.msg_type(), eq("kernel_info_reply"));
+    expect_that!(reply.content["protocol_version"], eq(json!("5.3")));
+    expect_that!(reply.content["language_info"]["name"], eq(json!("fake")));
+
+    let reply = request(
+        &session,
+        &mut control,
+        "shutdown_request",
+        json!({"restart": false}),
+    )
+    .await;
+    expect_that!(reply.msg_type(), eq("shutdown_reply"));
+    expect_that!(server.await.unwrap(), ok(anything()));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn jupyter_server_answers_control_requests_while_the_kernel_queue_is_full() {
+    let connection = ConnectionInfo::allocate("127.0.0.1").unwrap();
+    let script = FakeScript::new().on("hang", FakeResponse::new().hang());
+    let (terminal, _queue) = kernel::launch(launch_fake_repl(script), 1);
+    let server = task::spawn(server::serve(
+        ServerConfig::new(connection.clone(), "fake"),
+        terminal,
+    ));
+
+    let session = connection.session("client").unwrap();
+    let mut shell = DealerSocket::new();
+    shell
+        .connect(&connection.endpoint(connection.shell_port))
+        .await
+        .unwrap();
+    let mut control = DealerSocket::new();
+    control
+        .connect(&connection.endpoint(connection.control_port))
+        .await
+        .unwrap();
+
+    // One runs, one waits in the queue and the others wait for room in it
+    let mut executions = Vec::new();
+    for _ in 0..4 {
+        let request = session.message("execute_request", json!({"code": "hang"}));
+        shell.send(session.encode(&request)).await.unwrap();
+        executions.push(request.header.msg_id);
+    }
+    sleep(Duration::from_millis(100)).await;
+
+    let reply = timeout(
+        Duration::from_secs(5),
+        request(&session, &mut control, "interrupt_request", json!({})),
+    )
+    .await;
+    expect_that!(
+        reply.map(|reply| reply.msg_type().to_string()),
+        ok(eq("interrupt_reply"))
+    );
+
+    // Every execution sent before the interrupt is cancelled, even those not queued yet
+    let mut statuses = Vec::new();
+    let replies = timeout(Duration::from_secs(5), async {
+        while statuses.len() < executions.len() {
+            let reply = session.decode(shell.recv().await.unwrap()).unwrap();
+            if reply.msg_type() == "execute_reply" {
+                statuses.push(reply.content["status"].clone());
+            }
+        }
+    })
+    .await;
+    expect_that!(replies, ok(anything()));
+    expect_that!(
+        statuses,
+        elements_are![
+            eq(json!("error")),
+            eq(json!("aborted")),
+            eq(json!("aborted")),
+            eq(json!("aborted")),
+        ]
+    );
+
+    server.abort();
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn canal_kernel_serves_a_bash_kernel_to_jupyter_clients() {
+    let kernels = CanalKernels::create("serves", InterruptMode::Message);
+    let handle = kernels.launch();
+
+    let (result, output) = execute(&handle, "x=41").await;
+    expect_that!(result, ok(anything()));
+    expect_that!(output, eq(""));
+
+    let (result, output) = execute(&handle, "echo $((x + 1))").await;
+    expect_that!(result, ok(anything()));
+    expect_that!(output, eq("42\n"));
+
+    let (result, output) = execute(&handle, "echo bad; false").await;
+    expect_that!(result, err(pat!(ReplError::Failed)));
+    expect_that!(output, eq("bad\nExecutionError: Execution failed\n"));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn canal_kernel_is_interrupted_by_message() {
+    let kernels = CanalKernels::create("interrupt-message", InterruptMode::Message);
+    let handle = Arc::new(kernels.launch());
+
+    expect_that!(
+        interrupt_after(&handle, "sleep 30").await,
+        err(pat!(ReplError::Interrupted))
+    );
+
+    let (result, output) = execute(&handle, "echo after").await;
+    expect_that!(result, ok(anything()));
+    expect_that!(output, eq("after\n"));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn canal_kernel_is_interrupted_by_signal() {
+    let kernels = CanalKernels::create("interrupt-signal", InterruptMode::Signal);
+    let handle = Arc::new(kernels.launch());
+
+    expect_that!(
+        interrupt_after(&handle, "sleep 30").await,
+        err(pat!(ReplError::Interrupted))
+    );
+
+    let (result, output) = execute(&handle, "echo after").await;
+    expect_that!(result, ok(anything()));
+    expect_that!(output, eq("after\n"));
+}
+
+/// Kernels directory holding a bash kernelspec, and a kernelspec running it through
+/// `canal_kernel` as a Jupyter kernel
+struct CanalKernels {
+    dir: PathBuf,
+    registry: KernelRegistry,
+}
+
+impl CanalKernels {
+    fn create(name: &str, interrupt_mode: InterruptMode) -> Self {
+        let dir = env::temp_dir().join(format!(
+            "canal-kernel-jupyter-server-{name}-{}",
+            std::process::id()
+        ));
+
+        let bash = json!({
+            "display_name": "Bash",
+            "language": "bash",
+            "argv": ["bash"],
+            "repl": {"type": "bash"},
+        });
+        let canal_bash = json!({
+            "display_name": "Bash (Canal)",
+            "language": "bash",
+            "argv": [env!("CARGO_BIN_EXE_canal_kernel"), "jupyter", "test-bash", "{connection_file}"],
+            "env": {"CANAL_KERNEL_PATH": dir},
+            "interrupt_mode": interrupt_mode,
+        });
+        for (name, spec) in [("test-bash", bash), ("test-canal-bash", canal_bash)] {
+            fs::create_dir_all(dir.join(name)).unwrap();
+            fs::write(dir.join(name).join("kernel.json"), spec.to_string()).unwrap();
+        }
+
+        Self {
+            registry: KernelRegistry::from_dirs([dir.clone()]),
+            dir,
+        }
+    }
+
+    fn launch(&self) -> ReplHandle {
+        self.registry
+            .get("test-canal-bash")
+            .unwrap()
+            .launch_repl()
+            .unwrap()
+    }
+}
+
+impl Drop for CanalKernels {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+async fn request(
+    session: &Session,
+    socket: &mut DealerSocket,
+    msg_type: &str,
+    content: Value,
+) -> JupyterMessage {
+    let request = session.message(msg_type, content);
+    socket.send(session.encode(&request)).await.unwrap();
+
+    loop {
+        let reply = session.decode(socket.recv().await.unwrap()).unwrap();
+        if reply.parent_msg_id() == Some(request.header.msg_id.as_str()) {
+            return reply;
+        }
+    }
+}
+
+async fn execute(handle: &ReplHandle, code: &str) -> (std::result::Result<(), ReplError>, String) {
+    let (io_sender, io_receiver) = mpsc::channel(8);
+
+    let (result, output) = tokio::join!(
+        handle.execute(code.to_string(), io_sender, CancellationToken::new()),
+        take_all_output(io_receiver),
+    );
+
+    (result, String::from_utf8_lossy(&output).into_owned())
+}
+
+/// Executes `code` and interrupts it once it had time to start
+async fn interrupt_after(
+    handle: &Arc<ReplHandle>,
+    code: &str,
+) -> std::result::Result<(), ReplError> {
+    let (io_sender, _io_receiver) = mpsc::channel(8);
+    let sigint = CancellationToken::new();
+
+    let job = {
+        let handle = handle.clone();
+        let code = code.to_string();
+        let sigint = sigint.clone();
+        task::spawn(async move { handle.execute(code, io_sender, sigint).await })
+    };
+
+    sleep(Duration::from_millis(500)).await;
+    sigint.cancel();
+
+    job.await.unwrap()
+}
@@ -1,20 +1,17 @@
-mod mock_repl;
 mod utils;
 
-use std::{sync::Arc, time::Duration};
+use std::time::Duration;
 
-use bytes::Bytes;
 use canal_kernel::{
-    kernel::{self, KernelTerminal},
-    repl, KernelRequest, KernelResponse,
+    event::{EventContent, ExecStatus},
+    kernel::{self, KernelOptions, KernelTerminal},
+    queue::Lane,
+    test_util::{launch_fake_repl, take_all_stream, FakeResponse, FakeScript},
+    KernelRequest, KernelResponse,
 };
 use googletest::prelude::*;
-use mock_repl::MockRepl;
-use tokio::{
-    sync::{mpsc, Mutex},
-    time::sleep,
-};
-use utils::{spawn_dummy_repl, take_all_output};
+use tokio::time::{sleep, timeout};
+use utils::{create_request_exec, launch_mock_repl, ExecRequest};
 
 #[googletest::test]
 #[tokio::test]
@@ -25,7 +22,7 @@ async fn kernel_processes_a_message_succesfully() {
     terminal.send(request).await;
     let response = terminal.recv().await.unwrap();
 
-    expect_that!(take_all_output(io_receiver).await, is_utf8_string(eq("1")));
+    expect_that!(take_all_stream(io_receiver).await, is_utf8_string(eq("1")));
     expect_that!(response, pat!(KernelResponse::Success(pat!(1))));
 }
 
@@ -41,8 +38,8 @@ async fn kernel_processes_multiple_messages_succesfully() {
     let response1 = terminal.recv().await.unwrap();
     let response2 = terminal.recv().await.unwrap();
 
-    expect_that!(take_all_output(io_receiver1).await, is_utf8_string(eq("1")));
-    expect_that!(take_all_output(io_receiver2).await, is_utf8_string(eq("2")));
+    expect_that!(take_all_stream(io_receiver1).await, is_utf8_string(eq("1")));
+    expect_that!(take_all_stream(io_receiver2).await, is_utf8_string(eq("2")));
     expect_that!(response1, pat!(KernelResponse::Success(pat!(1))));
     expect_that!(response2, pat!(KernelResponse::Success(pat!(2))));
 }
@@ -62,7 +59,7 @@ async fn kernel_returns_an_error_when_interupted() {
     let response = terminal.recv().await.unwrap();
 
     expect_that!(
-        take_all_output(io_receiver).await,
+        take_all_stream(io_receiver).await,
         is_utf8_string(eq("partial..."))
     );
     expect_that!(response, pat!(KernelResponse::Cancelled(pat!(99))));
@@ -93,19 +90,127 @@ async fn kernel_drops_all_exec_message_in_queue_when_interupted() {
     expect_that!(response3, pat!(KernelResponse::Cancelled(pat!(3))));
 
     expect_that!(
-        take_all_output(io_receiver1).await,
+        take_all_stream(io_receiver1).await,
         is_utf8_string(eq("partial..."))
     );
     expect_that!(
-        take_all_output(io_receiver2).await,
+        take_all_stream(io_receiver2).await,
         is_utf8_string(eq("")) // no byte sent
     );
     expect_that!(
-        take_all_output(io_receiver3).await,
+        take_all_stream(io_receiver3).await,
         is_utf8_string(eq("")) // no byte sent
     );
 }
 
+#[googletest::test]
+#[tokio::test]
+async fn kernel_drops_exec_messages_of_all_lanes_when_interupted() {
+    let mut terminal = launch_terminal(10);
+    let (request1, _io_receiver1) = ExecRequest::new(99, "expensive").lane(Lane::Batch).build();
+    let (request2, _io_receiver2) = ExecRequest::new(2, "2").lane(Lane::Batch).build();
+    let (request3, _io_receiver3) = ExecRequest::new(3, "3").lane(Lane::Interactive).build();
+
+    terminal.send(request1).await;
+    sleep(Duration::from_micros(50)).await;
+    terminal.send(request2).await;
+    terminal.send(request3).await;
+
+    // sleep is needed to wait the request being executed
+    sleep(Duration::from_micros(50)).await;
+    terminal.send(KernelRequest::Interrupt).await;
+
+    let response1 = terminal.recv().await.unwrap();
+    let response2 = terminal.recv().await.unwrap();
+    let response3 = terminal.recv().await.unwrap();
+
+    expect_that!(response1, pat!(KernelResponse::Cancelled(pat!(99))));
+    expect_that!(response2, pat!(KernelResponse::Cancelled(pat!(3))));
+    expect_that!(response3, pat!(KernelResponse::Cancelled(pat!(2))));
+}
+
+#[googletest::test]
+#[tokio::test(flavor = "multi_thread")]
+async fn kernel_drops_exec_messages_of_all_lanes_on_failure_and_runs_later_ones() {
+    let script = FakeScript::new().on(
+        "slow bug",
+        FakeResponse::new().sleep(Duration::from_millis(200)).fail(),
+    );
+    let (mut terminal, _queue) = kernel::launch(launch_fake_repl(script), 10);
+    let (request1, _io_receiver1) = create_request_exec(1, "slow bug");
+    let (request2, _io_receiver2) = ExecRequest::new(2, "2").lane(Lane::Batch).build();
+    let (request3, _io_receiver3) = ExecRequest::new(3, "3").lane(Lane::Interactive).build();
+
+    terminal.send(request1).await;
+    terminal.send(request2).await;
+    terminal.send(request3).await;
+    expect_that!(terminal.recv().await, some(eq(KernelResponse::Failed(1))));
+
+    // Sent as the queued execs may still be dropped, each lane giving up only those it held
+    let (request4, _io_receiver4) = ExecRequest::new(4, "4").lane(Lane::Interactive).build();
+    terminal.send(request4).await;
+
+    let mut responses = Vec::new();
+    for _ in 0..3 {
+        responses.push(terminal.recv().await.unwrap());
+    }
+    expect_that!(
+        responses,
+        unordered_elements_are![
+            eq(KernelResponse::Cancelled(2)),
+            eq(KernelResponse::Cancelled(3)),
+            eq(KernelResponse::Success(4)),
+        ]
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_processes_messages_after_being_interupted() {
+    let mut terminal = launch_terminal(10);
+    let (request1, _io_receiver1) = create_request_exec(99, "expensive");
+
+    terminal.send(request1).await;
+
+    // sleep is needed to wait the request being executed
+    sleep(Duration::from_micros(50)).await;
+    terminal.send(KernelRequest::Interrupt).await;
+    let response1 = terminal.recv().await.unwrap();
+
+    let (request2, io_receiver2) = create_request_exec(2, "2");
+    terminal.send(request2).await;
+    let response2 = terminal.recv().await.unwrap();
+
+    expect_that!(response1, pat!(KernelResponse::Cancelled(pat!(99))));
+    expect_that!(response2, pat!(KernelResponse::Success(pat!(2))));
+    expect_that!(take_all_stream(io_receiver2).await, is_utf8_string(eq("2")));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_interrupts_only_the_execs_sent_before_the_interrupt() {
+    let mut terminal = launch_terminal(10);
+    let (request1, _io_receiver1) = create_request_exec(1, "expensive");
+    let (request2, io_receiver2) = create_request_exec(2, "2");
+    let (request3, io_receiver3) = create_request_exec(3, "3");
+
+    // No delay between the requests: the order in which they are sent is all that matters
+    terminal.send(request1).await;
+    terminal.send(request2).await;
+    terminal.send(KernelRequest::Interrupt).await;
+    terminal.send(request3).await;
+
+    let response1 = terminal.recv().await.unwrap();
+    let response2 = terminal.recv().await.unwrap();
+    let response3 = terminal.recv().await.unwrap();
+
+    expect_that!(response1, pat!(KernelResponse::Cancelled(pat!(1))));
+    expect_that!(response2, pat!(KernelResponse::Cancelled(pat!(2))));
+    expect_that!(response3, pat!(KernelResponse::Success(pat!(3))));
+    expect_that!(take_all_stream(io_receiver2).await, is_utf8_string(eq("")));
+    expect_that!(take_all_stream(io_receiver3).await, is_utf8_string(eq("3")));
+}
+
 #[googletest::test]
 #[tokio::test]
 async fn kernel_returns_an_error_when_the_code_is_buggy() {
@@ -117,7 +222,7 @@ async fn kernel_returns_an_error_when_the_code_is_buggy() {
 
     expect_that!(response, pat!(KernelResponse::Failed(pat!(99))));
     expect_that!(
-        take_all_output(io_receiver).await,
+        take_all_stream(io_receiver).await,
         is_utf8_string(eq("error"))
     );
 }
@@ -143,38 +248,95 @@ async fn kernel_drops_all_exec_message_in_queue_when_the_code_is_buggy() {
     expect_that!(response3, pat!(KernelResponse::Cancelled(pat!(3))));
 
     expect_that!(
-        take_all_output(io_receiver1).await,
+        take_all_stream(io_receiver1).await,
         is_utf8_string(eq("error"))
     );
     expect_that!(
-        take_all_output(io_receiver2).await,
+        take_all_stream(io_receiver2).await,
         is_utf8_string(eq("")) // no byte sent
     );
     expect_that!(
-        take_all_output(io_receiver3).await,
+        take_all_stream(io_receiver3).await,
         is_utf8_string(eq("")) // no byte sent
     );
 }
 
-fn launch_terminal(capacity: usize) -> KernelTerminal {
-    let dummpy_repl_process = Arc::new(Mutex::new(spawn_dummy_repl()));
+#[googletest::test]
+#[tokio::test]
+async fn kernel_times_out_execs_running_longer_than_its_exec_timeout() {
+    let options = KernelOptions::new(10).exec_timeout(Duration::from_millis(100));
+    let (mut terminal, _queue) = kernel::launch_with(launch_mock_repl(), options);
+    let mut subscriber = terminal.subscribe();
+    let (request1, io_receiver1) = create_request_exec(1, "expensive");
+    let (request2, io_receiver2) = create_request_exec(2, "2");
+
+    terminal.send(request1).await;
+    terminal.send(request2).await;
+    let response1 = terminal.recv().await.unwrap();
+    let response2 = terminal.recv().await.unwrap();
 
-    let (terminal, _queue_semaphore) =
-        kernel::launch(repl::launch::<MockRepl>(dummpy_repl_process), capacity);
+    expect_that!(response1, eq(KernelResponse::TimedOut(1)));
+    expect_that!(
+        take_all_stream(io_receiver1).await,
+        is_utf8_string(eq("partial..."))
+    );
+    // Like a failure, the timeout drops the queued execs, but not those sent later
+    expect_that!(response2, eq(KernelResponse::Cancelled(2)));
+    expect_that!(take_all_stream(io_receiver2).await, is_utf8_string(eq("")));
 
-    terminal
+    let (request3, io_receiver3) = create_request_exec(3, "3");
+    terminal.send(request3).await;
+    expect_that!(terminal.recv().await, some(eq(KernelResponse::Success(3))));
+    expect_that!(take_all_stream(io_receiver3).await, is_utf8_string(eq("3")));
+
+    let mut statuses = Vec::new();
+    let _ = timeout(Duration::from_secs(5), async {
+        while let Some(event) = subscriber.recv().await {
+            if let EventContent::Status(status) = event.content {
+                statuses.push((event.parent, status));
+                if event.parent == Some(3) && status == ExecStatus::Succeeded {
+                    break;
+                }
+            }
+        }
+    })
+    .await;
+    expect_that!(statuses, contains(eq((Some(1), ExecStatus::TimedOut))));
 }
 
-fn create_request_exec(
-    message_id: u32,
-    code: &str,
-) -> (KernelRequest, mpsc::UnboundedReceiver<Bytes>) {
-    let (io_sender, io_receiver) = mpsc::unbounded_channel();
-    let message = KernelRequest::Execute {
-        message_id,
-        code: code.to_string(),
-        io_sender,
-    };
-
-    (message, io_receiver)
+#[googletest::test]
+#[tokio::test]
+async fn kernel_does_not_count_the_time_queued_in_the_exec_timeout() {
+    let script = FakeScript::new().on(
+        "slow",
+        FakeResponse::new().sleep(Duration::from_millis(200)),
+    );
+    let options = KernelOptions::new(10).exec_timeout(Duration::from_millis(300));
+    let (mut terminal, _queue) = kernel::launch_with(launch_fake_repl(script), options);
+
+    let mut io_receivers = Vec::new();
+    for message_id in 1..=3 {
+        let (request, io_receiver) = create_request_exec(message_id, "slow");
+        terminal.send(request).await;
+        io_receivers.push(io_receiver);
+    }
+
+    let mut responses = Vec::new();
+    for _ in 1..=3 {
+        responses.push(terminal.recv().await.unwrap());
+    }
+    expect_that!(
+        responses,
+        elements_are![
+            eq(KernelResponse::Success(1)),
+            eq(KernelResponse::Success(2)),
+            eq(KernelResponse::Success(3)),
+        ]
+    );
+}
+
+fn launch_terminal(capacity: usize) -> KernelTerminal {
+    let (terminal, _queue) = kernel::launch(launch_mock_repl(), capacity);
+
+    terminal
 }
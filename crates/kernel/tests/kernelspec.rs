@@ -0,0 +1,394 @@
+mod utils;
+
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use canal_kernel::{
+    kernel::KernelOptions,
+    kernelspec::{self, KernelRegistry, KernelSpec, KernelSpecError, ReplAdapter},
+    limits::{ResourceLimit, ResourceLimits},
+    test_util::take_all_stream,
+    KernelResponse,
+};
+use googletest::prelude::*;
+use tokio::time::sleep;
+use utils::create_request_exec;
+
+const BASH_SPEC: &str = r#"{
+    "display_name": "Bash",
+    "language": "bash",
+    "argv": ["bash"],
+    "env": { "GREETING": "hello" },
+    "repl": { "type": "bash" }
+}"#;
+
+const SH_SPEC: &str = r#"{
+    "display_name": "POSIX shell",
+    "language": "sh",
+    "argv": ["sh", "-c", "exec sh -i 2>&1"],
+    "env": { "PS1": "canal$ ", "PS2": "canal> " },
+    "repl": {
+        "type": "prompt",
+        "prompt": "canal\\$ $",
+        "continuation": "canal> $",
+        "error": "No such file"
+    }
+}"#;
+
+#[googletest::test]
+fn kernelspec_is_read_from_json() {
+    let dir = kernels_dir("read");
+    write_spec(&dir, "sh", SH_SPEC);
+
+    let spec = KernelSpec::load(&dir.join("sh").join("kernel.json")).unwrap();
+
+    expect_that!(
+        spec,
+        pat!(KernelSpec {
+            display_name: eq("POSIX shell"),
+            language: eq("sh"),
+            argv: elements_are![eq("sh"), eq("-c"), eq("exec sh -i 2>&1")],
+            env: eq(BTreeMap::from([
+                ("PS1".to_string(), "canal$ ".to_string()),
+                ("PS2".to_string(), "canal> ".to_string()),
+            ])),
+            repl: pat!(ReplAdapter::Prompt {
+                prompt: eq("canal\\$ $"),
+                continuation: some(eq("canal> $")),
+                error: some(eq("No such file")),
+                interrupt_input: none(),
+            }),
+            limits: eq(ResourceLimits::default()),
+            sandbox: none(),
+        })
+    );
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[googletest::test]
+fn registry_prefers_the_first_directories_and_skips_invalid_specs() {
+    let workspace = kernels_dir("workspace");
+    let user = kernels_dir("user");
+    write_spec(&workspace, "shell", BASH_SPEC);
+    write_spec(&user, "shell", SH_SPEC);
+    write_spec(&user, "sh", SH_SPEC);
+    write_spec(&user, "broken", r#"{ "display_name": "Broken" }"#);
+
+    let registry = KernelRegistry::from_dirs([workspace.clone(), user.clone()]);
+
+    expect_that!(
+        registry
+            .list()
+            .map(|entry| entry.name.as_str())
+            .collect::<Vec<_>>(),
+        elements_are![eq("sh"), eq("shell")]
+    );
+    expect_that!(
+        registry
+            .get("shell")
+            .map(|entry| entry.spec.language.as_str()),
+        some(eq("bash"))
+    );
+    expect_that!(
+        registry
+            .get("shell")
+            .map(|entry| entry.resource_dir.clone()),
+        some(eq(workspace.join("shell")))
+    );
+
+    let _ = fs::remove_dir_all(workspace);
+    let _ = fs::remove_dir_all(user);
+}
+
+#[googletest::test]
+fn kernels_are_searched_in_the_workspace_first() {
+    let dirs = kernelspec::search_dirs(Path::new("/projects/demo"));
+
+    expect_that!(
+        dirs.first(),
+        some(eq(Path::new("/projects/demo/.canal/kernels")))
+    );
+    expect_that!(
+        dirs.last(),
+        some(eq(Path::new("/usr/share/jupyter/kernels")))
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn registry_launches_a_bash_kernel_with_the_env_of_its_spec() {
+    let dir = kernels_dir("launch-bash");
+    write_spec(&dir, "bash", BASH_SPEC);
+    let registry = KernelRegistry::from_dirs([dir.clone()]);
+
+    let (mut terminal, _queue) = registry.launch("bash", KernelOptions::new(10)).unwrap();
+    let (request, io_receiver) = create_request_exec(1, "echo $GREETING");
+    terminal.send(request).await;
+
+    expect_that!(
+        terminal.recv().await,
+        some(pat!(KernelResponse::Success(eq(1))))
+    );
+    expect_that!(
+        take_all_stream(io_receiver).await,
+        is_utf8_string(eq("hello\n"))
+    );
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn registry_launches_a_prompt_driven_kernel() {
+    let dir = kernels_dir("launch-sh");
+    write_spec(&dir, "sh", SH_SPEC);
+    let registry = KernelRegistry::from_dirs([dir.clone()]);
+
+    let (mut terminal, _queue) = registry.launch("sh", KernelOptions::new(10)).unwrap();
+    let (request1, io_receiver1) = create_request_exec(1, "echo hi");
+    let (request2, _io_receiver2) = create_request_exec(2, "ls /canal-does-not-exist");
+    terminal.send(request1).await;
+    terminal.send(request2).await;
+
+    expect_that!(
+        terminal.recv().await,
+        some(pat!(KernelResponse::Success(eq(1))))
+    );
+    expect_that!(
+        terminal.recv().await,
+        some(pat!(KernelResponse::Failed(eq(2))))
+    );
+    expect_that!(
+        take_all_stream(io_receiver1).await,
+        is_utf8_string(eq("hi\n"))
+    );
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn registry_launches_kernels_within_the_limits_of_their_spec() {
+    let dir = kernels_dir("limits");
+    write_spec(
+        &dir,
+        "bash",
+        &limited_bash_spec(
+            r#"{ "memory_bytes": 1073741824, "cpu_seconds": 30, "open_files": 64 }"#,
+        ),
+    );
+    let registry = KernelRegistry::from_dirs([dir.clone()]);
+
+    let (mut terminal, _queue) = registry.launch("bash", KernelOptions::new(10)).unwrap();
+    let (request1, io_receiver1) = create_request_exec(1, "ulimit -v; ulimit -t; ulimit -n");
+    // Exiting is not mistaken for exceeding a limit
+    let (request2, _io_receiver2) = create_request_exec(2, "exit 3");
+    terminal.send(request1).await;
+    terminal.send(request2).await;
+
+    expect_that!(
+        terminal.recv().await,
+        some(pat!(KernelResponse::Success(eq(1))))
+    );
+    expect_that!(
+        take_all_stream(io_receiver1).await,
+        is_utf8_string(eq("1048576\n30\n64\n"))
+    );
+    expect_that!(
+        terminal.recv().await,
+        some(pat!(KernelResponse::Failed(eq(2))))
+    );
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernels_with_limits_answer_plain_failures_at_once() {
+    let dir = kernels_dir("limits-failure");
+    write_spec(
+        &dir,
+        "bash",
+        &limited_bash_spec(r#"{ "memory_bytes": 1073741824, "cpu_seconds": 30 }"#),
+    );
+    let registry = KernelRegistry::from_dirs([dir.clone()]);
+    let (mut terminal, _queue) = registry.launch("bash", KernelOptions::new(10)).unwrap();
+
+    let started_at = Instant::now();
+    let (request, _io_receiver) = create_request_exec(1, "false");
+    terminal.send(request).await;
+
+    expect_that!(
+        terminal.recv().await,
+        some(pat!(KernelResponse::Failed(eq(1))))
+    );
+    expect_that!(started_at.elapsed(), lt(Duration::from_millis(500)));
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernels_killed_by_a_limit_report_it() {
+    let dir = kernels_dir("limits-exceeded");
+    write_spec(&dir, "cpu", &limited_bash_spec(r#"{ "cpu_seconds": 1 }"#));
+    write_spec(
+        &dir,
+        "memory",
+        &limited_bash_spec(r#"{ "memory_bytes": 200000000 }"#),
+    );
+    let registry = KernelRegistry::from_dirs([dir.clone()]);
+
+    let (mut terminal, _queue) = registry.launch("cpu", KernelOptions::new(10)).unwrap();
+    let (request, _io_receiver) = create_request_exec(1, "while :; do :; done");
+    terminal.send(request).await;
+    expect_that!(
+        terminal.recv().await,
+        some(pat!(KernelResponse::LimitExceeded(
+            eq(1),
+            eq(ResourceLimit::CpuTime)
+        )))
+    );
+
+    let (mut terminal, _queue) = registry.launch("memory", KernelOptions::new(10)).unwrap();
+    let (request, _io_receiver) =
+        create_request_exec(1, "x=$(head -c 300000000 /dev/zero | tr '\\0' a)");
+    terminal.send(request).await;
+    expect_that!(
+        terminal.recv().await,
+        some(pat!(KernelResponse::LimitExceeded(
+            eq(1),
+            eq(ResourceLimit::Memory)
+        )))
+    );
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernels_only_report_their_memory_limit_after_a_failed_allocation() {
+    let dir = kernels_dir("limits-memory");
+    write_spec(
+        &dir,
+        "memory",
+        &limited_bash_spec(r#"{ "memory_bytes": 600000000 }"#),
+    );
+    let registry = KernelRegistry::from_dirs([dir.clone()]);
+
+    // Much of the limit is used as resident memory, yet the interpreter exits on its own
+    let (mut terminal, _queue) = registry.launch("memory", KernelOptions::new(10)).unwrap();
+    let (request, _io_receiver) =
+        create_request_exec(1, "perl -e '$x = q(a) x 250_000_000'; exit 3");
+    terminal.send(request).await;
+    expect_that!(
+        terminal.recv().await,
+        some(pat!(KernelResponse::Failed(eq(1))))
+    );
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn registry_kills_the_process_tree_of_dropped_kernels() {
+    let dir = kernels_dir("drop");
+    write_spec(&dir, "bash", BASH_SPEC);
+    let registry = KernelRegistry::from_dirs([dir.clone()]);
+
+    let (mut terminal, _queue) = registry.launch("bash", KernelOptions::new(10)).unwrap();
+    let (request, io_receiver) = create_request_exec(1, "sleep 300 >/dev/null 2>&1 & echo $$ $!");
+    terminal.send(request).await;
+    expect_that!(
+        terminal.recv().await,
+        some(pat!(KernelResponse::Success(eq(1))))
+    );
+    let output = String::from_utf8(take_all_stream(io_receiver).await.to_vec()).unwrap();
+    let pids: Vec<u32> = output
+        .split_whitespace()
+        .map(|pid| pid.parse().unwrap())
+        .collect();
+
+    drop(terminal);
+    sleep(Duration::from_millis(200)).await;
+
+    expect_that!(pids, len(eq(2)));
+    expect_that!(pids.iter().any(|pid| is_alive(*pid)), eq(false));
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn registry_refuses_unknown_and_invalid_specs() {
+    let dir = kernels_dir("invalid");
+    write_spec(
+        &dir,
+        "bad-prompt",
+        r#"{
+            "display_name": "Bad",
+            "language": "none",
+            "argv": ["sh"],
+            "repl": { "type": "prompt", "prompt": "(" }
+        }"#,
+    );
+    let registry = KernelRegistry::from_dirs([dir.clone()]);
+
+    expect_that!(
+        registry.launch("missing", KernelOptions::new(10)).err(),
+        some(pat!(KernelSpecError::NotFound(eq("missing"))))
+    );
+    expect_that!(
+        registry.launch("bad-prompt", KernelOptions::new(10)).err(),
+        some(pat!(KernelSpecError::Pattern(anything())))
+    );
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+/// Whether `pid` is running, which a zombie is not
+fn is_alive(pid: u32) -> bool {
+    fs::read_to_string(format!("/proc/{pid}/stat"))
+        .map(|stat| {
+            !stat
+                .rsplit(')')
+                .next()
+                .unwrap_or_default()
+                .starts_with(" Z")
+        })
+        .unwrap_or(false)
+}
+
+fn kernels_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!(
+        "canal-kernel-kernelspec-{name}-{}",
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&dir);
+
+    dir
+}
+
+/// Bash kernelspec with the `limits` given as JSON
+fn limited_bash_spec(limits: &str) -> String {
+    format!(
+        r#"{{
+            "display_name": "Bash",
+            "language": "bash",
+            "argv": ["bash"],
+            "repl": {{ "type": "bash" }},
+            "limits": {limits}
+        }}"#
+    )
+}
+
+fn write_spec(kernels_dir: &Path, name: &str, content: &str) {
+    let resource_dir = kernels_dir.join(name);
+    fs::create_dir_all(&resource_dir).unwrap();
+    fs::write(resource_dir.join("kernel.json"), content).unwrap();
+}
@@ -0,0 +1,422 @@
+use std::{env, fs, os::unix, path::PathBuf, time::Duration};
+
+use async_trait::async_trait;
+use canal_kernel::{
+    kernelspec::KernelSpec,
+    repl::{
+        magic::{Magic, MagicCall, MagicContext, Magics},
+        ReplError, ReplHandle,
+    },
+    sandbox::SandboxProfile,
+    test_util::{launch_fake_repl, take_all_output, FakeResponse, FakeScript},
+};
+use googletest::prelude::*;
+use tokio::{
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+use tokio_util::sync::CancellationToken;
+
+/// `%shout <text>`, printing its arguments in upper case
+struct Shout;
+
+#[async_trait]
+impl Magic for Shout {
+    async fn run(
+        &self,
+        call: &MagicCall<'_>,
+        context: &mut MagicContext<'_>,
+    ) -> std::result::Result<(), ReplError> {
+        context.print(call.args.to_uppercase()).await;
+        Ok(())
+    }
+}
+
+/// `%touch <file>`, creating or truncating a file opened without resolving it first
+struct Touch;
+
+#[async_trait]
+impl Magic for Touch {
+    async fn run(
+        &self,
+        call: &MagicCall<'_>,
+        context: &mut MagicContext<'_>,
+    ) -> std::result::Result<(), ReplError> {
+        let path = context.state.cwd.join(call.args);
+        let options = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .clone();
+
+        match context.open(&path, &options) {
+            Ok(_) => Ok(()),
+            Err(err) => context.fail(err.to_string()).await,
+        }
+    }
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn magics_run_between_the_code_of_the_cell() {
+    let script = FakeScript::new();
+    let handle =
+        launch_fake_repl(script.clone()).with_magics(Magics::new().line_magic("shout", Shout));
+
+    let (result, stdout, _) = execute(&handle, "a = 1\n%shout hi\n\nb = 2\nc = 3\n%shout ho").await;
+
+    expect_that!(result, ok(anything()));
+    expect_that!(stdout, eq("a = 1HIb = 2\nc = 3HO"));
+    expect_that!(
+        script.calls(),
+        elements_are![eq("a = 1"), eq("b = 2\nc = 3")]
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn magics_leave_code_calling_none_to_the_interpreter() {
+    let script = FakeScript::new();
+    let handle = launch_fake_repl(script.clone()).with_magics(Magics::new());
+
+    for code in [
+        "x = 10 % 3\n",
+        "%unknown\n%%time",
+        "%%unknown\ny",
+        " %time x",
+    ] {
+        let (result, _, _) = execute(&handle, code).await;
+        expect_that!(result, ok(anything()));
+    }
+
+    expect_that!(
+        script.calls(),
+        elements_are![
+            eq("x = 10 % 3\n"),
+            eq("%unknown\n%%time"),
+            eq("%%unknown\ny"),
+            eq(" %time x"),
+        ]
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn magics_time_executions() {
+    let script = FakeScript::new().on("slow", FakeResponse::new().sleep(Duration::from_millis(50)));
+    let handle = launch_fake_repl(script.clone()).with_magics(Magics::new());
+
+    let (result, stdout, _) = execute(&handle, "%time slow()").await;
+    expect_that!(result, ok(anything()));
+    expect_that!(stdout, matches_regex(r"^Wall time: [0-9.]+ms\n$"));
+
+    let (result, stdout, _) = execute(&handle, "%%time\nx = 1\ny = 2").await;
+    expect_that!(result, ok(anything()));
+    expect_that!(
+        stdout,
+        matches_regex(r"^x = 1\ny = 2Wall time: [0-9.]+[µn]?s\n$")
+    );
+
+    expect_that!(
+        script.calls(),
+        elements_are![eq("slow()"), eq("x = 1\ny = 2")]
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn magics_run_shell_cells_in_their_directory_and_environment() {
+    let dir = TempDir::new("shell");
+    let script = FakeScript::new();
+    let handle = launch_fake_repl(script.clone()).with_magics(Magics::new());
+
+    let (result, stdout, _) = execute(&handle, &format!("%cd {}", dir.0.display())).await;
+    expect_that!(result, ok(anything()));
+    expect_that!(stdout, eq(format!("{}\n", dir.0.display())));
+
+    let (result, stdout, _) = execute(&handle, "%env GREETING=hello").await;
+    expect_that!(result, ok(anything()));
+    expect_that!(stdout, eq("env: GREETING=hello\n"));
+
+    let (result, stdout, _) = execute(&handle, "%env GREETING").await;
+    expect_that!(result, ok(anything()));
+    expect_that!(stdout, eq("hello\n"));
+
+    let (result, stdout, stderr) =
+        execute(&handle, "%%bash\npwd\necho \"$GREETING\"\necho oops >&2").await;
+    expect_that!(result, ok(anything()));
+    expect_that!(stdout, eq(format!("{}\nhello\n", dir.0.display())));
+    expect_that!(stderr, eq("oops\n"));
+
+    let (result, _, _) = execute(&handle, "%%sh\nexit 3").await;
+    expect_that!(result, err(pat!(ReplError::Failed)));
+
+    expect_that!(script.calls(), empty());
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn magics_interrupt_shell_cells() {
+    let handle = launch_fake_repl(FakeScript::new()).with_magics(Magics::new());
+    let (io_sender, _io_receiver) = mpsc::channel(8);
+    let sigint = CancellationToken::new();
+
+    let interrupt = async {
+        sleep(Duration::from_millis(200)).await;
+        sigint.cancel();
+    };
+    let (result, _) = tokio::join!(
+        handle.execute("%%bash\nsleep 30".to_string(), io_sender, sigint.clone()),
+        interrupt,
+    );
+
+    expect_that!(result, err(pat!(ReplError::Interrupted)));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn magics_kill_shell_cells_ignoring_interrupts() {
+    let handle = launch_fake_repl(FakeScript::new()).with_magics(Magics::new());
+    let (io_sender, _io_receiver) = mpsc::channel(8);
+    let sigint = CancellationToken::new();
+
+    let interrupt = async {
+        sleep(Duration::from_millis(200)).await;
+        sigint.cancel();
+    };
+    let execution = handle.execute(
+        "%%bash\ntrap '' INT\nsleep 30".to_string(),
+        io_sender,
+        sigint.clone(),
+    );
+    let (result, _) = tokio::join!(timeout(Duration::from_secs(5), execution), interrupt);
+
+    expect_that!(result, ok(err(pat!(ReplError::Interrupted))));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn magics_write_files_relative_to_their_directory() {
+    let dir = TempDir::new("writefile");
+    let handle = launch_fake_repl(FakeScript::new()).with_magics(Magics::new());
+    let (result, _, _) = execute(&handle, &format!("%cd {}", dir.0.display())).await;
+    expect_that!(result, ok(anything()));
+
+    let (result, stdout, _) = execute(&handle, "%%writefile notes.txt\nfirst\n").await;
+    expect_that!(result, ok(anything()));
+    expect_that!(stdout, eq("Writing notes.txt\n"));
+
+    let (result, stdout, _) = execute(&handle, "%%writefile -a notes.txt\nsecond\n").await;
+    expect_that!(result, ok(anything()));
+    expect_that!(stdout, eq("Appending to notes.txt\n"));
+
+    expect_that!(
+        fs::read_to_string(dir.0.join("notes.txt")).unwrap(),
+        eq("first\nsecond\n")
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn sandboxed_magics_do_not_write_through_symbolic_links() {
+    let dir = TempDir::new("symlink");
+    let workspace = dir.0.join("workspace");
+    let outside = dir.0.join("outside");
+    fs::create_dir_all(&workspace).unwrap();
+    fs::create_dir_all(&outside).unwrap();
+    fs::write(outside.join("bashrc"), "original\n").unwrap();
+    unix::fs::symlink(outside.join("bashrc"), workspace.join("bashrc")).unwrap();
+    unix::fs::symlink(&outside, workspace.join("outside")).unwrap();
+    let handle = launch_fake_repl(FakeScript::new())
+        .with_magics(Magics::new().sandbox(SandboxProfile::default().workspace(&workspace)));
+
+    for code in [
+        "%%writefile bashrc\npwned\n",
+        "%%writefile -a bashrc\npwned\n",
+        "%%writefile outside/bashrc\npwned\n",
+        "%%writefile ../outside/bashrc\npwned\n",
+    ] {
+        let (result, _, stderr) = execute(&handle, code).await;
+        expect_that!(result, err(pat!(ReplError::Failed)));
+        expect_that!(stderr, starts_with("writefile: "));
+    }
+
+    let (result, _, _) = execute(&handle, "%%writefile notes.txt\nkept\n").await;
+    expect_that!(result, ok(anything()));
+    expect_that!(
+        fs::read_to_string(workspace.join("notes.txt")).unwrap(),
+        eq("kept\n")
+    );
+    expect_that!(
+        fs::read_to_string(outside.join("bashrc")).unwrap(),
+        eq("original\n")
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn sandboxed_magics_open_nothing_through_symbolic_links() {
+    let dir = TempDir::new("open");
+    let workspace = dir.0.join("workspace");
+    let outside = dir.0.join("outside");
+    fs::create_dir_all(&workspace).unwrap();
+    fs::create_dir_all(&outside).unwrap();
+    fs::write(outside.join("bashrc"), "original\n").unwrap();
+    unix::fs::symlink(&outside, workspace.join("outside")).unwrap();
+    let magics = Magics::new()
+        .line_magic("touch", Touch)
+        .sandbox(SandboxProfile::default().workspace(&workspace));
+    let handle = launch_fake_repl(FakeScript::new()).with_magics(magics);
+
+    // Unlike `%%writefile`, the path is not resolved before it is opened
+    for path in ["outside/created", "outside/bashrc"] {
+        let (result, _, _) = execute(&handle, &format!("%touch {path}")).await;
+        expect_that!(result, err(pat!(ReplError::Failed)));
+    }
+    let (result, _, _) = execute(&handle, "%touch created").await;
+    expect_that!(result, ok(anything()));
+
+    expect_that!(outside.join("created").exists(), eq(false));
+    expect_that!(
+        fs::read_to_string(outside.join("bashrc")).unwrap(),
+        eq("original\n")
+    );
+    expect_that!(workspace.join("created").exists(), eq(true));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn sandboxed_magics_do_not_change_directory_out_of_the_workspace() {
+    let dir = TempDir::new("cd");
+    let workspace = dir.0.join("workspace");
+    fs::create_dir_all(workspace.join("data")).unwrap();
+    unix::fs::symlink(&dir.0, workspace.join("parent")).unwrap();
+    let handle = launch_fake_repl(FakeScript::new())
+        .with_magics(Magics::new().sandbox(SandboxProfile::default().workspace(&workspace)));
+
+    // Whether the path exists outside is not told either
+    for target in [
+        "..",
+        "parent",
+        "/",
+        "/does/not/exist",
+        "data/../../workspace",
+    ] {
+        let (result, _, stderr) = execute(&handle, &format!("%cd {target}")).await;
+        expect_that!(result, err(pat!(ReplError::Failed)));
+        expect_that!(
+            stderr,
+            eq(format!("cd: {target}: outside of the workspace\n"))
+        );
+    }
+
+    let (result, stdout, _) = execute(&handle, "%cd data").await;
+    expect_that!(result, ok(anything()));
+    expect_that!(
+        stdout,
+        eq(format!("{}\n", workspace.join("data").display()))
+    );
+    let (result, stdout, _) = execute(&handle, "%cd ..").await;
+    expect_that!(result, ok(anything()));
+    expect_that!(stdout, eq(format!("{}\n", workspace.display())));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn magics_stop_the_cell_when_they_fail() {
+    let script = FakeScript::new();
+    let handle = launch_fake_repl(script.clone()).with_magics(Magics::new());
+
+    let (result, _, stderr) = execute(&handle, "before\n%cd /does/not/exist\nafter").await;
+
+    expect_that!(result, err(pat!(ReplError::Failed)));
+    expect_that!(stderr, starts_with("cd: /does/not/exist: "));
+    expect_that!(script.calls(), elements_are![eq("before")]);
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernelspec_runs_magics_in_front_of_its_interpreter() {
+    let spec: KernelSpec = serde_json::from_str(
+        r#"{
+            "display_name": "Bash",
+            "language": "bash",
+            "argv": ["bash"],
+            "repl": { "type": "bash" },
+            "env": { "GREETING": "hello" },
+            "magics": true
+        }"#,
+    )
+    .unwrap();
+    let handle = spec.launch_repl(&env::temp_dir()).unwrap();
+
+    let (result, stdout, _) = execute(&handle, "x=1\n%time sleep 0.1\necho $x").await;
+    expect_that!(result, ok(anything()));
+    expect_that!(stdout, matches_regex(r"^Wall time: [0-9.]+ms\n1\n$"));
+
+    let (result, stdout, _) = execute(&handle, "%%sh\necho \"$GREETING\"").await;
+    expect_that!(result, ok(anything()));
+    expect_that!(stdout, eq("hello\n"));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernelspec_leaves_magics_to_its_interpreter_unless_asked() {
+    let spec: KernelSpec = serde_json::from_str(
+        r#"{
+            "display_name": "Bash",
+            "language": "bash",
+            "argv": ["bash"],
+            "repl": { "type": "bash" }
+        }"#,
+    )
+    .unwrap();
+    let handle = spec.launch_repl(&env::temp_dir()).unwrap();
+
+    let (result, stdout, _) = execute(&handle, "%time echo hi").await;
+    expect_that!(result, err(pat!(ReplError::Failed)));
+    expect_that!(stdout, not(contains_substring("Wall time")));
+}
+
+async fn execute(
+    handle: &ReplHandle,
+    code: &str,
+) -> (std::result::Result<(), ReplError>, String, String) {
+    let (io_sender, io_receiver) = mpsc::channel(8);
+    let (stderr_sender, stderr_receiver) = mpsc::channel(8);
+
+    let (result, stdout, stderr) = tokio::join!(
+        handle.execute_with_stderr(
+            code.to_string(),
+            io_sender,
+            Some(stderr_sender),
+            CancellationToken::new()
+        ),
+        take_all_output(io_receiver),
+        take_all_output(stderr_receiver),
+    );
+
+    (
+        result,
+        String::from_utf8_lossy(&stdout).into_owned(),
+        String::from_utf8_lossy(&stderr).into_owned(),
+    )
+}
+
+/// Directory removed once the test is done
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!("canal-magic-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        Self(dir.canonicalize().unwrap())
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
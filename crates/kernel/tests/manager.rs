@@ -0,0 +1,676 @@
+mod utils;
+
+use std::{env, fs, path::PathBuf, sync::Arc, time::Duration};
+
+use canal_kernel::{
+    event::{EventContent, ExecStatus, KernelEvent},
+    kernelspec::KernelRegistry,
+    manager::{
+        cull::CullPolicy,
+        pool::{render_pool_prometheus, PoolConfig, PoolStats},
+        KernelId, KernelInfo, KernelManager, KernelState, ManagerError, ManagerEvent,
+    },
+    monitor::{MonitorConfig, UsageResource, UsageSample, UsageWarning},
+    procfs,
+    test_util::take_all_stream,
+    KernelRequest, KernelResponse,
+};
+use googletest::prelude::*;
+use tokio::time::{sleep, timeout};
+use utils::create_request_exec;
+
+const BASH_SPEC: &str = r#"{
+    "display_name": "Bash",
+    "language": "bash",
+    "argv": ["bash"],
+    "repl": { "type": "bash" }
+}"#;
+
+#[googletest::test]
+#[tokio::test]
+async fn manager_lists_kernels_with_their_status_and_usage() {
+    let (manager, _dir) = manager("list");
+
+    let first = manager.start("bash").unwrap();
+    let second = manager.start("bash").unwrap();
+    expect_that!(second, not(eq(first)));
+
+    let kernels = manager.list();
+    expect_that!(
+        kernels,
+        elements_are![
+            pat!(KernelInfo {
+                id: eq(first),
+                spec_name: eq("bash"),
+                state: eq(KernelState::Idle),
+                restarts: eq(0),
+            }),
+            pat!(KernelInfo {
+                id: eq(second),
+                state: eq(KernelState::Idle),
+            }),
+        ]
+    );
+    expect_that!(kernels[0].pid, not(eq(kernels[1].pid)));
+    expect_that!(kernels[0].usage.processes, ge(1));
+    expect_that!(kernels[0].usage.rss_bytes, gt(0));
+
+    let (request, _io_receiver) = create_request_exec(1, "sleep 30");
+    manager.send(first, request).await.unwrap();
+    sleep(Duration::from_millis(200)).await;
+    expect_that!(
+        manager.info(first),
+        ok(pat!(KernelInfo {
+            state: eq(KernelState::Busy)
+        }))
+    );
+    // The sleep is a descendant of the interpreter
+    expect_that!(manager.info(first).unwrap().usage.processes, ge(2));
+    expect_that!(
+        manager.info(second),
+        ok(pat!(KernelInfo {
+            state: eq(KernelState::Idle)
+        }))
+    );
+
+    manager.interrupt(first).await.unwrap();
+    expect_that!(
+        manager.recv(first).await,
+        ok(some(pat!(KernelResponse::Cancelled(eq(1)))))
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn manager_routes_requests_by_kernel_id() {
+    let (manager, _dir) = manager("route");
+    let first = manager.start("bash").unwrap();
+    let second = manager.start("bash").unwrap();
+
+    expect_that!(execute(&manager, first, 1, "x=first").await, eq(""));
+    expect_that!(
+        execute(&manager, second, 1, "echo ${x:-unset}").await,
+        eq("unset\n")
+    );
+    expect_that!(execute(&manager, first, 2, "echo $x").await, eq("first\n"));
+
+    expect_that!(
+        manager.send(42, KernelRequest::Interrupt).await,
+        err(pat!(ManagerError::NotFound(eq(42))))
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn manager_restarts_kernels_with_a_new_interpreter() {
+    let (manager, _dir) = manager("restart");
+    let id = manager.start("bash").unwrap();
+    let pid = manager.info(id).unwrap().pid;
+    expect_that!(execute(&manager, id, 1, "x=1").await, eq(""));
+
+    manager.restart(id).await.unwrap();
+
+    expect_that!(
+        manager.info(id),
+        ok(pat!(KernelInfo {
+            state: eq(KernelState::Idle),
+            restarts: eq(1),
+            pid: not(eq(pid)),
+        }))
+    );
+    expect_that!(is_alive(pid), eq(false));
+    expect_that!(
+        execute(&manager, id, 2, "echo ${x:-unset}").await,
+        eq("unset\n")
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn manager_shuts_down_kernels_with_their_process_tree() {
+    let (manager, _dir) = manager("shutdown");
+    let first = manager.start("bash").unwrap();
+    let second = manager.start("bash").unwrap();
+    let third = manager.start("bash").unwrap();
+
+    // A background job outlives the cells, and is only killed with the whole tree
+    let job = execute(&manager, first, 1, "sleep 300 >/dev/null & echo $!").await;
+    let job: u32 = job.trim().parse().unwrap();
+    let pid = manager.info(first).unwrap().pid;
+    expect_that!(procfs::tree(pid), contains(eq(job)));
+
+    manager.shutdown(first).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    expect_that!(is_alive(pid), eq(false));
+    expect_that!(is_alive(job), eq(false));
+    expect_that!(
+        manager.info(first),
+        err(pat!(ManagerError::NotFound(eq(first))))
+    );
+
+    let pids = [second, third].map(|id| manager.info(id).unwrap().pid);
+    manager.shutdown_all().await;
+    expect_that!(manager.list(), empty());
+    expect_that!(pids.map(is_alive), eq([false, false]));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn manager_shuts_down_the_jobs_of_dead_kernels() {
+    let (manager, _dir) = manager("dead");
+    let id = manager.start("bash").unwrap();
+
+    let job = execute(&manager, id, 1, "sleep 300 >/dev/null & echo $!").await;
+    let job: u32 = job.trim().parse().unwrap();
+    let (request, _io_receiver) = create_request_exec(2, "exit 3");
+    manager.send(id, request).await.unwrap();
+    expect_that!(
+        manager.recv(id).await,
+        ok(some(pat!(KernelResponse::Failed(eq(2)))))
+    );
+    sleep(Duration::from_millis(100)).await;
+
+    // Listing the kernel does not reap its interpreter, whose pid still leads the job's group
+    expect_that!(
+        manager.info(id),
+        ok(pat!(KernelInfo {
+            state: eq(KernelState::Dead)
+        }))
+    );
+    expect_that!(is_alive(job), eq(true));
+
+    timeout(Duration::from_secs(5), manager.shutdown(id))
+        .await
+        .expect("Dead kernel is not shut down in time")
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+    expect_that!(is_alive(job), eq(false));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn manager_shuts_down_the_children_of_dead_jupyter_kernels() {
+    let (manager, _dir) = manager("dead-jupyter");
+    let id = manager.start("stub").unwrap();
+
+    let child = execute(&manager, id, 1, "spawn").await;
+    let child: u32 = child.trim().parse().unwrap();
+    let (request, _io_receiver) = create_request_exec(2, "exit");
+    manager.send(id, request).await.unwrap();
+    expect_that!(
+        manager.recv(id).await,
+        ok(some(pat!(KernelResponse::Failed(eq(2)))))
+    );
+    expect_that!(is_alive(child), eq(true));
+
+    timeout(Duration::from_secs(5), manager.shutdown(id))
+        .await
+        .expect("Dead kernel is not shut down in time")
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+    expect_that!(is_alive(child), eq(false));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn manager_refuses_unknown_specs() {
+    let (manager, _dir) = manager("unknown");
+
+    expect_that!(
+        manager.start("cobol"),
+        err(pat!(ManagerError::Launch(anything())))
+    );
+    expect_that!(manager.list(), empty());
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn manager_culls_idle_kernels_after_announcing_it() {
+    let (manager, _dir) = manager("cull");
+    let mut events = manager.subscribe();
+    let idle = manager.start("bash").unwrap();
+    let pid = manager.info(idle).unwrap().pid;
+    sleep(Duration::from_millis(300)).await;
+    let recent = manager.start("bash").unwrap();
+
+    let culled = manager
+        .cull(&CullPolicy::new(Duration::from_millis(200)))
+        .await;
+
+    expect_that!(culled, elements_are![eq(idle)]);
+    expect_that!(
+        events.try_recv(),
+        ok(pat!(ManagerEvent::Culled {
+            id: eq(idle),
+            spec_name: eq("bash"),
+            idle_for: ge(Duration::from_millis(200)),
+        }))
+    );
+    expect_that!(is_alive(pid), eq(false));
+    expect_that!(
+        manager.list(),
+        elements_are![pat!(KernelInfo { id: eq(recent) })]
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn manager_keeps_busy_kernels_unless_configured() {
+    let (manager, _dir) = manager("cull-busy");
+    let id = manager.start("bash").unwrap();
+    let (request, _io_receiver) = create_request_exec(1, "sleep 30");
+    manager.send(id, request).await.unwrap();
+    sleep(Duration::from_millis(300)).await;
+
+    let policy = CullPolicy::new(Duration::from_millis(100));
+    expect_that!(manager.cull(&policy).await, empty());
+
+    sleep(Duration::from_millis(200)).await;
+    expect_that!(
+        manager.cull(&policy.cull_busy(true)).await,
+        elements_are![eq(id)]
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn manager_culls_busy_kernels_however_often_they_are_listed() {
+    let (manager, _dir) = manager("cull-listed");
+    let id = manager.start("bash").unwrap();
+    let (request, _io_receiver) = create_request_exec(1, "sleep 30");
+    manager.send(id, request).await.unwrap();
+
+    for _ in 0..6 {
+        sleep(Duration::from_millis(50)).await;
+        expect_that!(
+            manager.list(),
+            elements_are![pat!(KernelInfo {
+                state: eq(KernelState::Busy)
+            })]
+        );
+    }
+
+    let policy = CullPolicy::new(Duration::from_millis(200)).cull_busy(true);
+    expect_that!(manager.cull(&policy).await, elements_are![eq(id)]);
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn manager_keeps_connected_kernels_when_configured() {
+    let (manager, _dir) = manager("cull-connected");
+    let id = manager.start("bash").unwrap();
+    let connection = manager.connect(id).unwrap();
+    expect_that!(
+        manager.info(id),
+        ok(pat!(KernelInfo { connections: eq(1) }))
+    );
+    sleep(Duration::from_millis(300)).await;
+
+    let policy = CullPolicy::new(Duration::from_millis(200)).only_disconnected(true);
+    expect_that!(manager.cull(&policy).await, empty());
+
+    // Disconnecting counts as activity
+    drop(connection);
+    expect_that!(manager.cull(&policy).await, empty());
+
+    sleep(Duration::from_millis(300)).await;
+    expect_that!(manager.cull(&policy).await, elements_are![eq(id)]);
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn manager_culls_in_the_background() {
+    let (manager, _dir) = manager("culler");
+    let manager = Arc::new(manager);
+    let mut events = manager.subscribe();
+    let id = manager.start("bash").unwrap();
+
+    let policy = CullPolicy::new(Duration::from_millis(200)).interval(Duration::from_millis(50));
+    let culler = manager.spawn_culler(policy);
+
+    expect_that!(
+        timeout(Duration::from_secs(5), events.recv()).await,
+        ok(ok(pat!(ManagerEvent::Culled { id: eq(id) })))
+    );
+    while !manager.list().is_empty() {
+        sleep(Duration::from_millis(10)).await;
+    }
+
+    culler.abort();
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn manager_hands_out_warmed_up_kernels_from_its_pool() {
+    let (manager, _dir) = manager("pool");
+    manager
+        .set_pool("bash", PoolConfig::new(2).warm_up("x=warm"))
+        .unwrap();
+    wait_for_ready(&manager, 2).await;
+
+    let id = manager.start("bash").unwrap();
+    expect_that!(execute(&manager, id, 1, "echo $x").await, eq("warm\n"));
+    expect_that!(
+        manager.info(id),
+        ok(pat!(KernelInfo {
+            state: eq(KernelState::Idle),
+            restarts: eq(0),
+        }))
+    );
+
+    // The taken kernel is replaced in the background
+    wait_for_ready(&manager, 2).await;
+    expect_that!(
+        manager.pool_stats(),
+        elements_are![pat!(PoolStats {
+            spec_name: eq("bash"),
+            size: eq(2),
+            ready: eq(2),
+            hits: eq(1),
+            misses: eq(0),
+        })]
+    );
+    expect_that!(manager.list(), len(eq(1)));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn manager_launches_kernels_when_its_pool_is_empty() {
+    let (manager, _dir) = manager("pool-miss");
+    manager
+        .set_pool("bash", PoolConfig::new(1).warm_up("sleep 0.5; x=warm"))
+        .unwrap();
+
+    let missed = manager.start("bash").unwrap();
+    expect_that!(
+        execute(&manager, missed, 1, "echo ${x:-cold}").await,
+        eq("cold\n")
+    );
+    wait_for_ready(&manager, 1).await;
+    let hit = manager.start("bash").unwrap();
+    expect_that!(execute(&manager, hit, 1, "echo $x").await, eq("warm\n"));
+
+    let stats = manager.pool_stats();
+    expect_that!(
+        stats,
+        elements_are![pat!(PoolStats {
+            hits: eq(1),
+            misses: eq(1),
+        })]
+    );
+    expect_that!(
+        render_pool_prometheus(&stats),
+        contains_substring("canal_kernel_pool_hits_total{spec=\"bash\"} 1\n")
+            .and(contains_substring(
+                "canal_kernel_pool_misses_total{spec=\"bash\"} 1\n"
+            ))
+            .and(contains_substring("# TYPE canal_kernel_pool_ready gauge\n"))
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn manager_replaces_pooled_kernels_older_than_max_age() {
+    let (manager, dir) = manager("pool-expiry");
+    let pids = dir.0.join("pids");
+    manager
+        .set_pool(
+            "bash",
+            PoolConfig::new(1)
+                .warm_up(format!("echo $$ >> {}", pids.display()))
+                .max_age(Duration::from_millis(300)),
+        )
+        .unwrap();
+
+    sleep(Duration::from_millis(1000)).await;
+
+    let pids: Vec<u32> = fs::read_to_string(pids)
+        .unwrap()
+        .lines()
+        .map(|pid| pid.parse().unwrap())
+        .collect();
+    expect_that!(pids, len(ge(2)));
+    expect_that!(is_alive(pids[0]), eq(false));
+    expect_that!(manager.pool_stats()[0].hits, eq(0));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn manager_kills_pooled_kernels_with_their_pool() {
+    let (manager, dir) = manager("pool-remove");
+    let pids = dir.0.join("pids");
+    manager
+        .set_pool(
+            "bash",
+            PoolConfig::new(1).warm_up(format!("echo $$ >> {}", pids.display())),
+        )
+        .unwrap();
+    wait_for_ready(&manager, 1).await;
+    let pid: u32 = fs::read_to_string(&pids).unwrap().trim().parse().unwrap();
+
+    manager.remove_pool("bash");
+    sleep(Duration::from_millis(100)).await;
+    expect_that!(is_alive(pid), eq(false));
+    expect_that!(manager.pool_stats(), empty());
+
+    expect_that!(
+        manager.set_pool("cobol", PoolConfig::new(1)),
+        err(pat!(ManagerError::Launch(anything())))
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn manager_kills_kernels_warming_up_with_their_pool() {
+    let (manager, dir) = manager("pool-warming");
+    let pids = dir.0.join("pids");
+    manager
+        .set_pool(
+            "bash",
+            PoolConfig::new(1).warm_up(format!("echo $$ >> {}; sleep 30", pids.display())),
+        )
+        .unwrap();
+    let pid = timeout(Duration::from_secs(10), async {
+        loop {
+            if let Ok(pid) = fs::read_to_string(&pids).unwrap_or_default().trim().parse() {
+                break pid;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Pooled kernel does not warm up in time");
+
+    manager.remove_pool("bash");
+    sleep(Duration::from_millis(100)).await;
+    expect_that!(is_alive(pid), eq(false));
+}
+
+/// Removes the kernels directory when dropped
+struct KernelsDir(PathBuf);
+
+impl Drop for KernelsDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn manager_monitors_the_usage_of_interpreters() {
+    let (manager, _dir) = manager("monitor");
+    let manager = manager.monitor(MonitorConfig::new(Duration::from_millis(100)));
+    let id = manager.start("bash").unwrap();
+    let idle = manager.start("bash").unwrap();
+    let mut events = manager.sender(id).unwrap().subscribe();
+
+    let (request, _io_receiver) = create_request_exec(
+        1,
+        "end=$((SECONDS+2)); while [ $SECONDS -lt $end ]; do :; done",
+    );
+    manager.send(id, request).await.unwrap();
+    let busy = timeout(Duration::from_secs(5), async {
+        while let Some(event) = events.recv().await {
+            if let EventContent::Usage(sample) = event.content {
+                if sample.cpu_percent > 50.0 {
+                    return sample;
+                }
+            }
+        }
+        panic!("Kernel is gone");
+    })
+    .await;
+    expect_that!(busy, ok(pat!(UsageSample { processes: ge(1) })));
+
+    let sample = manager.info(id).unwrap().sample.unwrap();
+    expect_that!(sample.rss_bytes, gt(0));
+    expect_that!(sample.peak_rss_bytes, ge(sample.rss_bytes));
+    expect_that!(sample.threads, ge(1));
+    expect_that!(sample.processes, ge(1));
+    // Every kernel is sampled by the monitor of the manager
+    expect_that!(
+        manager.info(idle),
+        ok(pat!(KernelInfo {
+            sample: some(pat!(UsageSample { rss_bytes: gt(0) }))
+        }))
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn manager_keeps_usage_samples_out_of_the_replayed_events() {
+    let (manager, _dir) = manager("monitor-replay");
+    let manager = manager.monitor(MonitorConfig::new(Duration::from_millis(10)));
+    let id = manager.start("bash").unwrap();
+    let sender = manager.sender(id).unwrap();
+    let mut events = sender.subscribe();
+
+    execute(&manager, id, 1, "echo 1").await;
+    let sample = timeout(Duration::from_secs(5), async {
+        loop {
+            let event = events.recv().await.expect("Kernel is gone");
+            if let EventContent::Usage(_) = event.content {
+                return event;
+            }
+        }
+    })
+    .await;
+    expect_that!(
+        sample,
+        ok(pat!(KernelEvent {
+            seq: none(),
+            parent: none(),
+            client_id: none(),
+        }))
+    );
+
+    // Samples take no place among the replayed events, which follow each other
+    execute(&manager, id, 2, "echo 2").await;
+    let mut resumed = sender.resume(0);
+    let replayed = timeout(Duration::from_secs(5), async {
+        let mut replayed = Vec::new();
+        while let Some(event) = resumed.recv().await {
+            if event.seq.is_some() {
+                replayed.push((event.seq, event.parent));
+            }
+            if let (Some(2), EventContent::Status(ExecStatus::Succeeded)) =
+                (event.parent, event.content)
+            {
+                break;
+            }
+        }
+        replayed
+    })
+    .await;
+    expect_that!(
+        replayed,
+        ok(eq((0..8)
+            .map(|seq| (Some(seq), Some(seq as u32 / 4 + 1)))
+            .collect::<Vec<_>>()))
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn manager_warns_about_usage_above_thresholds() {
+    let (manager, _dir) = manager("monitor-warning");
+    let manager = manager.monitor(
+        MonitorConfig::new(Duration::from_millis(50))
+            .memory_warning(1)
+            .threads_warning(1000),
+    );
+    let mut events = manager.subscribe();
+    let id = manager.start("bash").unwrap();
+
+    expect_that!(
+        timeout(Duration::from_secs(5), events.recv()).await,
+        ok(ok(pat!(ManagerEvent::UsageWarning {
+            id: eq(id),
+            warning: pat!(UsageWarning {
+                resource: eq(UsageResource::Memory),
+                sample: pat!(UsageSample { rss_bytes: gt(1) }),
+            }),
+        })))
+    );
+
+    // Only crossing a threshold is warned about
+    sleep(Duration::from_millis(300)).await;
+    expect_that!(events.try_recv(), err(anything()));
+}
+
+fn manager(name: &str) -> (KernelManager, KernelsDir) {
+    let dir = env::temp_dir().join(format!(
+        "canal-kernel-manager-{name}-{}",
+        std::process::id()
+    ));
+    fs::create_dir_all(dir.join("bash")).unwrap();
+    fs::write(dir.join("bash").join("kernel.json"), BASH_SPEC).unwrap();
+    let stub_spec = format!(
+        r#"{{"display_name": "Stub", "language": "stub", "argv": ["{}", "{{connection_file}}"]}}"#,
+        env!("CARGO_BIN_EXE_stub_jupyter_kernel")
+    );
+    fs::create_dir_all(dir.join("stub")).unwrap();
+    fs::write(dir.join("stub").join("kernel.json"), stub_spec).unwrap();
+
+    let manager = KernelManager::new(KernelRegistry::from_dirs([dir.clone()]), 10);
+
+    (manager, KernelsDir(dir))
+}
+
+/// Waits until `ready` kernels are in the pool of the bash spec
+async fn wait_for_ready(manager: &KernelManager, ready: usize) {
+    timeout(Duration::from_secs(10), async {
+        while manager.pool_stats()[0].ready < ready {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Pool is not filled in time");
+}
+
+/// Runs `code` on the kernel `id`, returning its output once it succeeded
+async fn execute(manager: &KernelManager, id: KernelId, message_id: u32, code: &str) -> String {
+    let (request, io_receiver) = create_request_exec(message_id, code);
+    manager.send(id, request).await.unwrap();
+
+    let output = take_all_stream(io_receiver).await;
+    assert_eq!(
+        manager.recv(id).await.unwrap(),
+        Some(KernelResponse::Success(message_id))
+    );
+
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+/// Whether the process is running, zombies being as good as dead
+fn is_alive(pid: u32) -> bool {
+    fs::read_to_string(format!("/proc/{pid}/stat"))
+        .map(|stat| {
+            !stat
+                .rsplit(')')
+                .next()
+                .unwrap_or_default()
+                .starts_with(" Z")
+        })
+        .unwrap_or(false)
+}
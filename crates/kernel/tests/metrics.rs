@@ -0,0 +1,132 @@
+mod utils;
+
+use std::time::Duration;
+
+use canal_kernel::{
+    kernel::{self, KernelOptions, KernelTerminal},
+    metrics::{self, Histogram, MetricsSnapshot},
+    KernelResponse,
+};
+use googletest::prelude::*;
+use utils::{launch_mock_repl, ExecRequest};
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_counts_executions_by_result() {
+    let mut terminal = launch_terminal(KernelOptions::new(10));
+    let (request1, _io_receiver1) = ExecRequest::new(1, "1").client_id(1).build();
+    let (request2, _io_receiver2) = ExecRequest::new(2, "buggy").client_id(1).build();
+    let (request3, _io_receiver3) = ExecRequest::new(3, "3").client_id(1).build();
+
+    terminal.send(request1).await;
+    terminal.recv().await.unwrap();
+    terminal.send(request2).await;
+    terminal.send(request3).await;
+    terminal.recv().await.unwrap();
+    terminal.recv().await.unwrap();
+
+    expect_that!(
+        terminal.metrics(),
+        pat!(MetricsSnapshot {
+            succeeded: eq(1),
+            failed: eq(1),
+            cancelled: eq(1),
+            timed_out: eq(0),
+        })
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_interrupts_executions_exceeding_the_timeout() {
+    let options = KernelOptions::new(10).exec_timeout(Duration::from_millis(50));
+    let mut terminal = launch_terminal(options);
+    let (request1, _io_receiver1) = ExecRequest::new(1, "expensive").client_id(1).build();
+    let (request2, _io_receiver2) = ExecRequest::new(2, "2").client_id(1).build();
+
+    terminal.send(request1).await;
+    expect_that!(
+        terminal.recv().await,
+        some(pat!(KernelResponse::TimedOut(eq(1))))
+    );
+
+    // The kernel is still usable after a timeout
+    terminal.send(request2).await;
+    expect_that!(
+        terminal.recv().await,
+        some(pat!(KernelResponse::Success(eq(2))))
+    );
+    expect_that!(
+        terminal.metrics(),
+        pat!(MetricsSnapshot {
+            succeeded: eq(1),
+            timed_out: eq(1),
+        })
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_records_duration_wait_and_output_of_executions() {
+    let mut terminal = launch_terminal(KernelOptions::new(10));
+    let (request1, _io_receiver1) = ExecRequest::new(1, "12345").client_id(1).build();
+    let (request2, _io_receiver2) = ExecRequest::new(2, "1").client_id(1).build();
+
+    terminal.send(request1).await;
+    terminal.send(request2).await;
+    terminal.recv().await.unwrap();
+    terminal.recv().await.unwrap();
+
+    let metrics = terminal.metrics();
+
+    expect_that!(metrics.queue_wait.count, eq(2));
+    expect_that!(metrics.exec_duration.count, eq(2));
+    expect_that!(
+        metrics.output_bytes,
+        pat!(Histogram {
+            count: eq(2),
+            sum: eq(6.0),
+        })
+    );
+    // Both outputs are under the smallest bucket
+    expect_that!(metrics.output_bytes.counts[0], eq(2));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn metrics_are_rendered_in_prometheus_format() {
+    let mut terminal = launch_terminal(KernelOptions::new(10));
+    let (request, _io_receiver) = ExecRequest::new(1, "1").client_id(1).build();
+
+    terminal.send(request).await;
+    terminal.recv().await.unwrap();
+
+    let snapshot = terminal.metrics();
+    let text = metrics::render_prometheus([(&[("kernel", "python-1")][..], &snapshot)]);
+
+    expect_that!(
+        text.lines().collect::<Vec<_>>(),
+        contains_each![
+            eq("# TYPE canal_kernel_queue_wait_seconds histogram"),
+            eq("# TYPE canal_kernel_exec_duration_seconds histogram"),
+            eq("# TYPE canal_kernel_output_bytes histogram"),
+            eq("canal_kernel_output_bytes_bucket{kernel=\"python-1\",le=\"64\"} 1"),
+            eq("canal_kernel_output_bytes_bucket{kernel=\"python-1\",le=\"+Inf\"} 1"),
+            eq("canal_kernel_output_bytes_sum{kernel=\"python-1\"} 1"),
+            eq("canal_kernel_output_bytes_count{kernel=\"python-1\"} 1"),
+            eq("# TYPE canal_kernel_executions_total counter"),
+            eq("canal_kernel_executions_total{kernel=\"python-1\",result=\"success\"} 1"),
+            eq("canal_kernel_executions_total{kernel=\"python-1\",result=\"failed\"} 0"),
+        ]
+    );
+    expect_that!(
+        snapshot.to_prometheus(),
+        contains_substring("canal_kernel_executions_total{result=\"success\"} 1")
+    );
+}
+
+fn launch_terminal(options: KernelOptions) -> KernelTerminal {
+    let (terminal, _queue) = kernel::launch_with(launch_mock_repl(), options);
+
+    terminal
+}
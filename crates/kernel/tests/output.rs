@@ -0,0 +1,164 @@
+mod utils;
+
+use std::{env, fs};
+
+use canal_kernel::{
+    kernel::{self, KernelTerminal},
+    output::{Output, OutputPolicy},
+    terminal::{Style, StyledSpan, TerminalMode},
+    test_util::take_all_outputs,
+    KernelResponse,
+};
+use googletest::prelude::*;
+use utils::{create_request_exec, launch_mock_repl, ExecRequest};
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_delivers_the_whole_output_when_blocking() {
+    let mut terminal = launch_terminal(10);
+    let code = "a".repeat(100);
+    let (request, io_receiver) = create_request_exec(1, &code);
+
+    terminal.send(request).await;
+    let response = terminal.recv().await.unwrap();
+
+    expect_that!(response, pat!(KernelResponse::Success(pat!(1))));
+    expect_that!(
+        take_all_outputs(io_receiver).await,
+        elements_are![eq(Output::Stream(code.into()))]
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_drops_the_middle_of_a_long_output() {
+    let mut terminal = launch_terminal(10);
+    let code = format!("{}{}{}", "h".repeat(10), "m".repeat(85), "t".repeat(5));
+    let policy = OutputPolicy::DropMiddle { head: 10, tail: 5 };
+    let (request, io_receiver) = ExecRequest::new(1, &code).output_policy(policy).build();
+
+    terminal.send(request).await;
+    let response = terminal.recv().await.unwrap();
+
+    expect_that!(response, pat!(KernelResponse::Success(pat!(1))));
+    expect_that!(
+        take_all_outputs(io_receiver).await,
+        elements_are![
+            eq(Output::Stream("h".repeat(10).into())),
+            eq(Output::Truncated {
+                bytes: 85,
+                spill_path: None
+            }),
+            eq(Output::Stream("t".repeat(5).into())),
+        ]
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_does_not_truncate_an_output_within_the_limit() {
+    let mut terminal = launch_terminal(10);
+    let policy = OutputPolicy::DropMiddle { head: 10, tail: 5 };
+    let (request, io_receiver) = ExecRequest::new(1, "short").output_policy(policy).build();
+
+    terminal.send(request).await;
+    terminal.recv().await.unwrap();
+
+    expect_that!(
+        take_all_outputs(io_receiver).await,
+        elements_are![eq(Output::Stream("short".into()))]
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_spills_the_rest_of_a_long_output_to_disk() {
+    let mut terminal = launch_terminal(10);
+    let dir = env::temp_dir().join(format!("canal-kernel-spill-{}", std::process::id()));
+    let code = format!("{}{}", "h".repeat(10), "s".repeat(90));
+    let policy = OutputPolicy::SpillToDisk {
+        limit: 10,
+        dir: dir.clone(),
+    };
+    let (request, io_receiver) = ExecRequest::new(1, &code).output_policy(policy).build();
+
+    terminal.send(request).await;
+    terminal.recv().await.unwrap();
+
+    let outputs = take_all_outputs(io_receiver).await;
+
+    assert_that!(
+        outputs,
+        elements_are![
+            eq(Output::Stream("h".repeat(10).into())),
+            pat!(Output::Truncated {
+                bytes: eq(90),
+                spill_path: some(anything()),
+            }),
+        ]
+    );
+
+    let Output::Truncated {
+        spill_path: Some(spill_path),
+        ..
+    } = &outputs[1]
+    else {
+        unreachable!()
+    };
+
+    expect_that!(fs::read_to_string(spill_path).unwrap(), eq("s".repeat(90)));
+    expect_that!(
+        outputs[1].to_string(),
+        starts_with("output truncated 90 bytes")
+    );
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_drops_the_middle_of_a_long_styled_output() {
+    let mut terminal = launch_terminal(10);
+    let code = "\x1b[1mhead\x1b[0m-middle-\x1b[1mtail\x1b[0m\n";
+    let policy = OutputPolicy::DropMiddle { head: 5, tail: 5 };
+    let (request, io_receiver) = ExecRequest::new(1, code)
+        .output_policy(policy)
+        .terminal(TerminalMode::Styled)
+        .build();
+
+    terminal.send(request).await;
+    terminal.recv().await.unwrap();
+
+    let bold = Style {
+        bold: true,
+        ..Style::default()
+    };
+
+    expect_that!(
+        take_all_outputs(io_receiver).await,
+        elements_are![
+            eq(Output::Styled(vec![
+                span("head", bold),
+                span("-", Style::default())
+            ])),
+            eq(Output::Truncated {
+                bytes: 7,
+                spill_path: None
+            }),
+            eq(Output::Styled(vec![span("tail\n", bold)])),
+        ]
+    );
+}
+
+fn span(text: &str, style: Style) -> StyledSpan {
+    StyledSpan {
+        text: text.to_string(),
+        style,
+    }
+}
+
+fn launch_terminal(capacity: usize) -> KernelTerminal {
+    let (terminal, _queue) = kernel::launch(launch_mock_repl(), capacity);
+
+    terminal
+}
@@ -0,0 +1,240 @@
+mod utils;
+
+use std::{fs, sync::Arc, time::Duration};
+
+use canal_kernel::{
+    kernel::{self, KernelOptions, KernelTerminal},
+    polyglot::{Polyglot, Table, Value, ValueError, ValueStore},
+    repl::{
+        self,
+        bash::{self, BashRepl},
+        ReplHandle,
+    },
+    test_util::{launch_fake_repl, take_all_stream, FakeResponse, FakeScript},
+    KernelRequest, KernelResponse,
+};
+use googletest::prelude::*;
+use tokio::{sync::Mutex, time::sleep};
+use utils::{create_request_exec, mock_script, ExecRequest};
+
+#[googletest::test]
+#[tokio::test]
+async fn polyglot_kernel_routes_executions_by_language_in_order() {
+    let python = FakeScript::new().latency(Duration::from_millis(20));
+    let sql = FakeScript::new();
+    let polyglot = Polyglot::new("python", launch_fake_repl(python.clone()))
+        .language("sql", launch_fake_repl(sql.clone()));
+    let (mut terminal, _) = kernel::launch_polyglot(polyglot, KernelOptions::new(10));
+
+    let (request1, io_receiver1) = ExecRequest::new(1, "x = 1").language("python").build();
+    let (request2, io_receiver2) = ExecRequest::new(2, "SELECT 1").language("sql").build();
+    let (request3, io_receiver3) = create_request_exec(3, "y = 2");
+    terminal.send(request1).await;
+    terminal.send(request2).await;
+    terminal.send(request3).await;
+
+    let responses = [
+        terminal.recv().await,
+        terminal.recv().await,
+        terminal.recv().await,
+    ];
+    expect_that!(
+        responses,
+        elements_are![
+            some(eq(KernelResponse::Success(1))),
+            some(eq(KernelResponse::Success(2))),
+            some(eq(KernelResponse::Success(3))),
+        ]
+    );
+    expect_that!(
+        take_all_stream(io_receiver1).await,
+        is_utf8_string(eq("x = 1"))
+    );
+    expect_that!(
+        take_all_stream(io_receiver2).await,
+        is_utf8_string(eq("SELECT 1"))
+    );
+    expect_that!(
+        take_all_stream(io_receiver3).await,
+        is_utf8_string(eq("y = 2"))
+    );
+    expect_that!(python.calls(), elements_are![eq("x = 1"), eq("y = 2")]);
+    expect_that!(sql.calls(), elements_are![eq("SELECT 1")]);
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn polyglot_kernel_fails_executions_of_unknown_languages() {
+    let polyglot = Polyglot::new("python", launch_fake_repl(FakeScript::new()))
+        .language("sql", launch_fake_repl(FakeScript::new()));
+    let (mut terminal, _) = kernel::launch_polyglot(polyglot, KernelOptions::new(10));
+
+    let (request, io_receiver) = ExecRequest::new(1, "puts 1").language("ruby").build();
+    terminal.send(request).await;
+
+    expect_that!(terminal.recv().await, some(eq(KernelResponse::Failed(1))));
+    expect_that!(
+        take_all_stream(io_receiver).await,
+        is_utf8_string(eq("No REPL runs ruby, the kernel runs python, sql\n"))
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn polyglot_kernel_interrupts_executions_of_every_language() {
+    let polyglot = Polyglot::new("python", launch_fake_repl(mock_script()))
+        .language("sql", launch_fake_repl(FakeScript::new()));
+    let (mut terminal, _) = kernel::launch_polyglot(polyglot, KernelOptions::new(10));
+
+    let (request1, _io_receiver1) = ExecRequest::new(1, "expensive").language("python").build();
+    let (request2, _io_receiver2) = ExecRequest::new(2, "SELECT 1").language("sql").build();
+    terminal.send(request1).await;
+    terminal.send(request2).await;
+    sleep(Duration::from_millis(50)).await;
+    terminal.send(KernelRequest::Interrupt).await;
+
+    expect_that!(
+        terminal.recv().await,
+        some(eq(KernelResponse::Cancelled(1)))
+    );
+    expect_that!(
+        terminal.recv().await,
+        some(eq(KernelResponse::Cancelled(2)))
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn polyglot_kernel_passes_values_between_repls() {
+    let python = FakeScript::new().on("print(total)", FakeResponse::new().output("42\n"));
+    let polyglot =
+        Polyglot::new("python", launch_fake_repl(python.clone())).language("bash", launch_bash());
+    let values = polyglot.values().clone();
+    let (mut terminal, _) = kernel::launch_polyglot(polyglot, KernelOptions::new(10));
+
+    let output = execute(
+        &mut terminal,
+        1,
+        Some("python"),
+        "%%set total\nprint(total)",
+    )
+    .await;
+    expect_that!(output, eq(""));
+    expect_that!(values.get("total"), some(eq(Value::Int(42))));
+
+    let output = execute(
+        &mut terminal,
+        2,
+        Some("bash"),
+        "%%set scores --csv\necho 'name,score'\necho '\"Doe, J\",{{total}}'",
+    )
+    .await;
+    expect_that!(output, eq(""));
+    expect_that!(
+        values.get("scores"),
+        some(eq(Value::Table(Table {
+            columns: vec!["name".to_string(), "score".to_string()],
+            rows: vec![vec!["Doe, J".to_string(), "42".to_string()]],
+        })))
+    );
+
+    let output = execute(&mut terminal, 3, Some("bash"), "tail -n 1 {{scores}}").await;
+    expect_that!(output, eq("\"Doe, J\",42\n"));
+
+    let output = execute(&mut terminal, 4, None, "load({{scores:msgpack}})").await;
+    let path = output
+        .strip_prefix("load(")
+        .and_then(|path| path.strip_suffix(')'))
+        .unwrap();
+    expect_that!(
+        Value::from_msgpack(&fs::read(path).unwrap()),
+        ok(eq(values.get("scores").unwrap()))
+    );
+
+    // Code referring to unknown values is left as it is
+    let output = execute(&mut terminal, 5, None, "f'{{unknown}}'").await;
+    expect_that!(output, eq("f'{{unknown}}'"));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn polyglot_kernel_refuses_invalid_values() {
+    let polyglot = Polyglot::new("bash", launch_bash());
+    let values = polyglot.values().clone();
+    let (mut terminal, _) = kernel::launch_polyglot(polyglot, KernelOptions::new(10));
+
+    let (request, io_receiver) = create_request_exec(1, "%%set rows --csv\necho 'a,b'\necho '1'");
+    terminal.send(request).await;
+
+    expect_that!(terminal.recv().await, some(eq(KernelResponse::Failed(1))));
+    expect_that!(
+        take_all_stream(io_receiver).await,
+        is_utf8_string(eq(
+            "rows could not be set: CSV is invalid: row 1 has 1 fields, the header has 2\n"
+        ))
+    );
+    expect_that!(values.names(), empty());
+    expect_that!(
+        values.set("../escape", Value::Bool(true)),
+        err(pat!(ValueError::InvalidName(eq("../escape"))))
+    );
+}
+
+#[googletest::test]
+fn values_are_read_from_text_and_csv() {
+    expect_that!(Value::parse(" true\n"), eq(Value::Bool(true)));
+    expect_that!(Value::parse("-3\n"), eq(Value::Int(-3)));
+    expect_that!(Value::parse("2.5"), eq(Value::Float(2.5)));
+    expect_that!(
+        Value::parse("hello world\n"),
+        eq(Value::String("hello world".to_string()))
+    );
+
+    let table =
+        Table::from_csv("id,comment\r\n1,\"said \"\"hi\"\"\nthen left\"\r\n2,\r\n").unwrap();
+    expect_that!(
+        table,
+        eq(Table {
+            columns: vec!["id".to_string(), "comment".to_string()],
+            rows: vec![
+                vec!["1".to_string(), "said \"hi\"\nthen left".to_string()],
+                vec!["2".to_string(), String::new()],
+            ],
+        })
+    );
+    expect_that!(Table::from_csv(&table.to_csv()), ok(eq(table.clone())));
+
+    let store = ValueStore::new();
+    store.set("table", table.clone().into()).unwrap();
+    expect_that!(store.names(), elements_are![eq("table")]);
+    expect_that!(store.remove("table"), some(eq(Value::Table(table))));
+}
+
+/// Runs `code` and returns its output once it succeeded
+async fn execute(
+    terminal: &mut KernelTerminal,
+    message_id: u32,
+    language: Option<&str>,
+    code: &str,
+) -> String {
+    let mut exec = ExecRequest::new(message_id, code);
+    if let Some(language) = language {
+        exec = exec.language(language);
+    }
+    let (request, io_receiver) = exec.build();
+    terminal.send(request).await;
+
+    let output = take_all_stream(io_receiver).await;
+    assert_eq!(
+        terminal.recv().await,
+        Some(KernelResponse::Success(message_id))
+    );
+
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+fn launch_bash() -> ReplHandle {
+    let process = bash::command().spawn().unwrap();
+
+    repl::launch::<BashRepl>(Arc::new(Mutex::new(process)))
+}
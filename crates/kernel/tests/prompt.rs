@@ -0,0 +1,149 @@
+use std::{
+    os::unix::process::CommandExt,
+    process::{self, Command, Stdio},
+    sync::Arc,
+    time::Duration,
+};
+
+use canal_kernel::{
+    repl::{
+        self,
+        prompt::{PromptConfig, PromptRepl, Regex},
+        ReplError, ReplHandle,
+    },
+    test_util::take_all_output,
+};
+use googletest::prelude::*;
+use tokio::{
+    sync::{mpsc, Mutex},
+    task,
+    time::sleep,
+};
+use tokio_util::sync::CancellationToken;
+
+#[googletest::test]
+#[tokio::test]
+async fn prompt_repl_returns_the_output_without_banner_nor_prompts() {
+    let handle = launch_shell();
+
+    let (result, output) = execute(&handle, "echo hello").await;
+
+    expect_that!(result, ok(anything()));
+    expect_that!(output, eq("hello\n"));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn prompt_repl_executes_statements_spanning_several_lines() {
+    let handle = launch_shell();
+
+    let (result, output) = execute(&handle, "if true\nthen echo a\nfi\necho b").await;
+
+    expect_that!(result, ok(anything()));
+    expect_that!(output, eq("a\nb\n"));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn prompt_repl_fails_when_the_output_matches_the_error_pattern() {
+    let handle = launch_shell();
+
+    let (result, output) = execute(&handle, "ls /canal-does-not-exist").await;
+
+    expect_that!(result, pat!(Err(pat!(ReplError::Failed))));
+    expect_that!(output, contains_substring("No such file"));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn prompt_repl_abandons_incomplete_statements() {
+    let handle = launch_shell();
+
+    let (result, _) = execute(&handle, "if true").await;
+    expect_that!(result, pat!(Err(pat!(ReplError::Failed))));
+
+    // The interpreter is back to its primary prompt
+    let (result, output) = execute(&handle, "echo after").await;
+    expect_that!(result, ok(anything()));
+    expect_that!(output, eq("after\n"));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn prompt_repl_can_be_interrupted() {
+    let handle = Arc::new(launch_shell());
+    let (io_sender, io_receiver) = mpsc::channel(8);
+    let sigint = CancellationToken::new();
+    let sigint_job = sigint.clone();
+
+    let job = {
+        let handle = handle.clone();
+        task::spawn(async move {
+            handle
+                .execute(
+                    "echo before\nsleep 5\necho never".to_string(),
+                    io_sender,
+                    sigint_job,
+                )
+                .await
+        })
+    };
+
+    sleep(Duration::from_millis(200)).await;
+    sigint.cancel();
+
+    expect_that!(job.await.unwrap(), pat!(Err(pat!(ReplError::Interrupted))));
+    expect_that!(
+        take_all_output(io_receiver).await,
+        is_utf8_string(starts_with("before\n"))
+    );
+
+    let (result, output) = execute(&handle, "echo after").await;
+    expect_that!(result, ok(anything()));
+    expect_that!(output, eq("after\n"));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn prompt_repl_fails_once_the_interpreter_exited() {
+    let handle = launch_shell();
+
+    let (result, _) = execute(&handle, "exit").await;
+    expect_that!(result, pat!(Err(pat!(ReplError::Failed))));
+
+    let (result, _) = execute(&handle, "echo after").await;
+    expect_that!(result, pat!(Err(pat!(ReplError::Failed))));
+}
+
+async fn execute(handle: &ReplHandle, code: &str) -> (std::result::Result<(), ReplError>, String) {
+    let (io_sender, io_receiver) = mpsc::channel(8);
+
+    let (result, output) = tokio::join!(
+        handle.execute(code.to_string(), io_sender, CancellationToken::new()),
+        take_all_output(io_receiver),
+    );
+
+    (result, String::from_utf8_lossy(&output).into_owned())
+}
+
+fn launch_shell() -> ReplHandle {
+    let config = PromptConfig::new(Regex::new(r"canal\$ $").unwrap())
+        .continuation(Regex::new(r"canal> $").unwrap())
+        .error(Regex::new(r"No such file").unwrap());
+
+    repl::launch_with::<PromptRepl>(Arc::new(Mutex::new(spawn_shell())), config)
+}
+
+/// Interactive shell prompting on stdout, in its own process group so that an interrupt reaches
+/// the command it runs
+fn spawn_shell() -> process::Child {
+    Command::new("sh")
+        .args(["-c", "exec sh -i 2>&1"])
+        .env("PS1", "canal$ ")
+        .env("PS2", "canal> ")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .process_group(0)
+        .spawn()
+        .unwrap()
+}
@@ -0,0 +1,110 @@
+mod utils;
+
+use std::{sync::Arc, time::Duration};
+
+use canal_kernel::{
+    kernel::{self, KernelTerminal},
+    queue::{KernelQueue, Lane, QueueStatus, RunningExec},
+    KernelRequest, KernelResponse,
+};
+use googletest::prelude::*;
+use tokio::time::sleep;
+use utils::{create_request_exec, launch_mock_repl, ExecRequest};
+
+#[googletest::test]
+#[tokio::test]
+async fn queue_lists_queued_and_running_executions_in_order() {
+    let (terminal, queue) = launch_terminal(10);
+    let (request1, _io_receiver1) = create_request_exec(99, "expensive");
+    let (request2, _io_receiver2) = create_request_exec(2, "2");
+    let (request3, _io_receiver3) = create_request_exec(3, "3");
+
+    terminal.send(request1).await;
+    terminal.send(request2).await;
+    terminal.send(request3).await;
+
+    // sleep is needed to wait the request being executed
+    sleep(Duration::from_millis(10)).await;
+
+    let status = queue.status();
+
+    expect_that!(status.queued, elements_are![eq(2), eq(3)]);
+    expect_that!(status.running, some(field!(RunningExec.message_id, eq(99))));
+    expect_that!(status.capacity, eq(10));
+    expect_that!(status.available, eq(7));
+    expect_that!(status.wait.count, eq(1));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn queue_puts_interactive_executions_ahead_of_batch_executions() {
+    let (terminal, queue) = launch_terminal(10);
+    let (request1, _io_receiver1) = ExecRequest::new(99, "expensive").lane(Lane::Batch).build();
+    let (request2, _io_receiver2) = ExecRequest::new(2, "2").lane(Lane::Batch).build();
+    let (request3, _io_receiver3) = ExecRequest::new(3, "3").lane(Lane::Batch).build();
+    let (request4, _io_receiver4) = ExecRequest::new(4, "4").lane(Lane::Interactive).build();
+    let (request5, _io_receiver5) = ExecRequest::new(5, "5").lane(Lane::Interactive).build();
+
+    terminal.send(request1).await;
+    sleep(Duration::from_millis(10)).await;
+    terminal.send(request2).await;
+    terminal.send(request3).await;
+    terminal.send(request4).await;
+    terminal.send(request5).await;
+
+    sleep(Duration::from_millis(10)).await;
+
+    expect_that!(
+        queue.running(),
+        some(field!(RunningExec.message_id, eq(99)))
+    );
+    expect_that!(queue.queued(), elements_are![eq(4), eq(5), eq(2), eq(3)]);
+    expect_that!(queue.queued_in(Lane::Batch), elements_are![eq(2), eq(3)]);
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn queue_is_empty_when_all_executions_are_done() {
+    let (mut terminal, queue) = launch_terminal(10);
+    let (request1, _io_receiver1) = create_request_exec(1, "1");
+    let (request2, _io_receiver2) = create_request_exec(2, "2");
+
+    terminal.send(request1).await;
+    terminal.send(request2).await;
+    terminal.recv().await.unwrap();
+    terminal.recv().await.unwrap();
+
+    expect_that!(queue.queued(), empty());
+    expect_that!(queue.running(), none());
+    expect_that!(queue.available(), eq(10));
+    expect_that!(queue.wait_stats().count, eq(2));
+    expect_that!(queue.wait_stats().max, ge(queue.wait_stats().mean()));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_responds_with_queue_status_when_requested() {
+    let (mut terminal, _queue) = launch_terminal(10);
+    let (request1, _io_receiver1) = create_request_exec(99, "expensive");
+    let (request2, _io_receiver2) = create_request_exec(2, "2");
+
+    terminal.send(request1).await;
+    terminal.send(request2).await;
+
+    sleep(Duration::from_millis(10)).await;
+    terminal.send(KernelRequest::QueueStatus).await;
+
+    let response = terminal.recv().await.unwrap();
+
+    expect_that!(
+        response,
+        pat!(KernelResponse::QueueStatus(pat!(QueueStatus {
+            queued: elements_are![eq(2)],
+            running: some(field!(RunningExec.message_id, eq(99))),
+        })))
+    );
+}
+
+fn launch_terminal(capacity: usize) -> (KernelTerminal, Arc<KernelQueue>) {
+    kernel::launch(launch_mock_repl(), capacity)
+}
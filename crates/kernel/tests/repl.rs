@@ -1,9 +1,11 @@
-mod mock_repl;
 mod utils;
 
-use canal_kernel::repl::{self, ReplError, ReplHandle};
+use bytes::Bytes;
+use canal_kernel::{
+    repl::{self, ReplError, ReplHandle},
+    test_util::{launch_fake_repl, take_all_output, FakeRepl, FakeResponse, FakeScript},
+};
 use googletest::prelude::*;
-use mock_repl::MockRepl;
 use std::{sync::Arc, time::Duration};
 use tokio::{
     sync::{mpsc, Mutex},
@@ -11,7 +13,7 @@
     time::sleep,
 };
 use tokio_util::sync::CancellationToken;
-use utils::{spawn_dummy_repl, take_all_output};
+use utils::{launch_mock_repl, spawn_dummy_repl};
 
 // TODO: Test repl with mock repl process with stdin and stdout
 // see https://stackoverflow.com/questions/77120851/rust-mocking-stdprocesschild-for-test
@@ -20,7 +22,7 @@
 #[tokio::test]
 async fn repl_executes_a_code_in_mockrepl() {
     let handle = launch_repl();
-    let (io_sender, io_receiver) = mpsc::unbounded_channel();
+    let (io_sender, io_receiver) = mpsc::channel(8);
     let sigint = CancellationToken::new();
     let sigint_job = sigint.clone();
 
@@ -31,14 +33,14 @@ async fn repl_executes_a_code_in_mockrepl() {
     expect_that!(take_all_output(io_receiver).await, is_utf8_string(eq("1")));
 
     // Check the completion status of the REPL job
-    expect_that!(job.await.unwrap(), pat!(Ok(_)));
+    expect_that!(job.await.unwrap(), ok(anything()));
 }
 
 #[googletest::test]
 #[tokio::test]
 async fn repl_executes_a_buggy_code_in_mockrepl() {
     let handle = launch_repl();
-    let (io_sender, io_receiver) = mpsc::unbounded_channel();
+    let (io_sender, io_receiver) = mpsc::channel(8);
     let sigint = CancellationToken::new();
     let sigint_job = sigint.clone();
 
@@ -62,7 +64,7 @@ async fn repl_executes_a_buggy_code_in_mockrepl() {
 #[tokio::test]
 async fn repl_can_be_interupted_in_mockrepl() {
     let handle = launch_repl();
-    let (io_sender, io_receiver) = mpsc::unbounded_channel();
+    let (io_sender, io_receiver) = mpsc::channel(8);
     let sigint = CancellationToken::new();
     let sigint_job = sigint.clone();
 
@@ -85,8 +87,78 @@ async fn repl_can_be_interupted_in_mockrepl() {
     expect_that!(job.await.unwrap(), pat!(Err(pat!(ReplError::Interrupted))));
 }
 
-fn launch_repl() -> ReplHandle {
+#[googletest::test]
+#[tokio::test]
+async fn repl_launched_from_a_process_echoes_the_code_in_fakerepl() {
     let repl_process = Arc::new(Mutex::new(spawn_dummy_repl()));
-    let handle = repl::launch::<MockRepl>(repl_process);
-    handle
+    let handle = repl::launch::<FakeRepl>(repl_process);
+    let (io_sender, io_receiver) = mpsc::channel(8);
+
+    let result = handle
+        .execute("1 + 1".to_string(), io_sender, CancellationToken::new())
+        .await;
+
+    expect_that!(result, ok(anything()));
+    expect_that!(
+        take_all_output(io_receiver).await,
+        is_utf8_string(eq("1 + 1"))
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn repl_follows_its_script_in_fakerepl() {
+    let script = FakeScript::new()
+        .latency(Duration::from_millis(1))
+        .on("print", FakeResponse::new().output("a").output("b"))
+        .on("raise", FakeResponse::new().output("Traceback").fail())
+        .on("input()", FakeResponse::new().output("? ").hang());
+    let handle = launch_fake_repl(script.clone());
+
+    let (io_sender, io_receiver) = mpsc::channel(8);
+    let result = handle
+        .execute("print(1)".to_string(), io_sender, CancellationToken::new())
+        .await;
+    expect_that!(result, ok(anything()));
+    expect_that!(take_all_output(io_receiver).await, is_utf8_string(eq("ab")));
+
+    let (io_sender, io_receiver) = mpsc::channel(8);
+    let result = handle
+        .execute("raise".to_string(), io_sender, CancellationToken::new())
+        .await;
+    expect_that!(result, pat!(Err(pat!(ReplError::Failed))));
+    expect_that!(
+        take_all_output(io_receiver).await,
+        is_utf8_string(eq("Traceback"))
+    );
+
+    let (io_sender, io_receiver) = mpsc::channel(8);
+    let sigint = CancellationToken::new();
+    let sigint_job = sigint.clone();
+    let job = task::spawn(async move {
+        handle
+            .execute("input()".to_string(), io_sender, sigint_job)
+            .await
+    });
+    expect_that!(take_n_bytes(io_receiver, 2).await, is_utf8_string(eq("? ")));
+    sigint.cancel();
+    expect_that!(job.await.unwrap(), pat!(Err(pat!(ReplError::Interrupted))));
+
+    expect_that!(
+        script.calls(),
+        elements_are![eq("print(1)"), eq("raise"), eq("input()")]
+    );
+}
+
+async fn take_n_bytes(mut source: mpsc::Receiver<Bytes>, n: usize) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    while buffer.len() < n {
+        buffer.extend_from_slice(&source.recv().await.unwrap());
+    }
+
+    buffer
+}
+
+fn launch_repl() -> ReplHandle {
+    launch_mock_repl()
 }
@@ -0,0 +1,276 @@
+mod utils;
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use canal_kernel::{
+    kernel::{self, KernelOptions, KernelTerminal},
+    kernelspec::{KernelRegistry, KernelSpecError},
+    polyglot::{Polyglot, Table},
+    test_util::take_all_stream,
+    KernelRequest, KernelResponse,
+};
+use googletest::prelude::*;
+use serde_json::{json, Value};
+use tokio::time::sleep;
+use utils::create_request_exec;
+
+#[googletest::test]
+fn sandboxed_process_cannot_open_paths_outside_its_workspace() {
+    let dir = SandboxDir::create("paths");
+    let notes = dir.workspace().join("notes");
+    fs::write(&notes, "").unwrap();
+    let secret = dir.0.join("outside").join("secret");
+    fs::create_dir_all(secret.parent().unwrap()).unwrap();
+    fs::write(&secret, "secret").unwrap();
+
+    // The temporary directory holding the secret is hidden by default, like the home
+    // directories the test binaries may be in
+    let dummy_repl = dir.copy_to_workspace(env!("CARGO_BIN_EXE_dummy_repl"));
+    let registry = dir.registry(json!({
+        "argv": [dummy_repl, notes, secret, "/etc/passwd"],
+        "sandbox": {"workspace": dir.workspace()},
+    }));
+    let entry = registry.get("sandboxed").unwrap();
+    let output = entry
+        .spec
+        .command(&entry.resource_dir)
+        .unwrap()
+        .stdout(Stdio::piped())
+        .output()
+        .unwrap();
+
+    expect_that!(output.status.success(), eq(true));
+    expect_that!(
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .collect::<Vec<_>>(),
+        elements_are![
+            eq(format!("{}: read ok, write ok", notes.display())),
+            eq(format!("{}: read denied, write denied", secret.display())),
+            eq("/etc/passwd: read ok, write denied"),
+        ]
+    );
+    expect_that!(fs::read_to_string(secret).unwrap(), eq("secret"));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn sandboxed_bash_kernel_runs_and_is_interrupted() {
+    let dir = SandboxDir::create("bash");
+    let registry = dir.registry(json!({
+        "argv": ["bash"],
+        "repl": {"type": "bash"},
+        "sandbox": {"workspace": dir.workspace()},
+    }));
+
+    let (mut terminal, _queue) = registry
+        .launch("sandboxed", KernelOptions::new(10))
+        .unwrap();
+
+    // Bash is the first process of its pid namespace, and runs in the workspace
+    let (request, io_receiver) = create_request_exec(1, "echo $$; pwd; echo done > result");
+    terminal.send(request).await;
+    expect_that!(
+        terminal.recv().await,
+        some(pat!(KernelResponse::Success(eq(1))))
+    );
+    expect_that!(
+        take_all_stream(io_receiver).await,
+        is_utf8_string(eq(format!("1\n{}\n", dir.workspace().display())))
+    );
+    expect_that!(
+        fs::read_to_string(dir.workspace().join("result")).unwrap(),
+        eq("done\n")
+    );
+
+    // The seccomp filter refuses new namespaces
+    let (request, io_receiver) = create_request_exec(
+        2,
+        "touch /etc/canal-sandbox 2>/dev/null || echo read-only; unshare -U true 2>/dev/null || echo refused",
+    );
+    terminal.send(request).await;
+    expect_that!(
+        terminal.recv().await,
+        some(pat!(KernelResponse::Success(eq(2))))
+    );
+    expect_that!(
+        take_all_stream(io_receiver).await,
+        is_utf8_string(eq("read-only\nrefused\n"))
+    );
+
+    let (request, _io_receiver) = create_request_exec(3, "sleep 30");
+    terminal.send(request).await;
+    sleep(std::time::Duration::from_millis(300)).await;
+    terminal.send(KernelRequest::Interrupt).await;
+    expect_that!(
+        terminal.recv().await,
+        some(pat!(KernelResponse::Cancelled(eq(3))))
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn sandboxed_jupyter_kernel_reads_its_connection_file_and_keeps_the_network() {
+    let dir = SandboxDir::create("jupyter");
+    let stub = dir.copy_to_workspace(env!("CARGO_BIN_EXE_stub_jupyter_kernel"));
+    let registry = dir.registry(json!({
+        "argv": [stub, "{connection_file}"],
+        "interrupt_mode": "message",
+        "sandbox": {"workspace": dir.workspace()},
+    }));
+
+    let (mut terminal, _queue) = registry
+        .launch("sandboxed", KernelOptions::new(10))
+        .unwrap();
+    let (request, io_receiver) = create_request_exec(1, "result");
+    terminal.send(request).await;
+
+    expect_that!(
+        terminal.recv().await,
+        some(pat!(KernelResponse::Success(eq(1))))
+    );
+    expect_that!(
+        take_all_stream(io_receiver).await,
+        is_utf8_string(eq("42\n"))
+    );
+
+    let registry = dir.registry(json!({
+        "argv": [stub, "{connection_file}"],
+        "sandbox": {"workspace": dir.workspace(), "network": false},
+    }));
+    expect_that!(
+        registry.launch("sandboxed", KernelOptions::new(10)).err(),
+        some(pat!(KernelSpecError::SandboxNetwork))
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn sandboxed_jupyter_kernel_leaves_no_connection_file_in_its_workspace() {
+    let dir = SandboxDir::create("jupyter-connection");
+    let stub = dir.copy_to_workspace(env!("CARGO_BIN_EXE_stub_jupyter_kernel"));
+    let registry = dir.registry(json!({
+        "argv": [stub, "{connection_file}"],
+        "interrupt_mode": "message",
+        "sandbox": {"workspace": dir.workspace()},
+    }));
+
+    let (mut terminal, _queue) = registry
+        .launch("sandboxed", KernelOptions::new(10))
+        .unwrap();
+    let (request, _io_receiver) = create_request_exec(1, "result");
+    terminal.send(request).await;
+
+    expect_that!(
+        terminal.recv().await,
+        some(pat!(KernelResponse::Success(eq(1))))
+    );
+    expect_that!(
+        fs::read_dir(dir.workspace())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>(),
+        elements_are![eq(stub)]
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn sandboxed_polyglot_kernel_reads_values_from_its_workspace() {
+    let dir = SandboxDir::create("polyglot");
+    let registry = dir.registry(json!({
+        "argv": ["bash"],
+        "repl": {"type": "bash"},
+        "sandbox": {"workspace": dir.workspace()},
+    }));
+    let repl = registry.get("sandboxed").unwrap().launch_repl().unwrap();
+    let polyglot = Polyglot::new("bash", repl).values_dir(dir.workspace());
+    let values = polyglot.values().clone();
+    values
+        .set(
+            "scores",
+            Table {
+                columns: vec!["name".to_string(), "score".to_string()],
+                rows: vec![vec!["Doe".to_string(), "42".to_string()]],
+            }
+            .into(),
+        )
+        .unwrap();
+    let (mut terminal, _queue) = kernel::launch_polyglot(polyglot, KernelOptions::new(10));
+
+    let output = execute(&mut terminal, 1, "tail -n 1 {{scores}}").await;
+
+    expect_that!(output, eq("Doe,42\n"));
+    expect_that!(values.dir().starts_with(dir.workspace()), eq(true));
+}
+
+/// Runs `code` and returns its output once it succeeded
+async fn execute(terminal: &mut KernelTerminal, message_id: u32, code: &str) -> String {
+    let (request, io_receiver) = create_request_exec(message_id, code);
+    terminal.send(request).await;
+
+    let output = take_all_stream(io_receiver).await;
+    assert_eq!(
+        terminal.recv().await,
+        Some(KernelResponse::Success(message_id))
+    );
+
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+/// Kernels directory with a sandboxed kernelspec, removed when dropped
+struct SandboxDir(PathBuf);
+
+impl SandboxDir {
+    fn create(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!(
+            "canal-kernel-sandbox-{name}-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("workspace")).unwrap();
+
+        Self(dir)
+    }
+
+    fn workspace(&self) -> PathBuf {
+        self.0.join("workspace")
+    }
+
+    /// Copies the program at `path` to the workspace, the only directory of the host that the
+    /// default sandbox is sure to show
+    fn copy_to_workspace(&self, path: &str) -> PathBuf {
+        let copy = self.workspace().join(Path::new(path).file_name().unwrap());
+        fs::copy(path, &copy).unwrap();
+
+        copy
+    }
+
+    /// Registry of a spec named `sandboxed`, made of `fields` and a display name and language
+    fn registry(&self, fields: Value) -> KernelRegistry {
+        let kernels = self.0.join("kernels");
+        let mut spec = json!({"display_name": "Sandboxed", "language": "bash"});
+        spec.as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        write_spec(&kernels, "sandboxed", &spec.to_string());
+
+        KernelRegistry::from_dirs([kernels])
+    }
+}
+
+impl Drop for SandboxDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn write_spec(kernels_dir: &Path, name: &str, content: &str) {
+    let resource_dir = kernels_dir.join(name);
+    fs::create_dir_all(&resource_dir).unwrap();
+    fs::write(resource_dir.join("kernel.json"), content).unwrap();
+}
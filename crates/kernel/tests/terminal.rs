@@ -0,0 +1,207 @@
+mod utils;
+
+use canal_kernel::{
+    kernel::{self, KernelTerminal},
+    output::Output,
+    terminal::{Color, Style, StyledSpan, TerminalMode, MAX_LINE_LENGTH},
+    test_util::{launch_fake_repl, take_all_outputs, take_all_stream, FakeResponse, FakeScript},
+};
+use googletest::prelude::*;
+use std::time::Duration;
+use utils::{create_request_exec, launch_mock_repl, ExecRequest};
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_forwards_raw_output_unchanged() {
+    let mut terminal = launch_terminal(10);
+    let code = "10%\r\x1b[31m100%\x1b[0m\n";
+    let (request, io_receiver) = create_request_exec(1, code);
+
+    terminal.send(request).await;
+    terminal.recv().await.unwrap();
+
+    expect_that!(take_all_stream(io_receiver).await, is_utf8_string(eq(code)));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_collapses_carriage_return_overwrites() {
+    let mut terminal = launch_terminal(10);
+    let code = "start\n10%|#   |\r50%|##  |\r100%|####|\ndone";
+    let (request, io_receiver) = ExecRequest::new(1, code)
+        .terminal(TerminalMode::Strip)
+        .build();
+
+    terminal.send(request).await;
+    terminal.recv().await.unwrap();
+
+    expect_that!(
+        take_all_stream(io_receiver).await,
+        is_utf8_string(eq("start\n100%|####|\ndone"))
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_applies_backspaces_and_line_erasure() {
+    let mut terminal = launch_terminal(10);
+    let code = "abc\x08\x08X\nloading...\r\x1b[Kok\n";
+    let (request, io_receiver) = ExecRequest::new(1, code)
+        .terminal(TerminalMode::Strip)
+        .build();
+
+    terminal.send(request).await;
+    terminal.recv().await.unwrap();
+
+    expect_that!(
+        take_all_stream(io_receiver).await,
+        is_utf8_string(eq("aXc\nok\n"))
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_strips_escape_sequences() {
+    let mut terminal = launch_terminal(10);
+    let code = "\x1b]0;title\x07\x1b[1;31merror\x1b[0m: bad\n";
+    let (request, io_receiver) = ExecRequest::new(1, code)
+        .terminal(TerminalMode::Strip)
+        .build();
+
+    terminal.send(request).await;
+    terminal.recv().await.unwrap();
+
+    expect_that!(
+        take_all_stream(io_receiver).await,
+        is_utf8_string(eq("error: bad\n"))
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_keeps_colors_as_sgr_codes() {
+    let mut terminal = launch_terminal(10);
+    let code = "\x1b[31mred\x1b[39m\x1b[2K\r\x1b[32mgreen\x1b[m plain\n";
+    let (request, io_receiver) = ExecRequest::new(1, code)
+        .terminal(TerminalMode::Keep)
+        .build();
+
+    terminal.send(request).await;
+    terminal.recv().await.unwrap();
+
+    expect_that!(
+        take_all_stream(io_receiver).await,
+        is_utf8_string(eq("\x1b[0;32mgreen\x1b[0m plain\n"))
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_converts_colors_to_styled_spans() {
+    let mut terminal = launch_terminal(10);
+    let code = "\x1b[1;31merror\x1b[0m: \x1b[38;5;208mbad\x1b[0m\n";
+    let (request, io_receiver) = ExecRequest::new(1, code)
+        .terminal(TerminalMode::Styled)
+        .build();
+
+    terminal.send(request).await;
+    terminal.recv().await.unwrap();
+
+    let bold_red = Style {
+        bold: true,
+        foreground: Some(Color::Indexed(1)),
+        ..Style::default()
+    };
+    let orange = Style {
+        foreground: Some(Color::Indexed(208)),
+        ..Style::default()
+    };
+
+    expect_that!(
+        take_all_outputs(io_receiver).await,
+        elements_are![eq(Output::Styled(vec![
+            span("error", bold_red),
+            span(": ", Style::default()),
+            span("bad\n", orange),
+        ]))]
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_forwards_lines_longer_than_the_limit_in_parts() {
+    let mut terminal = launch_terminal(10);
+    let code = format!("{}\ry\n", "x".repeat(MAX_LINE_LENGTH + 10));
+    let (request, io_receiver) = ExecRequest::new(1, &code)
+        .terminal(TerminalMode::Strip)
+        .build();
+
+    terminal.send(request).await;
+    terminal.recv().await.unwrap();
+
+    // The carriage return only goes back to the start of the part still held
+    let expected = format!("{}y{}\n", "x".repeat(MAX_LINE_LENGTH), "x".repeat(9));
+    expect_that!(
+        take_all_stream(io_receiver).await,
+        is_utf8_string(eq(expected))
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_clamps_cursor_moves_to_the_line_limit() {
+    let mut terminal = launch_terminal(10);
+    let code = format!("a{}b\n", "\x1b[65535C".repeat(100));
+    let (request, io_receiver) = ExecRequest::new(1, &code)
+        .terminal(TerminalMode::Strip)
+        .build();
+
+    terminal.send(request).await;
+    terminal.recv().await.unwrap();
+
+    let expected = format!("a{}b\n", " ".repeat(MAX_LINE_LENGTH - 1));
+    expect_that!(
+        take_all_stream(io_receiver).await,
+        is_utf8_string(eq(expected))
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_forwards_an_idle_unterminated_line() {
+    let script = FakeScript::new().on(
+        "prompt",
+        FakeResponse::new()
+            .output("Name: ")
+            .sleep(Duration::from_secs(1))
+            .output("done\n"),
+    );
+    let (mut terminal, _queue) = kernel::launch(launch_fake_repl(script), 10);
+    let (request, io_receiver) = ExecRequest::new(1, "prompt")
+        .terminal(TerminalMode::Strip)
+        .build();
+
+    terminal.send(request).await;
+    terminal.recv().await.unwrap();
+
+    expect_that!(
+        take_all_outputs(io_receiver).await,
+        elements_are![
+            eq(Output::Stream("Name: ".into())),
+            eq(Output::Stream("done\n".into())),
+        ]
+    );
+}
+
+fn span(text: &str, style: Style) -> StyledSpan {
+    StyledSpan {
+        text: text.to_string(),
+        style,
+    }
+}
+
+fn launch_terminal(capacity: usize) -> KernelTerminal {
+    let (terminal, _queue) = kernel::launch(launch_mock_repl(), capacity);
+
+    terminal
+}
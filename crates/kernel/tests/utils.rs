@@ -1,8 +1,18 @@
 #![allow(dead_code)]
 
-use std::process::{self, Command};
+use std::{
+    process::{self, Command},
+    time::Duration,
+};
 
-use bytes::{BufMut, Bytes, BytesMut};
+use canal_kernel::{
+    output::{Output, OutputPolicy},
+    queue::Lane,
+    repl::ReplHandle,
+    terminal::TerminalMode,
+    test_util::{launch_fake_repl, FakeResponse, FakeScript},
+    ClientId, KernelRequest, MessageId,
+};
 use tokio::sync::mpsc;
 
 pub fn spawn_dummy_repl() -> process::Child {
@@ -11,11 +21,104 @@ pub fn spawn_dummy_repl() -> process::Child {
         .unwrap()
 }
 
-pub async fn take_all_output(mut source: mpsc::UnboundedReceiver<Bytes>) -> BytesMut {
-    let mut buffer = BytesMut::new();
-    while let Some(b) = source.recv().await {
-        buffer.put(b);
+/// REPL shared by the tests:
+/// - Buggy code contains `buggy` and produces output `error`
+/// - `expensive` simulates a long operation that outputs `partial...` then sleeps
+/// - Working code prints anything in code
+pub fn launch_mock_repl() -> ReplHandle {
+    launch_fake_repl(mock_script())
+}
+
+pub fn mock_script() -> FakeScript {
+    FakeScript::new()
+        .on("buggy", FakeResponse::new().output("error").fail())
+        .on(
+            "expensive",
+            FakeResponse::new()
+                .output("partial...")
+                .sleep(Duration::from_secs(5))
+                .output("...rest"),
+        )
+}
+
+/// `Execute` request of `code` by client 0, delivering its output as is and without limit
+pub fn create_request_exec(
+    message_id: MessageId,
+    code: &str,
+) -> (KernelRequest, mpsc::Receiver<Output>) {
+    ExecRequest::new(message_id, code).build()
+}
+
+/// Builds an `Execute` request along with the receiver of its output
+pub struct ExecRequest {
+    message_id: MessageId,
+    client_id: ClientId,
+    code: String,
+    output_policy: OutputPolicy,
+    terminal: TerminalMode,
+    lane: Lane,
+    language: Option<String>,
+    independent: bool,
+}
+
+impl ExecRequest {
+    pub fn new(message_id: MessageId, code: &str) -> Self {
+        Self {
+            message_id,
+            client_id: 0,
+            code: code.to_string(),
+            output_policy: OutputPolicy::Block,
+            terminal: TerminalMode::Raw,
+            lane: Lane::Interactive,
+            language: None,
+            independent: false,
+        }
+    }
+
+    pub fn client_id(mut self, client_id: ClientId) -> Self {
+        self.client_id = client_id;
+        self
+    }
+
+    pub fn output_policy(mut self, output_policy: OutputPolicy) -> Self {
+        self.output_policy = output_policy;
+        self
+    }
+
+    pub fn terminal(mut self, terminal: TerminalMode) -> Self {
+        self.terminal = terminal;
+        self
     }
 
-    buffer
+    pub fn lane(mut self, lane: Lane) -> Self {
+        self.lane = lane;
+        self
+    }
+
+    pub fn language(mut self, language: &str) -> Self {
+        self.language = Some(language.to_string());
+        self
+    }
+
+    pub fn independent(mut self, independent: bool) -> Self {
+        self.independent = independent;
+        self
+    }
+
+    pub fn build(self) -> (KernelRequest, mpsc::Receiver<Output>) {
+        let (io_sender, io_receiver) = mpsc::channel(8);
+        let message = KernelRequest::Execute {
+            message_id: self.message_id,
+            client_id: self.client_id,
+            code: self.code,
+            io_sender,
+            output_policy: self.output_policy,
+            terminal: self.terminal,
+            lane: self.lane,
+            language: self.language,
+            independent: self.independent,
+        };
+
+        (message, io_receiver)
+    }
 }
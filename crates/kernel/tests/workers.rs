@@ -0,0 +1,268 @@
+mod utils;
+
+use std::time::{Duration, Instant};
+
+use canal_kernel::{
+    kernel::{self, KernelOptions, KernelTerminal},
+    limits::ResourceLimit,
+    polyglot::{Polyglot, Value},
+    queue::KernelQueue,
+    repl::ReplHandle,
+    test_util::{launch_fake_repl, take_all_stream, FakeResponse, FakeScript},
+    KernelRequest, KernelResponse,
+};
+use googletest::prelude::*;
+use std::sync::Arc;
+use tokio::time::sleep;
+use utils::{create_request_exec, mock_script, ExecRequest};
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_runs_independent_execs_in_parallel_on_its_workers() {
+    let workers = FakeScript::new().on(
+        "sweep",
+        FakeResponse::new()
+            .sleep(Duration::from_millis(300))
+            .output("done"),
+    );
+    let (mut terminal, queue) = launch_with_workers(FakeScript::new(), &workers, 3);
+
+    let started_at = Instant::now();
+    let mut io_receivers = Vec::new();
+    for message_id in 1..=3 {
+        let (request, io_receiver) = ExecRequest::new(message_id, "sweep(p)")
+            .independent(true)
+            .build();
+        terminal.send(request).await;
+        io_receivers.push(io_receiver);
+    }
+    sleep(Duration::from_millis(100)).await;
+    expect_that!(queue.status().on_workers, len(eq(3)));
+    expect_that!(queue.running(), none());
+
+    let mut responses = Vec::new();
+    for _ in 1..=3 {
+        responses.push(terminal.recv().await.unwrap());
+    }
+
+    expect_that!(started_at.elapsed(), lt(Duration::from_millis(800)));
+    expect_that!(
+        responses,
+        unordered_elements_are![
+            eq(KernelResponse::Success(1)),
+            eq(KernelResponse::Success(2)),
+            eq(KernelResponse::Success(3)),
+        ]
+    );
+    for io_receiver in io_receivers {
+        expect_that!(
+            take_all_stream(io_receiver).await,
+            is_utf8_string(eq("done"))
+        );
+    }
+    expect_that!(workers.calls(), len(eq(3)));
+    expect_that!(queue.running_on_workers(), empty());
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_runs_ordinary_execs_in_order_on_its_primary_repl() {
+    let primary = FakeScript::new().latency(Duration::from_millis(20));
+    let workers = FakeScript::new().on(
+        "sweep",
+        FakeResponse::new().sleep(Duration::from_millis(200)),
+    );
+    let (mut terminal, _) = launch_with_workers(primary.clone(), &workers, 1);
+
+    let (request1, _io_receiver1) = ExecRequest::new(1, "sweep(1)").independent(true).build();
+    let (request2, _io_receiver2) = create_request_exec(2, "x = 1");
+    let (request3, _io_receiver3) = create_request_exec(3, "y = 2");
+    terminal.send(request1).await;
+    terminal.send(request2).await;
+    terminal.send(request3).await;
+
+    let responses = [
+        terminal.recv().await,
+        terminal.recv().await,
+        terminal.recv().await,
+    ];
+
+    // The ordinary execs do not wait for the independent one
+    expect_that!(
+        responses,
+        elements_are![
+            some(eq(KernelResponse::Success(2))),
+            some(eq(KernelResponse::Success(3))),
+            some(eq(KernelResponse::Success(1))),
+        ]
+    );
+    expect_that!(primary.calls(), elements_are![eq("x = 1"), eq("y = 2")]);
+    expect_that!(workers.calls(), elements_are![eq("sweep(1)")]);
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_interrupts_every_worker() {
+    let workers = mock_script();
+    let (mut terminal, queue) = launch_with_workers(mock_script(), &workers, 2);
+
+    let (request1, _io_receiver1) = ExecRequest::new(1, "expensive").independent(true).build();
+    let (request2, _io_receiver2) = ExecRequest::new(2, "expensive").independent(true).build();
+    let (request3, _io_receiver3) = create_request_exec(3, "expensive");
+    let (request4, _io_receiver4) = ExecRequest::new(4, "queued").independent(true).build();
+    terminal.send(request1).await;
+    terminal.send(request2).await;
+    terminal.send(request3).await;
+    terminal.send(request4).await;
+    sleep(Duration::from_millis(100)).await;
+    terminal.send(KernelRequest::Interrupt).await;
+
+    let mut responses = Vec::new();
+    for _ in 1..=4 {
+        responses.push(terminal.recv().await.unwrap());
+    }
+
+    expect_that!(
+        responses,
+        unordered_elements_are![
+            eq(KernelResponse::Cancelled(1)),
+            eq(KernelResponse::Cancelled(2)),
+            eq(KernelResponse::Cancelled(3)),
+            eq(KernelResponse::Cancelled(4)),
+        ]
+    );
+    expect_that!(workers.calls(), len(eq(2)));
+    expect_that!(queue.is_empty(), eq(true));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_keeps_queued_execs_when_an_independent_exec_fails() {
+    let (mut terminal, _) = launch_with_workers(FakeScript::new(), &mock_script(), 1);
+
+    let (request1, _io_receiver1) = ExecRequest::new(1, "buggy").independent(true).build();
+    let (request2, _io_receiver2) = ExecRequest::new(2, "sweep").independent(true).build();
+    terminal.send(request1).await;
+    terminal.send(request2).await;
+
+    expect_that!(terminal.recv().await, some(eq(KernelResponse::Failed(1))));
+    expect_that!(terminal.recv().await, some(eq(KernelResponse::Success(2))));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_without_workers_runs_independent_execs_in_order() {
+    let primary = FakeScript::new();
+    let mut terminal = kernel::launch(launch_fake_repl(primary.clone()), 10).0;
+
+    let (request1, _io_receiver1) = ExecRequest::new(1, "a").independent(true).build();
+    let (request2, _io_receiver2) = create_request_exec(2, "b");
+    terminal.send(request1).await;
+    terminal.send(request2).await;
+
+    expect_that!(terminal.recv().await, some(eq(KernelResponse::Success(1))));
+    expect_that!(terminal.recv().await, some(eq(KernelResponse::Success(2))));
+    expect_that!(primary.calls(), elements_are![eq("a"), eq("b")]);
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn polyglot_kernel_runs_independent_execs_on_the_workers_of_their_language() {
+    let python = FakeScript::new();
+    let sql = FakeScript::new();
+    let python_workers = FakeScript::new().on("print(total)", FakeResponse::new().output("42\n"));
+    let sql_workers = FakeScript::new();
+    let polyglot = Polyglot::new("python", launch_fake_repl(python.clone()))
+        .language("sql", launch_fake_repl(sql.clone()));
+    let values = polyglot.values().clone();
+    let options = KernelOptions::new(10)
+        .workers(vec![launch_fake_repl(python_workers.clone())])
+        .language_workers("sql", vec![launch_fake_repl(sql_workers.clone())]);
+    let (mut terminal, _) = kernel::launch_polyglot(polyglot, options);
+
+    let (request, _io_receiver) = ExecRequest::new(1, "%%set total\nprint(total)")
+        .language("python")
+        .independent(true)
+        .build();
+    terminal.send(request).await;
+    expect_that!(terminal.recv().await, some(eq(KernelResponse::Success(1))));
+    expect_that!(values.get("total"), some(eq(Value::Int(42))));
+
+    let (request, io_receiver) = ExecRequest::new(2, "SELECT {{total}}")
+        .language("sql")
+        .independent(true)
+        .build();
+    terminal.send(request).await;
+    expect_that!(terminal.recv().await, some(eq(KernelResponse::Success(2))));
+    expect_that!(
+        take_all_stream(io_receiver).await,
+        is_utf8_string(eq("SELECT 42"))
+    );
+
+    let (request, io_receiver) = ExecRequest::new(3, "puts 1")
+        .language("ruby")
+        .independent(true)
+        .build();
+    terminal.send(request).await;
+    expect_that!(terminal.recv().await, some(eq(KernelResponse::Failed(3))));
+    expect_that!(
+        take_all_stream(io_receiver).await,
+        is_utf8_string(eq("No worker runs ruby\n"))
+    );
+
+    expect_that!(python_workers.calls(), elements_are![eq("print(total)")]);
+    expect_that!(sql_workers.calls(), elements_are![eq("SELECT 42")]);
+    expect_that!(python.calls(), empty());
+    expect_that!(sql.calls(), empty());
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn kernel_drops_workers_killed_for_exceeding_a_limit() {
+    let workers = FakeScript::new().on("hog", FakeResponse::new().exceed(ResourceLimit::Memory));
+    let (mut terminal, _) = launch_with_workers(FakeScript::new(), &workers, 2);
+
+    let mut responses = Vec::new();
+    let mut outputs = Vec::new();
+    for (message_id, code) in [(1, "hog"), (2, "a"), (3, "b"), (4, "hog"), (5, "c")] {
+        let (request, io_receiver) = ExecRequest::new(message_id, code).independent(true).build();
+        terminal.send(request).await;
+        responses.push(terminal.recv().await.unwrap());
+        outputs.push(take_all_stream(io_receiver).await);
+    }
+
+    expect_that!(
+        responses,
+        elements_are![
+            eq(KernelResponse::LimitExceeded(1, ResourceLimit::Memory)),
+            eq(KernelResponse::Success(2)),
+            eq(KernelResponse::Success(3)),
+            eq(KernelResponse::LimitExceeded(4, ResourceLimit::Memory)),
+            eq(KernelResponse::Failed(5)),
+        ]
+    );
+    expect_that!(
+        outputs.last(),
+        some(is_utf8_string(eq("No worker is left\n")))
+    );
+    expect_that!(
+        workers.calls(),
+        elements_are![eq("hog"), eq("a"), eq("b"), eq("hog")]
+    );
+}
+
+/// Launches a kernel on a REPL following `primary`, with `count` workers sharing `workers`
+fn launch_with_workers(
+    primary: FakeScript,
+    workers: &FakeScript,
+    count: usize,
+) -> (KernelTerminal, Arc<KernelQueue>) {
+    let workers: Vec<ReplHandle> = (0..count)
+        .map(|_| launch_fake_repl(workers.clone()))
+        .collect();
+
+    kernel::launch_with(
+        launch_fake_repl(primary),
+        KernelOptions::new(10).workers(workers),
+    )
+}